@@ -0,0 +1,436 @@
+use std::path::Path;
+use std::str::FromStr;
+use image::{GrayImage, Luma};
+use imageproc::contours::{find_contours, BorderType};
+use nalgebra::{Matrix3, Point2, Vector2};
+use crate::calibration::checkerboard::{blurred_f32, refine_corner};
+use crate::geometry::{apply_homography, find_homography};
+use crate::util::dark_mask;
+
+mod predefined;
+
+/// OpenCV's predefined dictionaries, by marker size and number of markers.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PredefinedDictionary {
+    Dict4x4_50,
+    Dict4x4_100,
+    Dict4x4_250,
+    Dict4x4_1000,
+    Dict5x5_50,
+    Dict5x5_100,
+    Dict5x5_250,
+    Dict5x5_1000,
+    Dict6x6_50,
+    Dict6x6_100,
+    Dict6x6_250,
+    Dict6x6_1000,
+}
+
+impl PredefinedDictionary {
+    pub const ALL: [PredefinedDictionary; 12] = [
+        PredefinedDictionary::Dict4x4_50,
+        PredefinedDictionary::Dict4x4_100,
+        PredefinedDictionary::Dict4x4_250,
+        PredefinedDictionary::Dict4x4_1000,
+        PredefinedDictionary::Dict5x5_50,
+        PredefinedDictionary::Dict5x5_100,
+        PredefinedDictionary::Dict5x5_250,
+        PredefinedDictionary::Dict5x5_1000,
+        PredefinedDictionary::Dict6x6_50,
+        PredefinedDictionary::Dict6x6_100,
+        PredefinedDictionary::Dict6x6_250,
+        PredefinedDictionary::Dict6x6_1000,
+    ];
+
+    pub fn marker_size(self) -> u32 {
+        4 + self as u32 / 4
+    }
+
+    /// How many markers the dictionary has.
+    pub fn markers(self) -> usize {
+        [50, 100, 250, 1000][self as usize % 4]
+    }
+
+    /// The name OpenCV gives it, e.g. `DICT_4X4_50`.
+    pub fn name(self) -> String {
+        format!("DICT_{0}X{0}_{1}", self.marker_size(), self.markers())
+    }
+}
+
+impl FromStr for PredefinedDictionary {
+    type Err = crate::Error;
+
+    /// Takes OpenCV's names, with or without the `DICT_` prefix, in any case: `DICT_4X4_50` or
+    /// `4x4_50`.
+    fn from_str(s: &str) -> crate::Result<Self> {
+        let name = s.to_ascii_uppercase();
+        let name = name.strip_prefix("DICT_").unwrap_or(&name);
+        PredefinedDictionary::ALL
+            .into_iter()
+            .find(|kind| kind.name()[5..] == *name)
+            .ok_or_else(|| format!("Unknown dictionary {}", s).into())
+    }
+}
+
+/// A set of square markers with `marker_size` x `marker_size` inner cells each.
+///
+/// Codes hold the inner cells row-major, top left cell in the highest bit, with a set bit for a
+/// white cell, the same layout OpenCV's `Dictionary::writeDictionary` writes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dictionary {
+    pub marker_size: u32,
+    /// How many wrong cells a marker can have and still be identified.
+    pub max_correction_bits: u32,
+    codes: Vec<u64>,
+}
+
+impl Dictionary {
+    /// A dictionary of `codes`, correcting as many bits as their Hamming distance, rotations
+    /// included, allows.
+    pub fn new(marker_size: u32, codes: Vec<u64>) -> crate::Result<Self> {
+        if !(2..=8).contains(&marker_size) {
+            return Err(format!("Unsupported marker size {}", marker_size).into());
+        }
+        let mut dictionary = Dictionary { marker_size, max_correction_bits: 0, codes };
+        let mut min_distance = u32::MAX;
+        for (i, code) in dictionary.codes.iter().enumerate() {
+            for k in 1..4 {
+                min_distance = min_distance.min((dictionary.rotate(*code, k) ^ code).count_ones());
+            }
+            for other in dictionary.codes[i + 1..].iter() {
+                for k in 0..4 {
+                    min_distance = min_distance.min((dictionary.rotate(*code, k) ^ other).count_ones());
+                }
+            }
+        }
+        dictionary.max_correction_bits = min_distance.saturating_sub(1) / 2;
+        Ok(dictionary)
+    }
+
+    /// The 1024 5x5 markers of the original ArUco library, where each row is one of four
+    /// words carrying two bits of the id.
+    pub fn aruco_original() -> Self {
+        const WORDS: [u64; 4] = [0x10, 0x17, 0x09, 0x0e];
+        let codes = (0..1024u64)
+            .map(|id| (0..5).fold(0, |code, y| code << 5 | WORDS[(id >> (2 * (4 - y)) & 3) as usize]))
+            .collect();
+        Dictionary::new(5, codes).unwrap()
+    }
+
+    /// One of OpenCV's predefined dictionaries.
+    pub fn predefined(kind: PredefinedDictionary) -> crate::Result<Self> {
+        let bits = kind.marker_size() * kind.marker_size();
+        let codes: Vec<u64> = match kind.marker_size() {
+            4 => predefined::DICT_4X4_1000.iter().map(|m| predefined::code(&m[0], bits)).collect(),
+            5 => predefined::DICT_5X5_1000.iter().map(|m| predefined::code(&m[0], bits)).collect(),
+            _ => predefined::DICT_6X6_1000.iter().map(|m| predefined::code(&m[0], bits)).collect(),
+        };
+        if codes.len() < kind.markers() {
+            return Err(format!("{} isn't bundled", kind.name()).into());
+        }
+        Dictionary::new(kind.marker_size(), codes[..kind.markers()].to_vec())
+    }
+
+    /// A predefined dictionary by name, such as `4x4_50`, or else the dictionary file at `name`.
+    pub fn predefined_or_load(name: &str) -> crate::Result<Self> {
+        match name.parse::<PredefinedDictionary>() {
+            Ok(kind) => Dictionary::predefined(kind),
+            Err(_) => Dictionary::load(name),
+        }
+    }
+
+    /// Parses the YAML OpenCV's `Dictionary::writeDictionary` writes.
+    pub fn from_yaml(contents: &str) -> crate::Result<Self> {
+        let mut marker_size = None;
+        let mut max_correction_bits = None;
+        let mut markers: Vec<(usize, u64)> = Vec::new();
+        for line in contents.lines() {
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) if !key.starts_with('%') => (key.trim(), value.trim().trim_matches('"')),
+                _ => continue,
+            };
+            let number = || value.parse::<u32>().map_err(|_| format!("Invalid value for {}: {}", key, value));
+            if key == "markersize" {
+                marker_size = Some(number()?);
+            } else if key == "maxCorrectionBits" {
+                max_correction_bits = Some(number()?);
+            } else if let Some(index) = key.strip_prefix("marker_") {
+                let index = index.parse().map_err(|_| format!("Invalid marker key {}", key))?;
+                let code = u64::from_str_radix(value, 2).map_err(|_| format!("Invalid bits for {}: {}", key, value))?;
+                markers.push((index, code));
+            }
+        }
+        let marker_size = marker_size.ok_or("Dictionary has no markersize")?;
+        markers.sort_by_key(|(index, _)| *index);
+        let mut dictionary = Dictionary::new(marker_size, markers.into_iter().map(|(_, code)| code).collect())?;
+        if let Some(bits) = max_correction_bits {
+            dictionary.max_correction_bits = dictionary.max_correction_bits.min(bits);
+        }
+        Ok(dictionary)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        Dictionary::from_yaml(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn len(&self) -> usize {
+        self.codes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codes.is_empty()
+    }
+
+    pub fn code(&self, id: usize) -> Option<u64> {
+        self.codes.get(id).copied()
+    }
+
+    /// The id of the marker `bits` are closest to, how many quarter turns clockwise `bits` have to
+    /// be rotated to match it, and how many bits differ.
+    pub fn identify(&self, bits: u64) -> Option<(usize, u32, u32)> {
+        let mut best: Option<(usize, u32, u32)> = None;
+        for rotation in 0..4 {
+            let rotated = self.rotate(bits, rotation);
+            for (id, code) in self.codes.iter().enumerate() {
+                let distance = (rotated ^ code).count_ones();
+                let better = match best {
+                    Some((_, _, d)) => distance < d,
+                    None => true,
+                };
+                if distance <= self.max_correction_bits && better {
+                    best = Some((id, rotation, distance));
+                }
+            }
+        }
+        best
+    }
+
+    /// The marker with its black border, `cell_size` pixels per cell, for printing.
+    pub fn marker_image(&self, id: usize, cell_size: u32) -> Option<GrayImage> {
+        let code = self.code(id)?;
+        let n = self.marker_size;
+        Some(GrayImage::from_fn((n + 2) * cell_size, (n + 2) * cell_size, |x, y| {
+            let (column, row) = (x / cell_size, y / cell_size);
+            let inside = (1..=n).contains(&column) && (1..=n).contains(&row);
+            Luma([if inside && self.bit(code, row - 1, column - 1) { 255 } else { 0 }])
+        }))
+    }
+
+    fn bit(&self, code: u64, row: u32, column: u32) -> bool {
+        let n = self.marker_size;
+        code >> (n * n - 1 - (row * n + column)) & 1 == 1
+    }
+
+    // The code turned `quarter_turns` times clockwise.
+    fn rotate(&self, code: u64, quarter_turns: u32) -> u64 {
+        let n = self.marker_size;
+        let mut code = code;
+        for _ in 0..quarter_turns {
+            let mut rotated = 0;
+            for row in 0..n {
+                for column in 0..n {
+                    rotated = rotated << 1 | self.bit(code, n - 1 - column, row) as u64;
+                }
+            }
+            code = rotated;
+        }
+        code
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArucoMarker {
+    pub id: usize,
+    /// Corners of the marker's outer black border as it was printed: top left, top right, bottom
+    /// right, bottom left, the order [`crate::pose::estimate_tag_pose`] takes.
+    pub corners: [Point2<f64>; 4],
+    /// Cells that had to be corrected to identify the marker.
+    pub hamming: u32,
+}
+
+impl ArucoMarker {
+    pub fn center(&self) -> Point2<f64> {
+        Point2::from(self.corners.iter().fold(Vector2::zeros(), |sum, c| sum + c.coords) / 4.0)
+    }
+}
+
+/// Finds markers from a dictionary: dark quadrilaterals in a locally thresholded image, read by
+/// sampling their cells through the homography of their corners.
+pub struct ArucoDetector {
+    pub dictionary: Dictionary,
+    /// Radii of the windows the image is thresholded against the mean of, each tried in turn.
+    /// Small windows find small markers and large ones find large markers.
+    pub threshold_radii: Vec<u32>,
+    /// How far below the local mean, in gray levels, a pixel has to be to count as dark.
+    pub threshold_offset: u8,
+    /// Shortest marker side, in pixels.
+    pub min_side: f64,
+    /// Fraction of border cells allowed to read as white.
+    pub max_border_error_rate: f64,
+    /// Whether to refine corners to subpixel accuracy.
+    pub refine_corners: bool,
+}
+
+impl ArucoDetector {
+    pub fn new(dictionary: Dictionary) -> Self {
+        ArucoDetector {
+            dictionary,
+            threshold_radii: vec![5, 15],
+            threshold_offset: 7,
+            min_side: 8.0,
+            max_border_error_rate: 0.35,
+            refine_corners: true,
+        }
+    }
+
+    /// A detector for one of OpenCV's predefined dictionaries.
+    pub fn predefined(kind: PredefinedDictionary) -> crate::Result<Self> {
+        Ok(ArucoDetector::new(Dictionary::predefined(kind)?))
+    }
+
+    pub fn detect(&self, image: &GrayImage) -> Vec<ArucoMarker> {
+        let blurred = if self.refine_corners { Some(blurred_f32(image, 1.0)) } else { None };
+        let mut mask = GrayImage::new(image.width(), image.height());
+        let mut markers: Vec<ArucoMarker> = Vec::new();
+        for radius in self.threshold_radii.iter() {
+            dark_mask(image, *radius, self.threshold_offset, &mut mask);
+            for contour in find_contours::<i32>(&mask) {
+                if contour.border_type != BorderType::Outer || (contour.points.len() as f64) < 4.0 * self.min_side {
+                    continue;
+                }
+                let points: Vec<Point2<f64>> = contour.points.iter().map(|p| Point2::new(p.x as f64, p.y as f64)).collect();
+                let mut corners = match self.fit_quad(&points, image.width(), image.height()) {
+                    Some(corners) => corners,
+                    None => continue,
+                };
+                if let Some(blurred) = &blurred {
+                    let side = (0..4).map(|i| (corners[(i + 1) % 4] - corners[i]).norm()).fold(f64::MAX, f64::min);
+                    let window = ((side / 12.0) as i32).clamp(2, 5);
+                    for corner in corners.iter_mut() {
+                        *corner = refine_corner(blurred, corner, window);
+                    }
+                }
+                let marker = match self.read_marker(image, &corners) {
+                    Some(marker) => marker,
+                    None => continue,
+                };
+                let center = marker.center();
+                let duplicate = markers.iter().any(|m| m.id == marker.id
+                    && (m.center() - center).norm() < (marker.corners[1] - marker.corners[0]).norm() / 2.0);
+                if !duplicate {
+                    markers.push(marker);
+                }
+            }
+        }
+        markers
+    }
+
+    // Four corners, clockwise on screen, if the contour is close to a quadrilateral with sides of
+    // at least the minimum length that doesn't touch the edge of the image.
+    fn fit_quad(&self, points: &[Point2<f64>], width: u32, height: u32) -> Option<[Point2<f64>; 4]> {
+        let centroid = Point2::from(points.iter().fold(Vector2::zeros(), |sum, p| sum + p.coords) / points.len() as f64);
+        let farthest = |from: &Point2<f64>| points.iter().cloned()
+            .max_by(|a, b| (a - from).norm_squared().partial_cmp(&(b - from).norm_squared()).unwrap());
+        let a = farthest(&centroid)?;
+        let b = farthest(&a)?;
+        // The other two corners are the points farthest from the diagonal on either side.
+        let diagonal = (b - a).normalize();
+        let offset = |p: &Point2<f64>| diagonal.perp(&(p - a));
+        let c = points.iter().cloned().max_by(|p, q| offset(p).partial_cmp(&offset(q)).unwrap())?;
+        let d = points.iter().cloned().min_by(|p, q| offset(p).partial_cmp(&offset(q)).unwrap())?;
+        let mut corners = [a, c, b, d];
+        // Clockwise on screen is counterclockwise with y up.
+        let area: f64 = (0..4).map(|i| corners[i].coords.perp(&corners[(i + 1) % 4].coords)).sum();
+        if area < 0.0 {
+            corners.swap(1, 3);
+        }
+
+        let sides: Vec<f64> = (0..4).map(|i| (corners[(i + 1) % 4] - corners[i]).norm()).collect();
+        if sides.iter().any(|s| *s < self.min_side) {
+            return None;
+        }
+        for i in 0..4 {
+            let (p, q, r) = (corners[i], corners[(i + 1) % 4], corners[(i + 2) % 4]);
+            if (q - p).perp(&(r - q)) <= 0.0 {
+                return None;
+            }
+        }
+        if corners.iter().any(|p| p.x < 1.0 || p.y < 1.0 || p.x > width as f64 - 2.0 || p.y > height as f64 - 2.0) {
+            return None;
+        }
+        let tolerance = 1.5 + 0.04 * sides.iter().cloned().fold(f64::MAX, f64::min);
+        let off_edge = points.iter()
+            .filter(|p| (0..4).all(|i| segment_distance(p, &corners[i], &corners[(i + 1) % 4]) > tolerance))
+            .count();
+        if off_edge as f64 > 0.05 * points.len() as f64 {
+            return None;
+        }
+        Some(corners)
+    }
+
+    fn read_marker(&self, image: &GrayImage, corners: &[Point2<f64>; 4]) -> Option<ArucoMarker> {
+        let n = self.dictionary.marker_size as i32;
+        let cells = (n + 2) as f64;
+        let square = [Point2::new(0.0, 0.0), Point2::new(cells, 0.0), Point2::new(cells, cells), Point2::new(0.0, cells)];
+        let h = find_homography(&square, corners)?;
+
+        // The border cells are black and a strip just outside the marker should be white, which
+        // gives the level to read the inner cells against.
+        let mut border = Vec::new();
+        let mut outside = Vec::new();
+        for i in 0..n + 2 {
+            let along = i as f64 + 0.5;
+            for (x, y) in [(along, -0.3), (along, cells + 0.3), (-0.3, along), (cells + 0.3, along)] {
+                outside.push(sample(image, &h, x, y, 0.1)?);
+            }
+            for (x, y) in [(i, 0), (i, n + 1), (0, i), (n + 1, i)] {
+                border.push(sample(image, &h, x as f64 + 0.5, y as f64 + 0.5, 0.25)?);
+            }
+        }
+        let black = border.iter().sum::<f64>() / border.len() as f64;
+        let white = outside.iter().sum::<f64>() / outside.len() as f64;
+        if white - black < 20.0 {
+            return None;
+        }
+        let level = (white + black) / 2.0;
+        let border_errors = border.iter().filter(|v| **v > level).count();
+        if border_errors as f64 > self.max_border_error_rate * border.len() as f64 {
+            return None;
+        }
+
+        let mut bits = 0u64;
+        for row in 1..=n {
+            for column in 1..=n {
+                let value = sample(image, &h, column as f64 + 0.5, row as f64 + 0.5, 0.25)?;
+                bits = bits << 1 | (value > level) as u64;
+            }
+        }
+        let (id, rotation, hamming) = self.dictionary.identify(bits)?;
+        // Turning the cells clockwise moves the printed top left corner to the one that was
+        // read as the top right.
+        let corners = [0, 1, 2, 3].map(|i| corners[(i + 4 - rotation as usize) % 4]);
+        Some(ArucoMarker { id, corners, hamming })
+    }
+}
+
+// Mean of a 3x3 grid of pixels `spread` cells apart around a point in cell coordinates.
+fn sample(image: &GrayImage, h: &Matrix3<f64>, x: f64, y: f64, spread: f64) -> Option<f64> {
+    let mut sum = 0.0;
+    for dy in -1..=1 {
+        for dx in -1..=1 {
+            let p = apply_homography(h, &Point2::new(x + dx as f64 * spread, y + dy as f64 * spread));
+            let (px, py) = (p.x.round(), p.y.round());
+            if px < 0.0 || py < 0.0 || px >= image.width() as f64 || py >= image.height() as f64 {
+                return None;
+            }
+            sum += image.get_pixel(px as u32, py as u32)[0] as f64;
+        }
+    }
+    Some(sum / 9.0)
+}
+
+fn segment_distance(p: &Point2<f64>, a: &Point2<f64>, b: &Point2<f64>) -> f64 {
+    let ab = b - a;
+    let t = ((p - a).dot(&ab) / ab.norm_squared()).clamp(0.0, 1.0);
+    (p - (a + ab * t)).norm()
+}
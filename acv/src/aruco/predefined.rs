@@ -0,0 +1,78 @@
+// OpenCV's predefined dictionaries, from `predefined_dictionaries.hpp`, in the same layout: per
+// marker, its cells in each of the four rotations, row-major and most significant bit first.
+// The smaller dictionaries are the first markers of these. Only rotation 0 is read; the others
+// are checked against `Dictionary::rotate` by the tests.
+//
+// The tables are left empty until they are copied over from OpenCV, which only needs its braces
+// turned into brackets. `Dictionary::predefined` refuses sizes a table doesn't have enough markers
+// for rather than returning a short dictionary.
+
+pub(super) const DICT_4X4_1000: &[[[u8; 2]; 4]] = &[];
+
+pub(super) const DICT_5X5_1000: &[[[u8; 4]; 4]] = &[];
+
+pub(super) const DICT_6X6_1000: &[[[u8; 5]; 4]] = &[];
+
+/// The code of a marker's first `bits` cells.
+pub(super) fn code(bytes: &[u8], bits: u32) -> u64 {
+    let all = bytes.iter().fold(0u64, |code, byte| code << 8 | *byte as u64);
+    all >> (8 * bytes.len() as u32 - bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{imageops, GrayImage, Luma};
+    use crate::aruco::{ArucoDetector, Dictionary, PredefinedDictionary};
+
+    // Every rotation stored matches one of ours, which catches tables copied in the wrong layout.
+    fn check_rotations<const N: usize>(table: &[[[u8; N]; 4]], marker_size: u32) {
+        let dictionary = Dictionary::new(marker_size, Vec::new()).unwrap();
+        let bits = marker_size * marker_size;
+        assert!(table.is_empty() || table.len() == 1000, "{}x{} table is incomplete", marker_size, marker_size);
+        for (id, marker) in table.iter().enumerate() {
+            let ours: Vec<u64> = (0..4).map(|k| dictionary.rotate(code(&marker[0], bits), k)).collect();
+            for rotation in marker.iter() {
+                assert!(ours.contains(&code(rotation, bits)), "marker {} of {}x{}", id, marker_size, marker_size);
+            }
+        }
+    }
+
+    #[test]
+    fn tables_match_rotations() {
+        check_rotations(DICT_4X4_1000, 4);
+        check_rotations(DICT_5X5_1000, 5);
+        check_rotations(DICT_6X6_1000, 6);
+    }
+
+    // Prints a few markers on white paper and reads them back.
+    fn check_round_trip(dictionary: Dictionary) {
+        let detector = ArucoDetector::new(dictionary);
+        let count = detector.dictionary.len();
+        for id in [0, 1, count / 2, count - 1] {
+            let marker = detector.dictionary.marker_image(id, 12).unwrap();
+            let mut page = GrayImage::from_pixel(marker.width() + 60, marker.height() + 60, Luma([255]));
+            imageops::overlay(&mut page, &marker, 30, 30);
+            let found: Vec<usize> = detector.detect(&page).iter().map(|m| m.id).collect();
+            assert_eq!(found, [id]);
+        }
+    }
+
+    #[test]
+    fn markers_round_trip() {
+        check_round_trip(Dictionary::aruco_original());
+        for kind in PredefinedDictionary::ALL {
+            if let Ok(dictionary) = Dictionary::predefined(kind) {
+                check_round_trip(dictionary);
+            }
+        }
+    }
+
+    #[test]
+    fn parses_names() {
+        assert_eq!("DICT_5X5_250".parse::<PredefinedDictionary>().unwrap(), PredefinedDictionary::Dict5x5_250);
+        assert_eq!("6x6_1000".parse::<PredefinedDictionary>().unwrap(), PredefinedDictionary::Dict6x6_1000);
+        assert!("7x7_50".parse::<PredefinedDictionary>().is_err());
+        assert!("dictionary.yml".parse::<PredefinedDictionary>().is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use image::GrayImage;
+use imageproc::contrast::otsu_level;
+use crate::util::dark_mask;
+
+pub mod ean;
+pub mod qr;
+mod reed_solomon;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Symbology {
+    Qr,
+    /// EAN-13, which also reads UPC-A codes with a leading zero.
+    Ean13,
+}
+
+/// A code read from an image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedCode {
+    pub symbology: Symbology,
+    pub text: String,
+    /// The code's corners in the image as it was printed: top left, top right, bottom right,
+    /// bottom left. For linear codes these span the scan lines the code was read on.
+    pub corners: [(f32, f32); 4],
+}
+
+impl DecodedCode {
+    pub fn center(&self) -> (f32, f32) {
+        let (x, y) = self.corners.iter().fold((0.0, 0.0), |sum, c| (sum.0 + c.0, sum.1 + c.1));
+        (x / 4.0, y / 4.0)
+    }
+}
+
+/// Finds and decodes QR codes and linear barcodes.
+///
+/// Images are thresholded against their local mean first, and against a global Otsu level if
+/// that finds nothing. Code 128 isn't supported.
+pub struct BarcodeReader {
+    pub symbologies: Vec<Symbology>,
+    /// Radius of the window the local threshold averages over, as a fraction of the image's
+    /// smaller side. Has to be larger than a few modules of the biggest code.
+    pub threshold_window: f32,
+    /// How far below the local mean, in gray levels, a pixel has to be to count as dark.
+    pub threshold_offset: u8,
+    /// Spacing in pixels of the rows and columns scanned for linear codes.
+    pub scan_step: u32,
+}
+
+impl BarcodeReader {
+    pub fn new(symbologies: Vec<Symbology>) -> Self {
+        BarcodeReader {
+            symbologies,
+            threshold_window: 0.125,
+            threshold_offset: 5,
+            scan_step: 4,
+        }
+    }
+
+    /// Every code found in the image, each once.
+    pub fn read(&self, image: &GrayImage) -> Vec<DecodedCode> {
+        let radius = ((image.width().min(image.height()) as f32 * self.threshold_window) as u32).max(4);
+        let mut codes = self.read_binary(&BitImage::adaptive(image, radius, self.threshold_offset));
+        if codes.is_empty() {
+            codes = self.read_binary(&BitImage::global(image, otsu_level(image)));
+        }
+        codes
+    }
+
+    fn read_binary(&self, image: &BitImage) -> Vec<DecodedCode> {
+        let mut codes = Vec::new();
+        for symbology in self.symbologies.iter() {
+            let found = match symbology {
+                Symbology::Qr => qr::read(image),
+                Symbology::Ean13 => ean::read(image, self.scan_step.max(1)),
+            };
+            for code in found {
+                if !codes.iter().any(|c: &DecodedCode| c.symbology == code.symbology && c.text == code.text) {
+                    codes.push(code);
+                }
+            }
+        }
+        codes
+    }
+}
+
+impl Default for BarcodeReader {
+    fn default() -> Self {
+        BarcodeReader::new(vec![Symbology::Qr, Symbology::Ean13])
+    }
+}
+
+// A thresholded image where `true` is dark. Pixels outside the image are light.
+struct BitImage {
+    width: u32,
+    height: u32,
+    bits: Vec<bool>,
+}
+
+impl BitImage {
+    fn adaptive(image: &GrayImage, radius: u32, offset: u8) -> Self {
+        let mut mask = GrayImage::new(image.width(), image.height());
+        dark_mask(image, radius, offset, &mut mask);
+        let bits = mask.pixels().map(|p| p[0] != 0).collect();
+        BitImage { width: image.width(), height: image.height(), bits }
+    }
+
+    fn global(image: &GrayImage, level: u8) -> Self {
+        let bits = image.pixels().map(|p| p[0] <= level).collect();
+        BitImage { width: image.width(), height: image.height(), bits }
+    }
+
+    fn get(&self, x: i32, y: i32) -> bool {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return false;
+        }
+        self.bits[y as usize * self.width as usize + x as usize]
+    }
+}
@@ -0,0 +1,250 @@
+use super::{BitImage, DecodedCode, Symbology};
+
+// Widths in modules of the space, bar, space and bar of each digit in the L code. The G code has
+// the same widths reversed, and the R code has them with bars and spaces swapped.
+const DIGITS: [[f32; 4]; 10] = [
+    [3.0, 2.0, 1.0, 1.0],
+    [2.0, 2.0, 2.0, 1.0],
+    [2.0, 1.0, 2.0, 2.0],
+    [1.0, 4.0, 1.0, 1.0],
+    [1.0, 1.0, 3.0, 2.0],
+    [1.0, 2.0, 3.0, 1.0],
+    [1.0, 1.0, 1.0, 4.0],
+    [1.0, 3.0, 1.0, 2.0],
+    [1.0, 2.0, 1.0, 3.0],
+    [3.0, 1.0, 1.0, 2.0],
+];
+
+// Which of the left half's digits use the G code, first digit in the top bit, for each value of the
+// leading digit, which isn't itself drawn.
+const PARITY: [u8; 10] = [0b000000, 0b001011, 0b001101, 0b001110, 0b010011, 0b011001, 0b011100, 0b010101, 0b010110, 0b011010];
+
+// Runs from the first bar of the start guard to the last of the end guard.
+const SYMBOL_RUNS: usize = 59;
+const SYMBOL_MODULES: f32 = 95.0;
+
+// A run of same-colored pixels along a scan line.
+#[derive(Copy, Clone)]
+struct Run {
+    start: usize,
+    length: usize,
+    dark: bool,
+}
+
+// A code read along one scan line.
+struct Hit {
+    text: String,
+    vertical: bool,
+    reversed: bool,
+    // The line's position across the image, and where the code started and ended along it.
+    line: u32,
+    start: f32,
+    end: f32,
+}
+
+impl Hit {
+    fn point(&self, along: f32) -> (f32, f32) {
+        let across = self.line as f32 + 0.5;
+        if self.vertical { (across, along) } else { (along, across) }
+    }
+}
+
+/// EAN-13 codes read along rows and columns `step` pixels apart, in both directions, so codes
+/// have to be within about 30 degrees of level or upright.
+pub(super) fn read(image: &BitImage, step: u32) -> Vec<DecodedCode> {
+    let mut hits: Vec<Hit> = Vec::new();
+    for (vertical, lines, length) in [(false, image.height, image.width), (true, image.width, image.height)] {
+        for line in (step / 2..lines).step_by(step as usize) {
+            let mut bits: Vec<bool> = (0..length as i32)
+                .map(|i| if vertical { image.get(line as i32, i) } else { image.get(i, line as i32) })
+                .collect();
+            for reversed in [false, true] {
+                if reversed {
+                    bits.reverse();
+                }
+                for (text, start, end) in scan(&bits) {
+                    let (start, end) = if reversed {
+                        (length as f32 - start as f32, length as f32 - end as f32)
+                    } else {
+                        (start as f32, end as f32)
+                    };
+                    hits.push(Hit { text, vertical, reversed, line, start, end });
+                }
+            }
+        }
+    }
+
+    let mut codes: Vec<DecodedCode> = Vec::new();
+    for (i, hit) in hits.iter().enumerate() {
+        let same_code = |h: &&Hit| h.text == hit.text && h.vertical == hit.vertical && h.reversed == hit.reversed;
+        if hits[..i].iter().any(|h| same_code(&h)) {
+            continue;
+        }
+        let same: Vec<&Hit> = hits.iter().filter(same_code).collect();
+        // The printed top of the code is on the first line for codes read forwards along rows, and
+        // on the last for columns, since those codes are turned clockwise.
+        let (mut first, mut last) = (same[0], same[same.len() - 1]);
+        if hit.reversed != hit.vertical {
+            std::mem::swap(&mut first, &mut last);
+        }
+        codes.push(DecodedCode {
+            symbology: Symbology::Ean13,
+            text: hit.text.clone(),
+            corners: [first.point(first.start), first.point(first.end), last.point(last.end), last.point(last.start)],
+        });
+    }
+    codes
+}
+
+// Codes along one line, with the pixel positions of their first and just past their last bar.
+fn scan(bits: &[bool]) -> Vec<(String, usize, usize)> {
+    let mut runs: Vec<Run> = Vec::new();
+    for (i, dark) in bits.iter().enumerate() {
+        match runs.last_mut() {
+            Some(run) if run.dark == *dark => run.length += 1,
+            _ => runs.push(Run { start: i, length: 1, dark: *dark }),
+        }
+    }
+
+    let mut found = Vec::new();
+    let mut i = 0;
+    while i + SYMBOL_RUNS <= runs.len() {
+        if runs[i].dark {
+            if let Some(text) = decode(&runs[i..i + SYMBOL_RUNS], runs.get(i.wrapping_sub(1)), runs.get(i + SYMBOL_RUNS)) {
+                let last = &runs[i + SYMBOL_RUNS - 1];
+                found.push((text, runs[i].start, last.start + last.length));
+                i += SYMBOL_RUNS;
+                continue;
+            }
+        }
+        i += 1;
+    }
+    found
+}
+
+fn decode(runs: &[Run], before: Option<&Run>, after: Option<&Run>) -> Option<String> {
+    let width = (runs[SYMBOL_RUNS - 1].start + runs[SYMBOL_RUNS - 1].length - runs[0].start) as f32;
+    let module = width / SYMBOL_MODULES;
+    // Needs a few modules of quiet zone where the line doesn't end first.
+    let quiet = |run: Option<&Run>| match run {
+        Some(run) => run.length as f32 >= 3.0 * module,
+        None => true,
+    };
+    let guard = |runs: &[Run]| runs.iter().all(|r| (r.length as f32 - module).abs() < 0.7 * module);
+    if !quiet(before) || !quiet(after) || !guard(&runs[..3]) || !guard(&runs[27..32]) || !guard(&runs[56..]) {
+        return None;
+    }
+
+    let mut digits = [0u8; 13];
+    let mut parity = 0u8;
+    for d in 0..6 {
+        let (digit, g_code) = match_digit(&runs[3 + 4 * d..7 + 4 * d], true)?;
+        digits[d + 1] = digit;
+        parity |= (g_code as u8) << (5 - d);
+    }
+    for d in 0..6 {
+        digits[d + 7] = match_digit(&runs[32 + 4 * d..36 + 4 * d], false)?.0;
+    }
+    digits[0] = PARITY.iter().position(|p| *p == parity)? as u8;
+
+    let sum: u32 = digits[..12].iter().enumerate()
+        .map(|(i, d)| *d as u32 * if i % 2 == 0 { 1 } else { 3 })
+        .sum();
+    if (10 - sum % 10) % 10 != digits[12] as u32 {
+        return None;
+    }
+    Some(digits.iter().map(|d| (b'0' + d) as char).collect())
+}
+
+// The digit four runs most look like, and whether it was in the G code. Only the left half of the
+// code uses G.
+fn match_digit(runs: &[Run], left: bool) -> Option<(u8, bool)> {
+    let total: usize = runs.iter().map(|r| r.length).sum();
+    let widths: Vec<f32> = runs.iter().map(|r| r.length as f32 * 7.0 / total as f32).collect();
+    let variance = |pattern: [f32; 4]| {
+        let mut sum = 0.0;
+        for (w, p) in widths.iter().zip(pattern) {
+            let difference = (w - p).abs();
+            if difference > 0.7 {
+                return f32::MAX;
+            }
+            sum += difference;
+        }
+        sum
+    };
+    let mut best: Option<(f32, u8, bool)> = None;
+    for (digit, pattern) in DIGITS.iter().enumerate() {
+        let mut candidates = vec![(variance(*pattern), false)];
+        if left {
+            let [a, b, c, d] = *pattern;
+            candidates.push((variance([d, c, b, a]), true));
+        }
+        for (v, g_code) in candidates {
+            let better = match best {
+                Some((b, _, _)) => v < b,
+                None => true,
+            };
+            if v < 1.5 && better {
+                best = Some((v, digit as u8, g_code));
+            }
+        }
+    }
+    best.map(|(_, digit, g_code)| (digit, g_code))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma};
+    use super::{DIGITS, PARITY};
+    use crate::barcode::{BarcodeReader, Symbology};
+
+    // Modules of a code for `text`, without checking its check digit.
+    fn modules(text: &str) -> Vec<bool> {
+        let digits: Vec<usize> = text.bytes().map(|b| (b - b'0') as usize).collect();
+        let mut modules = Vec::new();
+        push_runs(&mut modules, &[1.0; 3], true);
+        for (i, digit) in digits[1..7].iter().enumerate() {
+            let [a, b, c, d] = DIGITS[*digit];
+            let g_code = PARITY[digits[0]] >> (5 - i) & 1 == 1;
+            push_runs(&mut modules, &if g_code { [d, c, b, a] } else { [a, b, c, d] }, false);
+        }
+        push_runs(&mut modules, &[1.0; 5], false);
+        for digit in digits[7..].iter() {
+            push_runs(&mut modules, &DIGITS[*digit], true);
+        }
+        push_runs(&mut modules, &[1.0; 3], true);
+        modules
+    }
+
+    // Runs of alternating color, `widths` modules long.
+    fn push_runs(modules: &mut Vec<bool>, widths: &[f32], dark_first: bool) {
+        for (i, width) in widths.iter().enumerate() {
+            modules.extend(std::iter::repeat_n((i % 2 == 0) == dark_first, *width as usize));
+        }
+    }
+
+    // Three pixels a module with a ten module quiet zone, 40 pixels high.
+    fn render(text: &str) -> GrayImage {
+        let modules = modules(text);
+        GrayImage::from_fn((modules.len() as u32 + 20) * 3, 40, |x, _| {
+            let module = (x / 3) as usize;
+            let dark = module >= 10 && modules.get(module - 10).copied().unwrap_or(false);
+            Luma([if dark { 20 } else { 235 }])
+        })
+    }
+
+    fn read(text: &str) -> Vec<String> {
+        BarcodeReader::new(vec![Symbology::Ean13]).read(&render(text)).into_iter().map(|c| c.text).collect()
+    }
+
+    #[test]
+    fn reads_valid_code() {
+        assert_eq!(read("4006381333931"), ["4006381333931"]);
+        assert_eq!(read("0012345678905"), ["0012345678905"]);
+    }
+
+    #[test]
+    fn rejects_wrong_check_digit() {
+        assert!(read("4006381333932").is_empty());
+    }
+}
@@ -0,0 +1,794 @@
+use nalgebra::{Matrix3, Point2, Vector2};
+use crate::geometry::{apply_homography, find_homography};
+use super::{reed_solomon, BitImage, DecodedCode, Symbology};
+
+// Error correction codewords per block and number of blocks, indexed by error correction level
+// (low, medium, quartile, high) and version. From the QR code specification.
+const ECC_CODEWORDS_PER_BLOCK: [[u8; 41]; 4] = [
+    [0, 7, 10, 15, 20, 26, 18, 20, 24, 30, 18, 20, 24, 26, 30, 22, 24, 28, 30, 28, 28, 28, 28, 30, 30, 26, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30],
+    [0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26, 26, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28],
+    [0, 13, 22, 18, 26, 18, 24, 18, 22, 20, 24, 28, 26, 24, 20, 30, 24, 28, 28, 26, 30, 28, 30, 30, 30, 30, 28, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30],
+    [0, 17, 28, 22, 16, 22, 28, 26, 26, 24, 28, 24, 28, 22, 24, 24, 30, 28, 28, 26, 28, 30, 24, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30, 30],
+];
+
+const ERROR_CORRECTION_BLOCKS: [[u8; 41]; 4] = [
+    [0, 1, 1, 1, 1, 1, 2, 2, 2, 2, 4, 4, 4, 4, 4, 6, 6, 6, 6, 7, 8, 8, 9, 9, 10, 12, 12, 12, 13, 14, 15, 16, 17, 18, 19, 19, 20, 21, 22, 24, 25],
+    [0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16, 17, 17, 18, 20, 21, 23, 25, 26, 28, 29, 31, 33, 35, 37, 38, 40, 43, 45, 47, 49],
+    [0, 1, 1, 2, 2, 4, 4, 6, 6, 8, 8, 8, 10, 12, 16, 12, 17, 16, 18, 21, 20, 23, 23, 25, 27, 29, 34, 34, 35, 38, 40, 43, 45, 48, 51, 53, 56, 59, 62, 65, 68],
+    [0, 1, 1, 2, 4, 4, 4, 5, 6, 8, 8, 11, 11, 16, 16, 18, 16, 19, 21, 25, 25, 25, 34, 30, 32, 35, 37, 40, 42, 45, 48, 51, 54, 57, 60, 63, 66, 70, 74, 77, 81],
+];
+
+const ALPHANUMERIC: &[u8; 45] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+
+// Finder candidates beyond this many, by number of rows they were seen on, are ignored.
+const MAX_FINDERS: usize = 16;
+
+#[derive(Copy, Clone, Debug)]
+struct Finder {
+    x: f32,
+    y: f32,
+    module: f32,
+    count: u32,
+}
+
+impl Finder {
+    fn distance(&self, other: &Finder) -> f32 {
+        (self.x - other.x).hypot(self.y - other.y)
+    }
+
+    fn point(&self) -> Point2<f64> {
+        Point2::new(self.x as f64, self.y as f64)
+    }
+}
+
+/// Every QR code in the image.
+pub(super) fn read(image: &BitImage) -> Vec<DecodedCode> {
+    let finders = find_finders(image);
+    let mut used = vec![false; finders.len()];
+    let mut codes = Vec::new();
+    for [tl, tr, bl] in group_finders(&finders) {
+        if used[tl] || used[tr] || used[bl] {
+            continue;
+        }
+        if let Some(code) = decode(image, &finders[tl], &finders[tr], &finders[bl]) {
+            used[tl] = true;
+            used[tr] = true;
+            used[bl] = true;
+            codes.push(code);
+        }
+    }
+    codes
+}
+
+// Finder pattern centers, found as 1:1:3:1:1 dark and light runs along a row and confirmed along
+// the column through their center.
+fn find_finders(image: &BitImage) -> Vec<Finder> {
+    let mut finders: Vec<Finder> = Vec::new();
+    for y in 0..image.height as i32 {
+        let mut runs = [0u32; 5];
+        let mut state = 0;
+        // One past the end so a pattern touching the right edge is still closed off.
+        for x in 0..=image.width as i32 {
+            let dark = image.get(x, y);
+            if dark == (state % 2 == 0) {
+                runs[state] += 1;
+            } else if state == 4 {
+                if let Some(finder) = check_finder(image, &runs, x, y) {
+                    add_finder(&mut finders, finder);
+                }
+                runs = [runs[2], runs[3], runs[4], 1, 0];
+                state = 3;
+            } else if runs[0] > 0 {
+                state += 1;
+                runs[state] = 1;
+            }
+        }
+    }
+    finders.retain(|f| f.count >= 2);
+    finders.sort_by_key(|f| std::cmp::Reverse(f.count));
+    finders.truncate(MAX_FINDERS);
+    finders
+}
+
+fn add_finder(finders: &mut Vec<Finder>, finder: Finder) {
+    let existing = finders.iter_mut().find(|f| {
+        (f.x - finder.x).abs() <= f.module && (f.y - finder.y).abs() <= f.module
+            && (f.module - finder.module).abs() <= f.module.max(1.0)
+    });
+    match existing {
+        Some(f) => {
+            let count = f.count as f32;
+            f.x = (f.x * count + finder.x) / (count + 1.0);
+            f.y = (f.y * count + finder.y) / (count + 1.0);
+            f.module = (f.module * count + finder.module) / (count + 1.0);
+            f.count += 1;
+        }
+        None => finders.push(finder),
+    }
+}
+
+fn finder_ratio(runs: &[u32; 5]) -> bool {
+    let total: u32 = runs.iter().sum();
+    if total < 7 || runs.contains(&0) {
+        return false;
+    }
+    let module = total as f32 / 7.0;
+    let tolerance = module / 2.0;
+    [0, 1, 3, 4].iter().all(|i| (runs[*i] as f32 - module).abs() < tolerance)
+        && (runs[2] as f32 - 3.0 * module).abs() < 3.0 * tolerance
+}
+
+// Checks a row's finder-like runs, ending just before `end`, against the column and then the row
+// through their center.
+fn check_finder(image: &BitImage, runs: &[u32; 5], end: i32, y: i32) -> Option<Finder> {
+    if !finder_ratio(runs) {
+        return None;
+    }
+    let total: u32 = runs.iter().sum();
+    let x = end as f32 - runs[4] as f32 - runs[3] as f32 - runs[2] as f32 / 2.0;
+    let (y, vertical) = cross_check(|i| image.get(x as i32, i), image.height as i32, y, total)?;
+    let (x, horizontal) = cross_check(|i| image.get(i, y as i32), image.width as i32, x as i32, total)?;
+    Some(Finder { x, y, module: (vertical + horizontal) as f32 / 14.0, count: 1 })
+}
+
+// Measures the 1:1:3:1:1 runs through `start` along a line of `length` pixels, returning their
+// center and total length if they look like a finder about `expected` pixels across.
+fn cross_check<F>(dark: F, length: i32, start: i32, expected: u32) -> Option<(f32, u32)> where F: Fn(i32) -> bool {
+    let mut runs = [0u32; 5];
+    let mut i = start;
+    while i >= 0 && dark(i) {
+        runs[2] += 1;
+        i -= 1;
+    }
+    while i >= 0 && !dark(i) && runs[1] <= expected {
+        runs[1] += 1;
+        i -= 1;
+    }
+    while i >= 0 && dark(i) && runs[0] <= expected {
+        runs[0] += 1;
+        i -= 1;
+    }
+    let mut i = start + 1;
+    while i < length && dark(i) {
+        runs[2] += 1;
+        i += 1;
+    }
+    while i < length && !dark(i) && runs[3] <= expected {
+        runs[3] += 1;
+        i += 1;
+    }
+    while i < length && dark(i) && runs[4] <= expected {
+        runs[4] += 1;
+        i += 1;
+    }
+    let total: u32 = runs.iter().sum();
+    if 5 * total.abs_diff(expected) >= 2 * expected || !finder_ratio(&runs) {
+        return None;
+    }
+    Some((i as f32 - runs[4] as f32 - runs[3] as f32 - runs[2] as f32 / 2.0, total))
+}
+
+// Triples of finders that could be the top left, top right and bottom left corners of one code,
+// most square first.
+fn group_finders(finders: &[Finder]) -> Vec<[usize; 3]> {
+    let mut groups = Vec::new();
+    for a in 0..finders.len() {
+        for b in a + 1..finders.len() {
+            for c in b + 1..finders.len() {
+                let modules = [finders[a].module, finders[b].module, finders[c].module];
+                let largest = modules.iter().copied().fold(f32::MIN, f32::max);
+                let smallest = modules.iter().copied().fold(f32::MAX, f32::min);
+                if largest > 2.0 * smallest {
+                    continue;
+                }
+                // The top left finder is the one opposite the longest side.
+                let (corner, p, q) = {
+                    let ab = finders[a].distance(&finders[b]);
+                    let bc = finders[b].distance(&finders[c]);
+                    let ca = finders[c].distance(&finders[a]);
+                    if bc >= ab && bc >= ca {
+                        (a, b, c)
+                    } else if ca >= ab {
+                        (b, c, a)
+                    } else {
+                        (c, a, b)
+                    }
+                };
+                let (o, p_finder, q_finder) = (&finders[corner], &finders[p], &finders[q]);
+                let (u, v) = ((p_finder.x - o.x, p_finder.y - o.y), (q_finder.x - o.x, q_finder.y - o.y));
+                let (lu, lv) = (u.0.hypot(u.1), v.0.hypot(v.1));
+                let module = (o.module + p_finder.module + q_finder.module) / 3.0;
+                if lu.min(lv) < 10.0 * module || lu.max(lv) > 1.6 * lu.min(lv) {
+                    continue;
+                }
+                let cos = (u.0 * v.0 + u.1 * v.1) / (lu * lv);
+                if cos.abs() > 0.4 {
+                    continue;
+                }
+                // Top right comes clockwise from top left before bottom left.
+                let (tr, bl) = if u.0 * v.1 - u.1 * v.0 > 0.0 { (p, q) } else { (q, p) };
+                let score = cos.abs() + (lu - lv).abs() / lu.max(lv);
+                groups.push((score, [corner, tr, bl]));
+            }
+        }
+    }
+    groups.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    groups.into_iter().map(|(_, g)| g).collect()
+}
+
+fn decode(image: &BitImage, tl: &Finder, tr: &Finder, bl: &Finder) -> Option<DecodedCode> {
+    // Runs along rows and columns stretch when the code is turned, so the modules used to size the
+    // code are measured along the lines between finders.
+    let along = [(tl, tr), (tr, tl), (tl, bl), (bl, tl)]
+        .iter()
+        .filter_map(|(from, to)| module_towards(image, from, to))
+        .collect::<Vec<f32>>();
+    if along.is_empty() {
+        return None;
+    }
+    let module = along.iter().sum::<f32>() / along.len() as f32;
+    let estimate = (tl.distance(tr) + tl.distance(bl)) / 2.0 / module + 7.0;
+    // The module size is only approximate, so try the versions with the closest sizes.
+    let mut versions: Vec<u32> = (1..=40).collect();
+    versions.sort_by(|a, b| {
+        let (a, b) = ((17 + 4 * a) as f32 - estimate, (17 + 4 * b) as f32 - estimate);
+        a.abs().partial_cmp(&b.abs()).unwrap()
+    });
+    let row_module = (tl.module + tr.module + bl.module) / 3.0;
+    versions.into_iter().take(3).find_map(|version| decode_version(image, tl, tr, bl, version, row_module))
+}
+
+// The size of a finder's modules along the line towards another finder, from its width across
+// that line.
+fn module_towards(image: &BitImage, from: &Finder, to: &Finder) -> Option<f32> {
+    let direction = (to.point() - from.point()).normalize();
+    let limit = 8.0 * from.module as f64;
+    let across = finder_edge(image, from.point(), direction, limit)? + finder_edge(image, from.point(), -direction, limit)?;
+    Some(across as f32 / 7.0)
+}
+
+// How far a finder's outer edge is from its center along `direction`, crossing the dark center
+// and the light and dark rings around it.
+fn finder_edge(image: &BitImage, center: Point2<f64>, direction: Vector2<f64>, limit: f64) -> Option<f64> {
+    let mut rings = 0;
+    let mut dark = true;
+    let mut t = 0.0;
+    while rings < 3 {
+        t += 0.5;
+        if t > limit {
+            return None;
+        }
+        let p = center + direction * t;
+        let pixel = image.get(p.x.floor() as i32, p.y.floor() as i32);
+        if pixel != dark {
+            dark = pixel;
+            rings += 1;
+        }
+    }
+    Some(t - 0.25)
+}
+
+// `module` is the finders' module size along rows and columns, which alignment patterns are
+// searched with.
+fn decode_version(image: &BitImage, tl: &Finder, tr: &Finder, bl: &Finder, version: u32, module: f32) -> Option<DecodedCode> {
+    let size = (17 + 4 * version) as f64;
+    // The finder centers and the middles of their outer edges pin down the perspective well enough
+    // to find the alignment pattern, and on their own for codes without one. The edges are found
+    // along the code's axes, first as if it were only skewed and then as the first fit has them.
+    let (right, down) = ((tr.point() - tl.point()).normalize(), (bl.point() - tl.point()).normalize());
+    let mut h: Option<Matrix3<f64>> = None;
+    for _ in 0..2 {
+        let mut src = Vec::new();
+        let mut dst = Vec::new();
+        for (finder, x, y) in [(tl, 3.5, 3.5), (tr, size - 3.5, 3.5), (bl, 3.5, size - 3.5)] {
+            let (right, down) = match &h {
+                Some(h) => {
+                    let center = apply_homography(h, &Point2::new(x, y));
+                    (
+                        (apply_homography(h, &Point2::new(x + 1.0, y)) - center).normalize(),
+                        (apply_homography(h, &Point2::new(x, y + 1.0)) - center).normalize(),
+                    )
+                }
+                None => (right, down),
+            };
+            src.push(Point2::new(x, y));
+            dst.push(finder.point());
+            for (direction, dx, dy) in [(right, 3.5, 0.0), (-right, -3.5, 0.0), (down, 0.0, 3.5), (-down, 0.0, -3.5)] {
+                if let Some(t) = finder_edge(image, finder.point(), direction, 8.0 * finder.module as f64) {
+                    src.push(Point2::new(x + dx, y + dy));
+                    dst.push(finder.point() + direction * t);
+                }
+            }
+        }
+        h = Some(find_homography(&src, &dst)?);
+    }
+    let mut h = h?;
+    if version >= 2 {
+        let estimate = apply_homography(&h, &Point2::new(size - 6.5, size - 6.5));
+        let axes = (
+            apply_homography(&h, &Point2::new(size - 5.5, size - 6.5)) - estimate,
+            apply_homography(&h, &Point2::new(size - 6.5, size - 5.5)) - estimate,
+        );
+        if let Some(center) = find_alignment(image, estimate, axes, module) {
+            h = find_homography(
+                &[Point2::new(3.5, 3.5), Point2::new(size - 3.5, 3.5), Point2::new(3.5, size - 3.5), Point2::new(size - 6.5, size - 6.5)],
+                &[tl.point(), tr.point(), bl.point(), center],
+            )?;
+        }
+    }
+    let grid = Grid::sample(image, &h, 17 + 4 * version as usize)?;
+    let text = grid.decode(version)?;
+    let corner = |x: f64, y: f64| {
+        let p = apply_homography(&h, &Point2::new(x, y));
+        (p.x as f32, p.y as f32)
+    };
+    Some(DecodedCode {
+        symbology: Symbology::Qr,
+        text,
+        corners: [corner(0.0, 0.0), corner(size, 0.0), corner(size, size), corner(0.0, size)],
+    })
+}
+
+// The alignment pattern center nearest `estimate`, found as a dark module ringed by light and
+// then dark ones along both the row and the column. Candidates are checked against the whole
+// pattern laid out along `axes`, one module across and down, since data can look like it too.
+fn find_alignment(image: &BitImage, estimate: Point2<f64>, axes: (Vector2<f64>, Vector2<f64>), module: f32) -> Option<Point2<f64>> {
+    let matches_pattern = |center: Point2<f64>| {
+        let mut wrong = 0;
+        for dy in -2..=2i32 {
+            for dx in -2..=2i32 {
+                let p = center + axes.0 * dx as f64 + axes.1 * dy as f64;
+                if image.get(p.x.floor() as i32, p.y.floor() as i32) != (dx.abs().max(dy.abs()) != 1) {
+                    wrong += 1;
+                }
+            }
+        }
+        wrong <= 2
+    };
+    for radius in [4.0, 8.0] {
+        let reach = (radius * module).ceil() as i32;
+        let (ex, ey) = (estimate.x as i32, estimate.y as i32);
+        let mut best: Option<(f32, Point2<f64>)> = None;
+        for y in ey - reach..=ey + reach {
+            let mut x = ex - reach;
+            while x <= ex + reach {
+                // Only try the first pixel of each dark run.
+                if !image.get(x, y) || image.get(x - 1, y) {
+                    x += 1;
+                    continue;
+                }
+                let found = alignment_cross_check(|i| image.get(i, y), image.width as i32, x, module)
+                    .and_then(|cx| {
+                        let cy = alignment_cross_check(|i| image.get(cx as i32, i), image.height as i32, y, module)?;
+                        let cx = alignment_cross_check(|i| image.get(i, cy as i32), image.width as i32, cx as i32, module)?;
+                        Some((cx, cy))
+                    });
+                if let Some((cx, cy)) = found.filter(|(cx, cy)| matches_pattern(Point2::new(*cx as f64, *cy as f64))) {
+                    let distance = (cx - estimate.x as f32).hypot(cy - estimate.y as f32);
+                    let better = match best {
+                        Some((d, _)) => distance < d,
+                        None => true,
+                    };
+                    if better {
+                        best = Some((distance, Point2::new(cx as f64, cy as f64)));
+                    }
+                }
+                x += 1;
+            }
+        }
+        if let Some((_, center)) = best {
+            return Some(center);
+        }
+    }
+    None
+}
+
+// Measures a module-sized dark run through `start` with module-sized light runs on either side,
+// each followed by dark, returning the run's center.
+fn alignment_cross_check<F>(dark: F, length: i32, start: i32, module: f32) -> Option<f32> where F: Fn(i32) -> bool {
+    let max = (module * 2.0).ceil() as u32;
+    let (mut center, mut before, mut after) = (0u32, 0u32, 0u32);
+    let mut i = start;
+    while i >= 0 && dark(i) && center <= max {
+        center += 1;
+        i -= 1;
+    }
+    while i >= 0 && !dark(i) && before <= max {
+        before += 1;
+        i -= 1;
+    }
+    if i < 0 || !dark(i) {
+        return None;
+    }
+    let mut i = start + 1;
+    while i < length && dark(i) && center <= max {
+        center += 1;
+        i += 1;
+    }
+    while i < length && !dark(i) && after <= max {
+        after += 1;
+        i += 1;
+    }
+    if i >= length || !dark(i) {
+        return None;
+    }
+    let close = |run: u32| (run as f32 - module).abs() < module * 0.6;
+    if !(close(center) && close(before) && close(after)) {
+        return None;
+    }
+    Some(i as f32 - after as f32 - center as f32 / 2.0)
+}
+
+fn format_code(data: u32) -> u32 {
+    let mut remainder = data;
+    for _ in 0..10 {
+        remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+    }
+    ((data << 10) | remainder) ^ 0x5412
+}
+
+fn version_code(version: u32) -> u32 {
+    let mut remainder = version;
+    for _ in 0..12 {
+        remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1f25);
+    }
+    (version << 12) | remainder
+}
+
+// The value in `range` whose code is closest to one of `readings`, if within 3 bits.
+fn closest_code<F>(readings: &[u32], range: std::ops::RangeInclusive<u32>, code: F) -> Option<u32> where F: Fn(u32) -> u32 {
+    range
+        .flat_map(|value| readings.iter().map(move |r| (value, r)))
+        .map(|(value, reading)| (value, (code(value) ^ reading).count_ones()))
+        .min_by_key(|(_, distance)| *distance)
+        .filter(|(_, distance)| *distance <= 3)
+        .map(|(value, _)| value)
+}
+
+fn alignment_positions(version: u32) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let count = version / 7 + 2;
+    let step = if version == 32 { 26 } else { (version * 4 + count * 2 + 1) / (count * 2 - 2) * 2 };
+    let size = 17 + 4 * version;
+    let mut positions: Vec<usize> = (0..count - 1).map(|i| (size - 7 - i * step) as usize).collect();
+    positions.push(6);
+    positions.reverse();
+    positions
+}
+
+fn raw_data_modules(version: u32) -> usize {
+    let v = version as usize;
+    let mut modules = (16 * v + 128) * v + 64;
+    if v >= 2 {
+        let count = v / 7 + 2;
+        modules -= (25 * count - 10) * count - 55;
+        if v >= 7 {
+            modules -= 36;
+        }
+    }
+    modules
+}
+
+fn mask_bit(mask: u32, x: usize, y: usize) -> bool {
+    match mask {
+        0 => (x + y).is_multiple_of(2),
+        1 => y.is_multiple_of(2),
+        2 => x.is_multiple_of(3),
+        3 => (x + y).is_multiple_of(3),
+        4 => (x / 3 + y / 2).is_multiple_of(2),
+        5 => x * y % 2 + x * y % 3 == 0,
+        6 => (x * y % 2 + x * y % 3).is_multiple_of(2),
+        _ => ((x + y) % 2 + x * y % 3).is_multiple_of(2),
+    }
+}
+
+// A sampled code, `true` for dark modules.
+struct Grid {
+    size: usize,
+    modules: Vec<bool>,
+}
+
+impl Grid {
+    // Samples the center of every module, given the homography from module coordinates to the
+    // image. Fails if the code runs off the image.
+    fn sample(image: &BitImage, h: &Matrix3<f64>, size: usize) -> Option<Self> {
+        let mut modules = Vec::with_capacity(size * size);
+        for y in 0..size {
+            for x in 0..size {
+                let p = apply_homography(h, &Point2::new(x as f64 + 0.5, y as f64 + 0.5));
+                if !(p.x >= 0.0 && p.y >= 0.0 && p.x < image.width as f64 && p.y < image.height as f64) {
+                    return None;
+                }
+                modules.push(image.get(p.x as i32, p.y as i32));
+            }
+        }
+        Some(Grid { size, modules })
+    }
+
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    fn bits<I>(&self, positions: I) -> u32 where I: Iterator<Item = (usize, usize)> {
+        positions.enumerate().fold(0, |bits, (i, (x, y))| bits | (self.get(x, y) as u32) << i)
+    }
+
+    fn decode(&self, version: u32) -> Option<String> {
+        let size = self.size;
+        let first = self.bits((0..15).map(|i| match i {
+            0..=5 => (8, i),
+            6 => (8, 7),
+            7 => (8, 8),
+            8 => (7, 8),
+            _ => (14 - i, 8),
+        }));
+        let second = self.bits((0..15).map(|i| if i < 8 { (size - 1 - i, 8) } else { (8, size - 15 + i) }));
+        let format = closest_code(&[first, second], 0..=31, format_code)?;
+        // Format bits 1, 0, 3, 2 are low, medium, quartile and high.
+        let level = [1, 0, 3, 2][(format >> 3) as usize];
+        let mask = format & 7;
+
+        if version >= 7 {
+            let first = self.bits((0..18).map(|i| (size - 11 + i % 3, i / 3)));
+            let second = self.bits((0..18).map(|i| (i / 3, size - 11 + i % 3)));
+            if let Some(read) = closest_code(&[first, second], 7..=40, version_code) {
+                if read != version {
+                    return None;
+                }
+            }
+        }
+
+        let function = function_modules(version);
+        let raw_codewords = raw_data_modules(version) / 8;
+        let mut codewords = vec![0u8; raw_codewords];
+        let mut bit = 0;
+        let mut right = size as i32 - 1;
+        while right >= 1 && bit < raw_codewords * 8 {
+            if right == 6 {
+                right = 5;
+            }
+            for vertical in 0..size {
+                for j in 0..2 {
+                    let x = right as usize - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward { size - 1 - vertical } else { vertical };
+                    if function[y * size + x] || bit >= raw_codewords * 8 {
+                        continue;
+                    }
+                    if self.get(x, y) != mask_bit(mask, x, y) {
+                        codewords[bit / 8] |= 0x80 >> (bit % 8);
+                    }
+                    bit += 1;
+                }
+            }
+            right -= 2;
+        }
+
+        let data = correct_blocks(&codewords, version, level)?;
+        parse_segments(&data, version)
+    }
+}
+
+// Which modules of a code are finder, timing, alignment, format or version patterns.
+fn function_modules(version: u32) -> Vec<bool> {
+    let size = (17 + 4 * version) as usize;
+    let mut function = vec![false; size * size];
+    let mut set = |x: usize, y: usize| function[y * size + x] = true;
+    for i in 0..size {
+        set(6, i);
+        set(i, 6);
+    }
+    // Finders with their separators, and the format information next to them.
+    for y in 0..9 {
+        for x in 0..9 {
+            set(x, y);
+        }
+    }
+    for i in 0..8 {
+        for j in 0..9 {
+            set(size - 8 + i, j);
+            set(j, size - 8 + i);
+        }
+    }
+    let positions = alignment_positions(version);
+    let last = positions.len().saturating_sub(1);
+    for (i, x) in positions.iter().enumerate() {
+        for (j, y) in positions.iter().enumerate() {
+            // Not on the finders.
+            if (i == 0 && (j == 0 || j == last)) || (i == last && j == 0) {
+                continue;
+            }
+            for dy in 0..5 {
+                for dx in 0..5 {
+                    set(x - 2 + dx, y - 2 + dy);
+                }
+            }
+        }
+    }
+    if version >= 7 {
+        for i in 0..6 {
+            for j in 0..3 {
+                set(size - 11 + j, i);
+                set(i, size - 11 + j);
+            }
+        }
+    }
+    function
+}
+
+// Splits the interleaved codewords into their blocks, corrects each and returns the data
+// codewords in order.
+fn correct_blocks(codewords: &[u8], version: u32, level: usize) -> Option<Vec<u8>> {
+    let blocks = ERROR_CORRECTION_BLOCKS[level][version as usize] as usize;
+    let ecc_len = ECC_CODEWORDS_PER_BLOCK[level][version as usize] as usize;
+    let short_blocks = blocks - codewords.len() % blocks;
+    let short_length = codewords.len() / blocks;
+    if short_length <= ecc_len {
+        return None;
+    }
+    let mut split: Vec<Vec<u8>> = vec![Vec::with_capacity(short_length + 1); blocks];
+    let mut next = codewords.iter();
+    for i in 0..=short_length {
+        for (j, block) in split.iter_mut().enumerate() {
+            // Short blocks have one data codeword less, and the long ones' extra comes last.
+            if i != short_length - ecc_len || j >= short_blocks {
+                block.push(*next.next()?);
+            }
+        }
+    }
+    let mut data = Vec::new();
+    for mut block in split {
+        if !reed_solomon::correct(&mut block, ecc_len) {
+            return None;
+        }
+        data.extend_from_slice(&block[..block.len() - ecc_len]);
+    }
+    Some(data)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl BitReader<'_> {
+    fn remaining(&self) -> usize {
+        self.data.len() * 8 - self.position
+    }
+
+    fn read(&mut self, bits: usize) -> Option<u32> {
+        if bits > self.remaining() {
+            return None;
+        }
+        let mut value = 0;
+        for _ in 0..bits {
+            let bit = (self.data[self.position / 8] >> (7 - self.position % 8)) & 1;
+            value = (value << 1) | bit as u32;
+            self.position += 1;
+        }
+        Some(value)
+    }
+}
+
+// Reads the numeric, alphanumeric and byte segments of a code's data. Byte segments are read as
+// UTF-8, or as Latin-1 if they aren't valid UTF-8; ECI designators are skipped. Kanji segments
+// aren't supported.
+fn parse_segments(data: &[u8], version: u32) -> Option<String> {
+    let group = match version {
+        1..=9 => 0,
+        10..=26 => 1,
+        _ => 2,
+    };
+    let mut reader = BitReader { data, position: 0 };
+    let mut text = Vec::new();
+    while reader.remaining() >= 4 {
+        match reader.read(4)? {
+            0 => break,
+            1 => {
+                let mut count = reader.read([10, 12, 14][group])?;
+                while count > 0 {
+                    let (bits, digits) = match count {
+                        1 => (4, 1),
+                        2 => (7, 2),
+                        _ => (10, 3),
+                    };
+                    let value = reader.read(bits)?;
+                    if value >= 10u32.pow(digits) {
+                        return None;
+                    }
+                    text.extend_from_slice(format!("{:0width$}", value, width = digits as usize).as_bytes());
+                    count -= digits;
+                }
+            }
+            2 => {
+                let mut count = reader.read([9, 11, 13][group])?;
+                while count >= 2 {
+                    let value = reader.read(11)? as usize;
+                    if value >= 45 * 45 {
+                        return None;
+                    }
+                    text.push(ALPHANUMERIC[value / 45]);
+                    text.push(ALPHANUMERIC[value % 45]);
+                    count -= 2;
+                }
+                if count == 1 {
+                    text.push(*ALPHANUMERIC.get(reader.read(6)? as usize)?);
+                }
+            }
+            4 => {
+                let count = reader.read([8, 16, 16][group])?;
+                for _ in 0..count {
+                    text.push(reader.read(8)? as u8);
+                }
+            }
+            7 => {
+                let first = reader.read(8)?;
+                if first & 0x80 != 0 {
+                    reader.read(if first & 0x40 == 0 { 8 } else { 16 })?;
+                }
+            }
+            // Structured append, which isn't joined up with the other codes.
+            3 => {
+                reader.read(16)?;
+            }
+            // FNC1 markers.
+            5 => {}
+            9 => {
+                reader.read(8)?;
+            }
+            _ => return None,
+        }
+    }
+    Some(String::from_utf8(text).unwrap_or_else(|e| e.into_bytes().iter().map(|b| *b as char).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma};
+    use qrcodegen::{Mask, QrCode, QrCodeEcc, QrSegment, Version};
+    use crate::barcode::{BarcodeReader, Symbology};
+
+    fn encode(text: &str, version: u8, mask: u8, level: QrCodeEcc) -> QrCode {
+        let segments = QrSegment::make_segments(text);
+        QrCode::encode_segments_advanced(&segments, level, Version::new(version), Version::new(version), Some(Mask::new(mask)), false).unwrap()
+    }
+
+    // Four pixels a module, with a four module quiet zone. `flipped` modules are drawn inverted.
+    fn render(code: &QrCode, flipped: &[(i32, i32)]) -> GrayImage {
+        let size = code.size() as u32 + 8;
+        GrayImage::from_fn(4 * size, 4 * size, |x, y| {
+            let (mx, my) = ((x / 4) as i32 - 4, (y / 4) as i32 - 4);
+            let dark = code.get_module(mx, my) != flipped.contains(&(mx, my));
+            Luma([if dark { 20 } else { 235 }])
+        })
+    }
+
+    fn read(image: &GrayImage) -> Vec<String> {
+        BarcodeReader::new(vec![Symbology::Qr]).read(image).into_iter().map(|c| c.text).collect()
+    }
+
+    #[test]
+    fn reads_versions_and_masks() {
+        let cases = [
+            ("HELLO WORLD", 1, 0, QrCodeEcc::Low),
+            ("0123456789012345", 2, 3, QrCodeEcc::Medium),
+            ("https://example.com/scenario?id=7", 7, 5, QrCodeEcc::Quartile),
+            ("mixed Case text, with punctuation!", 10, 6, QrCodeEcc::High),
+        ];
+        for (text, version, mask, level) in cases {
+            let code = encode(text, version, mask, level);
+            assert_eq!(read(&render(&code, &[])), [text], "version {} mask {}", version, mask);
+        }
+    }
+
+    #[test]
+    fn corrects_damaged_modules() {
+        let text = "DAMAGED CODE 42";
+        let code = encode(text, 3, 2, QrCodeEcc::High);
+        // A block of data modules between the finders and the alignment pattern
+        let flipped: Vec<(i32, i32)> = (9..13).flat_map(|y| (9..13).map(move |x| (x, y))).collect();
+        assert_eq!(read(&render(&code, &flipped)), [text]);
+    }
+}
@@ -0,0 +1,192 @@
+// Reed-Solomon error correction over GF(256) with the QR code polynomial x^8 + x^4 + x^3 + x^2 + 1,
+// for generators with consecutive roots starting at a^0.
+
+const fn exp_table() -> [u8; 512] {
+    let mut table = [0u8; 512];
+    let mut value: u16 = 1;
+    let mut i = 0;
+    while i < 512 {
+        table[i] = value as u8;
+        value <<= 1;
+        if value & 0x100 != 0 {
+            value ^= 0x11d;
+        }
+        i += 1;
+    }
+    table
+}
+
+const fn log_table() -> [u8; 256] {
+    let exp = exp_table();
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 255 {
+        table[exp[i] as usize] = i as u8;
+        i += 1;
+    }
+    table
+}
+
+const EXP: [u8; 512] = exp_table();
+const LOG: [u8; 256] = log_table();
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        0
+    } else {
+        EXP[LOG[a as usize] as usize + LOG[b as usize] as usize]
+    }
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    if a == 0 {
+        0
+    } else {
+        EXP[LOG[a as usize] as usize + 255 - LOG[b as usize] as usize]
+    }
+}
+
+// a^power, for any power.
+fn pow(power: i32) -> u8 {
+    EXP[power.rem_euclid(255) as usize]
+}
+
+// Evaluates a polynomial stored lowest degree first.
+fn evaluate(poly: &[u8], x: u8) -> u8 {
+    poly.iter().rev().fold(0, |acc, c| mul(acc, x) ^ c)
+}
+
+/// Corrects `block`, data codewords followed by `ecc_len` error correction codewords, in place.
+/// Returns false if there are more errors than can be corrected.
+pub(super) fn correct(block: &mut [u8], ecc_len: usize) -> bool {
+    let n = block.len();
+    // The block as a polynomial has its first codeword as the highest degree coefficient.
+    let syndromes: Vec<u8> = (0..ecc_len)
+        .map(|i| block.iter().fold(0, |acc, c| mul(acc, pow(i as i32)) ^ c))
+        .collect();
+    if syndromes.iter().all(|s| *s == 0) {
+        return true;
+    }
+
+    // Berlekamp-Massey for the error locator, lowest degree first.
+    let mut locator = vec![1u8];
+    let mut previous = vec![1u8];
+    let mut errors = 0;
+    let mut shift = 1;
+    let mut previous_discrepancy = 1u8;
+    for k in 0..ecc_len {
+        let discrepancy = (1..=errors.min(locator.len() - 1))
+            .fold(syndromes[k], |d, i| d ^ mul(locator[i], syndromes[k - i]));
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+        let scale = div(discrepancy, previous_discrepancy);
+        let last = locator.clone();
+        if locator.len() < previous.len() + shift {
+            locator.resize(previous.len() + shift, 0);
+        }
+        for (i, c) in previous.iter().enumerate() {
+            locator[i + shift] ^= mul(scale, *c);
+        }
+        if 2 * errors <= k {
+            errors = k + 1 - errors;
+            previous = last;
+            previous_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            shift += 1;
+        }
+    }
+    if 2 * errors > ecc_len {
+        return false;
+    }
+    locator.truncate(errors + 1);
+
+    // The error evaluator, syndromes times locator modulo x^ecc_len.
+    let mut evaluator = vec![0u8; ecc_len];
+    for (i, s) in syndromes.iter().enumerate() {
+        for (j, l) in locator.iter().enumerate() {
+            if i + j < ecc_len {
+                evaluator[i + j] ^= mul(*s, *l);
+            }
+        }
+    }
+    let derivative: Vec<u8> = locator.iter().enumerate().skip(1)
+        .map(|(i, c)| if i % 2 == 1 { *c } else { 0 })
+        .collect();
+
+    // Chien search for the error positions, then Forney for their values.
+    let mut found = 0;
+    for (position, codeword) in block.iter_mut().enumerate() {
+        let power = (n - 1 - position) as i32;
+        let x_inverse = pow(-power);
+        if evaluate(&locator, x_inverse) != 0 {
+            continue;
+        }
+        let denominator = evaluate(&derivative, x_inverse);
+        if denominator == 0 {
+            return false;
+        }
+        *codeword ^= mul(pow(power), div(evaluate(&evaluator, x_inverse), denominator));
+        found += 1;
+    }
+    found == errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `data` followed by the remainder of dividing it, times x^ecc_len, by the generator.
+    fn encode(data: &[u8], ecc_len: usize) -> Vec<u8> {
+        // Highest degree first
+        let mut generator = vec![1u8];
+        for i in 0..ecc_len {
+            let mut next = vec![0u8; generator.len() + 1];
+            for (j, c) in generator.iter().enumerate() {
+                next[j] ^= *c;
+                next[j + 1] ^= mul(*c, pow(i as i32));
+            }
+            generator = next;
+        }
+        let mut block = data.to_vec();
+        block.resize(data.len() + ecc_len, 0);
+        for i in 0..data.len() {
+            let factor = block[i];
+            for (j, g) in generator.iter().enumerate() {
+                block[i + j] ^= mul(*g, factor);
+            }
+        }
+        block[..data.len()].copy_from_slice(data);
+        block
+    }
+
+    #[test]
+    fn corrects_errors() {
+        let data: Vec<u8> = (0..20).map(|i| (i * 37 + 11) as u8).collect();
+        let encoded = encode(&data, 10);
+        let mut clean = encoded.clone();
+        assert!(correct(&mut clean, 10));
+        assert_eq!(clean, encoded);
+
+        // Up to half the error correction codewords, in data and in error correction
+        let mut damaged = encoded.clone();
+        for (position, error) in [(0, 0x55), (7, 0x01), (19, 0xff), (22, 0x80), (29, 0x3c)] {
+            damaged[position] ^= error;
+        }
+        assert!(correct(&mut damaged, 10));
+        assert_eq!(damaged, encoded);
+    }
+
+    #[test]
+    fn refuses_too_many_errors() {
+        let data: Vec<u8> = (0..20).map(|i| (i * 37 + 11) as u8).collect();
+        let encoded = encode(&data, 10);
+        let mut damaged = encoded.clone();
+        for (position, error) in [(0, 0x55), (3, 0x10), (7, 0x01), (19, 0xff), (22, 0x80), (29, 0x3c)] {
+            damaged[position] ^= error;
+        }
+        assert!(!correct(&mut damaged, 10));
+    }
+}
@@ -0,0 +1,65 @@
+use std::path::PathBuf;
+use clap::Parser;
+use acv::aruco::Dictionary;
+use acv::calibration::{self, CalibrationFile, CalibrationTarget};
+use acv::calibration::charuco::CharucoBoard;
+
+/// Calibrates a camera from a folder of checkerboard or ChArUco board captures and stores the
+/// result in a calibration file, keyed by camera id and resolution.
+#[derive(Parser, Debug)]
+struct Args {
+    /// Folder of captures taken at the resolution being calibrated
+    images: PathBuf,
+    /// Calibration file to add the result to (created if it doesn't exist)
+    #[arg(short, long, default_value = "calibration.json")]
+    output: PathBuf,
+    #[arg(long, default_value = "0")]
+    camera_id: String,
+    /// Inner corners along a row of the checkerboard, or squares along a row of a ChArUco board
+    #[arg(long)]
+    columns: u32,
+    /// Inner corners along a column of the checkerboard, or squares along a column of a ChArUco
+    /// board
+    #[arg(long)]
+    rows: u32,
+    /// Side length of one square, in the units the pose estimates should use
+    #[arg(long)]
+    square_size: f64,
+    /// ArUco dictionary of a ChArUco board: one of OpenCV's predefined ones, such as 4x4_50, or
+    /// a file as written by OpenCV's writeDictionary
+    #[arg(long, requires = "marker_size")]
+    charuco: Option<String>,
+    /// Side length of the ChArUco board's markers, in the same units as the squares
+    #[arg(long)]
+    marker_size: Option<f64>,
+}
+
+fn main() -> Result<(), acv::Error> {
+    // Skipped captures are reported as warnings, so show those and the progress by default.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = Args::parse();
+    let target = match (&args.charuco, args.marker_size) {
+        (Some(dictionary), Some(marker_size)) => CalibrationTarget::Charuco(CharucoBoard {
+            columns: args.columns,
+            rows: args.rows,
+            square_size: args.square_size,
+            marker_size,
+            dictionary: Dictionary::predefined_or_load(dictionary)?,
+        }),
+        _ => CalibrationTarget::Checkerboard {
+            columns: args.columns,
+            rows: args.rows,
+            square_size: args.square_size,
+        },
+    };
+    let calibration = calibration::calibrate_directory(&args.images, target, &args.camera_id)?;
+    println!("{:#?}", calibration);
+
+    let mut file = if args.output.exists() {
+        CalibrationFile::load(&args.output)?
+    } else {
+        CalibrationFile::default()
+    };
+    file.insert(calibration);
+    file.save(&args.output)
+}
@@ -0,0 +1,231 @@
+use std::path::Path;
+use image::GrayImage;
+use log::{info, warn};
+use nalgebra::{DMatrix, DVector, Point2, Point3, Vector3};
+use serde::{Deserialize, Serialize};
+use crate::geometry::{self, CameraIntrinsics, Distortion};
+use crate::pose;
+
+pub mod charuco;
+pub mod checkerboard;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CalibrationTarget {
+    /// A checkerboard with `columns` x `rows` inner corners and squares `square_size` across.
+    Checkerboard { columns: u32, rows: u32, square_size: f64 },
+    /// A ChArUco board, which can be partly out of view.
+    Charuco(charuco::CharucoBoard),
+}
+
+impl CalibrationTarget {
+    /// Detects the target and returns the matched object and image points.
+    pub fn detect(&self, image: &GrayImage) -> Option<CalibrationView> {
+        match *self {
+            CalibrationTarget::Checkerboard { columns, rows, square_size } => {
+                let corners = checkerboard::find_checkerboard_corners(image, columns, rows)?;
+                let object = (0..rows)
+                    .flat_map(|j| (0..columns).map(move |i| Point3::new(i as f64 * square_size, j as f64 * square_size, 0.0)))
+                    .collect();
+                Some(CalibrationView { object, image: corners })
+            }
+            CalibrationTarget::Charuco(ref board) => charuco::find_charuco_corners(image, board),
+        }
+    }
+}
+
+/// Object points on a planar target (z = 0) and where they were seen in one image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CalibrationView {
+    pub object: Vec<Point3<f64>>,
+    pub image: Vec<Point2<f64>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    pub camera_id: String,
+    pub width: u32,
+    pub height: u32,
+    pub intrinsics: CameraIntrinsics,
+    pub distortion: Distortion,
+    /// RMS reprojection error over all views, in pixels.
+    pub rms_error: f64,
+}
+
+impl Calibration {
+    pub fn project(&self, point: &Point3<f64>) -> Point2<f64> {
+        self.intrinsics.project_distorted(point, &self.distortion)
+    }
+}
+
+/// A set of calibrations, one per camera and resolution, stored as JSON.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CalibrationFile {
+    pub calibrations: Vec<Calibration>,
+}
+
+impl CalibrationFile {
+    pub fn load<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> crate::Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn get(&self, camera_id: &str, width: u32, height: u32) -> Option<&Calibration> {
+        self.calibrations.iter().find(|c| c.camera_id == camera_id && c.width == width && c.height == height)
+    }
+
+    /// Adds a calibration, replacing any existing one for the same camera and resolution.
+    pub fn insert(&mut self, calibration: Calibration) {
+        self.calibrations.retain(|c| !(c.camera_id == calibration.camera_id && c.width == calibration.width && c.height == calibration.height));
+        self.calibrations.push(calibration);
+    }
+}
+
+/// Collects views of a calibration target and solves for the camera intrinsics and distortion.
+pub struct Calibrator {
+    pub target: CalibrationTarget,
+    width: u32,
+    height: u32,
+    views: Vec<CalibrationView>,
+}
+
+impl Calibrator {
+    pub fn new(target: CalibrationTarget, width: u32, height: u32) -> Self {
+        Calibrator {
+            target,
+            width,
+            height,
+            views: Vec::new(),
+        }
+    }
+
+    /// Looks for the target in `image` and keeps the view if it was found.
+    pub fn add_image(&mut self, image: &GrayImage) -> crate::Result<bool> {
+        if image.dimensions() != (self.width, self.height) {
+            return Err(format!("Expected a {}x{} image, got {}x{}", self.width, self.height, image.width(), image.height()).into());
+        }
+        match self.target.detect(image) {
+            Some(view) => {
+                self.views.push(view);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn add_view(&mut self, view: CalibrationView) {
+        self.views.push(view);
+    }
+
+    pub fn views(&self) -> &[CalibrationView] {
+        &self.views
+    }
+
+    /// Zhang's method: a closed-form initial guess from the view homographies, then a joint
+    /// Levenberg-Marquardt refinement of intrinsics, distortion and every view's pose.
+    pub fn calibrate(&self, camera_id: &str) -> crate::Result<Calibration> {
+        if self.views.len() < 3 {
+            return Err(format!("Calibration needs at least 3 views of the target, got {}", self.views.len()).into());
+        }
+        let initial = self.initial_intrinsics()?;
+        let mut params = vec![initial.fx, initial.fy, initial.cx, initial.cy, 0.0, 0.0, 0.0, 0.0, 0.0];
+        for view in self.views.iter() {
+            let estimate = pose::estimate_planar_pose(&view.object, &view.image, &initial)?;
+            params.extend_from_slice(&geometry::isometry_to_params(&estimate.pose));
+        }
+
+        let params = geometry::levenberg_marquardt(DVector::from_vec(params), 100, |params| self.residuals(params));
+        let residuals = self.residuals(&params);
+        let point_count: usize = self.views.iter().map(|v| v.object.len()).sum();
+        let (intrinsics, distortion) = camera_from_params(params.as_slice());
+        Ok(Calibration {
+            camera_id: camera_id.to_string(),
+            width: self.width,
+            height: self.height,
+            intrinsics,
+            distortion,
+            rms_error: (residuals.norm_squared() / point_count as f64).sqrt(),
+        })
+    }
+
+    // Focal lengths from the orthogonality of each view's rotation columns, assuming the principal
+    // point is at the image center (the same initialization OpenCV uses)
+    fn initial_intrinsics(&self) -> crate::Result<CameraIntrinsics> {
+        let cx = (self.width as f64 - 1.0) / 2.0;
+        let cy = (self.height as f64 - 1.0) / 2.0;
+        let mut a = DMatrix::zeros(self.views.len() * 2, 2);
+        let mut b = DVector::zeros(self.views.len() * 2);
+        for (i, view) in self.views.iter().enumerate() {
+            let object: Vec<Point2<f64>> = view.object.iter().map(|p| Point2::new(p.x, p.y)).collect();
+            let h = geometry::find_homography(&object, &view.image).ok_or("Degenerate calibration view")?;
+            let column = |c: usize| Vector3::new(h[(0, c)] - cx * h[(2, c)], h[(1, c)] - cy * h[(2, c)], h[(2, c)]);
+            let (h1, h2) = (column(0), column(1));
+            let pairs = [(h1.normalize(), h2.normalize()), ((h1 + h2).normalize(), (h1 - h2).normalize())];
+            for (k, (u, v)) in pairs.iter().enumerate() {
+                a[(2 * i + k, 0)] = u.x * v.x;
+                a[(2 * i + k, 1)] = u.y * v.y;
+                b[2 * i + k] = -u.z * v.z;
+            }
+        }
+        let fallback = self.width.max(self.height) as f64;
+        let solution = a.svd(true, true).solve(&b, 1e-12).map_err(|e| e.to_string())?;
+        let focal = |inverse_square: f64| if inverse_square > 0.0 { 1.0 / inverse_square.sqrt() } else { fallback };
+        Ok(CameraIntrinsics::new(focal(solution[0]), focal(solution[1]), cx, cy))
+    }
+
+    fn residuals(&self, params: &DVector<f64>) -> DVector<f64> {
+        let (intrinsics, distortion) = camera_from_params(params.as_slice());
+        let point_count: usize = self.views.iter().map(|v| v.object.len()).sum();
+        let mut residuals = DVector::zeros(point_count * 2);
+        let mut row = 0;
+        for (i, view) in self.views.iter().enumerate() {
+            let pose = geometry::isometry_from_params(&params.as_slice()[9 + 6 * i..15 + 6 * i]);
+            for (object, image) in view.object.iter().zip(view.image.iter()) {
+                let projected = intrinsics.project_distorted(&(pose * object), &distortion);
+                residuals[row] = projected.x - image.x;
+                residuals[row + 1] = projected.y - image.y;
+                row += 2;
+            }
+        }
+        residuals
+    }
+}
+
+fn camera_from_params(params: &[f64]) -> (CameraIntrinsics, Distortion) {
+    (
+        CameraIntrinsics::new(params[0], params[1], params[2], params[3]),
+        Distortion { k1: params[4], k2: params[5], p1: params[6], p2: params[7], k3: params[8] },
+    )
+}
+
+/// Calibrates from every image in `directory`. The resolution is taken from the first readable
+/// image; images that fail to load, have another size or don't show the target are skipped.
+pub fn calibrate_directory<P: AsRef<Path>>(directory: P, target: CalibrationTarget, camera_id: &str) -> crate::Result<Calibration> {
+    let mut paths: Vec<_> = std::fs::read_dir(directory)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    let mut calibrator: Option<Calibrator> = None;
+    for path in paths.iter() {
+        let image = match image::open(path) {
+            Ok(image) => image.to_luma8(),
+            Err(e) => {
+                warn!("Skipping {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let calibrator = calibrator.get_or_insert_with(|| Calibrator::new(target.clone(), image.width(), image.height()));
+        match calibrator.add_image(&image) {
+            Ok(true) => info!("Found target in {}", path.display()),
+            Ok(false) => warn!("No target found in {}", path.display()),
+            Err(e) => warn!("Skipping {}: {}", path.display(), e),
+        }
+    }
+    calibrator.ok_or("No images found")?.calibrate(camera_id)
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use image::GrayImage;
+use nalgebra::{Point2, Point3, Vector2};
+use crate::aruco::{ArucoDetector, Dictionary};
+use crate::geometry::{apply_homography, find_homography};
+use super::checkerboard::{blurred_f32, refine_corner};
+use super::CalibrationView;
+
+/// A checkerboard with ArUco markers in its white squares, laid out like OpenCV's `CharucoBoard`:
+/// the top left square is black and markers are numbered row by row from 0.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CharucoBoard {
+    /// Squares along a row of the board.
+    pub columns: u32,
+    /// Squares along a column of the board.
+    pub rows: u32,
+    pub square_size: f64,
+    /// Side length of the markers, in the same units as the squares.
+    pub marker_size: f64,
+    pub dictionary: Dictionary,
+}
+
+impl CharucoBoard {
+    /// The square marker `id` is in.
+    pub fn marker_square(&self, id: usize) -> Option<(u32, u32)> {
+        (0..self.rows)
+            .flat_map(|y| (0..self.columns).map(move |x| (x, y)))
+            .filter(|(x, y)| (x + y) % 2 == 1)
+            .nth(id)
+    }
+
+    /// Number of inner corners, where four squares meet.
+    pub fn corner_count(&self) -> usize {
+        (self.columns.saturating_sub(1) * self.rows.saturating_sub(1)) as usize
+    }
+
+    // Inner corners are numbered row-major from the top left, with the first at the origin.
+    fn corner_position(&self, index: usize) -> Point3<f64> {
+        let columns = self.columns as usize - 1;
+        Point3::new((index % columns) as f64 * self.square_size, (index / columns) as f64 * self.square_size, 0.0)
+    }
+}
+
+/// Finds the board's inner corners next to the markers that were detected. Each corner is
+/// predicted from the homographies of the markers beside it and refined on the image. Returns
+/// `None` if fewer than six corners spanning at least two rows and columns were found.
+pub fn find_charuco_corners(image: &GrayImage, board: &CharucoBoard) -> Option<CalibrationView> {
+    if board.columns < 2 || board.rows < 2 {
+        return None;
+    }
+    let detector = ArucoDetector::new(board.dictionary.clone());
+    let margin = (board.square_size - board.marker_size) / 2.0;
+    let inner_columns = board.columns as i32 - 1;
+
+    // Each corner's predicted positions, and the square side in pixels around it.
+    let mut predictions: HashMap<usize, (Vector2<f64>, usize, f64)> = HashMap::new();
+    for marker in detector.detect(image) {
+        let (x, y) = match board.marker_square(marker.id) {
+            Some(square) => square,
+            None => continue,
+        };
+        let (left, top) = (x as f64 * board.square_size, y as f64 * board.square_size);
+        let (near, far) = (margin, margin + board.marker_size);
+        let board_corners = [
+            Point2::new(left + near, top + near),
+            Point2::new(left + far, top + near),
+            Point2::new(left + far, top + far),
+            Point2::new(left + near, top + far),
+        ];
+        let h = match find_homography(&board_corners, &marker.corners) {
+            Some(h) => h,
+            None => continue,
+        };
+        let side = (marker.corners[1] - marker.corners[0]).norm() * board.square_size / board.marker_size;
+        for (dx, dy) in [(0, 0), (1, 0), (1, 1), (0, 1)] {
+            // Square corners on the edge of the board aren't inner corners.
+            let (i, j) = (x as i32 + dx - 1, y as i32 + dy - 1);
+            if i < 0 || j < 0 || i >= inner_columns || j >= board.rows as i32 - 1 {
+                continue;
+            }
+            let board_point = Point2::new((x as i32 + dx) as f64 * board.square_size, (y as i32 + dy) as f64 * board.square_size);
+            let predicted = apply_homography(&h, &board_point).coords;
+            let entry = predictions.entry((j * inner_columns + i) as usize).or_insert((Vector2::zeros(), 0, 0.0));
+            entry.0 += predicted;
+            entry.1 += 1;
+            entry.2 = entry.2.max(side);
+        }
+    }
+
+    let blurred = blurred_f32(image, 1.0);
+    let mut corners: Vec<(usize, Point2<f64>)> = predictions.into_iter()
+        .map(|(index, (sum, count, side))| {
+            let predicted = Point2::from(sum / count as f64);
+            let window = ((0.8 * side * margin / board.square_size) as i32).clamp(2, 6);
+            (index, refine_corner(&blurred, &predicted, window))
+        })
+        .filter(|(_, p)| p.x >= 0.0 && p.y >= 0.0 && p.x < image.width() as f64 && p.y < image.height() as f64)
+        .collect();
+    corners.sort_by_key(|(index, _)| *index);
+
+    if corners.len() < 6 {
+        return None;
+    }
+    // Corners all along one row or column don't pin down the board's plane.
+    let columns = inner_columns as usize;
+    let first = corners[0].0;
+    if corners.iter().all(|(index, _)| index % columns == first % columns) || corners.iter().all(|(index, _)| index / columns == first / columns) {
+        return None;
+    }
+    Some(CalibrationView {
+        object: corners.iter().map(|(index, _)| board.corner_position(*index)).collect(),
+        image: corners.into_iter().map(|(_, p)| p).collect(),
+    })
+}
@@ -0,0 +1,258 @@
+use std::collections::{HashMap, VecDeque};
+use image::{GrayImage, Luma};
+use imageproc::definitions::Image;
+use nalgebra::{Matrix2, Point2, Vector2};
+
+/// Finds the inner corners of a checkerboard with `columns` x `rows` inner corners. Corners are
+/// returned row-major, starting from the corner closest to the top left of the image, with rows
+/// running down the board. Returns `None` unless every corner was found.
+pub fn find_checkerboard_corners(image: &GrayImage, columns: u32, rows: u32) -> Option<Vec<Point2<f64>>> {
+    let blurred = blurred_f32(image, 1.5);
+    let candidates = saddle_points(&blurred, (columns * rows * 4) as usize);
+    let candidates: Vec<Point2<f64>> = candidates.iter()
+        .filter(|p| is_x_corner(&blurred, p, 5.0))
+        .map(|p| refine_corner(&blurred, p, 4))
+        .collect();
+    assemble_grid(&candidates, columns as i32, rows as i32)
+}
+
+pub(crate) fn blurred_f32(image: &GrayImage, sigma: f32) -> Image<Luma<f32>> {
+    let image: Image<Luma<f32>> = Image::from_fn(image.width(), image.height(), |x, y| Luma([image.get_pixel(x, y)[0] as f32]));
+    imageproc::filter::gaussian_blur_f32(&image, sigma)
+}
+
+/// Local maxima of the negated Hessian determinant, which peaks where two dark and two light
+/// squares meet. Returns at most `limit` points, strongest first.
+pub(crate) fn saddle_points(image: &Image<Luma<f32>>, limit: usize) -> Vec<Point2<f64>> {
+    let (width, height) = image.dimensions();
+    if width < 3 || height < 3 {
+        return Vec::new();
+    }
+    let at = |x: u32, y: u32| image.get_pixel(x, y)[0];
+    let mut response = vec![0.0_f32; (width * height) as usize];
+    let mut max_response = 0.0_f32;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let center = at(x, y);
+            let ixx = at(x + 1, y) - 2.0 * center + at(x - 1, y);
+            let iyy = at(x, y + 1) - 2.0 * center + at(x, y - 1);
+            let ixy = (at(x + 1, y + 1) - at(x + 1, y - 1) - at(x - 1, y + 1) + at(x - 1, y - 1)) / 4.0;
+            let value = (ixy * ixy - ixx * iyy).max(0.0);
+            response[(y * width + x) as usize] = value;
+            max_response = max_response.max(value);
+        }
+    }
+    if max_response <= 0.0 {
+        return Vec::new();
+    }
+
+    let threshold = max_response * 0.05;
+    let radius = 3_i32;
+    let mut peaks = Vec::new();
+    for y in 1..height as i32 - 1 {
+        for x in 1..width as i32 - 1 {
+            let value = response[(y as u32 * width + x as u32) as usize];
+            if value < threshold {
+                continue;
+            }
+            let mut is_max = true;
+            'window: for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if (dx, dy) == (0, 0) || nx < 0 || ny < 0 || nx >= width as i32 || ny >= height as i32 {
+                        continue;
+                    }
+                    let other = response[(ny as u32 * width + nx as u32) as usize];
+                    // Ties go to the first pixel in scan order
+                    if other > value || (other == value && (dy, dx) < (0, 0)) {
+                        is_max = false;
+                        break 'window;
+                    }
+                }
+            }
+            if is_max {
+                peaks.push((value, Point2::new(x as f64, y as f64)));
+            }
+        }
+    }
+    peaks.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+    peaks.into_iter().take(limit).map(|(_, p)| p).collect()
+}
+
+/// Checks that a circle around the point crosses two dark and two light sectors, which rejects the
+/// T and L junctions along the edges of the board.
+pub(crate) fn is_x_corner(image: &Image<Luma<f32>>, point: &Point2<f64>, radius: f64) -> bool {
+    const SAMPLES: usize = 24;
+    let (width, height) = image.dimensions();
+    let mut values = [0.0_f32; SAMPLES];
+    for (i, value) in values.iter_mut().enumerate() {
+        let angle = i as f64 * std::f64::consts::TAU / SAMPLES as f64;
+        let x = (point.x + radius * angle.cos()).round();
+        let y = (point.y + radius * angle.sin()).round();
+        if x < 0.0 || y < 0.0 || x >= width as f64 || y >= height as f64 {
+            return false;
+        }
+        *value = image.get_pixel(x as u32, y as u32)[0];
+    }
+    let min = values.iter().cloned().fold(f32::MAX, f32::min);
+    let max = values.iter().cloned().fold(f32::MIN, f32::max);
+    if max - min < 20.0 {
+        return false;
+    }
+    let mid = (min + max) / 2.0;
+    let signs: Vec<bool> = values.iter().map(|v| *v > mid).collect();
+    let changes = (0..SAMPLES).filter(|&i| signs[i] != signs[(i + 1) % SAMPLES]).count();
+    changes == 4
+}
+
+/// Moves a corner to the point where the image gradients in a window around it are orthogonal to
+/// the offsets from it, like OpenCV's `cornerSubPix`.
+pub(crate) fn refine_corner(image: &Image<Luma<f32>>, corner: &Point2<f64>, radius: i32) -> Point2<f64> {
+    let (width, height) = image.dimensions();
+    let mut estimate = *corner;
+    for _ in 0..10 {
+        let cx = estimate.x.round() as i32;
+        let cy = estimate.y.round() as i32;
+        let mut a = Matrix2::zeros();
+        let mut b = Vector2::zeros();
+        for y in cy - radius..=cy + radius {
+            for x in cx - radius..=cx + radius {
+                if x < 1 || y < 1 || x >= width as i32 - 1 || y >= height as i32 - 1 {
+                    continue;
+                }
+                let (ux, uy) = (x as u32, y as u32);
+                let gx = ((image.get_pixel(ux + 1, uy)[0] - image.get_pixel(ux - 1, uy)[0]) / 2.0) as f64;
+                let gy = ((image.get_pixel(ux, uy + 1)[0] - image.get_pixel(ux, uy - 1)[0]) / 2.0) as f64;
+                let g = Vector2::new(gx, gy);
+                let ggt = g * g.transpose();
+                a += ggt;
+                b += ggt * Vector2::new(x as f64, y as f64);
+            }
+        }
+        let next = match a.try_inverse() {
+            Some(inverse) => inverse * b,
+            None => return estimate,
+        };
+        let next = Point2::new(next.x, next.y);
+        // Don't let the refinement wander off to a different corner
+        if (next - corner).norm() > radius as f64 {
+            return estimate;
+        }
+        let shift = (next - estimate).norm();
+        estimate = next;
+        if shift < 0.01 {
+            break;
+        }
+    }
+    estimate
+}
+
+/// Grows a lattice out from each candidate in turn until one yields a complete `columns` x `rows`
+/// grid, then puts it into canonical order.
+pub(crate) fn assemble_grid(candidates: &[Point2<f64>], columns: i32, rows: i32) -> Option<Vec<Point2<f64>>> {
+    let expected = (columns * rows) as usize;
+    if candidates.len() < expected {
+        return None;
+    }
+    let centroid = candidates.iter().fold(Vector2::zeros(), |acc, p| acc + p.coords) / candidates.len() as f64;
+    let mut seeds: Vec<usize> = (0..candidates.len()).collect();
+    seeds.sort_by(|a, b| {
+        let da = (candidates[*a].coords - centroid).norm();
+        let db = (candidates[*b].coords - centroid).norm();
+        da.partial_cmp(&db).unwrap()
+    });
+
+    for &seed in seeds.iter().take(expected.max(16)) {
+        let grid = match grow_lattice(candidates, seed) {
+            Some(grid) => grid,
+            None => continue,
+        };
+        if grid.len() != expected {
+            continue;
+        }
+        let min_i = grid.keys().map(|k| k.0).min()?;
+        let max_i = grid.keys().map(|k| k.0).max()?;
+        let min_j = grid.keys().map(|k| k.1).min()?;
+        let max_j = grid.keys().map(|k| k.1).max()?;
+        let (span_i, span_j) = (max_i - min_i + 1, max_j - min_j + 1);
+        let transposed = if (span_i, span_j) == (columns, rows) {
+            false
+        } else if (span_i, span_j) == (rows, columns) {
+            true
+        } else {
+            continue;
+        };
+        let lattice: HashMap<(i32, i32), Point2<f64>> = grid.into_iter()
+            .map(|((i, j), p)| if transposed { ((j - min_j, i - min_i), p) } else { ((i - min_i, j - min_j), p) })
+            .collect();
+        return Some(canonical_order(&lattice, columns, rows));
+    }
+    None
+}
+
+fn grow_lattice(candidates: &[Point2<f64>], seed: usize) -> Option<HashMap<(i32, i32), Point2<f64>>> {
+    let origin = candidates[seed];
+    let mut neighbours: Vec<(f64, Vector2<f64>)> = candidates.iter().enumerate()
+        .filter(|(i, _)| *i != seed)
+        .map(|(_, p)| ((p - origin).norm(), p - origin))
+        .collect();
+    neighbours.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let step_i = neighbours.first()?.1;
+    let step_j = neighbours.iter().take(8)
+        .map(|(_, v)| *v)
+        .find(|v| (v.dot(&step_i) / (v.norm() * step_i.norm())).abs() < 0.5 && v.norm() < 2.0 * step_i.norm())?;
+
+    let mut used = vec![false; candidates.len()];
+    used[seed] = true;
+    let mut grid = HashMap::new();
+    grid.insert((0, 0), origin);
+    let mut queue = VecDeque::new();
+    queue.push_back((0, 0));
+    while let Some((i, j)) = queue.pop_front() {
+        let here = grid[&(i, j)];
+        for (di, dj) in [(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            let key = (i + di, j + dj);
+            if grid.contains_key(&key) {
+                continue;
+            }
+            // Prefer the local spacing from the opposite neighbour, which follows perspective
+            let step = match grid.get(&(i - di, j - dj)) {
+                Some(behind) => here - behind,
+                None => if di != 0 { step_i * di as f64 } else { step_j * dj as f64 },
+            };
+            let predicted = here + step;
+            let best = candidates.iter().enumerate()
+                .filter(|(index, _)| !used[*index])
+                .map(|(index, p)| (index, (p - predicted).norm()))
+                .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+            if let Some((index, distance)) = best {
+                if distance < 0.3 * step.norm() {
+                    used[index] = true;
+                    grid.insert(key, candidates[index]);
+                    queue.push_back(key);
+                }
+            }
+        }
+    }
+    Some(grid)
+}
+
+fn canonical_order(lattice: &HashMap<(i32, i32), Point2<f64>>, columns: i32, rows: i32) -> Vec<Point2<f64>> {
+    let at = |i: i32, j: i32| lattice[&(i, j)];
+    let along_row = at(columns - 1, 0) - at(0, 0);
+    let down_column = at(0, rows - 1) - at(0, 0);
+    // Rows should run down the board when columns run left to right (a right-handed image frame)
+    let flip_rows = along_row.x * down_column.y - along_row.y * down_column.x < 0.0;
+    let index = |i: i32, j: i32| if flip_rows { (i, rows - 1 - j) } else { (i, j) };
+    let mut corners: Vec<Point2<f64>> = (0..rows)
+        .flat_map(|j| (0..columns).map(move |i| (i, j)))
+        .map(|(i, j)| lattice[&index(i, j)])
+        .collect();
+    // A half turn keeps the handedness, so pick the one that starts nearest the top left
+    let first = corners[0];
+    let last = corners[corners.len() - 1];
+    if last.x + last.y < first.x + first.y {
+        corners.reverse();
+    }
+    corners
+}
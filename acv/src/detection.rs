@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+
+/// An axis-aligned box in pixel coordinates, with `x` and `y` at the top left.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BoundingBox {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl BoundingBox {
+    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Self {
+        BoundingBox { x, y, width, height }
+    }
+
+    pub fn from_center(cx: f32, cy: f32, width: f32, height: f32) -> Self {
+        BoundingBox::new(cx - width / 2.0, cy - height / 2.0, width, height)
+    }
+
+    pub fn center(&self) -> (f32, f32) {
+        (self.x + self.width / 2.0, self.y + self.height / 2.0)
+    }
+
+    pub fn area(&self) -> f32 {
+        self.width.max(0.0) * self.height.max(0.0)
+    }
+
+    pub fn intersection(&self, other: &BoundingBox) -> f32 {
+        let width = (self.x + self.width).min(other.x + other.width) - self.x.max(other.x);
+        let height = (self.y + self.height).min(other.y + other.height) - self.y.max(other.y);
+        width.max(0.0) * height.max(0.0)
+    }
+
+    /// Intersection over union, from 0 for disjoint boxes to 1 for identical ones.
+    pub fn iou(&self, other: &BoundingBox) -> f32 {
+        let intersection = self.intersection(other);
+        let union = self.area() + other.area() - intersection;
+        if union <= 0.0 {
+            0.0
+        } else {
+            intersection / union
+        }
+    }
+}
+
+/// Something found in one frame: a color blob, a tag, a model prediction.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Detection {
+    pub bbox: BoundingBox,
+    /// What kind of object this is, e.g. a tag id or a model class. Only detections of the same
+    /// class are compared with each other.
+    pub class_id: u32,
+    pub score: f32,
+}
+
+impl Detection {
+    pub fn new(bbox: BoundingBox, class_id: u32, score: f32) -> Self {
+        Detection { bbox, class_id, score }
+    }
+}
@@ -3,6 +3,9 @@ use std::fmt::{Debug, Display, Formatter};
 pub enum Error {
     Image(image::error::ImageError),
     Io(std::io::Error),
+    Json(serde_json::Error),
+    #[cfg(feature = "onnx")]
+    Inference(tract_onnx::prelude::TractError),
     Other(String),
 }
 
@@ -30,11 +33,27 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+#[cfg(feature = "onnx")]
+impl From<tract_onnx::prelude::TractError> for Error {
+    fn from(e: tract_onnx::prelude::TractError) -> Self {
+        Error::Inference(e)
+    }
+}
+
 impl Debug for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::Image(e) => write!(f, "Image error: {:?}", e),
             Error::Io(e) => write!(f, "IO error: {:?}", e),
+            Error::Json(e) => write!(f, "JSON error: {:?}", e),
+            #[cfg(feature = "onnx")]
+            Error::Inference(e) => write!(f, "Inference error: {:?}", e),
             Error::Other(s) => write!(f, "{}", s),
         }
     }
@@ -45,6 +64,9 @@ impl Display for Error {
         match self {
             Error::Image(e) => write!(f, "Image error: {}", e),
             Error::Io(e) => write!(f, "IO error: {}", e),
+            Error::Json(e) => write!(f, "JSON error: {}", e),
+            #[cfg(feature = "onnx")]
+            Error::Inference(e) => write!(f, "Inference error: {}", e),
             Error::Other(s) => write!(f, "{}", s),
         }
     }
@@ -55,6 +77,9 @@ impl std::error::Error for Error {
         match self {
             Error::Image(e) => Some(e),
             Error::Io(e) => Some(e),
+            Error::Json(e) => Some(e),
+            #[cfg(feature = "onnx")]
+            Error::Inference(e) => Some(e.as_ref()),
             Error::Other(_) => None,
         }
     }
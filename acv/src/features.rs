@@ -0,0 +1,211 @@
+use bitarray::BitArray;
+use image::imageops::{resize, FilterType};
+use image::GrayImage;
+use imageproc::corners::corners_fast9;
+use crate::util::Xorshift;
+
+/// A 256 bit binary descriptor, compared by Hamming distance.
+pub type Descriptor = BitArray<32>;
+
+// Radius of the circle used for orientation, and how far keypoints stay from the image border
+const PATCH_RADIUS: i32 = 15;
+// Descriptor sample points stay inside this radius so they still fit the patch once rotated
+const PATTERN_RADIUS: f32 = 13.0;
+
+/// A feature point in full resolution pixel coordinates.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Keypoint {
+    pub x: f32,
+    pub y: f32,
+    /// Orientation in radians, from the x axis towards y.
+    pub angle: f32,
+    /// Pyramid level the point was found at.
+    pub octave: u32,
+    /// Harris corner response.
+    pub response: f32,
+}
+
+/// ORB features: FAST corners ranked by Harris response over an image pyramid, oriented by
+/// intensity centroid and described by rotated BRIEF tests.
+#[derive(Clone, Debug)]
+pub struct Orb {
+    pub max_features: usize,
+    pub levels: u32,
+    /// Scale between consecutive pyramid levels.
+    pub scale_factor: f32,
+    pub fast_threshold: u8,
+    pattern: Vec<[(f32, f32); 2]>,
+}
+
+impl Orb {
+    pub fn new(max_features: usize) -> Self {
+        Orb {
+            max_features,
+            levels: 6,
+            scale_factor: 1.2,
+            fast_threshold: 20,
+            pattern: brief_pattern(),
+        }
+    }
+
+    pub fn detect_and_compute(&self, image: &GrayImage) -> (Vec<Keypoint>, Vec<Descriptor>) {
+        let mut keypoints = Vec::new();
+        let mut descriptors = Vec::new();
+        // Spread the features over the levels in proportion to their area
+        let factor = 1.0 / self.scale_factor;
+        let first_level = self.max_features as f32 * (1.0 - factor) / (1.0 - factor.powi(self.levels as i32));
+
+        for level in 0..self.levels {
+            let scale = self.scale_factor.powi(level as i32);
+            let (width, height) = ((image.width() as f32 / scale) as u32, (image.height() as f32 / scale) as u32);
+            if width <= 2 * PATCH_RADIUS as u32 + 2 || height <= 2 * PATCH_RADIUS as u32 + 2 {
+                break;
+            }
+            let level_image = if level == 0 { image.clone() } else { resize(image, width, height, FilterType::Triangle) };
+            let budget = (first_level * factor.powi(level as i32)).round() as usize;
+            let smoothed = imageproc::filter::gaussian_blur_f32(&level_image, 2.0);
+
+            for (x, y, response) in self.level_corners(&level_image, budget) {
+                let angle = intensity_centroid_angle(&level_image, x, y);
+                descriptors.push(self.describe(&smoothed, x, y, angle));
+                keypoints.push(Keypoint {
+                    x: x as f32 * scale,
+                    y: y as f32 * scale,
+                    angle,
+                    octave: level,
+                    response,
+                });
+            }
+        }
+        (keypoints, descriptors)
+    }
+
+    // FAST corners away from the border, thinned to local maxima and ranked by Harris response
+    fn level_corners(&self, image: &GrayImage, budget: usize) -> Vec<(u32, u32, f32)> {
+        let (width, height) = image.dimensions();
+        let border = PATCH_RADIUS as u32 + 1;
+        let corners: Vec<_> = corners_fast9(image, self.fast_threshold).into_iter()
+            .filter(|c| c.x >= border && c.y >= border && c.x < width - border && c.y < height - border)
+            .collect();
+        let mut scores = vec![0.0_f32; (width * height) as usize];
+        for c in corners.iter() {
+            scores[(c.y * width + c.x) as usize] = c.score;
+        }
+        let mut ranked: Vec<(u32, u32, f32)> = corners.iter()
+            .filter(|c| {
+                let score = c.score;
+                (c.y - 1..=c.y + 1).all(|y| (c.x - 1..=c.x + 1).all(|x| {
+                    let other = scores[(y * width + x) as usize];
+                    other < score || (other == score && (x, y) >= (c.x, c.y))
+                }))
+            })
+            .map(|c| (c.x, c.y, harris_response(image, c.x, c.y)))
+            .collect();
+        ranked.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
+        ranked.truncate(budget);
+        ranked
+    }
+
+    fn describe(&self, smoothed: &GrayImage, x: u32, y: u32, angle: f32) -> Descriptor {
+        let (sin, cos) = angle.sin_cos();
+        let sample = |(px, py): (f32, f32)| {
+            let rx = (px * cos - py * sin).round() as i32;
+            let ry = (px * sin + py * cos).round() as i32;
+            smoothed.get_pixel((x as i32 + rx) as u32, (y as i32 + ry) as u32)[0]
+        };
+        let mut descriptor = Descriptor::zeros();
+        for (i, [a, b]) in self.pattern.iter().enumerate() {
+            if sample(*a) < sample(*b) {
+                descriptor.bytes_mut()[i / 8] |= 1 << (i % 8);
+            }
+        }
+        descriptor
+    }
+}
+
+impl Default for Orb {
+    fn default() -> Self {
+        Orb::new(500)
+    }
+}
+
+// Point pairs drawn from an isotropic Gaussian around the keypoint, the BRIEF paper's best
+// performing sampling strategy
+fn brief_pattern() -> Vec<[(f32, f32); 2]> {
+    let mut rng = Xorshift::new(0x0b81ef);
+    let sigma = (2 * PATCH_RADIUS + 1) as f64 / 5.0;
+    let mut point = || loop {
+        // Box-Muller
+        let (u, v) = (rng.next_f64().max(f64::MIN_POSITIVE), rng.next_f64());
+        let r = (-2.0 * u.ln()).sqrt() * sigma;
+        let (x, y) = (r * (std::f64::consts::TAU * v).cos(), r * (std::f64::consts::TAU * v).sin());
+        if x * x + y * y <= (PATTERN_RADIUS * PATTERN_RADIUS) as f64 {
+            return (x as f32, y as f32);
+        }
+    };
+    (0..256).map(|_| [point(), point()]).collect()
+}
+
+fn intensity_centroid_angle(image: &GrayImage, x: u32, y: u32) -> f32 {
+    let (mut m01, mut m10) = (0.0_f32, 0.0_f32);
+    for dy in -PATCH_RADIUS..=PATCH_RADIUS {
+        for dx in -PATCH_RADIUS..=PATCH_RADIUS {
+            if dx * dx + dy * dy > PATCH_RADIUS * PATCH_RADIUS {
+                continue;
+            }
+            let value = image.get_pixel((x as i32 + dx) as u32, (y as i32 + dy) as u32)[0] as f32;
+            m10 += dx as f32 * value;
+            m01 += dy as f32 * value;
+        }
+    }
+    m01.atan2(m10)
+}
+
+// Harris measure det(M) - 0.04 trace(M)^2 over a 7x7 window of Sobel gradients
+fn harris_response(image: &GrayImage, x: u32, y: u32) -> f32 {
+    let at = |x: i32, y: i32| image.get_pixel(x as u32, y as u32)[0] as f32;
+    let (mut a, mut b, mut c) = (0.0_f32, 0.0_f32, 0.0_f32);
+    for dy in -3..=3 {
+        for dx in -3..=3 {
+            let (px, py) = (x as i32 + dx, y as i32 + dy);
+            let ix = (at(px + 1, py - 1) + 2.0 * at(px + 1, py) + at(px + 1, py + 1))
+                - (at(px - 1, py - 1) + 2.0 * at(px - 1, py) + at(px - 1, py + 1));
+            let iy = (at(px - 1, py + 1) + 2.0 * at(px, py + 1) + at(px + 1, py + 1))
+                - (at(px - 1, py - 1) + 2.0 * at(px, py - 1) + at(px + 1, py - 1));
+            a += ix * ix;
+            b += iy * iy;
+            c += ix * iy;
+        }
+    }
+    a * b - c * c - 0.04 * (a + b).powi(2)
+}
+
+/// A correspondence between a query descriptor and a train descriptor.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct FeatureMatch {
+    pub query: usize,
+    pub train: usize,
+    pub distance: u32,
+}
+
+/// Brute force matching by Hamming distance with Lowe's ratio test: a match is kept only when the
+/// best distance is below `ratio` times the second best.
+pub fn match_descriptors(query: &[Descriptor], train: &[Descriptor], ratio: f32) -> Vec<FeatureMatch> {
+    query.iter().enumerate().filter_map(|(q, descriptor)| {
+        let mut best = (usize::MAX, u32::MAX);
+        let mut second = u32::MAX;
+        for (t, other) in train.iter().enumerate() {
+            let distance = descriptor.distance(other);
+            if distance < best.1 {
+                second = best.1;
+                best = (t, distance);
+            } else if distance < second {
+                second = distance;
+            }
+        }
+        if best.0 == usize::MAX || (best.1 as f32) >= ratio * second as f32 {
+            return None;
+        }
+        Some(FeatureMatch { query: q, train: best.0, distance: best.1 })
+    }).collect()
+}
@@ -0,0 +1,320 @@
+use nalgebra::{DMatrix, DVector, Isometry3, Matrix3, Point2, Point3, SymmetricEigen, UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+use crate::util::Xorshift;
+
+/// Pinhole camera intrinsics in pixels.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CameraIntrinsics {
+    pub fx: f64,
+    pub fy: f64,
+    pub cx: f64,
+    pub cy: f64,
+}
+
+impl CameraIntrinsics {
+    pub fn new(fx: f64, fy: f64, cx: f64, cy: f64) -> Self {
+        CameraIntrinsics { fx, fy, cx, cy }
+    }
+
+    pub fn matrix(&self) -> Matrix3<f64> {
+        Matrix3::new(
+            self.fx, 0.0, self.cx,
+            0.0, self.fy, self.cy,
+            0.0, 0.0, 1.0,
+        )
+    }
+
+    /// Projects a point in the camera frame (x right, y down, z forward) to pixel coordinates.
+    pub fn project(&self, point: &Point3<f64>) -> Point2<f64> {
+        Point2::new(
+            self.fx * point.x / point.z + self.cx,
+            self.fy * point.y / point.z + self.cy,
+        )
+    }
+
+    /// Projects a point in the camera frame through a lens with the given distortion.
+    pub fn project_distorted(&self, point: &Point3<f64>, distortion: &Distortion) -> Point2<f64> {
+        let distorted = distortion.distort(&Point2::new(point.x / point.z, point.y / point.z));
+        self.denormalize(&distorted)
+    }
+
+    pub fn denormalize(&self, point: &Point2<f64>) -> Point2<f64> {
+        Point2::new(self.fx * point.x + self.cx, self.fy * point.y + self.cy)
+    }
+
+    /// Converts a pixel to normalized image coordinates (the ray through the pixel at z = 1).
+    pub fn normalize(&self, pixel: &Point2<f64>) -> Point2<f64> {
+        Point2::new((pixel.x - self.cx) / self.fx, (pixel.y - self.cy) / self.fy)
+    }
+}
+
+/// Brown-Conrady lens distortion, with the same coefficients and ordering as OpenCV.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Distortion {
+    pub k1: f64,
+    pub k2: f64,
+    pub p1: f64,
+    pub p2: f64,
+    pub k3: f64,
+}
+
+impl Distortion {
+    /// Applies the distortion to a point in normalized image coordinates.
+    pub fn distort(&self, point: &Point2<f64>) -> Point2<f64> {
+        let (x, y) = (point.x, point.y);
+        let r2 = x * x + y * y;
+        let radial = 1.0 + r2 * (self.k1 + r2 * (self.k2 + r2 * self.k3));
+        Point2::new(
+            x * radial + 2.0 * self.p1 * x * y + self.p2 * (r2 + 2.0 * x * x),
+            y * radial + self.p1 * (r2 + 2.0 * y * y) + 2.0 * self.p2 * x * y,
+        )
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Distortion::default()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct EulerAngles {
+    pub roll: f64,
+    pub pitch: f64,
+    pub yaw: f64,
+}
+
+impl From<&UnitQuaternion<f64>> for EulerAngles {
+    fn from(value: &UnitQuaternion<f64>) -> Self {
+        let (roll, pitch, yaw) = value.euler_angles();
+        EulerAngles { roll, pitch, yaw }
+    }
+}
+
+/// Estimates the homography mapping `src` onto `dst` with the normalized DLT. Needs at least four
+/// correspondences, no three of which are collinear.
+pub fn find_homography(src: &[Point2<f64>], dst: &[Point2<f64>]) -> Option<Matrix3<f64>> {
+    if src.len() != dst.len() || src.len() < 4 {
+        return None;
+    }
+    if src.iter().chain(dst).any(|p| !p.x.is_finite() || !p.y.is_finite()) {
+        return None;
+    }
+    let src_norm = normalizing_transform(src)?;
+    let dst_norm = normalizing_transform(dst)?;
+    let mut ata = nalgebra::SMatrix::<f64, 9, 9>::zeros();
+    for (s, d) in src.iter().zip(dst) {
+        let s = src_norm.transform_point(s);
+        let d = dst_norm.transform_point(d);
+        let rows = [
+            [-s.x, -s.y, -1.0, 0.0, 0.0, 0.0, d.x * s.x, d.x * s.y, d.x],
+            [0.0, 0.0, 0.0, -s.x, -s.y, -1.0, d.y * s.x, d.y * s.y, d.y],
+        ];
+        for row in rows.iter() {
+            for i in 0..9 {
+                for j in 0..9 {
+                    ata[(i, j)] += row[i] * row[j];
+                }
+            }
+        }
+    }
+    let eigen = SymmetricEigen::new(ata);
+    let (min_index, _) = eigen.eigenvalues.iter().enumerate()
+        .min_by(|a, b| a.1.total_cmp(b.1))?;
+    let h = eigen.eigenvectors.column(min_index);
+    let h = Matrix3::new(h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7], h[8]);
+    let h = dst_norm.matrix.try_inverse()? * h * src_norm.matrix;
+    if h[(2, 2)].abs() < f64::EPSILON {
+        return None;
+    }
+    Some(h / h[(2, 2)])
+}
+
+/// Estimates the homography mapping `src` onto `dst` when some correspondences are wrong, with
+/// RANSAC. Correspondences that land within `threshold` pixels are inliers, and the result is refit
+/// on all of them. Returns the homography and which correspondences are inliers.
+pub fn find_homography_ransac(src: &[Point2<f64>], dst: &[Point2<f64>], threshold: f64, iterations: usize) -> Option<(Matrix3<f64>, Vec<bool>)> {
+    ransac(src, dst, 4, threshold, iterations, find_homography)
+}
+
+/// Least squares affine transform mapping `src` onto `dst`, as a homography with a last row of
+/// `[0, 0, 1]`. Needs at least three correspondences that aren't collinear.
+pub fn find_affine(src: &[Point2<f64>], dst: &[Point2<f64>]) -> Option<Matrix3<f64>> {
+    if src.len() != dst.len() || src.len() < 3 {
+        return None;
+    }
+    // Both rows of the transform share the same normal equations
+    let mut ata = Matrix3::<f64>::zeros();
+    let mut atx = Vector3::<f64>::zeros();
+    let mut aty = Vector3::<f64>::zeros();
+    for (s, d) in src.iter().zip(dst) {
+        let row = Vector3::new(s.x, s.y, 1.0);
+        ata += row * row.transpose();
+        atx += row * d.x;
+        aty += row * d.y;
+    }
+    let lu = ata.lu();
+    let x = lu.solve(&atx)?;
+    let y = lu.solve(&aty)?;
+    Some(Matrix3::new(
+        x[0], x[1], x[2],
+        y[0], y[1], y[2],
+        0.0, 0.0, 1.0,
+    ))
+}
+
+/// Estimates the affine transform mapping `src` onto `dst` when some correspondences are wrong,
+/// like `find_homography_ransac`.
+pub fn find_affine_ransac(src: &[Point2<f64>], dst: &[Point2<f64>], threshold: f64, iterations: usize) -> Option<(Matrix3<f64>, Vec<bool>)> {
+    ransac(src, dst, 3, threshold, iterations, find_affine)
+}
+
+fn ransac<F>(src: &[Point2<f64>], dst: &[Point2<f64>], sample_size: usize, threshold: f64, iterations: usize, fit: F) -> Option<(Matrix3<f64>, Vec<bool>)>
+    where F: Fn(&[Point2<f64>], &[Point2<f64>]) -> Option<Matrix3<f64>> {
+    if src.len() != dst.len() || src.len() < sample_size {
+        return None;
+    }
+    let inliers_of = |h: &Matrix3<f64>| -> Vec<bool> {
+        src.iter().zip(dst).map(|(s, d)| {
+            let p = h * Vector3::new(s.x, s.y, 1.0);
+            p.z.abs() > f64::EPSILON && (Point2::new(p.x / p.z, p.y / p.z) - d).norm() <= threshold
+        }).collect()
+    };
+    let mut rng = Xorshift::new(src.len() as u64);
+    let mut best: Option<(Matrix3<f64>, Vec<bool>, usize)> = None;
+    let mut sample = Vec::with_capacity(sample_size);
+    for _ in 0..iterations {
+        sample.clear();
+        while sample.len() < sample_size {
+            let candidate = rng.below(src.len());
+            if !sample.contains(&candidate) {
+                sample.push(candidate);
+            }
+        }
+        let sample_src: Vec<_> = sample.iter().map(|i| src[*i]).collect();
+        let sample_dst: Vec<_> = sample.iter().map(|i| dst[*i]).collect();
+        let h = match fit(&sample_src, &sample_dst) {
+            Some(h) => h,
+            None => continue,
+        };
+        let inliers = inliers_of(&h);
+        let count = inliers.iter().filter(|i| **i).count();
+        let better = match &best {
+            Some(b) => count > b.2,
+            None => true,
+        };
+        if better {
+            best = Some((h, inliers, count));
+        }
+    }
+    let (_, inliers, count) = best?;
+    if count < sample_size {
+        return None;
+    }
+    let (inlier_src, inlier_dst): (Vec<_>, Vec<_>) = src.iter().zip(dst).zip(inliers.iter())
+        .filter(|(_, inlier)| **inlier)
+        .map(|((s, d), _)| (*s, *d))
+        .unzip();
+    let h = fit(&inlier_src, &inlier_dst)?;
+    let inliers = inliers_of(&h);
+    Some((h, inliers))
+}
+
+pub fn apply_homography(h: &Matrix3<f64>, point: &Point2<f64>) -> Point2<f64> {
+    let p = h * Vector3::new(point.x, point.y, 1.0);
+    Point2::new(p.x / p.z, p.y / p.z)
+}
+
+struct NormalizingTransform {
+    matrix: Matrix3<f64>,
+}
+
+impl NormalizingTransform {
+    fn transform_point(&self, point: &Point2<f64>) -> Point2<f64> {
+        apply_homography(&self.matrix, point)
+    }
+}
+
+// Hartley normalization: centroid at the origin, mean distance sqrt(2)
+fn normalizing_transform(points: &[Point2<f64>]) -> Option<NormalizingTransform> {
+    let n = points.len() as f64;
+    let cx = points.iter().map(|p| p.x).sum::<f64>() / n;
+    let cy = points.iter().map(|p| p.y).sum::<f64>() / n;
+    let mean_distance = points.iter().map(|p| ((p.x - cx).powi(2) + (p.y - cy).powi(2)).sqrt()).sum::<f64>() / n;
+    if mean_distance < f64::EPSILON {
+        return None;
+    }
+    let scale = std::f64::consts::SQRT_2 / mean_distance;
+    Some(NormalizingTransform {
+        matrix: Matrix3::new(
+            scale, 0.0, -scale * cx,
+            0.0, scale, -scale * cy,
+            0.0, 0.0, 1.0,
+        ),
+    })
+}
+
+/// Builds an isometry from a 6-vector of (rotation vector, translation).
+pub fn isometry_from_params(params: &[f64]) -> Isometry3<f64> {
+    Isometry3::from_parts(
+        Vector3::new(params[3], params[4], params[5]).into(),
+        UnitQuaternion::from_scaled_axis(Vector3::new(params[0], params[1], params[2])),
+    )
+}
+
+pub fn isometry_to_params(pose: &Isometry3<f64>) -> [f64; 6] {
+    let r = pose.rotation.scaled_axis();
+    let t = pose.translation.vector;
+    [r.x, r.y, r.z, t.x, t.y, t.z]
+}
+
+/// Minimizes the sum of squared residuals returned by `residuals` with Levenberg-Marquardt, using
+/// a forward-difference Jacobian. Returns the optimized parameters.
+pub fn levenberg_marquardt<F>(initial: DVector<f64>, max_iterations: usize, mut residuals: F) -> DVector<f64>
+    where F: FnMut(&DVector<f64>) -> DVector<f64> {
+    let mut params = initial;
+    let mut r = residuals(&params);
+    let mut cost = r.norm_squared();
+    let mut lambda = 1e-3;
+    for _ in 0..max_iterations {
+        let mut jacobian = DMatrix::zeros(r.len(), params.len());
+        for i in 0..params.len() {
+            let step = 1e-6 * params[i].abs().max(1e-3);
+            let mut shifted = params.clone();
+            shifted[i] += step;
+            let column = (residuals(&shifted) - &r) / step;
+            jacobian.set_column(i, &column);
+        }
+        let jtj = jacobian.transpose() * &jacobian;
+        let jtr = jacobian.transpose() * &r;
+        let mut improved = false;
+        while lambda < 1e10 {
+            let mut damped = jtj.clone();
+            for i in 0..params.len() {
+                damped[(i, i)] += lambda * jtj[(i, i)].max(1e-9);
+            }
+            let delta = match damped.cholesky() {
+                Some(cholesky) => cholesky.solve(&-&jtr),
+                None => {
+                    lambda *= 10.0;
+                    continue;
+                }
+            };
+            let candidate = &params + &delta;
+            let candidate_r = residuals(&candidate);
+            let candidate_cost = candidate_r.norm_squared();
+            if candidate_cost < cost {
+                let converged = (cost - candidate_cost) < 1e-12 * cost.max(1e-12) || delta.norm() < 1e-12;
+                params = candidate;
+                r = candidate_r;
+                cost = candidate_cost;
+                lambda = (lambda / 10.0).max(1e-12);
+                improved = !converged;
+                break;
+            }
+            lambda *= 10.0;
+        }
+        if !improved {
+            break;
+        }
+    }
+    params
+}
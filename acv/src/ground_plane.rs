@@ -0,0 +1,76 @@
+use nalgebra::{Matrix3, Point2, Vector3};
+use crate::geometry::{self, CameraIntrinsics};
+use crate::localization::{self, CameraMount};
+
+/// Maps between image pixels and points on the floor, in robot coordinates (x forward, y left,
+/// in whatever units the mounting or reference points were given in).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GroundPlane {
+    // Scaled so that the third coordinate of a mapped floor point is its depth in front of the
+    // camera, which tells points below the horizon from points above it
+    floor_to_image: Matrix3<f64>,
+    image_to_floor: Matrix3<f64>,
+}
+
+impl GroundPlane {
+    /// Builds the mapping from where the camera sits on the robot. The floor is the robot's z = 0
+    /// plane, so `mount.z` is the camera height and `mount.pitch` how far it tilts down.
+    pub fn from_mounting(intrinsics: &CameraIntrinsics, mount: &CameraMount) -> crate::Result<Self> {
+        let camera_from_robot = localization::camera_body_in_opencv() * mount.transform().inverse();
+        let rotation = camera_from_robot.rotation.to_rotation_matrix();
+        let r = rotation.matrix();
+        let t = camera_from_robot.translation.vector;
+        let extrinsics = Matrix3::from_columns(&[r.column(0).into_owned(), r.column(1).into_owned(), t]);
+        Self::from_floor_to_image(intrinsics.matrix() * extrinsics)
+    }
+
+    /// Builds the mapping from four or more pixels whose floor positions were measured.
+    pub fn from_correspondences(image: &[Point2<f64>], floor: &[Point2<f64>]) -> crate::Result<Self> {
+        let floor_to_image = geometry::find_homography(floor, image).ok_or("Degenerate ground plane reference points")?;
+        // Any scale of a homography is equivalent; pick the sign that puts the points in front
+        let depth = floor.iter().map(|p| (floor_to_image * Vector3::new(p.x, p.y, 1.0)).z).sum::<f64>();
+        let floor_to_image = if depth < 0.0 { -floor_to_image } else { floor_to_image };
+        Self::from_floor_to_image(floor_to_image)
+    }
+
+    fn from_floor_to_image(floor_to_image: Matrix3<f64>) -> crate::Result<Self> {
+        let image_to_floor = floor_to_image.try_inverse().ok_or("Camera can't see the floor")?;
+        Ok(GroundPlane { floor_to_image, image_to_floor })
+    }
+
+    /// The homography taking image pixels to floor coordinates.
+    pub fn homography(&self) -> &Matrix3<f64> {
+        &self.image_to_floor
+    }
+
+    /// Where on the floor a pixel is, or `None` if the pixel is at or above the horizon.
+    pub fn image_to_floor(&self, pixel: &Point2<f64>) -> Option<Point2<f64>> {
+        let p = self.image_to_floor * Vector3::new(pixel.x, pixel.y, 1.0);
+        if p.z <= f64::EPSILON {
+            return None;
+        }
+        Some(Point2::new(p.x / p.z, p.y / p.z))
+    }
+
+    /// Where a floor point appears in the image, or `None` if it is behind the camera.
+    pub fn floor_to_image(&self, floor: &Point2<f64>) -> Option<Point2<f64>> {
+        let p = self.floor_to_image * Vector3::new(floor.x, floor.y, 1.0);
+        if p.z <= f64::EPSILON {
+            return None;
+        }
+        Some(Point2::new(p.x / p.z, p.y / p.z))
+    }
+
+    /// Floor direction of an image direction at `pixel`, in radians counterclockwise from the
+    /// robot's x axis.
+    pub fn floor_angle(&self, pixel: &Point2<f64>, direction: (f64, f64)) -> Option<f64> {
+        let start = self.image_to_floor(pixel)?;
+        let end = self.image_to_floor(&Point2::new(pixel.x + direction.0, pixel.y + direction.1))?;
+        Some((end.y - start.y).atan2(end.x - start.x))
+    }
+
+    /// Distance along the floor from the robot's origin to the point under `pixel`.
+    pub fn distance(&self, pixel: &Point2<f64>) -> Option<f64> {
+        self.image_to_floor(pixel).map(|p| p.coords.norm())
+    }
+}
@@ -0,0 +1,237 @@
+use std::path::Path;
+use image::imageops::{resize, FilterType};
+use image::{Rgb, RgbImage};
+use imageproc::definitions::Image;
+use crate::detection::{BoundingBox, Detection};
+
+/// How a model's input image was fitted into its input size: scaled to fit and centered, with
+/// the rest padded.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Letterbox {
+    pub scale: f32,
+    pub pad_x: f32,
+    pub pad_y: f32,
+}
+
+impl Letterbox {
+    /// Maps a box in model input coordinates back to the original image.
+    pub fn to_image(&self, bbox: &BoundingBox) -> BoundingBox {
+        BoundingBox::new(
+            (bbox.x - self.pad_x) / self.scale,
+            (bbox.y - self.pad_y) / self.scale,
+            bbox.width / self.scale,
+            bbox.height / self.scale,
+        )
+    }
+}
+
+/// Scales `image` to fit in `width` by `height` keeping its aspect ratio, padding the remainder
+/// with `fill`.
+pub fn letterbox(image: &Image<Rgb<u8>>, width: u32, height: u32, fill: Rgb<u8>) -> (RgbImage, Letterbox) {
+    let scale = (width as f32 / image.width() as f32).min(height as f32 / image.height() as f32);
+    let (scaled_width, scaled_height) = (
+        ((image.width() as f32 * scale).round() as u32).clamp(1, width),
+        ((image.height() as f32 * scale).round() as u32).clamp(1, height),
+    );
+    let scaled = resize(image, scaled_width, scaled_height, FilterType::Triangle);
+    let (pad_x, pad_y) = ((width - scaled_width) / 2, (height - scaled_height) / 2);
+    let mut output = RgbImage::from_pixel(width, height, fill);
+    image::imageops::replace(&mut output, &scaled, pad_x as i64, pad_y as i64);
+    (output, Letterbox { scale, pad_x: pad_x as f32, pad_y: pad_y as f32 })
+}
+
+/// Turns 0-255 pixel values into model inputs: `(value / 255 - mean) / std` per channel.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Normalization {
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+}
+
+impl Normalization {
+    /// Scale to 0..1, as YOLO models expect.
+    pub const UNIT: Normalization = Normalization { mean: [0.0; 3], std: [1.0; 3] };
+    /// The ImageNet statistics most classification backbones were trained with.
+    pub const IMAGENET: Normalization = Normalization { mean: [0.485, 0.456, 0.406], std: [0.229, 0.224, 0.225] };
+
+    /// The image as a planar (channels, height, width) buffer.
+    pub fn to_planar(&self, image: &RgbImage) -> Vec<f32> {
+        let plane = (image.width() * image.height()) as usize;
+        let mut data = vec![0.0; 3 * plane];
+        for (i, pixel) in image.pixels().enumerate() {
+            for c in 0..3 {
+                data[c * plane + i] = (pixel[c] as f32 / 255.0 - self.mean[c]) / self.std[c];
+            }
+        }
+        data
+    }
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Normalization::UNIT
+    }
+}
+
+/// How to read a model's output tensor.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// `[1, boxes, 5 + classes]`: center x, center y, width, height, objectness, class scores.
+    YoloV5,
+    /// `[1, 4 + classes, boxes]`: center x, center y, width, height, class scores.
+    YoloV8,
+    /// `[1, classes]` of logits or probabilities, reported as one box covering the whole frame.
+    Classification,
+}
+
+/// Reads the detections above `confidence` out of a raw YOLO output, in model input coordinates.
+pub fn decode_yolo(output: &[f32], shape: &[usize], format: OutputFormat, confidence: f32) -> crate::Result<Vec<Detection>> {
+    let dims: Vec<usize> = shape.iter().copied().filter(|d| *d != 1).collect();
+    let (boxes, attributes) = match (format, dims.as_slice()) {
+        (OutputFormat::YoloV5, [boxes, attributes]) if *attributes > 5 => (*boxes, *attributes),
+        (OutputFormat::YoloV8, [attributes, boxes]) if *attributes > 4 => (*boxes, *attributes),
+        _ => return Err(format!("Unexpected {:?} output shape {:?}", format, shape).into()),
+    };
+    let at = |b: usize, a: usize| match format {
+        OutputFormat::YoloV8 => output[a * boxes + b],
+        _ => output[b * attributes + a],
+    };
+    let first_class = if format == OutputFormat::YoloV5 { 5 } else { 4 };
+
+    let mut detections = Vec::new();
+    for b in 0..boxes {
+        let objectness = if format == OutputFormat::YoloV5 { at(b, 4) } else { 1.0 };
+        if !objectness.is_finite() || objectness < confidence {
+            continue;
+        }
+        let (class_id, class_score) = (first_class..attributes)
+            .map(|a| (a - first_class, at(b, a)))
+            .fold((0, f32::MIN), |best, c| if c.1 > best.1 { c } else { best });
+        let score = objectness * class_score;
+        if !score.is_finite() || score < confidence {
+            continue;
+        }
+        let bbox = BoundingBox::from_center(at(b, 0), at(b, 1), at(b, 2), at(b, 3));
+        detections.push(Detection::new(bbox, class_id as u32, score));
+    }
+    Ok(detections)
+}
+
+/// Class probabilities from a classification output, applying a softmax if the values aren't
+/// probabilities already. Best first.
+pub fn decode_classification(output: &[f32]) -> Vec<(u32, f32)> {
+    let is_probability = output.iter().all(|v| (0.0..=1.0).contains(v)) && (output.iter().sum::<f32>() - 1.0).abs() < 1e-3;
+    let probabilities: Vec<f32> = if is_probability {
+        output.to_vec()
+    } else {
+        let max = output.iter().copied().fold(f32::MIN, f32::max);
+        let exp: Vec<f32> = output.iter().map(|v| (v - max).exp()).collect();
+        let sum: f32 = exp.iter().sum();
+        exp.iter().map(|v| v / sum).collect()
+    };
+    let mut classes: Vec<(u32, f32)> = probabilities.into_iter().enumerate().map(|(i, p)| (i as u32, p)).collect();
+    classes.sort_by(|a, b| b.1.total_cmp(&a.1));
+    classes
+}
+
+/// Keeps the best of every group of detections overlapping by more than `iou`. Unless
+/// `class_agnostic`, only detections of the same class suppress each other.
+pub fn non_max_suppression(mut detections: Vec<Detection>, iou: f32, class_agnostic: bool) -> Vec<Detection> {
+    detections.sort_by(|a, b| b.score.total_cmp(&a.score));
+    let mut kept: Vec<Detection> = Vec::new();
+    for detection in detections {
+        let suppressed = kept.iter().any(|k| {
+            (class_agnostic || k.class_id == detection.class_id) && k.bbox.iou(&detection.bbox) > iou
+        });
+        if !suppressed {
+            kept.push(detection);
+        }
+    }
+    kept
+}
+
+/// Reads class names from a file with one per line.
+pub fn load_labels<P: AsRef<Path>>(path: P) -> crate::Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(text.lines().map(|l| l.trim().to_string()).filter(|l| !l.is_empty()).collect())
+}
+
+#[cfg(feature = "onnx")]
+pub use self::onnx::OnnxModel;
+
+#[cfg(feature = "onnx")]
+mod onnx {
+    use std::path::Path;
+    use image::Rgb;
+    use imageproc::definitions::Image;
+    use tract_onnx::prelude::*;
+    use crate::detection::{BoundingBox, Detection};
+    use super::{decode_classification, decode_yolo, letterbox, non_max_suppression, Normalization, OutputFormat};
+
+    /// An ONNX model run on the CPU with tract. Takes one `[1, 3, height, width]` float input.
+    pub struct OnnxModel {
+        plan: TypedRunnableModel<TypedModel>,
+        pub input_size: (u32, u32),
+        pub format: OutputFormat,
+        pub normalization: Normalization,
+        /// Color of the letterbox padding.
+        pub fill: Rgb<u8>,
+    }
+
+    impl OnnxModel {
+        pub fn load<P: AsRef<Path>>(path: P, input_size: (u32, u32), format: OutputFormat) -> crate::Result<Self> {
+            let bytes = std::fs::read(path)?;
+            Self::from_bytes(&bytes, input_size, format)
+        }
+
+        /// Loads a model already in memory, e.g. read from an Android asset.
+        pub fn from_bytes(bytes: &[u8], input_size: (u32, u32), format: OutputFormat) -> crate::Result<Self> {
+            let (width, height) = (input_size.0 as usize, input_size.1 as usize);
+            let plan = tract_onnx::onnx()
+                .model_for_read(&mut std::io::Cursor::new(bytes))?
+                .with_input_fact(0, f32::fact([1, 3, height, width]).into())?
+                .into_optimized()?
+                .into_runnable()?;
+            let normalization = match format {
+                OutputFormat::Classification => Normalization::IMAGENET,
+                _ => Normalization::UNIT,
+            };
+            Ok(OnnxModel {
+                plan,
+                input_size,
+                format,
+                normalization,
+                fill: Rgb([114, 114, 114]),
+            })
+        }
+
+        /// Runs the model on an image, returning its first output and that output's shape.
+        pub fn infer(&self, image: &Image<Rgb<u8>>) -> crate::Result<(Vec<f32>, Vec<usize>, super::Letterbox)> {
+            let (width, height) = self.input_size;
+            let (input, letterbox) = letterbox(image, width, height, self.fill);
+            let data = self.normalization.to_planar(&input);
+            let tensor = Tensor::from_shape(&[1, 3, height as usize, width as usize], &data)?;
+            let outputs = self.plan.run(tvec!(tensor.into()))?;
+            let output = outputs.first().ok_or("Model has no outputs")?;
+            let view = output.to_array_view::<f32>()?;
+            Ok((view.iter().copied().collect(), view.shape().to_vec(), letterbox))
+        }
+
+        /// Detections in image coordinates above `confidence`, after non-maximum suppression.
+        /// Classification models give a single frame-sized box for the best class.
+        pub fn detect(&self, image: &Image<Rgb<u8>>, confidence: f32, iou: f32, class_agnostic: bool) -> crate::Result<Vec<Detection>> {
+            let (output, shape, letterbox) = self.infer(image)?;
+            if self.format == OutputFormat::Classification {
+                let frame = BoundingBox::new(0.0, 0.0, image.width() as f32, image.height() as f32);
+                return Ok(decode_classification(&output).into_iter()
+                    .take(1)
+                    .filter(|(_, p)| *p >= confidence)
+                    .map(|(class_id, p)| Detection::new(frame, class_id, p))
+                    .collect());
+            }
+            let detections = decode_yolo(&output, &shape, self.format, confidence)?.into_iter()
+                .map(|d| Detection::new(letterbox.to_image(&d.bbox), d.class_id, d.score))
+                .collect();
+            Ok(non_max_suppression(detections, iou, class_agnostic))
+        }
+    }
+}
@@ -1,31 +1,136 @@
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Mutex};
 use image::{GrayImage, Luma, Rgb};
 pub use imageproc::definitions::Image;
 pub use error::Error;
 pub use imageproc;
-use log::error;
-use tokio::io::AsyncReadExt;
+use imageproc::point::Point;
+use log::{error, info};
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpSocket, UdpSocket, UnixListener, UnixStream};
 use output::Output;
+use overlay::Overlay;
 use pipeline::Pipeline;
 use crate::frame_generator::FrameGenerator;
+use crate::telemetry::Telemetry;
+use crate::util::{learn_thresholds, ThresholdLearning};
 
+pub mod aruco;
+pub mod barcode;
+pub mod calibration;
+pub mod detection;
 pub mod error;
+pub mod features;
 pub mod frame_generator;
+pub mod geometry;
+pub mod ground_plane;
+pub mod inference;
+pub mod lines;
+pub mod localization;
+pub mod mask;
+pub mod normalize;
+pub mod optical_flow;
 pub mod output;
+pub mod overlay;
+mod parallel;
 pub mod pipeline;
+pub mod pool;
+pub mod pose;
+pub mod preprocess;
+pub mod stage;
+pub mod telemetry;
+pub mod template_matching;
+pub mod tracker;
+pub mod undistort;
 pub mod util;
 
 // TODO: Differentiate between the different types of errors
 type Result<T> = std::result::Result<T, Error>;
 
+/// Control commands waiting for the next camera frame.
+pub type FrameRequests = Arc<Mutex<Vec<oneshot::Sender<Image<Rgb<u8>>>>>>;
+
+/// What the control socket needs from a running camera.
+#[derive(Clone)]
+pub struct Control {
+    pub telemetry: Arc<Mutex<Telemetry>>,
+    pub frame_requests: FrameRequests,
+}
+
+impl Control {
+    /// The next frame the camera captures, or `None` if it stops first.
+    pub async fn next_frame(&self) -> Option<Image<Rgb<u8>>> {
+        let (sender, receiver) = oneshot::channel();
+        self.frame_requests.lock().await.push(sender);
+        receiver.await.ok()
+    }
+
+    /// Answers one command, or returns `None` for commands that have no reply:
+    ///
+    /// - `telemetry`: the current [`telemetry::TelemetrySnapshot`] as JSON.
+    /// - `learn {"samples": [[[x, y], ...], ...], "negatives": [...], "options": {...}}`: the
+    ///   [`util::LearnedThresholds`] for the sample polygons in the next frame, as JSON.
+    ///
+    /// Errors are answered as `{"error": "..."}`.
+    pub async fn answer(&self, command: &str) -> Option<String> {
+        if command == "telemetry" {
+            let snapshot = self.telemetry.lock().await.snapshot();
+            return Some(serde_json::to_string(&snapshot).unwrap_or_default());
+        }
+        if let Some(arguments) = command.strip_prefix("learn ") {
+            let result = match serde_json::from_str::<LearnCommand>(arguments) {
+                Ok(learn) => match self.next_frame().await {
+                    Some(frame) => learn.run(&frame),
+                    None => Err("Camera stopped".into()),
+                },
+                Err(e) => Err(Error::from(format!("Bad learn command: {}", e))),
+            };
+            return Some(match result {
+                Ok(reply) => reply,
+                Err(e) => serde_json::json!({ "error": e.to_string() }).to_string(),
+            });
+        }
+        None
+    }
+}
+
+// Arguments of the `learn` control command.
+#[derive(Deserialize)]
+struct LearnCommand {
+    samples: Vec<Vec<[i32; 2]>>,
+    #[serde(default)]
+    negatives: Vec<Vec<[i32; 2]>>,
+    #[serde(default)]
+    options: ThresholdLearning,
+}
+
+impl LearnCommand {
+    fn run(&self, frame: &Image<Rgb<u8>>) -> Result<String> {
+        let polygons = |polygons: &[Vec<[i32; 2]>]| -> Vec<Vec<Point<i32>>> {
+            polygons.iter().map(|p| p.iter().map(|[x, y]| Point::new(*x, *y)).collect()).collect()
+        };
+        let thresholds = learn_thresholds(frame, &polygons(&self.samples), &polygons(&self.negatives), &self.options)?;
+        Ok(serde_json::to_string(&thresholds).unwrap_or_default())
+    }
+}
+
 pub struct SinglePipelineCamera {
     pub width: u32,
     pub height: u32,
     pub pipeline: Option<Arc<Mutex<dyn Pipeline>>>,
     pub output: Arc<Mutex<dyn Output>>,
-    pub camera: Arc<Mutex<dyn FrameGenerator>>
+    pub camera: Arc<Mutex<dyn FrameGenerator>>,
+    /// Frame timings, shared with the control socket.
+    pub telemetry: Arc<Mutex<Telemetry>>,
+    /// How often to log telemetry, if at all.
+    pub log_interval: Option<Duration>,
+    /// Control commands waiting for a frame, answered with a copy of the next one captured.
+    pub frame_requests: FrameRequests,
+    /// Draws the frame rate over every output frame. Use [`Overlay::disabled`] to leave it off.
+    pub fps_overlay: Overlay,
+    last_log: Instant,
 }
 
 impl SinglePipelineCamera {
@@ -35,10 +140,20 @@ impl SinglePipelineCamera {
             height,
             pipeline: None,
             output: Arc::new(Mutex::new(output::NoOutput::default())),
-            camera
+            camera,
+            telemetry: Arc::new(Mutex::new(Telemetry::default())),
+            log_interval: Some(Duration::from_secs(10)),
+            frame_requests: Arc::new(Mutex::new(Vec::new())),
+            fps_overlay: Overlay::default(),
+            last_log: Instant::now(),
         }
     }
 
+    /// A handle for answering control commands about this camera.
+    pub fn control(&self) -> Control {
+        Control { telemetry: self.telemetry.clone(), frame_requests: self.frame_requests.clone() }
+    }
+
     pub fn set_pipeline(&mut self, pipeline: Option<Arc<Mutex<dyn Pipeline>>>) {
         self.pipeline = pipeline;
     }
@@ -52,21 +167,73 @@ impl SinglePipelineCamera {
     }
 
     pub async fn process_frame(&mut self) {
+        let frame_start = Instant::now();
         let frame_result = self.camera.lock().await.frame();
+        let capture_duration = frame_start.elapsed();
+        self.telemetry.lock().await.record("capture", capture_duration);
+        let mut dropped = false;
         match frame_result {
             Ok(frame) => {
+                for request in self.frame_requests.lock().await.drain(..) {
+                    let _ = request.send(frame.clone());
+                }
                 if let Some(pipeline) = &self.pipeline {
                     let mut pipeline = pipeline.lock().await;
-                    let frame = pipeline.pipeline(frame);
+                    let pipeline_start = Instant::now();
+                    let mut frame = pipeline.pipeline(frame);
+                    let pipeline_duration = pipeline_start.elapsed();
+                    if let Ok(Some(image)) = &mut frame {
+                        if self.fps_overlay.enabled {
+                            let fps = self.telemetry.lock().await.fps();
+                            self.fps_overlay.clear();
+                            self.fps_overlay.fps(fps).draw(image);
+                        }
+                    }
+                    dropped = frame.is_err();
                     let mut output_sender = self.output.lock().await;
+                    let output_start = Instant::now();
                     let output_result = output_sender.output(frame, pipeline.output_color_type());
+                    let output_duration = output_start.elapsed();
                     if let Err(e) = output_result {
                         error!("Error sending output: {}", e);
+                        dropped = true;
+                    }
+
+                    let snapshot = {
+                        let mut telemetry = self.telemetry.lock().await;
+                        telemetry.record("pipeline", pipeline_duration);
+                        for stage in pipeline.stage_timings() {
+                            telemetry.record(&format!("stage/{}", stage.name), stage.duration);
+                        }
+                        telemetry.record("output", output_duration);
+                        if let Some(duration) = output_sender.encode_duration() {
+                            telemetry.record("encode", duration);
+                        }
+                        if dropped {
+                            telemetry.frame_dropped();
+                        }
+                        telemetry.snapshot()
+                    };
+                    if let Err(e) = output_sender.telemetry(&snapshot) {
+                        error!("Error sending telemetry: {}", e);
                     }
                 }
             },
             Err(e) => {
                 error!("Error getting frame from camera: {}", e);
+                self.telemetry.lock().await.frame_dropped();
+                dropped = true;
+            }
+        }
+        let mut telemetry = self.telemetry.lock().await;
+        telemetry.record("frame", frame_start.elapsed());
+        if !dropped {
+            telemetry.frame_completed(Instant::now());
+        }
+        if let Some(interval) = self.log_interval {
+            if self.last_log.elapsed() >= interval {
+                info!("Telemetry: {}", telemetry.snapshot());
+                self.last_log = Instant::now();
             }
         }
     }
@@ -77,24 +244,40 @@ impl SinglePipelineCamera {
     }
 }
 
-async fn terminate_on_signal(mut socket: UnixStream) {
-    loop {
-        let mut response = String::new();
-        let res = socket.read_to_string(&mut response).await; // TODO: Do something with the response
-        if response == "terminate" {
+// Answers control commands, one per line, until told to terminate:
+// - `telemetry` replies with the camera's telemetry as JSON.
+// - `learn {"samples": [[[x, y], ...], ...], "negatives": [...], "options": {...}}` learns color
+//   thresholds from polygons on the next frame, replying with the learned ranges as JSON or
+//   `{"error": ...}`. `negatives` and `options` are optional.
+async fn terminate_on_signal(socket: UnixStream, control: Control) {
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let command = line.trim();
+        if command == "terminate" {
             return;
         }
+        if let Some(reply) = control.answer(command).await {
+            if let Err(e) = writer.write_all((reply + "\n").as_bytes()).await {
+                error!("Error answering control command: {}", e);
+            }
+        }
     }
 }
 
-async fn udp_socket_terminate_on_signal(socket: UdpSocket) {
+async fn udp_socket_terminate_on_signal(socket: UdpSocket, control: Control) {
     loop {
-        let mut buf = [0; 1024];
-        let resp = socket.recv(&mut buf).await.unwrap(); // TODO: Do something with the response
-        let cut_buff = &buf[..resp];
-        if cut_buff == b"terminate" {
+        let mut buf = [0; 65536];
+        let (resp, sender) = socket.recv_from(&mut buf).await.unwrap(); // TODO: Do something with the response
+        let command = String::from_utf8_lossy(&buf[..resp]);
+        if command == "terminate" {
             return;
         }
+        if let Some(reply) = control.answer(command.trim()).await {
+            if let Err(e) = socket.send_to(reply.as_bytes(), sender).await {
+                error!("Error answering control command: {}", e);
+            }
+        }
     }
 }
 
@@ -127,11 +310,11 @@ pub mod android {
             let output_stream = UnixStream::connect(path + "_output")?;
             let output = Arc::new(Mutex::new(crate::output::StreamOutput::from_socket(output_stream)));
             camera.set_output(Some(output));
-            crate::terminate_on_signal(input_stream)
+            crate::terminate_on_signal(input_stream, camera.control())
         } else {
             let input_socket = UdpSocket::bind(path.clone() + "0").await.unwrap();
             let output_socket = UdpSocket::bind(path + "1").await.unwrap();
-            crate::udp_socket_terminate_on_signal(input_socket)
+            crate::udp_socket_terminate_on_signal(input_socket, camera.control())
         };
 
         let camera_future = camera.run();
@@ -141,3 +324,44 @@ pub mod android {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::{hsv_in_range, ycrcb_in_range, Hsv, LearnedThresholds, YCrCb};
+
+    #[tokio::test]
+    async fn answers_learn_with_the_next_frame() {
+        // Red on the left, green carpet on the right.
+        let frame = Image::from_fn(80, 60, |x, _| if x < 40 { Rgb([180 + (x % 8) as u8 * 8, 30, 25]) } else { Rgb([40, 120, 40]) });
+        let control = Control { telemetry: Arc::new(Mutex::new(Telemetry::default())), frame_requests: Arc::new(Mutex::new(Vec::new())) };
+        let camera = control.clone();
+        let sent = frame.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Some(request) = camera.frame_requests.lock().await.pop() {
+                    let _ = request.send(sent);
+                    return;
+                }
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let command = r#"learn {"samples": [[[5, 5], [35, 5], [35, 55], [5, 55]]], "negatives": [[[45, 5], [75, 5], [75, 55], [45, 55]]]}"#;
+        let reply = control.answer(command).await.unwrap();
+        let thresholds: LearnedThresholds = serde_json::from_str(&reply).unwrap();
+        let (red, green) = (*frame.get_pixel(20, 30), *frame.get_pixel(60, 30));
+        assert!(hsv_in_range(Hsv::from(red), thresholds.hsv.lower, thresholds.hsv.higher));
+        assert!(!hsv_in_range(Hsv::from(green), thresholds.hsv.lower, thresholds.hsv.higher));
+        assert!(ycrcb_in_range(YCrCb::from(red), thresholds.ycrcb.lower, thresholds.ycrcb.higher));
+        assert!(!ycrcb_in_range(YCrCb::from(green), thresholds.ycrcb.lower, thresholds.ycrcb.higher));
+    }
+
+    #[tokio::test]
+    async fn answers_errors_as_json() {
+        let control = Control { telemetry: Arc::new(Mutex::new(Telemetry::default())), frame_requests: Arc::new(Mutex::new(Vec::new())) };
+        let reply = control.answer("learn {}").await.unwrap();
+        assert!(reply.contains("error"), "{}", reply);
+        assert_eq!(control.answer("unknown").await, None);
+    }
+}
@@ -0,0 +1,202 @@
+use image::GrayImage;
+use crate::util::Xorshift;
+
+/// A line segment in pixel coordinates.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LineSegment {
+    pub start: (f32, f32),
+    pub end: (f32, f32),
+}
+
+impl LineSegment {
+    pub fn new(start: (f32, f32), end: (f32, f32)) -> Self {
+        LineSegment { start, end }
+    }
+
+    pub fn length(&self) -> f32 {
+        (self.end.0 - self.start.0).hypot(self.end.1 - self.start.1)
+    }
+
+    /// Angle in radians from the x axis towards y, in -pi/2..pi/2.
+    pub fn angle(&self) -> f32 {
+        let angle = (self.end.1 - self.start.1).atan2(self.end.0 - self.start.0);
+        normalize_line_angle(angle)
+    }
+
+    pub fn midpoint(&self) -> (f32, f32) {
+        ((self.start.0 + self.end.0) / 2.0, (self.start.1 + self.end.1) / 2.0)
+    }
+
+    /// Perpendicular distance from `point` to the infinite line through the segment.
+    pub fn distance_to_line(&self, point: (f32, f32)) -> f32 {
+        let length = self.length();
+        if length < f32::EPSILON {
+            return (point.0 - self.start.0).hypot(point.1 - self.start.1);
+        }
+        let (dx, dy) = ((self.end.0 - self.start.0) / length, (self.end.1 - self.start.1) / length);
+        ((point.0 - self.start.0) * dy - (point.1 - self.start.1) * dx).abs()
+    }
+}
+
+/// Folds an angle into -pi/2..pi/2, since a line has no direction.
+pub fn normalize_line_angle(angle: f32) -> f32 {
+    use std::f32::consts::{FRAC_PI_2, PI};
+    let mut angle = angle % PI;
+    if angle > FRAC_PI_2 {
+        angle -= PI;
+    } else if angle <= -FRAC_PI_2 {
+        angle += PI;
+    }
+    angle
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HoughOptions {
+    /// Distance resolution of the accumulator, in pixels.
+    pub rho: f32,
+    /// Angle resolution of the accumulator, in radians.
+    pub theta: f32,
+    /// Votes a line needs before it is traced.
+    pub threshold: u32,
+    pub min_length: f32,
+    /// Largest run of missing edge pixels a segment can bridge.
+    pub max_gap: u32,
+}
+
+impl Default for HoughOptions {
+    fn default() -> Self {
+        HoughOptions {
+            rho: 1.0,
+            theta: 1f32.to_radians(),
+            threshold: 30,
+            min_length: 30.0,
+            max_gap: 5,
+        }
+    }
+}
+
+/// Line segments through the nonzero pixels of an edge image, by the progressive probabilistic
+/// Hough transform: edge points vote in random order, and as soon as a line collects enough votes
+/// it is traced along the image and its pixels are removed from the vote.
+pub fn hough_segments(edges: &GrayImage, options: &HoughOptions) -> Vec<LineSegment> {
+    let (width, height) = edges.dimensions();
+    let angles = (std::f32::consts::PI / options.theta).round().max(1.0) as usize;
+    let max_rho = ((width * width + height * height) as f32).sqrt();
+    let rhos = ((2.0 * max_rho) / options.rho).ceil() as usize + 1;
+    let trig: Vec<(f32, f32)> = (0..angles).map(|n| {
+        let theta = n as f32 * options.theta;
+        (theta.cos() / options.rho, theta.sin() / options.rho)
+    }).collect();
+    let rho_offset = (rhos - 1) as f32 / 2.0;
+    let rho_index = |x: u32, y: u32, n: usize| (x as f32 * trig[n].0 + y as f32 * trig[n].1 + rho_offset).round() as usize;
+
+    let mut points: Vec<(u32, u32)> = edges.enumerate_pixels().filter(|(_, _, p)| p[0] != 0).map(|(x, y, _)| (x, y)).collect();
+    let mut rng = Xorshift::new(points.len() as u64);
+    for i in (1..points.len()).rev() {
+        points.swap(i, rng.below(i + 1));
+    }
+    let mut remaining: Vec<bool> = edges.pixels().map(|p| p[0] != 0).collect();
+    let mut accumulator = vec![0u32; angles * rhos];
+    let mut segments = Vec::new();
+
+    for &(x, y) in points.iter() {
+        if !remaining[(y * width + x) as usize] {
+            continue;
+        }
+        let mut best = (0, 0);
+        for n in 0..angles {
+            let cell = &mut accumulator[n * rhos + rho_index(x, y, n)];
+            *cell += 1;
+            if *cell > best.1 {
+                best = (n, *cell);
+            }
+        }
+        if best.1 < options.threshold {
+            continue;
+        }
+
+        // Walk both ways along the line, stepping one pixel along its major axis
+        let theta = best.0 as f32 * options.theta;
+        let (mut dx, mut dy) = (-theta.sin(), theta.cos());
+        let major = dx.abs().max(dy.abs());
+        dx /= major;
+        dy /= major;
+        let walk = |direction: f32, remaining: &[bool]| {
+            let mut end = (x as f32, y as f32);
+            let mut gap = 0;
+            for k in 1.. {
+                let (px, py) = (x as f32 + direction * dx * k as f32, y as f32 + direction * dy * k as f32);
+                let (ix, iy) = (px.round(), py.round());
+                if ix < 0.0 || iy < 0.0 || ix >= width as f32 || iy >= height as f32 {
+                    break;
+                }
+                if remaining[(iy as u32 * width + ix as u32) as usize] {
+                    gap = 0;
+                    end = (ix, iy);
+                } else {
+                    gap += 1;
+                    if gap > options.max_gap {
+                        break;
+                    }
+                }
+            }
+            end
+        };
+        let start = walk(-1.0, &remaining);
+        let end = walk(1.0, &remaining);
+        let segment = LineSegment::new(start, end);
+        let good = segment.length() >= options.min_length;
+
+        // Take the traced pixels out of the vote so the same line isn't found again
+        let steps = ((end.0 - start.0).abs().max((end.1 - start.1).abs())).round() as i32;
+        for k in 0..=steps {
+            let t = if steps == 0 { 0.0 } else { k as f32 / steps as f32 };
+            let px = (start.0 + (end.0 - start.0) * t).round() as u32;
+            let py = (start.1 + (end.1 - start.1) * t).round() as u32;
+            let index = (py * width + px) as usize;
+            if !remaining[index] {
+                continue;
+            }
+            remaining[index] = false;
+            if good {
+                for n in 0..angles {
+                    let cell = &mut accumulator[n * rhos + rho_index(px, py, n)];
+                    *cell = cell.saturating_sub(1);
+                }
+            }
+        }
+        if good {
+            segments.push(segment);
+        }
+    }
+    segments
+}
+
+/// Joins segments that lie along the same line, like the pieces of an edge the Hough transform
+/// broke up, into one segment spanning all of them. Longer segments absorb shorter ones.
+pub fn merge_collinear(segments: &[LineSegment], max_angle: f32, max_distance: f32) -> Vec<LineSegment> {
+    let mut sorted = segments.to_vec();
+    sorted.sort_by(|a, b| b.length().partial_cmp(&a.length()).unwrap());
+    let mut merged: Vec<LineSegment> = Vec::new();
+    for segment in sorted {
+        let target = merged.iter_mut().find(|m| {
+            normalize_line_angle(m.angle() - segment.angle()).abs() <= max_angle &&
+                m.distance_to_line(segment.start) <= max_distance &&
+                m.distance_to_line(segment.end) <= max_distance
+        });
+        match target {
+            Some(m) => {
+                // Extend along the existing segment's direction to cover the new one
+                let length = m.length();
+                let (dx, dy) = ((m.end.0 - m.start.0) / length, (m.end.1 - m.start.1) / length);
+                let along = |p: (f32, f32)| (p.0 - m.start.0) * dx + (p.1 - m.start.1) * dy;
+                let low = 0.0_f32.min(along(segment.start)).min(along(segment.end));
+                let high = length.max(along(segment.start)).max(along(segment.end));
+                let origin = m.start;
+                *m = LineSegment::new((origin.0 + dx * low, origin.1 + dy * low), (origin.0 + dx * high, origin.1 + dy * high));
+            }
+            None => merged.push(segment),
+        }
+    }
+    merged
+}
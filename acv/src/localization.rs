@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+use std::path::Path;
+use nalgebra::{Isometry3, Matrix3, Quaternion, Rotation3, Translation3, UnitQuaternion, Vector3};
+use serde::{Deserialize, Serialize};
+use crate::pose::PoseEstimate;
+
+// Field, robot and layout tag frames follow WPILib: field and robot are x forward, y left, z up,
+// and a tag's x axis points out of its face with z up. Camera-relative poses from `pose` are in
+// OpenCV frames instead: the camera is x right, y down, z forward and a tag is x right, y up, z
+// out of its face.
+
+/// Where each tag sits on the field for a season.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FieldLayout {
+    pub tags: HashMap<u32, Isometry3<f64>>,
+    pub length: f64,
+    pub width: f64,
+}
+
+impl FieldLayout {
+    /// Loads a layout in WPILib's AprilTag field layout JSON format.
+    pub fn load<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::from_json(&contents)
+    }
+
+    pub fn from_json(json: &str) -> crate::Result<Self> {
+        let file: LayoutFile = serde_json::from_str(json)?;
+        let tags = file.tags.iter().map(|tag| {
+            let t = &tag.pose.translation;
+            let q = &tag.pose.rotation.quaternion;
+            let rotation = UnitQuaternion::from_quaternion(Quaternion::new(q.w, q.x, q.y, q.z));
+            (tag.id, Isometry3::from_parts(Translation3::new(t.x, t.y, t.z), rotation))
+        }).collect();
+        Ok(FieldLayout {
+            tags,
+            length: file.field.length,
+            width: file.field.width,
+        })
+    }
+
+    pub fn tag_pose(&self, id: u32) -> Option<&Isometry3<f64>> {
+        self.tags.get(&id)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct LayoutFile {
+    tags: Vec<LayoutTag>,
+    field: LayoutField,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LayoutTag {
+    #[serde(rename = "ID")]
+    id: u32,
+    pose: LayoutPose,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LayoutPose {
+    translation: LayoutTranslation,
+    rotation: LayoutRotation,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LayoutTranslation {
+    x: f64,
+    y: f64,
+    z: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LayoutRotation {
+    quaternion: LayoutQuaternion,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LayoutQuaternion {
+    #[serde(rename = "W")]
+    w: f64,
+    #[serde(rename = "X")]
+    x: f64,
+    #[serde(rename = "Y")]
+    y: f64,
+    #[serde(rename = "Z")]
+    z: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LayoutField {
+    length: f64,
+    width: f64,
+}
+
+/// Where the camera is mounted on the robot, relative to the robot's center.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CameraMount {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub roll: f64,
+    /// Positive pitch tilts the camera down towards the floor.
+    pub pitch: f64,
+    pub yaw: f64,
+}
+
+impl CameraMount {
+    /// Transform from the camera body frame (x forward, y left, z up) to the robot frame.
+    pub fn transform(&self) -> Isometry3<f64> {
+        Isometry3::from_parts(
+            Translation3::new(self.x, self.y, self.z),
+            UnitQuaternion::from_euler_angles(self.roll, self.pitch, self.yaw),
+        )
+    }
+}
+
+/// A tag seen in one frame.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TagObservation {
+    pub id: u32,
+    pub estimate: PoseEstimate,
+}
+
+/// The robot's pose on the field from one frame, ready to be fused with odometry.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RobotPoseEstimate {
+    pub x: f64,
+    pub y: f64,
+    /// Counterclockwise from the field x axis, in radians.
+    pub heading: f64,
+    /// Suggested measurement standard deviations for x, y and heading, growing with distance and
+    /// shrinking with the number of tags.
+    pub std_devs: [f64; 3],
+    pub tag_ids: Vec<u32>,
+    pub average_distance: f64,
+}
+
+pub struct Localizer {
+    pub layout: FieldLayout,
+    pub mount: CameraMount,
+    /// Observations with a pose ambiguity above this are ignored.
+    pub max_ambiguity: f64,
+    /// Observations of tags further away than this are ignored.
+    pub max_distance: f64,
+    /// Standard deviations reported for a single tag one meter away.
+    pub base_std_devs: [f64; 3],
+}
+
+impl Localizer {
+    pub fn new(layout: FieldLayout, mount: CameraMount) -> Self {
+        Localizer {
+            layout,
+            mount,
+            max_ambiguity: 0.2,
+            max_distance: 5.0,
+            base_std_devs: [0.05, 0.05, 0.1],
+        }
+    }
+
+    /// Robot pose on the field implied by a single observation, if the tag is in the layout.
+    pub fn robot_pose(&self, observation: &TagObservation) -> Option<Isometry3<f64>> {
+        let field_to_tag = self.layout.tag_pose(observation.id)?;
+        let camera_to_tag = observation.estimate.pose * layout_tag_in_opencv_tag();
+        let robot_to_camera = self.mount.transform() * camera_body_in_opencv().inverse();
+        Some(field_to_tag * camera_to_tag.inverse() * robot_to_camera.inverse())
+    }
+
+    /// Fuses every usable observation into one robot pose, weighting each by inverse squared
+    /// distance and by how unambiguous its rotation was.
+    pub fn localize(&self, observations: &[TagObservation]) -> Option<RobotPoseEstimate> {
+        let mut total_weight = 0.0;
+        let (mut x, mut y, mut sin, mut cos, mut distance) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        let mut tag_ids = Vec::new();
+        for observation in observations {
+            let tag_distance = observation.estimate.distance();
+            if observation.estimate.ambiguity > self.max_ambiguity || tag_distance > self.max_distance {
+                continue;
+            }
+            let pose = match self.robot_pose(observation) {
+                Some(pose) => pose,
+                None => continue,
+            };
+            let weight = (1.0 - observation.estimate.ambiguity) / tag_distance.max(0.1).powi(2);
+            let (_, _, heading) = pose.rotation.euler_angles();
+            x += weight * pose.translation.x;
+            y += weight * pose.translation.y;
+            sin += weight * heading.sin();
+            cos += weight * heading.cos();
+            distance += tag_distance;
+            total_weight += weight;
+            tag_ids.push(observation.id);
+        }
+        if tag_ids.is_empty() || total_weight <= 0.0 {
+            return None;
+        }
+        let average_distance = distance / tag_ids.len() as f64;
+        let scale = average_distance.powi(2) / tag_ids.len() as f64;
+        Some(RobotPoseEstimate {
+            x: x / total_weight,
+            y: y / total_weight,
+            heading: sin.atan2(cos),
+            std_devs: self.base_std_devs.map(|s| s * scale),
+            tag_ids,
+            average_distance,
+        })
+    }
+}
+
+// The layout's tag frame (x out of the face, y left, z up) within the tag frame used by `pose`
+fn layout_tag_in_opencv_tag() -> Isometry3<f64> {
+    rotation_from_matrix(Matrix3::new(
+        0.0, 1.0, 0.0,
+        0.0, 0.0, 1.0,
+        1.0, 0.0, 0.0,
+    ))
+}
+
+// The camera body frame (x forward, y left, z up) within the OpenCV camera frame
+pub(crate) fn camera_body_in_opencv() -> Isometry3<f64> {
+    rotation_from_matrix(Matrix3::new(
+        0.0, -1.0, 0.0,
+        0.0, 0.0, -1.0,
+        1.0, 0.0, 0.0,
+    ))
+}
+
+fn rotation_from_matrix(matrix: Matrix3<f64>) -> Isometry3<f64> {
+    Isometry3::from_parts(
+        Translation3::from(Vector3::zeros()),
+        UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(matrix)),
+    )
+}
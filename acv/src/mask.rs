@@ -0,0 +1,384 @@
+use image::GrayImage;
+use crate::parallel::{par_bands, par_bands_mut, par_bands_with_scratch};
+
+pub mod blur;
+pub mod components;
+
+/// The neighbourhood of a morphology operation: which pixels around the anchor count.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StructuringElement {
+    width: u32,
+    height: u32,
+    anchor: (u32, u32),
+    // Positions of the cells that count, relative to the anchor.
+    offsets: Vec<(i32, i32)>,
+}
+
+impl StructuringElement {
+    /// A `width` x `height` rectangle anchored in the middle. Rectangles are separable, so they
+    /// cost the same whatever their size.
+    pub fn rect(width: u32, height: u32) -> Self {
+        StructuringElement::from_fn(width, height, |_, _| true)
+    }
+
+    /// The square of side `2 * radius + 1`, like imageproc's `Norm::LInf` morphology.
+    pub fn square(radius: u32) -> Self {
+        StructuringElement::rect(2 * radius + 1, 2 * radius + 1)
+    }
+
+    /// The ellipse inscribed in a `width` x `height` rectangle, for round blobs.
+    pub fn ellipse(width: u32, height: u32) -> Self {
+        let (a, b) = (width.max(1) as f32 / 2.0, height.max(1) as f32 / 2.0);
+        StructuringElement::from_fn(width, height, |x, y| {
+            let (dx, dy) = ((x as f32 + 0.5 - a) / a, (y as f32 + 0.5 - b) / b);
+            dx * dx + dy * dy <= 1.0
+        })
+    }
+
+    /// The middle row and column of a `width` x `height` rectangle.
+    pub fn cross(width: u32, height: u32) -> Self {
+        StructuringElement::from_fn(width, height, |x, y| x == width / 2 || y == height / 2)
+    }
+
+    /// The set pixels of `mask`, anchored at `anchor`.
+    pub fn from_mask(mask: &GrayImage, anchor: (u32, u32)) -> crate::Result<Self> {
+        if anchor.0 >= mask.width() || anchor.1 >= mask.height() {
+            return Err("Structuring element anchor is outside it".into());
+        }
+        let mut element = StructuringElement::from_fn(mask.width(), mask.height(), |x, y| mask.get_pixel(x, y)[0] > 0);
+        element.set_anchor(anchor);
+        Ok(element)
+    }
+
+    fn from_fn<F: Fn(u32, u32) -> bool>(width: u32, height: u32, f: F) -> Self {
+        let (width, height) = (width.max(1), height.max(1));
+        let mut element = StructuringElement { width, height, anchor: (0, 0), offsets: Vec::new() };
+        for y in 0..height {
+            for x in 0..width {
+                if f(x, y) {
+                    element.offsets.push((x as i32, y as i32));
+                }
+            }
+        }
+        element.set_anchor((width / 2, height / 2));
+        element
+    }
+
+    fn set_anchor(&mut self, anchor: (u32, u32)) {
+        let shift = (anchor.0 as i32 - self.anchor.0 as i32, anchor.1 as i32 - self.anchor.1 as i32);
+        for offset in self.offsets.iter_mut() {
+            *offset = (offset.0 - shift.0, offset.1 - shift.1);
+        }
+        self.anchor = anchor;
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn anchor(&self) -> (u32, u32) {
+        self.anchor
+    }
+
+    /// Whether every cell of the bounding rectangle counts.
+    pub fn is_rect(&self) -> bool {
+        self.offsets.len() == (self.width * self.height) as usize
+    }
+}
+
+/// Scratch space for the mask operations, grown on first use and reused after, so running them
+/// every frame doesn't allocate.
+#[derive(Clone, Debug, Default)]
+pub struct MaskBuffers {
+    counts: Vec<u32>,
+    bytes: Vec<u8>,
+    image: GrayImage,
+    stack: Vec<u32>,
+}
+
+impl MaskBuffers {
+    pub fn new() -> Self {
+        MaskBuffers::default()
+    }
+}
+
+/// Makes `image` `width` x `height`, reallocating only if it's a different size.
+pub fn fit(image: &mut GrayImage, width: u32, height: u32) {
+    if image.dimensions() != (width, height) {
+        *image = GrayImage::new(width, height);
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Operation {
+    Erode,
+    Dilate,
+}
+
+/// Sets pixels of `dst` where every pixel of the element placed on them is set in `mask`. Parts
+/// of the element off the image are ignored, so masks don't shrink away from the edges.
+pub fn erode_into(mask: &GrayImage, element: &StructuringElement, buffers: &mut MaskBuffers, dst: &mut GrayImage) {
+    morphology_into(mask, element, Operation::Erode, buffers, dst);
+}
+
+/// Sets pixels of `dst` where any pixel of the element placed on them is set in `mask`.
+pub fn dilate_into(mask: &GrayImage, element: &StructuringElement, buffers: &mut MaskBuffers, dst: &mut GrayImage) {
+    morphology_into(mask, element, Operation::Dilate, buffers, dst);
+}
+
+/// Erosion then dilation: removes specks smaller than the element.
+pub fn open_into(mask: &GrayImage, element: &StructuringElement, buffers: &mut MaskBuffers, dst: &mut GrayImage) {
+    let mut eroded = std::mem::take(&mut buffers.image);
+    erode_into(mask, element, buffers, &mut eroded);
+    dilate_into(&eroded, element, buffers, dst);
+    buffers.image = eroded;
+}
+
+/// Dilation then erosion: fills gaps smaller than the element.
+pub fn close_into(mask: &GrayImage, element: &StructuringElement, buffers: &mut MaskBuffers, dst: &mut GrayImage) {
+    let mut dilated = std::mem::take(&mut buffers.image);
+    dilate_into(mask, element, buffers, &mut dilated);
+    erode_into(&dilated, element, buffers, dst);
+    buffers.image = dilated;
+}
+
+pub fn erode(mask: &GrayImage, element: &StructuringElement) -> GrayImage {
+    let mut dst = GrayImage::new(mask.width(), mask.height());
+    erode_into(mask, element, &mut MaskBuffers::new(), &mut dst);
+    dst
+}
+
+pub fn dilate(mask: &GrayImage, element: &StructuringElement) -> GrayImage {
+    let mut dst = GrayImage::new(mask.width(), mask.height());
+    dilate_into(mask, element, &mut MaskBuffers::new(), &mut dst);
+    dst
+}
+
+pub fn open(mask: &GrayImage, element: &StructuringElement) -> GrayImage {
+    let mut dst = GrayImage::new(mask.width(), mask.height());
+    open_into(mask, element, &mut MaskBuffers::new(), &mut dst);
+    dst
+}
+
+pub fn close(mask: &GrayImage, element: &StructuringElement) -> GrayImage {
+    let mut dst = GrayImage::new(mask.width(), mask.height());
+    close_into(mask, element, &mut MaskBuffers::new(), &mut dst);
+    dst
+}
+
+fn morphology_into(mask: &GrayImage, element: &StructuringElement, operation: Operation, buffers: &mut MaskBuffers, dst: &mut GrayImage) {
+    let (width, height) = mask.dimensions();
+    fit(dst, width, height);
+    if width == 0 || height == 0 {
+        return;
+    }
+    if element.is_rect() {
+        separable_morphology(mask, element, operation, buffers, dst);
+        return;
+    }
+    let src = mask.as_raw();
+    let (width, height) = (width as i32, height as i32);
+    par_bands_mut(dst, width as usize, width as usize, |first_row, dst| {
+        for (i, out) in dst.iter_mut().enumerate() {
+            let (x, y) = (i as i32 % width, first_row as i32 + i as i32 / width);
+            let mut set = element.offsets.iter()
+                .map(|(dx, dy)| (x + dx, y + dy))
+                .filter(|(sx, sy)| *sx >= 0 && *sy >= 0 && *sx < width && *sy < height)
+                .map(|(sx, sy)| src[(sy * width + sx) as usize] > 0);
+            let value = match operation {
+                Operation::Erode => set.all(|s| s),
+                Operation::Dilate => set.any(|s| s),
+            };
+            *out = if value { 255 } else { 0 };
+        }
+    });
+}
+
+// Rectangles as a pass along rows then one down columns, each counting set pixels in a sliding
+// window: eroded where the count is the whole window, dilated where it isn't zero. Both passes
+// run a band of rows per core; bands of the column pass start their windows afresh.
+fn separable_morphology(mask: &GrayImage, element: &StructuringElement, operation: Operation, buffers: &mut MaskBuffers, dst: &mut GrayImage) {
+    let (width, height) = (mask.width() as usize, mask.height() as usize);
+    let (before_x, after_x) = (element.anchor.0 as usize, (element.width - 1 - element.anchor.0) as usize);
+    let (before_y, after_y) = (element.anchor.1 as usize, (element.height - 1 - element.anchor.1) as usize);
+    let decide = |count: usize, window: usize| match operation {
+        Operation::Erode => count == window,
+        Operation::Dilate => count > 0,
+    };
+
+    buffers.bytes.clear();
+    buffers.bytes.resize(width * height, 0);
+    par_bands(mask.as_raw(), width, &mut buffers.bytes, width, width, |_, src, out| {
+        for (row, out) in src.chunks_exact(width).zip(out.chunks_exact_mut(width)) {
+            // Set pixels in x - before_x..=x + after_x, slid along the row.
+            let mut count = row[..after_x.min(width)].iter().filter(|v| **v > 0).count();
+            for (x, o) in out.iter_mut().enumerate() {
+                if x + after_x < width {
+                    count += usize::from(row[x + after_x] > 0);
+                }
+                if x > before_x {
+                    count -= usize::from(row[x - before_x - 1] > 0);
+                }
+                let window = (x + after_x + 1).min(width) - x.saturating_sub(before_x);
+                *o = u8::from(decide(count, window));
+            }
+        }
+    });
+
+    let rows = &buffers.bytes;
+    par_bands_with_scratch(dst, width, width, &mut buffers.counts, width, |first_row, dst, counts| {
+        // Rows of the row pass in the window of the band's first row, less the entering one.
+        let start = first_row.saturating_sub(before_y);
+        for row in rows.chunks_exact(width).take((first_row + after_y).min(height)).skip(start) {
+            for (count, value) in counts.iter_mut().zip(row) {
+                *count += *value as u32;
+            }
+        }
+        for (i, out) in dst.chunks_exact_mut(width).enumerate() {
+            let y = first_row + i;
+            if y + after_y < height {
+                for (count, value) in counts.iter_mut().zip(&rows[(y + after_y) * width..(y + after_y + 1) * width]) {
+                    *count += *value as u32;
+                }
+            }
+            if y > before_y && y - before_y > start {
+                for (count, value) in counts.iter_mut().zip(&rows[(y - before_y - 1) * width..(y - before_y) * width]) {
+                    *count -= *value as u32;
+                }
+            }
+            let window = (y + after_y + 1).min(height) - y.saturating_sub(before_y);
+            for (o, count) in out.iter_mut().zip(counts.iter()) {
+                *o = if decide(*count as usize, window) { 255 } else { 0 };
+            }
+        }
+    });
+}
+
+/// Sets the unset regions of `mask` that don't touch its edge, so blobs come out solid. Holes
+/// are found as unset pixels not 4-connected to the edge, which suits 8-connected blobs.
+pub fn fill_holes_into(mask: &GrayImage, buffers: &mut MaskBuffers, dst: &mut GrayImage) {
+    let (width, height) = mask.dimensions();
+    fit(dst, width, height);
+    if width == 0 || height == 0 {
+        return;
+    }
+    let src = mask.as_raw();
+    // 1 where the unset area reaches the edge.
+    let outside = &mut buffers.bytes;
+    outside.clear();
+    outside.resize(src.len(), 0);
+    let stack = &mut buffers.stack;
+    stack.clear();
+    let visit = |index: usize, outside: &mut [u8], stack: &mut Vec<u32>| {
+        if src[index] == 0 && outside[index] == 0 {
+            outside[index] = 1;
+            stack.push(index as u32);
+        }
+    };
+    for x in 0..width as usize {
+        visit(x, outside, stack);
+        visit((height as usize - 1) * width as usize + x, outside, stack);
+    }
+    for y in 0..height as usize {
+        visit(y * width as usize, outside, stack);
+        visit(y * width as usize + width as usize - 1, outside, stack);
+    }
+    while let Some(index) = stack.pop() {
+        let (x, y) = (index % width, index / width);
+        let index = index as usize;
+        if x > 0 {
+            visit(index - 1, outside, stack);
+        }
+        if x + 1 < width {
+            visit(index + 1, outside, stack);
+        }
+        if y > 0 {
+            visit(index - width as usize, outside, stack);
+        }
+        if y + 1 < height {
+            visit(index + width as usize, outside, stack);
+        }
+    }
+    for (o, reached) in dst.iter_mut().zip(outside.iter()) {
+        *o = if *reached == 0 { 255 } else { 0 };
+    }
+}
+
+pub fn fill_holes(mask: &GrayImage) -> GrayImage {
+    let mut dst = GrayImage::new(mask.width(), mask.height());
+    fill_holes_into(mask, &mut MaskBuffers::new(), &mut dst);
+    dst
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+    use imageproc::distance_transform::Norm;
+    use imageproc::morphology;
+    use super::*;
+    use crate::util::Xorshift;
+
+    // Large enough to be split into several bands.
+    fn random_mask(density: usize, seed: u64) -> GrayImage {
+        let mut rng = Xorshift::new(seed);
+        GrayImage::from_fn(301, 217, |_, _| Luma([if rng.below(100) < density { 255 } else { 0 }]))
+    }
+
+    #[test]
+    fn morphology_matches_imageproc() {
+        let mut buffers = MaskBuffers::new();
+        let mut dst = GrayImage::new(0, 0);
+        for (seed, density) in [(1, 30), (2, 60), (3, 85)] {
+            let mask = random_mask(density, seed);
+            for radius in 0..4 {
+                let element = StructuringElement::square(radius as u32);
+                erode_into(&mask, &element, &mut buffers, &mut dst);
+                assert_eq!(dst, morphology::erode(&mask, Norm::LInf, radius), "erode {}", radius);
+                dilate_into(&mask, &element, &mut buffers, &mut dst);
+                assert_eq!(dst, morphology::dilate(&mask, Norm::LInf, radius), "dilate {}", radius);
+                open_into(&mask, &element, &mut buffers, &mut dst);
+                assert_eq!(dst, morphology::open(&mask, Norm::LInf, radius), "open {}", radius);
+                close_into(&mask, &element, &mut buffers, &mut dst);
+                assert_eq!(dst, morphology::close(&mask, Norm::LInf, radius), "close {}", radius);
+            }
+
+            // A diamond isn't a rectangle, so this goes through the general path
+            let diamond = GrayImage::from_fn(5, 5, |x, y| Luma([if x.abs_diff(2) + y.abs_diff(2) <= 2 { 255 } else { 0 }]));
+            let element = StructuringElement::from_mask(&diamond, (2, 2)).unwrap();
+            assert!(!element.is_rect());
+            assert_eq!(erode(&mask, &element), morphology::erode(&mask, Norm::L1, 2));
+            assert_eq!(dilate(&mask, &element), morphology::dilate(&mask, Norm::L1, 2));
+            assert_eq!(open(&mask, &element), morphology::open(&mask, Norm::L1, 2));
+            assert_eq!(close(&mask, &element), morphology::close(&mask, Norm::L1, 2));
+        }
+    }
+
+    #[test]
+    fn fills_enclosed_holes() {
+        // A 12x12 square with a 6x6 hole, and a C shape whose gap reaches the outside
+        let mut mask = GrayImage::new(40, 20);
+        for y in 3..15 {
+            for x in 3..15 {
+                if !(6..12).contains(&x) || !(6..12).contains(&y) {
+                    mask.put_pixel(x, y, Luma([255]));
+                    mask.put_pixel(x + 20, y, Luma([255]));
+                }
+            }
+        }
+        for y in 8..10 {
+            for x in 32..35 {
+                mask.put_pixel(x, y, Luma([0]));
+            }
+        }
+        let filled = fill_holes(&mask);
+        let set = |x0: u32, x1: u32| (0..20).flat_map(|y| (x0..x1).map(move |x| (x, y))).filter(|(x, y)| filled.get_pixel(*x, *y)[0] != 0).count();
+        assert_eq!(set(0, 20), 144);
+        assert_eq!(set(20, 40), 144 - 36 - 6);
+        assert_eq!(filled.get_pixel(8, 8)[0], 255);
+        assert_eq!(filled.get_pixel(28, 8)[0], 0);
+    }
+}
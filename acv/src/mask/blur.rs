@@ -0,0 +1,238 @@
+use image::GrayImage;
+use crate::mask::fit;
+use crate::parallel::{par_bands, par_bands_mut, par_bands_with_scratch};
+
+/// Scratch space for the blurs, reused between calls.
+#[derive(Clone, Debug, Default)]
+pub struct BlurBuffers {
+    rows: Vec<u32>,
+    sums: Vec<u32>,
+    kernel: Vec<u32>,
+}
+
+impl BlurBuffers {
+    pub fn new() -> Self {
+        BlurBuffers::default()
+    }
+}
+
+/// Mean of the square window of `radius` around each pixel. Windows are cut off at the edges
+/// rather than padded, so edges aren't darkened.
+pub fn box_blur_into(src: &GrayImage, radius: u32, buffers: &mut BlurBuffers, dst: &mut GrayImage) {
+    let (width, height) = (src.width() as usize, src.height() as usize);
+    fit(dst, src.width(), src.height());
+    if width == 0 || height == 0 {
+        return;
+    }
+    let radius = radius as usize;
+    // Row sums of each window, then column sums of those slid down each band of rows.
+    buffers.rows.clear();
+    buffers.rows.resize(width * height, 0);
+    par_bands(src.as_raw(), width, &mut buffers.rows, width, width, |_, src, rows| {
+        for (row, out) in src.chunks_exact(width).zip(rows.chunks_exact_mut(width)) {
+            let mut sum: u32 = row[..radius.min(width)].iter().map(|v| *v as u32).sum();
+            for (x, o) in out.iter_mut().enumerate() {
+                if x + radius < width {
+                    sum += row[x + radius] as u32;
+                }
+                if x > radius {
+                    sum -= row[x - radius - 1] as u32;
+                }
+                *o = sum;
+            }
+        }
+    });
+    let window_width = |x: usize| (x + radius + 1).min(width) - x.saturating_sub(radius);
+    let rows = &buffers.rows;
+    par_bands_with_scratch(dst, width, width, &mut buffers.sums, width, |first_row, dst, sums| {
+        let start = first_row.saturating_sub(radius);
+        for row in rows.chunks_exact(width).take((first_row + radius).min(height)).skip(start) {
+            for (s, v) in sums.iter_mut().zip(row) {
+                *s += v;
+            }
+        }
+        for (i, out) in dst.chunks_exact_mut(width).enumerate() {
+            let y = first_row + i;
+            if y + radius < height {
+                for (s, v) in sums.iter_mut().zip(&rows[(y + radius) * width..(y + radius + 1) * width]) {
+                    *s += v;
+                }
+            }
+            if y > radius && y - radius > start {
+                for (s, v) in sums.iter_mut().zip(&rows[(y - radius - 1) * width..(y - radius) * width]) {
+                    *s -= v;
+                }
+            }
+            let window_height = (y + radius + 1).min(height) - y.saturating_sub(radius);
+            for (x, (o, s)) in out.iter_mut().zip(sums.iter()).enumerate() {
+                let count = (window_width(x) * window_height) as u32;
+                *o = ((s + count / 2) / count) as u8;
+            }
+        }
+    });
+}
+
+// Kernel weights sum to this, and the row pass keeps this many extra bits.
+const KERNEL_BITS: u32 = 12;
+const ROW_BITS: u32 = 4;
+
+/// Gaussian blur with a kernel reaching 3 `sigma`, in fixed point. Edge pixels are repeated
+/// past the edge.
+pub fn gaussian_blur_into(src: &GrayImage, sigma: f32, buffers: &mut BlurBuffers, dst: &mut GrayImage) {
+    let (width, height) = (src.width() as usize, src.height() as usize);
+    fit(dst, src.width(), src.height());
+    if width == 0 || height == 0 {
+        return;
+    }
+    let radius = (3.0 * sigma.max(0.01)).ceil() as usize;
+    gaussian_kernel(sigma.max(0.01), radius, &mut buffers.kernel);
+    let kernel = &buffers.kernel;
+
+    buffers.rows.clear();
+    buffers.rows.resize(width * height, 0);
+    let row_shift = KERNEL_BITS - ROW_BITS;
+    par_bands(src.as_raw(), width, &mut buffers.rows, width, width, |_, src, rows| {
+        for (row, out) in src.chunks_exact(width).zip(rows.chunks_exact_mut(width)) {
+            for (x, o) in out.iter_mut().enumerate() {
+                let mut sum = 0u32;
+                for (i, weight) in kernel.iter().enumerate() {
+                    let sx = (x + i).saturating_sub(radius).min(width - 1);
+                    sum += weight * row[sx] as u32;
+                }
+                *o = (sum + (1 << (row_shift - 1))) >> row_shift;
+            }
+        }
+    });
+    let shift = KERNEL_BITS + ROW_BITS;
+    let rows = &buffers.rows;
+    par_bands_mut(dst, width, width, |first_row, dst| {
+        for (i, out) in dst.chunks_exact_mut(width).enumerate() {
+            let y = first_row + i;
+            for (x, o) in out.iter_mut().enumerate() {
+                let mut sum = 0u32;
+                for (i, weight) in kernel.iter().enumerate() {
+                    let sy = (y + i).saturating_sub(radius).min(height - 1);
+                    sum += weight * rows[sy * width + x];
+                }
+                *o = ((sum + (1 << (shift - 1))) >> shift).min(255) as u8;
+            }
+        }
+    });
+}
+
+// Weights of `2 * radius + 1` taps summing to exactly 1 << KERNEL_BITS.
+fn gaussian_kernel(sigma: f32, radius: usize, kernel: &mut Vec<u32>) {
+    let weight = |i: usize| {
+        let d = i as f32 - radius as f32;
+        (-d * d / (2.0 * sigma * sigma)).exp()
+    };
+    let total: f32 = (0..2 * radius + 1).map(weight).sum();
+    kernel.clear();
+    kernel.extend((0..2 * radius + 1).map(|i| (weight(i) / total * (1 << KERNEL_BITS) as f32).round() as u32));
+    let sum: u32 = kernel.iter().sum();
+    kernel[radius] = (kernel[radius] + (1 << KERNEL_BITS)).saturating_sub(sum);
+}
+
+/// Median of the square window of `radius` around each pixel, with edge pixels repeated past the
+/// edge. Keeps a histogram of the window as it slides along each row, so the cost grows with the
+/// radius rather than its square. Needs no buffers beyond a histogram per row on the stack.
+pub fn median_blur_into(src: &GrayImage, radius: u32, dst: &mut GrayImage) {
+    let (width, height) = (src.width() as i64, src.height() as i64);
+    fit(dst, src.width(), src.height());
+    if width == 0 || height == 0 {
+        return;
+    }
+    let radius = radius as i64;
+    let half = ((2 * radius + 1) * (2 * radius + 1) / 2) as u32;
+    let pixel = |x: i64, y: i64| src.as_raw()[(y.clamp(0, height - 1) * width + x.clamp(0, width - 1)) as usize] as usize;
+    par_bands_mut(dst, width as usize, width as usize, |first_row, dst| {
+        for (i, out) in dst.chunks_exact_mut(width as usize).enumerate() {
+            let y = first_row as i64 + i as i64;
+            let mut histogram = [0u32; 256];
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    histogram[pixel(dx, y + dy)] += 1;
+                }
+            }
+            // The median and how many window pixels are below it.
+            let (mut median, mut below) = (0usize, 0u32);
+            for (x, o) in out.iter_mut().enumerate() {
+                let x = x as i64;
+                if x > 0 {
+                    for dy in -radius..=radius {
+                        let (leaving, entering) = (pixel(x - radius - 1, y + dy), pixel(x + radius, y + dy));
+                        histogram[leaving] -= 1;
+                        histogram[entering] += 1;
+                        below = below + u32::from(entering < median) - u32::from(leaving < median);
+                    }
+                }
+                while below > half {
+                    median -= 1;
+                    below -= histogram[median];
+                }
+                while below + histogram[median] <= half {
+                    below += histogram[median];
+                    median += 1;
+                }
+                *o = median as u8;
+            }
+        }
+    });
+}
+
+pub fn box_blur(src: &GrayImage, radius: u32) -> GrayImage {
+    let mut dst = GrayImage::new(src.width(), src.height());
+    box_blur_into(src, radius, &mut BlurBuffers::new(), &mut dst);
+    dst
+}
+
+pub fn gaussian_blur(src: &GrayImage, sigma: f32) -> GrayImage {
+    let mut dst = GrayImage::new(src.width(), src.height());
+    gaussian_blur_into(src, sigma, &mut BlurBuffers::new(), &mut dst);
+    dst
+}
+
+pub fn median_blur(src: &GrayImage, radius: u32) -> GrayImage {
+    let mut dst = GrayImage::new(src.width(), src.height());
+    median_blur_into(src, radius, &mut dst);
+    dst
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+    use super::*;
+    use crate::util::Xorshift;
+
+    fn random_image(seed: u64) -> GrayImage {
+        let mut rng = Xorshift::new(seed);
+        GrayImage::from_fn(301, 217, |_, _| Luma([rng.below(256) as u8]))
+    }
+
+    #[test]
+    fn median_matches_imageproc() {
+        let image = random_image(7);
+        for radius in [1, 2, 4] {
+            assert_eq!(median_blur(&image, radius), imageproc::filter::median_filter(&image, radius, radius));
+        }
+    }
+
+    #[test]
+    fn box_blur_averages_the_window_inside_the_image() {
+        let image = random_image(8);
+        let (width, height) = image.dimensions();
+        let blurred = box_blur(&image, 4);
+        for (x, y, pixel) in blurred.enumerate_pixels() {
+            let (xs, ys) = (x.saturating_sub(4)..(x + 5).min(width), y.saturating_sub(4)..(y + 5).min(height));
+            let count = xs.len() as u32 * ys.len() as u32;
+            let sum: u32 = ys.flat_map(|sy| xs.clone().map(move |sx| (sx, sy))).map(|(sx, sy)| image.get_pixel(sx, sy)[0] as u32).sum();
+            assert_eq!(pixel[0] as u32, (sum + count / 2) / count, "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn gaussian_keeps_flat_images() {
+        let flat = GrayImage::from_pixel(40, 30, Luma([200]));
+        assert_eq!(gaussian_blur(&flat, 2.0), flat);
+    }
+}
@@ -0,0 +1,250 @@
+use image::GrayImage;
+use imageproc::region_labelling::Connectivity;
+use crate::detection::BoundingBox;
+
+/// A connected region of set pixels.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Component {
+    pub area: u32,
+    pub bbox: BoundingBox,
+    /// Mean position of the pixels, with pixel centers at integer coordinates.
+    pub centroid: (f32, f32),
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Stats {
+    min: (u32, u32),
+    max: (u32, u32),
+    sum: (u64, u64),
+    area: u32,
+}
+
+impl Stats {
+    fn new(x: u32, y: u32) -> Self {
+        Stats { min: (x, y), max: (x, y), sum: (0, 0), area: 0 }
+    }
+
+    fn add(&mut self, x: u32, y: u32) {
+        self.min = (self.min.0.min(x), self.min.1.min(y));
+        self.max = (self.max.0.max(x), self.max.1.max(y));
+        self.sum = (self.sum.0 + x as u64, self.sum.1 + y as u64);
+        self.area += 1;
+    }
+
+    fn merge(&mut self, other: &Stats) {
+        self.min = (self.min.0.min(other.min.0), self.min.1.min(other.min.1));
+        self.max = (self.max.0.max(other.max.0), self.max.1.max(other.max.1));
+        self.sum = (self.sum.0 + other.sum.0, self.sum.1 + other.sum.1);
+        self.area += other.area;
+    }
+}
+
+/// Connected component labelling that gathers each component's stats in the same pass over the
+/// mask, merging provisional labels with union-find as regions meet. Keep one around to reuse
+/// its buffers between frames.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectedComponents {
+    labels: Vec<u32>,
+    parents: Vec<u32>,
+    stats: Vec<Stats>,
+    // Component of each root label, plus one.
+    finals: Vec<u32>,
+    components: Vec<Component>,
+    width: u32,
+    resolved: bool,
+}
+
+impl ConnectedComponents {
+    pub fn new() -> Self {
+        ConnectedComponents::default()
+    }
+
+    /// Finds the components of the set pixels of `mask`, in the order their first pixels are met
+    /// scanning row by row.
+    pub fn label(&mut self, mask: &GrayImage, connectivity: Connectivity) -> &[Component] {
+        let (width, height) = mask.dimensions();
+        self.width = width;
+        self.resolved = false;
+        self.labels.clear();
+        self.labels.resize((width * height) as usize, 0);
+        // Provisional label 0 is the background.
+        self.parents.clear();
+        self.parents.push(0);
+        self.stats.clear();
+        self.stats.push(Stats::new(0, 0));
+        self.components.clear();
+
+        let src = mask.as_raw();
+        for y in 0..height {
+            for x in 0..width {
+                let index = (y * width + x) as usize;
+                if src[index] == 0 {
+                    continue;
+                }
+                let mut label = 0;
+                let mut join = |neighbour: u32, parents: &mut Vec<u32>| {
+                    if neighbour == 0 {
+                        return;
+                    }
+                    if label == 0 {
+                        label = neighbour;
+                    } else {
+                        union(parents, label, neighbour);
+                    }
+                };
+                if x > 0 {
+                    join(self.labels[index - 1], &mut self.parents);
+                }
+                if y > 0 {
+                    let above = index - width as usize;
+                    join(self.labels[above], &mut self.parents);
+                    if connectivity == Connectivity::Eight {
+                        if x > 0 {
+                            join(self.labels[above - 1], &mut self.parents);
+                        }
+                        if x + 1 < width {
+                            join(self.labels[above + 1], &mut self.parents);
+                        }
+                    }
+                }
+                if label == 0 {
+                    label = self.parents.len() as u32;
+                    self.parents.push(label);
+                    self.stats.push(Stats::new(x, y));
+                }
+                self.labels[index] = label;
+                self.stats[label as usize].add(x, y);
+            }
+        }
+
+        // Fold every provisional label's stats into its root, numbering roots as they're met.
+        self.finals.clear();
+        self.finals.resize(self.parents.len(), 0);
+        for label in 1..self.parents.len() as u32 {
+            let root = find(&mut self.parents, label) as usize;
+            if self.finals[root] == 0 {
+                self.finals[root] = self.components.len() as u32 + 1;
+                self.components.push(Component { area: 0, bbox: BoundingBox::new(0.0, 0.0, 0.0, 0.0), centroid: (0.0, 0.0) });
+            }
+            if root != label as usize {
+                let stats = self.stats[label as usize];
+                self.stats[root].merge(&stats);
+            }
+        }
+        for (label, stats) in self.stats.iter().enumerate().skip(1) {
+            let component = self.finals[label];
+            if component == 0 {
+                continue;
+            }
+            self.components[component as usize - 1] = Component {
+                area: stats.area,
+                bbox: BoundingBox::new(
+                    stats.min.0 as f32,
+                    stats.min.1 as f32,
+                    (stats.max.0 - stats.min.0 + 1) as f32,
+                    (stats.max.1 - stats.min.1 + 1) as f32,
+                ),
+                centroid: (stats.sum.0 as f32 / stats.area as f32, stats.sum.1 as f32 / stats.area as f32),
+            };
+        }
+        &self.components
+    }
+
+    /// Components found by the last [`ConnectedComponents::label`].
+    pub fn components(&self) -> &[Component] {
+        &self.components
+    }
+
+    /// The component of each pixel of the last mask, row by row: 0 for unset pixels and `i + 1`
+    /// for pixels of `components()[i]`. Only worked out when first asked for.
+    pub fn labels(&mut self) -> &[u32] {
+        if !self.resolved {
+            for label in self.labels.iter_mut().filter(|l| **l != 0) {
+                let root = find(&mut self.parents, *label);
+                *label = self.finals[root as usize];
+            }
+            self.resolved = true;
+        }
+        &self.labels
+    }
+
+    /// The component of pixel `(x, y)` of the last mask, as in [`ConnectedComponents::labels`].
+    pub fn label_at(&mut self, x: u32, y: u32) -> u32 {
+        let width = self.width as usize;
+        self.labels().get(y as usize * width + x as usize).copied().unwrap_or(0)
+    }
+}
+
+fn find(parents: &mut [u32], mut label: u32) -> u32 {
+    while parents[label as usize] != label {
+        // Path halving
+        let grandparent = parents[parents[label as usize] as usize];
+        parents[label as usize] = grandparent;
+        label = grandparent;
+    }
+    label
+}
+
+// Keeps the smaller label as the root, so roots are the first labels of their components.
+fn union(parents: &mut [u32], a: u32, b: u32) {
+    let (a, b) = (find(parents, a), find(parents, b));
+    if a < b {
+        parents[b as usize] = a;
+    } else if b < a {
+        parents[a as usize] = b;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma};
+    use imageproc::region_labelling::{connected_components, Connectivity};
+    use super::*;
+    use crate::util::Xorshift;
+
+    fn mask(rows: &[&str]) -> GrayImage {
+        GrayImage::from_fn(rows[0].len() as u32, rows.len() as u32, |x, y| {
+            Luma([if rows[y as usize].as_bytes()[x as usize] == b'#' { 255 } else { 0 }])
+        })
+    }
+
+    #[test]
+    fn diagonal_neighbours_join_with_eight_connectivity() {
+        let mask = mask(&[
+            "#.....",
+            ".#..##",
+            "..#..#",
+            "......",
+        ]);
+        let mut components = ConnectedComponents::new();
+
+        let four = components.label(&mask, Connectivity::Four).to_vec();
+        assert_eq!(four.iter().map(|c| c.area).collect::<Vec<_>>(), [1, 1, 3, 1]);
+        assert_eq!(four[2].bbox, BoundingBox::new(4.0, 1.0, 2.0, 2.0));
+        assert_eq!(four[2].centroid, (14.0 / 3.0, 4.0 / 3.0));
+        assert_eq!(components.label_at(2, 2), 4);
+
+        let eight = components.label(&mask, Connectivity::Eight).to_vec();
+        assert_eq!(eight.iter().map(|c| c.area).collect::<Vec<_>>(), [3, 3]);
+        assert_eq!(eight[0].bbox, BoundingBox::new(0.0, 0.0, 3.0, 3.0));
+        assert_eq!(eight[0].centroid, (1.0, 1.0));
+        assert_eq!(components.label_at(2, 2), 1);
+        assert_eq!(components.label_at(0, 3), 0);
+    }
+
+    #[test]
+    fn labels_match_imageproc() {
+        let mut rng = Xorshift::new(11);
+        let mut components = ConnectedComponents::new();
+        for density in [30, 50, 70] {
+            let mask = GrayImage::from_fn(301, 217, |_, _| Luma([if rng.below(100) < density { 255 } else { 0 }]));
+            for connectivity in [Connectivity::Four, Connectivity::Eight] {
+                let count = components.label(&mask, connectivity).len();
+                // Both number components in the order their first pixels are met
+                let reference = connected_components(&mask, connectivity, Luma([0]));
+                assert_eq!(count as u32, reference.pixels().map(|p| p[0]).max().unwrap());
+                assert!(components.labels().iter().zip(reference.pixels()).all(|(a, b)| *a == b[0]));
+            }
+        }
+    }
+}
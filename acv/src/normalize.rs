@@ -0,0 +1,338 @@
+use std::fmt::{Display, Formatter};
+use image::Rgb;
+use imageproc::definitions::Image;
+use nalgebra::{Matrix4, Vector4};
+use serde::{Deserialize, Serialize};
+use crate::detection::BoundingBox;
+use crate::util::YCrCb;
+
+/// An affine correction of RGB values, `matrix * rgb + offset`, as estimated by white balancing
+/// or fitted to a reference card. White balance only ever sets the diagonal.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ColorCorrection {
+    pub matrix: [[f32; 3]; 3],
+    pub offset: [f32; 3],
+}
+
+impl ColorCorrection {
+    pub fn identity() -> Self {
+        ColorCorrection::from_gains([1.0; 3])
+    }
+
+    pub fn from_gains(gains: [f32; 3]) -> Self {
+        let mut matrix = [[0.0; 3]; 3];
+        for (i, row) in matrix.iter_mut().enumerate() {
+            row[i] = gains[i];
+        }
+        ColorCorrection { matrix, offset: [0.0; 3] }
+    }
+
+    /// The diagonal of the matrix, which is all there is to a white balance.
+    pub fn gains(&self) -> [f32; 3] {
+        [self.matrix[0][0], self.matrix[1][1], self.matrix[2][2]]
+    }
+
+    pub fn correct(&self, pixel: Rgb<u8>) -> Rgb<u8> {
+        let [r, g, b] = pixel.0.map(|c| c as f32);
+        let mut out = [0u8; 3];
+        for (o, (row, offset)) in out.iter_mut().zip(self.matrix.iter().zip(self.offset)) {
+            *o = (row[0] * r + row[1] * g + row[2] * b + offset).round().clamp(0.0, 255.0) as u8;
+        }
+        Rgb(out)
+    }
+
+    pub fn apply(&self, image: &Image<Rgb<u8>>) -> Image<Rgb<u8>> {
+        let mut corrected = image.clone();
+        for pixel in corrected.pixels_mut() {
+            *pixel = self.correct(*pixel);
+        }
+        corrected
+    }
+}
+
+impl Default for ColorCorrection {
+    fn default() -> Self {
+        ColorCorrection::identity()
+    }
+}
+
+impl Display for ColorCorrection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let [r, g, b] = self.gains();
+        write!(f, "gains {:.3}/{:.3}/{:.3}", r, g, b)?;
+        let off_diagonal = (0..3).any(|i| (0..3).any(|j| i != j && self.matrix[i][j] != 0.0));
+        if off_diagonal || self.offset.iter().any(|o| *o != 0.0) {
+            write!(f, ", matrix {:?}, offset {:?}", self.matrix, self.offset)?;
+        }
+        Ok(())
+    }
+}
+
+fn channel_means(image: &Image<Rgb<u8>>) -> [f64; 3] {
+    let mut sum = [0u64; 3];
+    for pixel in image.pixels() {
+        for (s, c) in sum.iter_mut().zip(pixel.0) {
+            *s += c as u64;
+        }
+    }
+    let count = (image.width() as u64 * image.height() as u64).max(1) as f64;
+    sum.map(|s| s as f64 / count)
+}
+
+/// Gains that make the average of the frame gray, keeping its brightness. Works when the scene
+/// has no dominant color, which a field mostly covered in carpet doesn't always.
+pub fn gray_world(image: &Image<Rgb<u8>>) -> ColorCorrection {
+    let means = channel_means(image);
+    let gray = means.iter().sum::<f64>() / 3.0;
+    ColorCorrection::from_gains(means.map(|m| if m > 0.0 { (gray / m) as f32 } else { 1.0 }))
+}
+
+/// Gains that take the `percentile` (0 to 1) brightest value of each channel to 255, so the
+/// brightest things in view come out white. A percentile below 1 keeps a few specular highlights
+/// or saturated pixels from setting the gains.
+pub fn white_patch(image: &Image<Rgb<u8>>, percentile: f64) -> ColorCorrection {
+    let mut histograms = [[0u32; 256]; 3];
+    for pixel in image.pixels() {
+        for (histogram, c) in histograms.iter_mut().zip(pixel.0) {
+            histogram[c as usize] += 1;
+        }
+    }
+    let count = image.width() as u64 * image.height() as u64;
+    let rank = ((percentile.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+    ColorCorrection::from_gains(histograms.map(|histogram| {
+        let mut seen = 0u64;
+        let value = histogram.iter()
+            .position(|n| {
+                seen += *n as u64;
+                seen >= rank
+            })
+            .unwrap_or(255);
+        if value > 0 { 255.0 / value as f32 } else { 1.0 }
+    }))
+}
+
+/// A patch of a color card in view and the color it should come out as.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ReferencePatch {
+    /// Where the patch is in the frame, in pixels.
+    pub region: BoundingBox,
+    pub color: Rgb<u8>,
+}
+
+/// The mean color of the pixels inside `region`, or `None` if it covers none.
+fn region_mean(image: &Image<Rgb<u8>>, region: &BoundingBox) -> Option<[f64; 3]> {
+    let x0 = region.x.max(0.0).round() as u32;
+    let y0 = region.y.max(0.0).round() as u32;
+    let x1 = ((region.x + region.width).round().max(0.0) as u32).min(image.width());
+    let y1 = ((region.y + region.height).round().max(0.0) as u32).min(image.height());
+    if x1 <= x0 || y1 <= y0 {
+        return None;
+    }
+    let mut sum = [0u64; 3];
+    for y in y0..y1 {
+        for x in x0..x1 {
+            for (s, c) in sum.iter_mut().zip(image.get_pixel(x, y).0) {
+                *s += c as u64;
+            }
+        }
+    }
+    let count = ((x1 - x0) * (y1 - y0)) as f64;
+    Some(sum.map(|s| s as f64 / count))
+}
+
+/// The correction that takes the measured patches closest to their reference colors, least
+/// squares. With fewer than four patches only per channel gains can be fitted; with four or more
+/// (a white, a gray and a couple of saturated colors is plenty) a full matrix and offset are,
+/// which also corrects crosstalk between channels.
+pub fn reference_patches(image: &Image<Rgb<u8>>, patches: &[ReferencePatch]) -> crate::Result<ColorCorrection> {
+    let mut samples = Vec::with_capacity(patches.len());
+    for patch in patches.iter() {
+        let measured = region_mean(image, &patch.region).ok_or("Reference patch is outside the frame")?;
+        samples.push((measured, patch.color.0.map(|c| c as f64)));
+    }
+    if samples.is_empty() {
+        return Err("No reference patches".into());
+    }
+
+    if samples.len() < 4 {
+        let mut gains = [1.0f32; 3];
+        for (channel, gain) in gains.iter_mut().enumerate() {
+            let measured_squared: f64 = samples.iter().map(|(m, _)| m[channel] * m[channel]).sum();
+            let product: f64 = samples.iter().map(|(m, t)| m[channel] * t[channel]).sum();
+            if measured_squared > 0.0 {
+                *gain = (product / measured_squared) as f32;
+            }
+        }
+        return Ok(ColorCorrection::from_gains(gains));
+    }
+
+    let mut ata = Matrix4::<f64>::zeros();
+    let mut atb = [Vector4::<f64>::zeros(); 3];
+    for (measured, target) in samples.iter() {
+        let row = Vector4::new(measured[0], measured[1], measured[2], 1.0);
+        ata += row * row.transpose();
+        for (b, t) in atb.iter_mut().zip(target) {
+            *b += row * *t;
+        }
+    }
+    let inverse = ata.try_inverse().ok_or("Reference patch colors are too similar to fit a correction")?;
+    let mut correction = ColorCorrection::identity();
+    for (channel, b) in atb.iter().enumerate() {
+        let solution = inverse * b;
+        for (i, m) in correction.matrix[channel].iter_mut().enumerate() {
+            *m = solution[i] as f32;
+        }
+        correction.offset[channel] = solution[3] as f32;
+    }
+    Ok(correction)
+}
+
+/// How a luminance normalization changed the frame.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExposureCorrection {
+    /// Mean luma before and after, 0 to 255.
+    pub mean_before: f32,
+    pub mean_after: f32,
+}
+
+impl Display for ExposureCorrection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mean luma {:.1} -> {:.1}", self.mean_before, self.mean_after)
+    }
+}
+
+/// Equalizes the histogram of the luma channel, leaving chroma alone so hues don't shift.
+pub fn equalize_luminance(image: &Image<Rgb<u8>>) -> (Image<Rgb<u8>>, ExposureCorrection) {
+    let converted: Vec<YCrCb> = image.pixels().map(|p| YCrCb::from(*p)).collect();
+    let mut histogram = [0u32; 256];
+    for pixel in converted.iter() {
+        histogram[pixel.y as usize] += 1;
+    }
+    let lut = equalization_lut(&histogram);
+    remap_luma(image, &converted, |_, _, y| lut[y as usize] as f32)
+}
+
+// Maps each level to its place in the cumulative histogram, stretched so the darkest level
+// present goes to 0 and the brightest to 255.
+fn equalization_lut(histogram: &[u32; 256]) -> [u8; 256] {
+    let total: u32 = histogram.iter().sum();
+    let first = histogram.iter().find(|n| **n > 0).copied().unwrap_or(0);
+    let mut lut = [0u8; 256];
+    if total == first {
+        for (i, l) in lut.iter_mut().enumerate() {
+            *l = i as u8;
+        }
+        return lut;
+    }
+    let mut cumulative = 0u32;
+    for (l, n) in lut.iter_mut().zip(histogram) {
+        cumulative += n;
+        let value = cumulative.saturating_sub(first) as f32 * 255.0 / (total - first) as f32;
+        *l = value.round() as u8;
+    }
+    lut
+}
+
+/// Contrast limited adaptive histogram equalization of the luma channel.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Clahe {
+    /// Tiles across and down the frame, each equalized on its own.
+    pub tiles: (u32, u32),
+    /// How many times the average count a histogram bin can hold before the excess is spread
+    /// over the other bins. Lower values amplify noise less; 2 to 4 is typical.
+    pub clip_limit: f32,
+}
+
+impl Default for Clahe {
+    fn default() -> Self {
+        Clahe { tiles: (8, 8), clip_limit: 3.0 }
+    }
+}
+
+impl Clahe {
+    /// Equalizes each tile and interpolates between the neighbouring tiles' mappings, so dark
+    /// corners and a bright middle both end up with usable contrast.
+    pub fn apply(&self, image: &Image<Rgb<u8>>) -> (Image<Rgb<u8>>, ExposureCorrection) {
+        let (width, height) = image.dimensions();
+        let tiles_x = self.tiles.0.clamp(1, width.max(1));
+        let tiles_y = self.tiles.1.clamp(1, height.max(1));
+        let converted: Vec<YCrCb> = image.pixels().map(|p| YCrCb::from(*p)).collect();
+        let tile_width = width as f32 / tiles_x as f32;
+        let tile_height = height as f32 / tiles_y as f32;
+
+        let mut luts = Vec::with_capacity((tiles_x * tiles_y) as usize);
+        for ty in 0..tiles_y {
+            let (y0, y1) = ((ty as f32 * tile_height) as u32, ((ty + 1) as f32 * tile_height) as u32);
+            for tx in 0..tiles_x {
+                let (x0, x1) = ((tx as f32 * tile_width) as u32, ((tx + 1) as f32 * tile_width) as u32);
+                let mut histogram = [0u32; 256];
+                for y in y0..y1 {
+                    for x in x0..x1 {
+                        histogram[converted[(y * width + x) as usize].y as usize] += 1;
+                    }
+                }
+                clip_histogram(&mut histogram, self.clip_limit);
+                luts.push(tile_lut(&histogram));
+            }
+        }
+
+        remap_luma(image, &converted, |x, y, luma| {
+            // Position in tile centers, clamped so edges use the outermost tiles only.
+            let fx = ((x as f32 + 0.5) / tile_width - 0.5).clamp(0.0, (tiles_x - 1) as f32);
+            let fy = ((y as f32 + 0.5) / tile_height - 0.5).clamp(0.0, (tiles_y - 1) as f32);
+            let (x0, y0) = (fx as u32, fy as u32);
+            let (x1, y1) = ((x0 + 1).min(tiles_x - 1), (y0 + 1).min(tiles_y - 1));
+            let (ax, ay) = (fx - x0 as f32, fy - y0 as f32);
+            let lut = |tx: u32, ty: u32| luts[(ty * tiles_x + tx) as usize][luma as usize] as f32;
+            let top = lut(x0, y0) * (1.0 - ax) + lut(x1, y0) * ax;
+            let bottom = lut(x0, y1) * (1.0 - ax) + lut(x1, y1) * ax;
+            top * (1.0 - ay) + bottom * ay
+        })
+    }
+}
+
+// Caps every bin at `clip_limit` times the mean and spreads what was cut evenly over all bins.
+fn clip_histogram(histogram: &mut [u32; 256], clip_limit: f32) {
+    let total: u32 = histogram.iter().sum();
+    let limit = ((clip_limit.max(1.0) * total as f32 / 256.0) as u32).max(1);
+    let mut excess = 0;
+    for n in histogram.iter_mut() {
+        if *n > limit {
+            excess += *n - limit;
+            *n = limit;
+        }
+    }
+    let (share, remainder) = (excess / 256, excess % 256);
+    for (i, n) in histogram.iter_mut().enumerate() {
+        *n += share + u32::from((i as u32) < remainder);
+    }
+}
+
+// The plain cumulative histogram mapping. Unlike the global one it isn't stretched, so a tile of
+// one flat color doesn't get blown up to full contrast.
+fn tile_lut(histogram: &[u32; 256]) -> [u8; 256] {
+    let total = histogram.iter().sum::<u32>().max(1);
+    let mut lut = [0u8; 256];
+    let mut cumulative = 0u32;
+    for (l, n) in lut.iter_mut().zip(histogram) {
+        cumulative += n;
+        *l = (cumulative as f32 * 255.0 / total as f32).round() as u8;
+    }
+    lut
+}
+
+// Rebuilds the image with luma from `map(x, y, luma)` and the original chroma.
+fn remap_luma<F: Fn(u32, u32, u8) -> f32>(image: &Image<Rgb<u8>>, converted: &[YCrCb], map: F) -> (Image<Rgb<u8>>, ExposureCorrection) {
+    let width = image.width();
+    let (mut before, mut after) = (0u64, 0u64);
+    let mut output = Image::new(width, image.height());
+    for (i, (pixel, source)) in output.pixels_mut().zip(converted).enumerate() {
+        let (x, y) = (i as u32 % width, i as u32 / width);
+        let luma = map(x, y, source.y).round().clamp(0.0, 255.0) as u8;
+        before += source.y as u64;
+        after += luma as u64;
+        *pixel = YCrCb { y: luma, ..*source }.to_rgb();
+    }
+    let count = converted.len().max(1) as f32;
+    (output, ExposureCorrection { mean_before: before as f32 / count, mean_after: after as f32 / count })
+}
@@ -0,0 +1,365 @@
+use image::GrayImage;
+use nalgebra::{Matrix2, Matrix3, Point2, Vector2};
+use crate::geometry::find_affine_ransac;
+
+// A single channel float image, for sampling between pixels
+struct Plane {
+    width: u32,
+    height: u32,
+    data: Vec<f32>,
+}
+
+impl Plane {
+    fn from_gray(image: &GrayImage) -> Self {
+        Plane {
+            width: image.width(),
+            height: image.height(),
+            data: image.as_raw().iter().map(|v| *v as f32).collect(),
+        }
+    }
+
+    // Blur with the 1 4 6 4 1 kernel, then drop every other row and column
+    fn downsample(&self) -> Self {
+        const KERNEL: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+        let (width, height) = (self.width.div_ceil(2), self.height.div_ceil(2));
+        let at = |x: i64, y: i64| {
+            let x = x.clamp(0, self.width as i64 - 1) as u32;
+            let y = y.clamp(0, self.height as i64 - 1) as u32;
+            self.data[(y * self.width + x) as usize]
+        };
+        let mut data = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let (cx, cy) = (2 * x as i64, 2 * y as i64);
+                let mut sum = 0.0;
+                for (j, ky) in KERNEL.iter().enumerate() {
+                    for (i, kx) in KERNEL.iter().enumerate() {
+                        sum += kx * ky * at(cx + i as i64 - 2, cy + j as i64 - 2);
+                    }
+                }
+                data.push(sum);
+            }
+        }
+        Plane { width, height, data }
+    }
+
+    fn contains(&self, x: f32, y: f32) -> bool {
+        x >= 0.0 && y >= 0.0 && x <= (self.width - 1) as f32 && y <= (self.height - 1) as f32
+    }
+
+    fn sample(&self, x: f32, y: f32) -> f32 {
+        let x = x.clamp(0.0, (self.width - 1) as f32);
+        let y = y.clamp(0.0, (self.height - 1) as f32);
+        let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+        let at = |x: u32, y: u32| self.data[(y * self.width + x) as usize];
+        let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+        let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+fn pyramid(image: &GrayImage, levels: u32) -> Vec<Plane> {
+    let mut planes = vec![Plane::from_gray(image)];
+    for _ in 1..levels {
+        let last = planes.last().unwrap();
+        if last.width < 16 || last.height < 16 {
+            break;
+        }
+        planes.push(last.downsample());
+    }
+    planes
+}
+
+/// Corners worth tracking (Shi-Tomasi): points where the smaller eigenvalue of the local gradient
+/// structure tensor is at least `quality` times the strongest one, at least `min_distance` apart.
+/// Strongest first.
+pub fn good_features_to_track(image: &GrayImage, max_corners: usize, quality: f32, min_distance: f32) -> Vec<(f32, f32)> {
+    let (width, height) = image.dimensions();
+    if width < 8 || height < 8 {
+        return Vec::new();
+    }
+    let at = |x: u32, y: u32| image.get_pixel(x, y)[0] as f32;
+    let mut gradients = vec![(0.0_f32, 0.0_f32); (width * height) as usize];
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let gx = (at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1))
+                - (at(x - 1, y - 1) + 2.0 * at(x - 1, y) + at(x - 1, y + 1));
+            let gy = (at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1))
+                - (at(x - 1, y - 1) + 2.0 * at(x, y - 1) + at(x + 1, y - 1));
+            gradients[(y * width + x) as usize] = (gx, gy);
+        }
+    }
+
+    let mut response = vec![0.0_f32; (width * height) as usize];
+    let mut strongest = 0.0_f32;
+    for y in 2..height - 2 {
+        for x in 2..width - 2 {
+            let (mut a, mut b, mut c) = (0.0, 0.0, 0.0);
+            for ny in y - 1..=y + 1 {
+                for nx in x - 1..=x + 1 {
+                    let (gx, gy) = gradients[(ny * width + nx) as usize];
+                    a += gx * gx;
+                    b += gx * gy;
+                    c += gy * gy;
+                }
+            }
+            let min_eigenvalue = (a + c) / 2.0 - (((a - c) / 2.0).powi(2) + b * b).sqrt();
+            response[(y * width + x) as usize] = min_eigenvalue;
+            strongest = strongest.max(min_eigenvalue);
+        }
+    }
+    if strongest <= 0.0 {
+        return Vec::new();
+    }
+
+    let threshold = quality * strongest;
+    let mut candidates = Vec::new();
+    for y in 3..height - 3 {
+        for x in 3..width - 3 {
+            let r = response[(y * width + x) as usize];
+            let is_max = r >= threshold && (y - 1..=y + 1).all(|ny| (x - 1..=x + 1).all(|nx| {
+                let other = response[(ny * width + nx) as usize];
+                other < r || (other == r && (nx, ny) >= (x, y))
+            }));
+            if is_max {
+                candidates.push((x as f32, y as f32, r));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
+
+    let mut corners: Vec<(f32, f32)> = Vec::new();
+    for (x, y, _) in candidates {
+        if corners.len() >= max_corners {
+            break;
+        }
+        if corners.iter().all(|c| (c.0 - x).hypot(c.1 - y) >= min_distance) {
+            corners.push((x, y));
+        }
+    }
+    corners
+}
+
+/// Pyramidal Lucas-Kanade: follows points from one frame to the next by matching the window
+/// around each one, coarse to fine.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LucasKanade {
+    /// Half the side of the window matched around each point.
+    pub window_radius: u32,
+    pub levels: u32,
+    pub max_iterations: u32,
+    /// Iteration stops once the update is smaller than this, in pixels.
+    pub epsilon: f32,
+    /// Points whose window at full resolution has a smaller minimum gradient eigenvalue than this
+    /// (per pixel) are on flat or edge-like texture and can't be tracked.
+    pub min_eigenvalue: f32,
+}
+
+impl Default for LucasKanade {
+    fn default() -> Self {
+        LucasKanade {
+            window_radius: 7,
+            levels: 3,
+            max_iterations: 20,
+            epsilon: 0.01,
+            min_eigenvalue: 1e-2,
+        }
+    }
+}
+
+impl LucasKanade {
+    /// Where each point in `previous` went in `next`, or `None` where it was lost.
+    pub fn track(&self, previous: &GrayImage, next: &GrayImage, points: &[(f32, f32)]) -> Vec<Option<(f32, f32)>> {
+        let previous = pyramid(previous, self.levels);
+        let next = pyramid(next, self.levels);
+        points.iter().map(|p| self.track_point(&previous, &next, *p)).collect()
+    }
+
+    fn track_point(&self, previous: &[Plane], next: &[Plane], point: (f32, f32)) -> Option<(f32, f32)> {
+        let levels = previous.len().min(next.len());
+        let r = self.window_radius as i32;
+        let window_area = ((2 * r + 1) * (2 * r + 1)) as f32;
+        let mut guess = Vector2::new(0.0_f32, 0.0);
+
+        for level in (0..levels).rev() {
+            let (prev, next) = (&previous[level], &next[level]);
+            let scale = (1 << level) as f32;
+            let p = Vector2::new(point.0 / scale, point.1 / scale);
+
+            // Template and its gradients, which stay fixed while iterating
+            let mut template = Vec::with_capacity(window_area as usize);
+            let mut g = Matrix2::zeros();
+            for dy in -r..=r {
+                for dx in -r..=r {
+                    let (x, y) = (p.x + dx as f32, p.y + dy as f32);
+                    let ix = (prev.sample(x + 1.0, y) - prev.sample(x - 1.0, y)) / 2.0;
+                    let iy = (prev.sample(x, y + 1.0) - prev.sample(x, y - 1.0)) / 2.0;
+                    template.push((dx, dy, prev.sample(x, y), ix, iy));
+                    g += Matrix2::new(ix * ix, ix * iy, ix * iy, iy * iy);
+                }
+            }
+            // Like OpenCV, only losing the point at the finest level drops it. A coarser level that
+            // is too flat, or that runs off the image, is skipped with the guess carried down.
+            let min_eigenvalue = (g[(0, 0)] + g[(1, 1)]) / 2.0 - (((g[(0, 0)] - g[(1, 1)]) / 2.0).powi(2) + g[(0, 1)].powi(2)).sqrt();
+            let g_inverse = match g.try_inverse() {
+                Some(g_inverse) if min_eigenvalue / window_area >= self.min_eigenvalue => g_inverse,
+                _ if level == 0 => return None,
+                _ => {
+                    guess *= 2.0;
+                    continue;
+                }
+            };
+
+            let mut v = Vector2::zeros();
+            for _ in 0..self.max_iterations {
+                let target = p + guess + v;
+                if !next.contains(target.x, target.y) {
+                    if level == 0 {
+                        return None;
+                    }
+                    break;
+                }
+                let mut b = Vector2::zeros();
+                for &(dx, dy, value, ix, iy) in template.iter() {
+                    let error = value - next.sample(target.x + dx as f32, target.y + dy as f32);
+                    b += Vector2::new(error * ix, error * iy);
+                }
+                let step = g_inverse * b;
+                v += step;
+                if step.norm() < self.epsilon {
+                    break;
+                }
+            }
+            guess = if level > 0 { (guess + v) * 2.0 } else { guess + v };
+        }
+        let end = (point.0 + guess.x, point.1 + guess.y);
+        if !previous[0].contains(end.0, end.1) {
+            return None;
+        }
+        Some(end)
+    }
+}
+
+/// A point followed from one frame to the next.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FlowVector {
+    pub from: (f32, f32),
+    pub to: (f32, f32),
+    /// Whether the point agrees with the global motion.
+    pub inlier: bool,
+}
+
+/// The motion of the whole image between two frames.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GlobalMotion {
+    /// Affine transform taking previous frame pixels to the current frame.
+    pub affine: Matrix3<f64>,
+    pub translation: (f64, f64),
+    /// Rotation in radians, from the x axis towards y.
+    pub rotation: f64,
+    pub scale: f64,
+}
+
+impl GlobalMotion {
+    fn from_affine(affine: Matrix3<f64>) -> Self {
+        let linear = affine.fixed_slice::<2, 2>(0, 0);
+        GlobalMotion {
+            affine,
+            translation: (affine[(0, 2)], affine[(1, 2)]),
+            rotation: (linear[(1, 0)] - linear[(0, 1)]).atan2(linear[(0, 0)] + linear[(1, 1)]),
+            scale: linear.determinant().abs().sqrt(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlowResult {
+    pub vectors: Vec<FlowVector>,
+    /// `None` when too few points were tracked to tell.
+    pub motion: Option<GlobalMotion>,
+}
+
+/// Tracks corners across a stream of frames, finding new ones whenever too many are lost.
+pub struct FlowTracker {
+    pub lucas_kanade: LucasKanade,
+    pub max_corners: usize,
+    pub quality: f32,
+    pub min_distance: f32,
+    /// Corners are detected again when fewer than this many are still tracked.
+    pub min_tracked: usize,
+    /// Points that don't land back within this many pixels when tracked backwards are dropped.
+    pub max_round_trip_error: f32,
+    /// Reprojection error in pixels below which a point agrees with the global motion.
+    pub ransac_threshold: f64,
+    previous: Option<GrayImage>,
+    points: Vec<(f32, f32)>,
+}
+
+impl FlowTracker {
+    pub fn new() -> Self {
+        FlowTracker {
+            lucas_kanade: LucasKanade::default(),
+            max_corners: 200,
+            quality: 0.01,
+            min_distance: 10.0,
+            min_tracked: 50,
+            max_round_trip_error: 1.0,
+            ransac_threshold: 2.0,
+            previous: None,
+            points: Vec::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.previous = None;
+        self.points.clear();
+    }
+
+    /// Tracks the current points into `frame`. The first frame only finds corners.
+    pub fn update(&mut self, frame: &GrayImage) -> FlowResult {
+        let previous = match self.previous.take() {
+            Some(previous) if previous.dimensions() == frame.dimensions() => previous,
+            _ => {
+                self.points = good_features_to_track(frame, self.max_corners, self.quality, self.min_distance);
+                self.previous = Some(frame.clone());
+                return FlowResult { vectors: Vec::new(), motion: None };
+            }
+        };
+
+        let forward = self.lucas_kanade.track(&previous, frame, &self.points);
+        let tracked: Vec<((f32, f32), (f32, f32))> = self.points.iter().zip(forward)
+            .filter_map(|(from, to)| to.map(|to| (*from, to)))
+            .collect();
+        let ends: Vec<(f32, f32)> = tracked.iter().map(|t| t.1).collect();
+        let backward = self.lucas_kanade.track(frame, &previous, &ends);
+        let tracked: Vec<((f32, f32), (f32, f32))> = tracked.into_iter().zip(backward)
+            .filter(|((from, _), back)| matches!(back, Some(b) if (b.0 - from.0).hypot(b.1 - from.1) <= self.max_round_trip_error))
+            .map(|(t, _)| t)
+            .collect();
+
+        let src: Vec<Point2<f64>> = tracked.iter().map(|t| Point2::new(t.0.0 as f64, t.0.1 as f64)).collect();
+        let dst: Vec<Point2<f64>> = tracked.iter().map(|t| Point2::new(t.1.0 as f64, t.1.1 as f64)).collect();
+        let fit = find_affine_ransac(&src, &dst, self.ransac_threshold, 200);
+        let vectors = tracked.iter().enumerate().map(|(i, (from, to))| FlowVector {
+            from: *from,
+            to: *to,
+            inlier: fit.as_ref().is_some_and(|(_, inliers)| inliers[i]),
+        }).collect();
+        let motion = fit.map(|(affine, _)| GlobalMotion::from_affine(affine));
+
+        self.points = tracked.into_iter().map(|t| t.1).collect();
+        if self.points.len() < self.min_tracked {
+            self.points = good_features_to_track(frame, self.max_corners, self.quality, self.min_distance);
+        }
+        self.previous = Some(frame.clone());
+        FlowResult { vectors, motion }
+    }
+}
+
+impl Default for FlowTracker {
+    fn default() -> Self {
+        FlowTracker::new()
+    }
+}
@@ -1,11 +1,24 @@
+use std::time::Duration;
+#[cfg(any(feature = "output-udp", feature = "output-unix-stream"))]
+use std::time::Instant;
 use imageproc::definitions::Image;
 use image::{ColorType, EncodableLayout, Rgb};
 use log::error;
-use tokio::io::AsyncWriteExt;
 use tokio::net::ToSocketAddrs;
+use crate::telemetry::TelemetrySnapshot;
 
 pub trait Output {
     fn output(&mut self, image: crate::Result<Option<Image<Rgb<u8>>>>, color_type: ColorType) -> crate::Result<()>;
+
+    /// How long encoding the last image took, for outputs that encode.
+    fn encode_duration(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Passes on the camera's telemetry after each frame. Ignored unless the output sends it.
+    fn telemetry(&mut self, _: &TelemetrySnapshot) -> crate::Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Copy, Clone, Default, Debug, Eq, PartialEq)]
@@ -20,6 +33,10 @@ impl Output for NoOutput {
 #[cfg(feature = "output-udp")]
 pub struct UdpOutput {
     socket: tokio::net::UdpSocket,
+    /// Whether to send telemetry after each frame, as `TLM`, the JSON's length as a big-endian
+    /// `u32` and the JSON. Dropped when the socket isn't ready.
+    pub send_telemetry: bool,
+    encode_duration: Option<Duration>,
 }
 
 #[cfg(feature = "output-udp")]
@@ -27,11 +44,11 @@ impl UdpOutput {
     pub async fn new<A: ToSocketAddrs>(address: A, target: A) -> crate::Result<Self> {
         let socket = tokio::net::UdpSocket::bind(address).await?;
         socket.connect(target).await?;
-        Ok(Self { socket })
+        Ok(Self::from_socket(socket))
     }
 
     pub fn from_socket(socket: tokio::net::UdpSocket) -> Self {
-        Self { socket }
+        Self { socket, send_telemetry: false, encode_duration: None }
     }
 }
 
@@ -39,11 +56,14 @@ impl UdpOutput {
 impl Output for UdpOutput {
     // TODO: don't use write_all
     fn output(&mut self, image: crate::Result<Option<Image<Rgb<u8>>>>, color_type: ColorType) -> crate::Result<()> {
+        self.encode_duration = None;
         match image {
             Ok(Some(image)) => {
+                let start = Instant::now();
                 let mut buf: Vec<u8> = Vec::new();
                 let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buf, 75); // TODO: add quality setting and encoder type
                 let res = encoder.encode(&image, image.width(), image.height(), color_type);
+                self.encode_duration = Some(start.elapsed());
                 if res.is_ok() {
                     let prefix = [0_u8, 0, 0, 0];
                     self.socket.send(&([&prefix, buf.as_slice()].concat())).await?;
@@ -61,49 +81,109 @@ impl Output for UdpOutput {
         }
         Ok(())
     }
+
+    fn encode_duration(&self) -> Option<Duration> {
+        self.encode_duration
+    }
+
+    fn telemetry(&mut self, telemetry: &TelemetrySnapshot) -> crate::Result<()> {
+        if self.send_telemetry {
+            if let Err(e) = self.socket.try_send(&telemetry_message(telemetry)?) {
+                if e.kind() != std::io::ErrorKind::WouldBlock {
+                    return Err(e.into());
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(feature = "output-unix-stream")]
 pub struct StreamOutput {
     socket: tokio::net::UnixStream,
+    /// Whether to send telemetry after each frame, as `TLM`, the JSON's length as a big-endian
+    /// `u32` and the JSON. Dropped when the socket isn't ready.
+    pub send_telemetry: bool,
+    encode_duration: Option<Duration>,
+    // The rest of a message the socket only took part of
+    pending: Vec<u8>,
 }
 
 #[cfg(feature = "output-unix-stream")]
 impl StreamOutput {
     pub async fn new(address: &str) -> crate::Result<Self> {
         let socket = tokio::net::UnixStream::connect(address).await?;
-        Ok(Self { socket })
+        Self::from_socket(socket)
     }
 
     pub fn from_socket(socket: tokio::net::UnixStream) -> crate::Result<Self> {
-        Ok(Self { socket })
+        Ok(Self { socket, send_telemetry: false, encode_duration: None, pending: Vec::new() })
+    }
+
+    // Writes whole messages without blocking. A message is dropped if the socket can't take any of
+    // it, and the rest of one it took part of goes out before anything else.
+    fn send(&mut self, message: &[u8]) -> crate::Result<()> {
+        while !self.pending.is_empty() {
+            match self.socket.try_write(&self.pending) {
+                Ok(written) => { self.pending.drain(..written); }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        match self.socket.try_write(message) {
+            Ok(written) => self.pending.extend_from_slice(&message[written..]),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(())
     }
 }
 
 #[cfg(feature = "output-unix-stream")]
 impl Output for StreamOutput {
     fn output(&mut self, image: crate::Result<Option<Image<Rgb<u8>>>>, color_type: ColorType) -> crate::Result<()> {
+        self.encode_duration = None;
         match image {
             Ok(Some(image)) => {
+                let start = Instant::now();
                 let mut buf: Vec<u8> = Vec::new();
                 let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut buf, 75); // TODO: add quality setting and encoder type
                 let res = encoder.encode(&image, image.width(), image.height(), color_type);
+                self.encode_duration = Some(start.elapsed());
                 if res.is_ok() {
                     let prefix = [0_u8, 0, 0, 0];
-                    let res = self.socket.write_all(&([&prefix, buf.as_slice()].concat()));
+                    self.send(&[&prefix, buf.as_slice()].concat())?;
                 }
             }
             Ok(None) => {
-                let res = self.socket.write_all(&[110, 117, 108, 108]);
+                self.send(&[110, 117, 108, 108])?;
             }
             Err(e) => {
                 let message = format!("{}", e);
                 let prefix = [83_u8, 79, 83];
                 let bytes = message.as_bytes();
-                let res = self.socket.write_all(&[prefix.as_bytes(), bytes].concat());
+                self.send(&[prefix.as_bytes(), bytes].concat())?;
             }
         }
         Ok(())
     }
+
+    fn encode_duration(&self) -> Option<Duration> {
+        self.encode_duration
+    }
+
+    fn telemetry(&mut self, telemetry: &TelemetrySnapshot) -> crate::Result<()> {
+        if self.send_telemetry {
+            self.send(&telemetry_message(telemetry)?)?;
+        }
+        Ok(())
+    }
 }
 
+
+// `TLM`, the length of the JSON and the JSON
+#[cfg(any(feature = "output-udp", feature = "output-unix-stream"))]
+fn telemetry_message(telemetry: &TelemetrySnapshot) -> crate::Result<Vec<u8>> {
+    let json = serde_json::to_vec(telemetry)?;
+    Ok([b"TLM".as_slice(), &(json.len() as u32).to_be_bytes(), &json].concat())
+}
@@ -0,0 +1,209 @@
+use std::sync::OnceLock;
+use image::Rgb;
+use imageproc::definitions::Image;
+use imageproc::drawing::{draw_filled_rect_mut, draw_line_segment_mut, draw_text_mut, text_size};
+use imageproc::rect::Rect;
+use nalgebra::{Isometry3, Point3};
+use rusttype::{Font, Scale};
+use crate::detection::{BoundingBox, Detection};
+use crate::geometry::CameraIntrinsics;
+
+fn font() -> &'static Font<'static> {
+    static FONT: OnceLock<Font<'static>> = OnceLock::new();
+    FONT.get_or_init(|| Font::try_from_bytes(include_bytes!("../assets/DejaVuSansMono.ttf")).expect("Embedded font is valid"))
+}
+
+/// How overlays look.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OverlayStyle {
+    /// Color of outlines and markers.
+    pub color: Rgb<u8>,
+    /// Color of text, drawn on a box of `text_background`.
+    pub text_color: Rgb<u8>,
+    pub text_background: Rgb<u8>,
+    /// Text height in pixels.
+    pub text_size: f32,
+    /// Width of lines in pixels.
+    pub thickness: u32,
+}
+
+impl Default for OverlayStyle {
+    fn default() -> Self {
+        OverlayStyle {
+            color: Rgb([0, 255, 0]),
+            text_color: Rgb([255, 255, 255]),
+            text_background: Rgb([0, 0, 0]),
+            text_size: 14.0,
+            thickness: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Shape {
+    Line { from: (f32, f32), to: (f32, f32), color: Option<Rgb<u8>> },
+    Text { position: (f32, f32), text: String },
+    Crosshair { center: (f32, f32), size: f32, color: Option<Rgb<u8>> },
+}
+
+/// Annotations collected while processing a frame and drawn over the output in one style.
+///
+/// A disabled overlay ignores everything added to it and draws nothing, so pipelines can build
+/// one unconditionally and it costs nothing on match day.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Overlay {
+    pub enabled: bool,
+    pub style: OverlayStyle,
+    shapes: Vec<Shape>,
+}
+
+impl Default for Overlay {
+    fn default() -> Self {
+        Overlay::new(OverlayStyle::default())
+    }
+}
+
+impl Overlay {
+    pub fn new(style: OverlayStyle) -> Self {
+        Overlay { enabled: true, style, shapes: Vec::new() }
+    }
+
+    pub fn disabled() -> Self {
+        Overlay { enabled: false, ..Overlay::default() }
+    }
+
+    pub fn clear(&mut self) {
+        self.shapes.clear();
+    }
+
+    pub fn line(&mut self, from: (f32, f32), to: (f32, f32)) -> &mut Self {
+        self.push(Shape::Line { from, to, color: None })
+    }
+
+    /// A closed outline through `points`.
+    pub fn polygon(&mut self, points: &[(f32, f32)]) -> &mut Self {
+        for (i, point) in points.iter().enumerate() {
+            self.line(*point, points[(i + 1) % points.len()]);
+        }
+        self
+    }
+
+    /// A box, labelled above its top left corner if `label` is given.
+    pub fn bbox(&mut self, bbox: &BoundingBox, label: Option<&str>) -> &mut Self {
+        let (left, top, right, bottom) = (bbox.x, bbox.y, bbox.x + bbox.width, bbox.y + bbox.height);
+        self.polygon(&[(left, top), (right, top), (right, bottom), (left, bottom)]);
+        if let Some(label) = label {
+            self.text((left, top - self.style.text_size - 2.0), label);
+        }
+        self
+    }
+
+    /// Boxes labelled with their class and score.
+    pub fn detections(&mut self, detections: &[Detection]) -> &mut Self {
+        for detection in detections.iter() {
+            self.bbox(&detection.bbox, Some(&format!("{} {:.2}", detection.class_id, detection.score)));
+        }
+        self
+    }
+
+    /// Text with its top left corner at `position`.
+    pub fn text(&mut self, position: (f32, f32), text: &str) -> &mut Self {
+        self.push(Shape::Text { position, text: text.to_string() })
+    }
+
+    /// Text centered on `center`, for naming zones and regions.
+    pub fn label(&mut self, center: (f32, f32), text: &str) -> &mut Self {
+        if !self.enabled {
+            return self;
+        }
+        let (width, height) = text_size(Scale::uniform(self.style.text_size), font(), text);
+        self.text((center.0 - width as f32 / 2.0, center.1 - height as f32 / 2.0), text)
+    }
+
+    /// A cross `size` pixels from the center each way, in `color` or else the style's color.
+    pub fn crosshair(&mut self, center: (f32, f32), size: f32, color: Option<Rgb<u8>>) -> &mut Self {
+        self.push(Shape::Crosshair { center, size, color })
+    }
+
+    /// The frame rate in the top left corner.
+    pub fn fps(&mut self, fps: f64) -> &mut Self {
+        self.text((2.0, 2.0), &format!("{:.1} fps", fps))
+    }
+
+    /// The x (red), y (green) and z (blue) axes of a target `length` long, given its pose in the
+    /// camera frame. Axes behind the camera are left out.
+    pub fn axes(&mut self, pose: &Isometry3<f64>, intrinsics: &CameraIntrinsics, length: f64) -> &mut Self {
+        let origin = pose * Point3::origin();
+        let axes = [
+            (Point3::new(length, 0.0, 0.0), Rgb([255, 0, 0])),
+            (Point3::new(0.0, length, 0.0), Rgb([0, 255, 0])),
+            (Point3::new(0.0, 0.0, length), Rgb([0, 0, 255])),
+        ];
+        if origin.z <= 0.0 {
+            return self;
+        }
+        let project = |point: &Point3<f64>| {
+            let p = intrinsics.project(point);
+            (p.x as f32, p.y as f32)
+        };
+        for (end, color) in axes {
+            let end = pose * end;
+            if end.z > 0.0 {
+                self.push(Shape::Line { from: project(&origin), to: project(&end), color: Some(color) });
+            }
+        }
+        self
+    }
+
+    /// Draws everything added since the last [`Overlay::clear`].
+    pub fn draw(&self, image: &mut Image<Rgb<u8>>) {
+        if !self.enabled {
+            return;
+        }
+        for shape in self.shapes.iter() {
+            match shape {
+                Shape::Line { from, to, color } => self.draw_line(image, *from, *to, color.unwrap_or(self.style.color)),
+                Shape::Text { position, text } => self.draw_text(image, *position, text),
+                Shape::Crosshair { center: (x, y), size, color } => {
+                    let color = color.unwrap_or(self.style.color);
+                    self.draw_line(image, (x - size, *y), (x + size, *y), color);
+                    self.draw_line(image, (*x, y - size), (*x, y + size), color);
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, shape: Shape) -> &mut Self {
+        if self.enabled {
+            self.shapes.push(shape);
+        }
+        self
+    }
+
+    // Thick lines are drawn as parallel one pixel lines, half a pixel apart so diagonals don't
+    // leave gaps.
+    fn draw_line(&self, image: &mut Image<Rgb<u8>>, from: (f32, f32), to: (f32, f32), color: Rgb<u8>) {
+        let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+        let length = (dx * dx + dy * dy).sqrt().max(f32::EPSILON);
+        let normal = (-dy / length, dx / length);
+        let thickness = self.style.thickness.max(1);
+        for i in 0..2 * thickness - 1 {
+            let offset = (i as f32 - (thickness - 1) as f32) / 2.0;
+            let shift = (normal.0 * offset, normal.1 * offset);
+            draw_line_segment_mut(image, (from.0 + shift.0, from.1 + shift.1), (to.0 + shift.0, to.1 + shift.1), color);
+        }
+    }
+
+    fn draw_text(&self, image: &mut Image<Rgb<u8>>, position: (f32, f32), text: &str) {
+        let scale = Scale::uniform(self.style.text_size);
+        let (width, height) = text_size(scale, font(), text);
+        // Keep text that would start off the image on it.
+        let x = (position.0 as i32).clamp(0, (image.width() as i32 - width).max(0));
+        let y = (position.1 as i32).clamp(0, (image.height() as i32 - height).max(0));
+        if width > 0 && height > 0 {
+            let background = Rect::at(x - 1, y - 1).of_size(width as u32 + 2, height as u32 + 2);
+            draw_filled_rect_mut(image, background, self.style.text_background);
+        }
+        draw_text_mut(image, self.style.text_color, x, y, scale, font(), text);
+    }
+}
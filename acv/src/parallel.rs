@@ -0,0 +1,65 @@
+use rayon::prelude::*;
+
+// Pixels per rayon task: enough to outweigh handing the task to another core, few enough that a
+// frame splits over all of them.
+const BAND_PIXELS: usize = 16 * 1024;
+
+/// Rows in each band of an image `width` pixels wide.
+pub(crate) fn band_rows(width: usize) -> usize {
+    (BAND_PIXELS / width.max(1)).max(1)
+}
+
+/// Runs `f(first_row, src_rows, dst_rows)` on bands of rows in parallel, where rows of `src` are
+/// `src_row` values long and rows of `dst` `dst_row`, for images `width` pixels wide.
+pub(crate) fn par_bands<S, D, F>(src: &[S], src_row: usize, dst: &mut [D], dst_row: usize, width: usize, f: F)
+where
+    S: Sync,
+    D: Send,
+    F: Fn(usize, &[S], &mut [D]) + Sync + Send,
+{
+    if src_row == 0 || dst_row == 0 {
+        return;
+    }
+    let rows = band_rows(width);
+    dst.par_chunks_mut(dst_row * rows)
+        .zip(src.par_chunks(src_row * rows))
+        .enumerate()
+        .for_each(|(band, (dst, src))| f(band * rows, src, dst));
+}
+
+/// Runs `f(first_row, dst_rows)` on bands of rows of `dst` in parallel, for kernels that read
+/// their input from around the band.
+pub(crate) fn par_bands_mut<D, F>(dst: &mut [D], dst_row: usize, width: usize, f: F)
+where
+    D: Send,
+    F: Fn(usize, &mut [D]) + Sync + Send,
+{
+    if dst_row == 0 {
+        return;
+    }
+    let rows = band_rows(width);
+    dst.par_chunks_mut(dst_row * rows)
+        .enumerate()
+        .for_each(|(band, dst)| f(band * rows, dst));
+}
+
+/// Like [`par_bands_mut`], with each band also given its own `per_band` values of `scratch`,
+/// which is grown to fit.
+pub(crate) fn par_bands_with_scratch<D, T, F>(dst: &mut [D], dst_row: usize, width: usize, scratch: &mut Vec<T>, per_band: usize, f: F)
+where
+    D: Send,
+    T: Send + Default + Clone,
+    F: Fn(usize, &mut [D], &mut [T]) + Sync + Send,
+{
+    if dst_row == 0 || per_band == 0 {
+        return;
+    }
+    let rows = band_rows(width);
+    let bands = dst.len().div_ceil(dst_row * rows);
+    scratch.clear();
+    scratch.resize(bands * per_band, T::default());
+    dst.par_chunks_mut(dst_row * rows)
+        .zip(scratch.par_chunks_mut(per_band))
+        .enumerate()
+        .for_each(|(band, (dst, scratch))| f(band * rows, dst, scratch));
+}
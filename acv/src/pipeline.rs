@@ -1,8 +1,28 @@
 use imageproc::definitions::Image;
 use image::Rgb;
 
+pub mod aruco;
+pub mod barcode;
+pub mod feature_match;
+pub mod field_lines;
+pub mod graph;
+pub mod line_segments;
+pub mod motion;
+#[cfg(feature = "onnx")]
+pub mod object_detection;
+pub mod optical_flow;
+pub mod oriented_sample;
+pub mod preprocessed;
+pub mod template_match;
+pub mod zone_classifier;
+
 pub trait Pipeline {
     fn pipeline(&mut self, input: Image<Rgb<u8>>) -> crate::Result<Option<Image<Rgb<u8>>>>;
 
     fn output_color_type(&self) -> image::ColorType;
+
+    /// How long each stage took on the last frame, for pipelines made of stages.
+    fn stage_timings(&self) -> &[graph::StageTiming] {
+        &[]
+    }
 }
@@ -0,0 +1,53 @@
+use image::{ColorType, Rgb};
+use image::imageops::grayscale;
+use imageproc::definitions::Image;
+use crate::aruco::{ArucoDetector, ArucoMarker};
+use crate::overlay::{Overlay, OverlayStyle};
+use crate::pipeline::Pipeline;
+
+/// Detects ArUco markers in every frame and outlines them.
+pub struct ArucoPipeline {
+    pub detector: ArucoDetector,
+    pub draw: bool,
+    pub style: OverlayStyle,
+    markers: Vec<ArucoMarker>,
+}
+
+impl ArucoPipeline {
+    pub fn new(detector: ArucoDetector) -> Self {
+        ArucoPipeline {
+            detector,
+            draw: true,
+            style: OverlayStyle::default(),
+            markers: Vec::new(),
+        }
+    }
+
+    /// Markers found in the last frame.
+    pub fn markers(&self) -> &[ArucoMarker] {
+        &self.markers
+    }
+}
+
+impl Pipeline for ArucoPipeline {
+    fn pipeline(&mut self, input: Image<Rgb<u8>>) -> crate::Result<Option<Image<Rgb<u8>>>> {
+        self.markers = self.detector.detect(&grayscale(&input));
+        if !self.draw {
+            return Ok(None);
+        }
+        let mut overlay = Overlay::new(self.style);
+        for marker in self.markers.iter() {
+            let corners = marker.corners.map(|c| (c.x as f32, c.y as f32));
+            let center = marker.center();
+            // The red crosshair on the top left corner shows the orientation.
+            overlay.polygon(&corners).crosshair(corners[0], 3.0, Some(Rgb([255, 0, 0]))).label((center.x as f32, center.y as f32), &marker.id.to_string());
+        }
+        let mut output = input;
+        overlay.draw(&mut output);
+        Ok(Some(output))
+    }
+
+    fn output_color_type(&self) -> ColorType {
+        ColorType::Rgb8
+    }
+}
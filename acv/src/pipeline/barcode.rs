@@ -0,0 +1,57 @@
+use image::{ColorType, Rgb};
+use image::imageops::grayscale;
+use imageproc::definitions::Image;
+use crate::barcode::{BarcodeReader, DecodedCode};
+use crate::overlay::{Overlay, OverlayStyle};
+use crate::pipeline::Pipeline;
+
+/// Reads QR codes and barcodes in every frame and outlines them.
+pub struct BarcodePipeline {
+    pub reader: BarcodeReader,
+    pub draw: bool,
+    pub style: OverlayStyle,
+    codes: Vec<DecodedCode>,
+}
+
+impl BarcodePipeline {
+    pub fn new(reader: BarcodeReader) -> Self {
+        BarcodePipeline {
+            reader,
+            draw: true,
+            style: OverlayStyle::default(),
+            codes: Vec::new(),
+        }
+    }
+
+    /// Codes read in the last frame.
+    pub fn codes(&self) -> &[DecodedCode] {
+        &self.codes
+    }
+}
+
+impl Default for BarcodePipeline {
+    fn default() -> Self {
+        BarcodePipeline::new(BarcodeReader::default())
+    }
+}
+
+impl Pipeline for BarcodePipeline {
+    fn pipeline(&mut self, input: Image<Rgb<u8>>) -> crate::Result<Option<Image<Rgb<u8>>>> {
+        self.codes = self.reader.read(&grayscale(&input));
+        if !self.draw {
+            return Ok(None);
+        }
+        let mut overlay = Overlay::new(self.style);
+        for code in self.codes.iter() {
+            // The red crosshair on the top left corner shows the orientation.
+            overlay.polygon(&code.corners).crosshair(code.corners[0], 3.0, Some(Rgb([255, 0, 0]))).label(code.center(), &code.text);
+        }
+        let mut output = input;
+        overlay.draw(&mut output);
+        Ok(Some(output))
+    }
+
+    fn output_color_type(&self) -> ColorType {
+        ColorType::Rgb8
+    }
+}
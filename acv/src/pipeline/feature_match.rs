@@ -0,0 +1,127 @@
+use image::{ColorType, GrayImage, Rgb};
+use image::imageops::grayscale;
+use imageproc::definitions::Image;
+use nalgebra::{Matrix3, Point2};
+use crate::features::{match_descriptors, Descriptor, Keypoint, Orb};
+use crate::geometry::{apply_homography, find_homography_ransac};
+use crate::overlay::{Overlay, OverlayStyle};
+use crate::pipeline::Pipeline;
+
+/// Where the reference image was found in a frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeatureMatchResult {
+    /// Maps reference image pixels to frame pixels.
+    pub homography: Matrix3<f64>,
+    /// The reference image's corners in the frame: top left, top right, bottom right, bottom left.
+    pub outline: [(f32, f32); 4],
+    /// Matches that passed the ratio test.
+    pub matches: usize,
+    /// Matches consistent with the homography.
+    pub inliers: usize,
+}
+
+/// Finds a textured object, like a printed target or a sign, by matching ORB features against a
+/// reference image of it.
+pub struct FeatureMatchPipeline {
+    pub orb: Orb,
+    /// Lowe's ratio test threshold.
+    pub ratio: f32,
+    /// Reprojection error in pixels below which a match agrees with the homography.
+    pub ransac_threshold: f64,
+    pub ransac_iterations: usize,
+    /// Fewer inliers than this and the object is considered not found.
+    pub min_inliers: usize,
+    pub draw: bool,
+    pub style: OverlayStyle,
+    reference_size: (u32, u32),
+    reference_keypoints: Vec<Keypoint>,
+    reference_descriptors: Vec<Descriptor>,
+    result: Option<FeatureMatchResult>,
+}
+
+impl FeatureMatchPipeline {
+    pub fn new(reference: &GrayImage) -> Self {
+        let mut pipeline = FeatureMatchPipeline {
+            orb: Orb::default(),
+            ratio: 0.8,
+            ransac_threshold: 4.0,
+            ransac_iterations: 500,
+            min_inliers: 12,
+            draw: true,
+            style: OverlayStyle::default(),
+            reference_size: (0, 0),
+            reference_keypoints: Vec::new(),
+            reference_descriptors: Vec::new(),
+            result: None,
+        };
+        pipeline.set_reference(reference);
+        pipeline
+    }
+
+    /// Replaces the reference image, extracting its features with the current `orb` settings.
+    pub fn set_reference(&mut self, reference: &GrayImage) {
+        let (keypoints, descriptors) = self.orb.detect_and_compute(reference);
+        self.reference_size = reference.dimensions();
+        self.reference_keypoints = keypoints;
+        self.reference_descriptors = descriptors;
+    }
+
+    pub fn reference_keypoints(&self) -> &[Keypoint] {
+        &self.reference_keypoints
+    }
+
+    /// Where the reference was found in the last frame, if it was.
+    pub fn result(&self) -> Option<&FeatureMatchResult> {
+        self.result.as_ref()
+    }
+
+    pub fn locate(&self, frame: &GrayImage) -> Option<FeatureMatchResult> {
+        let (keypoints, descriptors) = self.orb.detect_and_compute(frame);
+        let matches = match_descriptors(&self.reference_descriptors, &descriptors, self.ratio);
+        if matches.len() < self.min_inliers.max(4) {
+            return None;
+        }
+        let (src, dst): (Vec<_>, Vec<_>) = matches.iter().map(|m| {
+            let r = &self.reference_keypoints[m.query];
+            let f = &keypoints[m.train];
+            (Point2::new(r.x as f64, r.y as f64), Point2::new(f.x as f64, f.y as f64))
+        }).unzip();
+        let (homography, inliers) = find_homography_ransac(&src, &dst, self.ransac_threshold, self.ransac_iterations)?;
+        let inliers = inliers.iter().filter(|i| **i).count();
+        if inliers < self.min_inliers {
+            return None;
+        }
+        let (width, height) = (self.reference_size.0 as f64, self.reference_size.1 as f64);
+        let outline = [(0.0, 0.0), (width, 0.0), (width, height), (0.0, height)].map(|(x, y)| {
+            let p = apply_homography(&homography, &Point2::new(x, y));
+            (p.x as f32, p.y as f32)
+        });
+        Some(FeatureMatchResult {
+            homography,
+            outline,
+            matches: matches.len(),
+            inliers,
+        })
+    }
+}
+
+impl Pipeline for FeatureMatchPipeline {
+    fn pipeline(&mut self, input: Image<Rgb<u8>>) -> crate::Result<Option<Image<Rgb<u8>>>> {
+        self.result = self.locate(&grayscale(&input));
+        if !self.draw {
+            return Ok(None);
+        }
+        let mut overlay = Overlay::new(self.style);
+        if let Some(result) = &self.result {
+            let center = result.outline.iter().fold((0.0, 0.0), |(x, y), p| (x + p.0 / 4.0, y + p.1 / 4.0));
+            overlay.polygon(&result.outline).crosshair(center, 3.0, Some(Rgb([255, 0, 255])));
+        }
+        let mut output = input;
+        overlay.draw(&mut output);
+        Ok(Some(output))
+    }
+
+    fn output_color_type(&self) -> ColorType {
+        ColorType::Rgb8
+    }
+}
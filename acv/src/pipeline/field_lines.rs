@@ -0,0 +1,208 @@
+use image::{ColorType, GrayImage, Rgb};
+use imageproc::definitions::Image;
+use imageproc::distance_transform::Norm;
+use imageproc::edges::canny;
+use nalgebra::{Point2, Vector2};
+use crate::ground_plane::GroundPlane;
+use crate::lines::{hough_segments, merge_collinear, normalize_line_angle, HoughOptions, LineSegment};
+use crate::overlay::{Overlay, OverlayStyle};
+use crate::pipeline::Pipeline;
+use crate::util::{in_range_hsv, Hsv};
+
+/// A strip of tape on the field.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TapeLine {
+    /// Centerline of the tape in the image.
+    pub center: LineSegment,
+    /// Distance between the tape's edges in the image, in pixels.
+    pub pixel_width: f32,
+    /// Where the tape is relative to the robot, if a ground plane is set.
+    pub floor: Option<FloorLine>,
+}
+
+/// A tape line in robot coordinates (x forward, y left).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FloorLine {
+    /// Angle of the line from the robot's x axis, counterclockwise, in -pi/2..pi/2.
+    pub angle: f64,
+    /// Perpendicular distance from the robot's origin to the line.
+    pub distance: f64,
+    /// Point on the line closest to the robot's origin.
+    pub closest: (f64, f64),
+    pub width: f64,
+}
+
+/// Finds tape lines of one color by pairing up the parallel edges on either side of the tape.
+pub struct FieldLinePipeline {
+    pub lower: Hsv,
+    pub higher: Hsv,
+    /// Width of the tape in floor units. Only checked when a ground plane is set.
+    pub tape_width: f64,
+    /// Allowed relative error in the measured floor width.
+    pub width_tolerance: f64,
+    /// Widest tape considered, in pixels.
+    pub max_pixel_width: f32,
+    /// Edges further from parallel than this, in radians, aren't paired. Perspective makes the
+    /// edges of a tape converge, so this can't be too tight.
+    pub max_angle_difference: f32,
+    pub hough: HoughOptions,
+    pub ground_plane: Option<GroundPlane>,
+    pub draw: bool,
+    pub style: OverlayStyle,
+    lines: Vec<TapeLine>,
+}
+
+impl FieldLinePipeline {
+    pub fn new(lower: Hsv, higher: Hsv, tape_width: f64) -> Self {
+        FieldLinePipeline {
+            lower,
+            higher,
+            tape_width,
+            width_tolerance: 0.5,
+            max_pixel_width: 100.0,
+            max_angle_difference: 10f32.to_radians(),
+            hough: HoughOptions::default(),
+            ground_plane: None,
+            draw: true,
+            style: OverlayStyle::default(),
+            lines: Vec::new(),
+        }
+    }
+
+    /// Tape lines found in the last frame, longest first.
+    pub fn lines(&self) -> &[TapeLine] {
+        &self.lines
+    }
+
+    pub fn detect(&self, input: &Image<Rgb<u8>>) -> Vec<TapeLine> {
+        let mut mask = GrayImage::new(input.width(), input.height());
+        in_range_hsv(input, self.lower, self.higher, &mut mask);
+        imageproc::morphology::open_mut(&mut mask, Norm::LInf, 1);
+        let edges = canny(&mask, 50.0, 150.0);
+        let mut segments = merge_collinear(&hough_segments(&edges, &self.hough), 2f32.to_radians(), 2.0);
+        segments.sort_by(|a, b| b.length().partial_cmp(&a.length()).unwrap());
+
+        let mut used = vec![false; segments.len()];
+        let mut lines = Vec::new();
+        for i in 0..segments.len() {
+            if used[i] {
+                continue;
+            }
+            let paired = (i + 1..segments.len())
+                .filter(|j| !used[*j])
+                .filter_map(|j| self.pair(&segments[i], &segments[j], &mask).map(|line| (j, line)))
+                .max_by(|a, b| a.1.center.length().partial_cmp(&b.1.center.length()).unwrap());
+            if let Some((j, line)) = paired {
+                used[i] = true;
+                used[j] = true;
+                lines.push(line);
+            }
+        }
+        lines.sort_by(|a, b| b.center.length().partial_cmp(&a.center.length()).unwrap());
+        lines
+    }
+
+    // The tape between two edges, if they are parallel, overlap, and have tape between them
+    fn pair(&self, a: &LineSegment, b: &LineSegment, mask: &GrayImage) -> Option<TapeLine> {
+        let angle_difference = normalize_line_angle(a.angle() - b.angle()).abs();
+        if angle_difference > self.max_angle_difference {
+            return None;
+        }
+        let pixel_width = (a.distance_to_line(b.midpoint()) + b.distance_to_line(a.midpoint())) / 2.0;
+        if pixel_width < 2.0 || pixel_width > self.max_pixel_width {
+            return None;
+        }
+
+        // Line the edges up in the same direction and keep the part where they overlap
+        let direction = Vector2::new(a.end.0 - a.start.0, a.end.1 - a.start.1).normalize();
+        let along = |p: (f32, f32)| Vector2::new(p.0 - a.start.0, p.1 - a.start.1).dot(&direction);
+        let (b_start, b_end) = if along(b.start) <= along(b.end) { (b.start, b.end) } else { (b.end, b.start) };
+        let overlap_start = along(a.start).max(along(b_start));
+        let overlap_end = along(a.end).min(along(b_end));
+        if overlap_end - overlap_start < 0.5 * a.length().min(b.length()) {
+            return None;
+        }
+        let (a_mid, b_mid) = (a.midpoint(), b.midpoint());
+        let inside = ((a_mid.0 + b_mid.0) / 2.0, (a_mid.1 + b_mid.1) / 2.0);
+        if !matches!(mask.get_pixel_checked(inside.0 as u32, inside.1 as u32), Some(p) if p[0] != 0) {
+            return None;
+        }
+
+        // Centerline: halfway between the edges, over the overlap
+        let offset = |p: (f32, f32)| {
+            let t = along(p);
+            (a.start.0 + direction.x * t, a.start.1 + direction.y * t)
+        };
+        let shift = (b_mid.0 - offset(b_mid).0, b_mid.1 - offset(b_mid).1);
+        let point_at = |t: f32| (
+            a.start.0 + direction.x * t + shift.0 / 2.0,
+            a.start.1 + direction.y * t + shift.1 / 2.0,
+        );
+        let center = LineSegment::new(point_at(overlap_start), point_at(overlap_end));
+
+        let floor = match &self.ground_plane {
+            Some(plane) => {
+                let floor = floor_line(plane, a, b)?;
+                if (floor.width - self.tape_width).abs() > self.width_tolerance * self.tape_width {
+                    return None;
+                }
+                Some(floor)
+            }
+            None => None,
+        };
+        Some(TapeLine { center, pixel_width, floor })
+    }
+}
+
+// Both edges are projected onto the floor, where unlike in the image they really are parallel,
+// and the tape runs halfway between them
+fn floor_line(plane: &GroundPlane, a: &LineSegment, b: &LineSegment) -> Option<FloorLine> {
+    let to_floor = |p: (f32, f32)| plane.image_to_floor(&Point2::new(p.0 as f64, p.1 as f64));
+    let (a_start, a_end) = (to_floor(a.start)?, to_floor(a.end)?);
+    let (b_start, b_end) = (to_floor(b.start)?, to_floor(b.end)?);
+    let a_direction = (a_end - a_start).try_normalize(f64::EPSILON)?;
+    let mut b_direction = (b_end - b_start).try_normalize(f64::EPSILON)?;
+    if a_direction.dot(&b_direction) < 0.0 {
+        b_direction = -b_direction;
+    }
+    let direction = (a_direction + b_direction).try_normalize(f64::EPSILON)?;
+    let normal = Vector2::new(-direction.y, direction.x);
+    // Signed distances along the normal from the origin to each edge
+    let a_offset = nalgebra::center(&a_start, &a_end).coords.dot(&normal);
+    let b_offset = nalgebra::center(&b_start, &b_end).coords.dot(&normal);
+    let offset = (a_offset + b_offset) / 2.0;
+    let closest = normal * offset;
+
+    let mut angle = direction.y.atan2(direction.x);
+    if angle > std::f64::consts::FRAC_PI_2 {
+        angle -= std::f64::consts::PI;
+    } else if angle <= -std::f64::consts::FRAC_PI_2 {
+        angle += std::f64::consts::PI;
+    }
+    Some(FloorLine {
+        angle,
+        distance: offset.abs(),
+        closest: (closest.x, closest.y),
+        width: (a_offset - b_offset).abs(),
+    })
+}
+
+impl Pipeline for FieldLinePipeline {
+    fn pipeline(&mut self, input: Image<Rgb<u8>>) -> crate::Result<Option<Image<Rgb<u8>>>> {
+        self.lines = self.detect(&input);
+        if !self.draw {
+            return Ok(None);
+        }
+        let mut overlay = Overlay::new(self.style);
+        for line in self.lines.iter() {
+            overlay.line(line.center.start, line.center.end);
+        }
+        let mut output = input;
+        overlay.draw(&mut output);
+        Ok(Some(output))
+    }
+
+    fn output_color_type(&self) -> ColorType {
+        ColorType::Rgb8
+    }
+}
@@ -0,0 +1,270 @@
+use std::any::Any;
+use std::time::{Duration, Instant};
+use image::{ColorType, Rgb};
+use imageproc::definitions::Image;
+use crate::overlay::Overlay;
+use crate::pipeline::Pipeline;
+use crate::pool::BufferPool;
+use crate::stage::{Data, DataType, Stage};
+
+/// A stage added to a [`StageGraph`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct NodeId(usize);
+
+/// Where a stage takes its input from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Input {
+    /// The camera frame.
+    Frame,
+    Stage(NodeId),
+}
+
+impl From<NodeId> for Input {
+    fn from(node: NodeId) -> Self {
+        Input::Stage(node)
+    }
+}
+
+// Lets stages be looked up by type once they're boxed.
+trait AnyStage: Stage {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<S: Stage + 'static> AnyStage for S {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+struct Node {
+    name: String,
+    stage: Box<dyn AnyStage>,
+    input: Input,
+    output_type: DataType,
+}
+
+/// Chains stages into a [`StagePipeline`]. Any number of stages can take the same input, so one
+/// frame can feed several branches. Stages run in the order they were added.
+#[derive(Default)]
+pub struct StageGraph {
+    nodes: Vec<Node>,
+}
+
+impl StageGraph {
+    pub fn new() -> Self {
+        StageGraph::default()
+    }
+
+    /// Adds a stage reading from `input`. Fails if the stage can't take what `input` produces.
+    pub fn add<S: Stage + 'static>(&mut self, name: &str, stage: S, input: impl Into<Input>) -> crate::Result<NodeId> {
+        let input = input.into();
+        let input_type = match input {
+            Input::Frame => DataType::Rgb,
+            Input::Stage(NodeId(index)) => self.nodes.get(index).ok_or("Unknown input stage")?.output_type,
+        };
+        let output_type = stage.output_type(input_type)
+            .ok_or_else(|| format!("Stage {} can't take {:?} input", name, input_type))?;
+        self.nodes.push(Node { name: name.to_string(), stage: Box::new(stage), input, output_type });
+        Ok(NodeId(self.nodes.len() - 1))
+    }
+
+    /// Adds a stage reading from the last stage added, or from the frame if it's the first.
+    pub fn then<S: Stage + 'static>(&mut self, name: &str, stage: S) -> crate::Result<NodeId> {
+        let input = match self.nodes.len() {
+            0 => Input::Frame,
+            n => Input::Stage(NodeId(n - 1)),
+        };
+        self.add(name, stage, input)
+    }
+
+    pub fn output_type(&self, node: NodeId) -> Option<DataType> {
+        self.nodes.get(node.0).map(|n| n.output_type)
+    }
+
+    /// The pipeline running the graph, drawing the result of `display` as its output.
+    pub fn build(self, display: Option<NodeId>) -> StagePipeline {
+        let count = self.nodes.len();
+        StagePipeline {
+            nodes: self.nodes,
+            display,
+            draw: true,
+            frame: None,
+            results: (0..count).map(|_| None).collect(),
+            timings: Vec::with_capacity(count),
+            pool: BufferPool::new(),
+        }
+    }
+}
+
+/// How long a stage took on the last frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StageTiming {
+    pub name: String,
+    pub duration: Duration,
+}
+
+/// Runs a [`StageGraph`] on every frame and keeps each stage's result until the next one, when
+/// their images go back into a pool for the stages to reuse.
+pub struct StagePipeline {
+    nodes: Vec<Node>,
+    /// The stage whose result is drawn. Images are shown as they are, and contours and
+    /// detections are drawn over the frame. Shows the frame if `None`.
+    pub display: Option<NodeId>,
+    pub draw: bool,
+    frame: Option<Data>,
+    results: Vec<Option<Data>>,
+    timings: Vec<StageTiming>,
+    pool: BufferPool,
+}
+
+impl StagePipeline {
+    /// Runs every stage on `frame`. Stops at the first stage that fails.
+    pub fn run(&mut self, frame: Image<Rgb<u8>>) -> crate::Result<()> {
+        let frame = self.frame.insert(Data::Rgb(frame));
+        self.timings.clear();
+        for result in self.results.iter_mut() {
+            if let Some(data) = result.take() {
+                self.pool.recycle(data);
+            }
+        }
+        for (index, node) in self.nodes.iter_mut().enumerate() {
+            let start = Instant::now();
+            let input = match node.input {
+                Input::Frame => &*frame,
+                Input::Stage(NodeId(input)) => self.results[input].as_ref().ok_or("Input stage has no result")?,
+            };
+            let output = node.stage.run_pooled(input, &mut self.pool)?;
+            self.timings.push(StageTiming { name: node.name.clone(), duration: start.elapsed() });
+            self.results[index] = Some(output);
+        }
+        Ok(())
+    }
+
+    /// The result of `node` on the last frame.
+    pub fn result(&self, node: NodeId) -> Option<&Data> {
+        self.results.get(node.0)?.as_ref()
+    }
+
+    /// Stage timings on the last frame, in the order the stages ran.
+    pub fn timings(&self) -> &[StageTiming] {
+        &self.timings
+    }
+
+    /// Looks a stage up by the name it was added with.
+    pub fn node(&self, name: &str) -> Option<NodeId> {
+        self.nodes.iter().position(|n| n.name == name).map(NodeId)
+    }
+
+    /// The stage at `node`, if it's an `S`, to read what it reports or change its settings
+    /// between frames.
+    pub fn stage<S: Stage + 'static>(&self, node: NodeId) -> Option<&S> {
+        self.nodes.get(node.0)?.stage.as_any().downcast_ref()
+    }
+
+    pub fn stage_mut<S: Stage + 'static>(&mut self, node: NodeId) -> Option<&mut S> {
+        self.nodes.get_mut(node.0)?.stage.as_any_mut().downcast_mut()
+    }
+}
+
+impl Pipeline for StagePipeline {
+    fn pipeline(&mut self, input: Image<Rgb<u8>>) -> crate::Result<Option<Image<Rgb<u8>>>> {
+        self.run(input)?;
+        if !self.draw {
+            return Ok(None);
+        }
+        let mut output = match self.frame.take() {
+            Some(Data::Rgb(frame)) => frame,
+            _ => return Err("Stage graph lost its frame".into()),
+        };
+        match self.display.and_then(|node| self.result(node)) {
+            Some(Data::Rgb(image)) => output = image.clone(),
+            Some(Data::Gray(image)) => output = Image::from_fn(image.width(), image.height(), |x, y| {
+                let value = image.get_pixel(x, y)[0];
+                Rgb([value, value, value])
+            }),
+            Some(Data::Contours(contours)) => {
+                for point in contours.iter().flat_map(|c| c.points.iter()) {
+                    if point.x >= 0 && point.y >= 0 && (point.x as u32) < output.width() && (point.y as u32) < output.height() {
+                        output.put_pixel(point.x as u32, point.y as u32, Rgb([0, 255, 0]));
+                    }
+                }
+            }
+            Some(Data::Detections(detections)) => Overlay::default().detections(detections).draw(&mut output),
+            None => {}
+        }
+        Ok(Some(output))
+    }
+
+    fn output_color_type(&self) -> ColorType {
+        ColorType::Rgb8
+    }
+
+    fn stage_timings(&self) -> &[StageTiming] {
+        &self.timings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stage::contours::{ContourBoxes, FindContours};
+    use crate::stage::filters::{Grayscale, Threshold};
+
+    // A bright 40x30 rectangle with its top left corner at (20, 10) on a dark frame.
+    fn frame() -> Image<Rgb<u8>> {
+        Image::from_fn(100, 60, |x, y| {
+            if (20..60).contains(&x) && (10..40).contains(&y) { Rgb([220, 200, 180]) } else { Rgb([20, 30, 40]) }
+        })
+    }
+
+    #[test]
+    fn rejects_mismatched_input() {
+        let mut graph = StageGraph::new();
+        let gray = graph.then("gray", Grayscale).unwrap();
+        let contours = graph.then("contours", FindContours { outer_only: true }).unwrap();
+        assert!(graph.add("threshold", Threshold { level: Some(128) }, contours).is_err());
+        assert!(graph.add("gray again", Grayscale, gray).is_err());
+        assert!(graph.add("boxes", ContourBoxes { min_area: 1.0, class_id: 0 }, Input::Frame).is_err());
+        assert_eq!(graph.output_type(contours), Some(DataType::Contours));
+    }
+
+    #[test]
+    fn runs_a_chain() {
+        let mut graph = StageGraph::new();
+        graph.then("gray", Grayscale).unwrap();
+        graph.then("threshold", Threshold { level: None }).unwrap();
+        graph.then("contours", FindContours { outer_only: true }).unwrap();
+        let boxes = graph.then("boxes", ContourBoxes { min_area: 100.0, class_id: 3 }).unwrap();
+        let mut pipeline = graph.build(Some(boxes));
+        pipeline.run(frame()).unwrap();
+
+        let detections = pipeline.result(boxes).and_then(Data::as_detections).unwrap();
+        assert_eq!(detections.len(), 1);
+        let bbox = &detections[0].bbox;
+        assert!((bbox.x - 20.0).abs() <= 1.0 && (bbox.y - 10.0).abs() <= 1.0, "{:?}", bbox);
+        assert!((bbox.width - 40.0).abs() <= 1.0 && (bbox.height - 30.0).abs() <= 1.0, "{:?}", bbox);
+        assert_eq!(detections[0].class_id, 3);
+        let names: Vec<&str> = pipeline.timings().iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["gray", "threshold", "contours", "boxes"]);
+    }
+
+    #[test]
+    fn branches_from_one_input() {
+        let mut graph = StageGraph::new();
+        let gray = graph.then("gray", Grayscale).unwrap();
+        let bright = graph.add("bright", Threshold { level: Some(128) }, gray).unwrap();
+        let everything = graph.add("everything", Threshold { level: Some(0) }, gray).unwrap();
+        let mut pipeline = graph.build(None);
+        pipeline.run(frame()).unwrap();
+
+        let count = |node| pipeline.result(node).and_then(Data::as_gray).unwrap().pixels().filter(|p| p[0] == 255).count();
+        assert_eq!(count(bright), 40 * 30);
+        assert_eq!(count(everything), 100 * 60);
+        assert_eq!(pipeline.result(gray).and_then(Data::as_gray).map(|g| g.dimensions()), Some((100, 60)));
+    }
+}
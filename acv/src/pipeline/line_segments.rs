@@ -0,0 +1,63 @@
+use image::{ColorType, Rgb};
+use image::imageops::grayscale;
+use imageproc::definitions::Image;
+use imageproc::edges::canny;
+use crate::lines::{hough_segments, HoughOptions, LineSegment};
+use crate::overlay::{Overlay, OverlayStyle};
+use crate::pipeline::Pipeline;
+
+/// Canny edges followed by the probabilistic Hough transform.
+pub struct LineSegmentPipeline {
+    /// Hysteresis thresholds on the Sobel gradient magnitude, taken after a Gaussian blur.
+    pub canny_low: f32,
+    pub canny_high: f32,
+    pub hough: HoughOptions,
+    pub draw: bool,
+    pub style: OverlayStyle,
+    segments: Vec<LineSegment>,
+}
+
+impl LineSegmentPipeline {
+    pub fn new() -> Self {
+        LineSegmentPipeline {
+            canny_low: 20.0,
+            canny_high: 50.0,
+            hough: HoughOptions::default(),
+            draw: true,
+            style: OverlayStyle { color: Rgb([255, 0, 255]), ..OverlayStyle::default() },
+            segments: Vec::new(),
+        }
+    }
+
+    /// Segments found in the last frame.
+    pub fn segments(&self) -> &[LineSegment] {
+        &self.segments
+    }
+}
+
+impl Default for LineSegmentPipeline {
+    fn default() -> Self {
+        LineSegmentPipeline::new()
+    }
+}
+
+impl Pipeline for LineSegmentPipeline {
+    fn pipeline(&mut self, input: Image<Rgb<u8>>) -> crate::Result<Option<Image<Rgb<u8>>>> {
+        let edges = canny(&grayscale(&input), self.canny_low, self.canny_high);
+        self.segments = hough_segments(&edges, &self.hough);
+        if !self.draw {
+            return Ok(None);
+        }
+        let mut overlay = Overlay::new(self.style);
+        for segment in self.segments.iter() {
+            overlay.line(segment.start, segment.end);
+        }
+        let mut output = input;
+        overlay.draw(&mut output);
+        Ok(Some(output))
+    }
+
+    fn output_color_type(&self) -> ColorType {
+        ColorType::Rgb8
+    }
+}
@@ -0,0 +1,174 @@
+use image::{ColorType, GrayImage, Luma, Rgb};
+use imageproc::definitions::Image;
+use imageproc::region_labelling::Connectivity;
+use crate::detection::BoundingBox;
+use crate::mask::{fit, open_into, MaskBuffers, StructuringElement};
+use crate::mask::components::ConnectedComponents;
+use crate::overlay::{Overlay, OverlayStyle};
+use crate::pipeline::Pipeline;
+
+/// How the background estimate follows the scene.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BackgroundModel {
+    /// Exponential running average of every frame.
+    RunningAverage,
+    /// Running approximation of the per-pixel median: each frame nudges the background a fixed
+    /// step towards the pixel. Ignores brief changes better than an average does.
+    Median,
+}
+
+/// A connected region of changed pixels.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MotionBlob {
+    pub bbox: BoundingBox,
+    pub centroid: (f32, f32),
+    pub area: u32,
+}
+
+/// Compares each frame against a learned background and reports what changed.
+pub struct MotionPipeline {
+    pub model: BackgroundModel,
+    /// How quickly the background absorbs changes, from 0 (never) to 1 (immediately). For the
+    /// median model this is the step per frame as a fraction of the full intensity range.
+    pub learning_rate: f32,
+    /// Pixels differing from the background by more than this in any channel have changed.
+    pub threshold: u8,
+    /// Radius of the opening applied to the change mask to drop noise.
+    pub open_radius: u8,
+    /// Blobs with fewer pixels than this are ignored.
+    pub min_area: u32,
+    pub draw: bool,
+    pub style: OverlayStyle,
+    background: Vec<f32>,
+    size: (u32, u32),
+    mask: GrayImage,
+    changed: GrayImage,
+    buffers: MaskBuffers,
+    components: ConnectedComponents,
+    blobs: Vec<MotionBlob>,
+}
+
+impl MotionPipeline {
+    pub fn new(model: BackgroundModel, learning_rate: f32) -> Self {
+        MotionPipeline {
+            model,
+            learning_rate,
+            threshold: 30,
+            open_radius: 1,
+            min_area: 50,
+            draw: true,
+            style: OverlayStyle::default(),
+            background: Vec::new(),
+            size: (0, 0),
+            mask: GrayImage::new(0, 0),
+            changed: GrayImage::new(0, 0),
+            buffers: MaskBuffers::new(),
+            components: ConnectedComponents::new(),
+            blobs: Vec::new(),
+        }
+    }
+
+    /// Pixels that differed from the background in the last frame.
+    pub fn mask(&self) -> &GrayImage {
+        &self.mask
+    }
+
+    /// Regions of motion in the last frame, largest first.
+    pub fn blobs(&self) -> &[MotionBlob] {
+        &self.blobs
+    }
+
+    /// Fraction of the frame that changed.
+    pub fn motion_fraction(&self) -> f32 {
+        let total = self.mask.width() * self.mask.height();
+        if total == 0 {
+            return 0.0;
+        }
+        self.mask.pixels().filter(|p| p[0] != 0).count() as f32 / total as f32
+    }
+
+    /// Forgets the background, so the next frame becomes the new one.
+    pub fn reset(&mut self) {
+        self.background.clear();
+        self.size = (0, 0);
+    }
+
+    fn update_blobs(&mut self) {
+        self.blobs.clear();
+        let min_area = self.min_area.max(1);
+        for component in self.components.label(&self.mask, Connectivity::Eight) {
+            if component.area >= min_area {
+                self.blobs.push(MotionBlob { bbox: component.bbox, centroid: component.centroid, area: component.area });
+            }
+        }
+        self.blobs.sort_by_key(|b| std::cmp::Reverse(b.area));
+    }
+
+    /// Updates the change mask, blobs and background with a new frame.
+    pub fn process(&mut self, input: &Image<Rgb<u8>>) {
+        let (width, height) = input.dimensions();
+        if self.size != (width, height) || self.background.is_empty() {
+            // Nothing to compare against yet
+            self.background = input.as_raw().iter().map(|v| *v as f32).collect();
+            self.size = (width, height);
+            self.mask = GrayImage::new(width, height);
+            self.blobs.clear();
+            return;
+        }
+
+        let threshold = self.threshold as f32;
+        fit(&mut self.changed, width, height);
+        for ((pixel, background), out) in input.pixels().zip(self.background.chunks(3)).zip(self.changed.pixels_mut()) {
+            let changed = (0..3).any(|c| (pixel[c] as f32 - background[c]).abs() > threshold);
+            *out = Luma([if changed { 255 } else { 0 }]);
+        }
+        if self.open_radius > 0 {
+            let element = StructuringElement::square(self.open_radius as u32);
+            open_into(&self.changed, &element, &mut self.buffers, &mut self.mask);
+        } else {
+            std::mem::swap(&mut self.mask, &mut self.changed);
+        }
+        self.update_blobs();
+
+        let rate = self.learning_rate.clamp(0.0, 1.0);
+        match self.model {
+            BackgroundModel::RunningAverage => {
+                for (background, value) in self.background.iter_mut().zip(input.as_raw()) {
+                    *background += rate * (*value as f32 - *background);
+                }
+            }
+            BackgroundModel::Median => {
+                let step = rate * 255.0;
+                for (background, value) in self.background.iter_mut().zip(input.as_raw()) {
+                    let difference = *value as f32 - *background;
+                    *background += difference.clamp(-step, step);
+                }
+            }
+        }
+    }
+}
+
+impl Pipeline for MotionPipeline {
+    fn pipeline(&mut self, input: Image<Rgb<u8>>) -> crate::Result<Option<Image<Rgb<u8>>>> {
+        self.process(&input);
+        if !self.draw {
+            return Ok(None);
+        }
+        let mut output = input;
+        for (pixel, changed) in output.pixels_mut().zip(self.mask.pixels()) {
+            if changed[0] != 0 {
+                pixel[0] = ((pixel[0] as u16 + 255) / 2) as u8;
+            }
+        }
+        let mut overlay = Overlay::new(self.style);
+        for blob in self.blobs.iter() {
+            overlay.bbox(&blob.bbox, None);
+        }
+        overlay.draw(&mut output);
+        Ok(Some(output))
+    }
+
+    fn output_color_type(&self) -> ColorType {
+        ColorType::Rgb8
+    }
+}
@@ -0,0 +1,63 @@
+use image::{ColorType, Rgb};
+use imageproc::definitions::Image;
+use crate::detection::Detection;
+use crate::inference::OnnxModel;
+use crate::overlay::{Overlay, OverlayStyle};
+use crate::pipeline::Pipeline;
+
+/// Runs an ONNX detection or classification model on every frame.
+pub struct ObjectDetectionPipeline {
+    pub model: OnnxModel,
+    /// Class names, indexed by class id.
+    pub labels: Vec<String>,
+    /// Detections scoring below this are dropped.
+    pub confidence: f32,
+    /// Overlap above which non-maximum suppression drops the weaker detection.
+    pub iou: f32,
+    /// Whether detections of different classes suppress each other.
+    pub class_agnostic: bool,
+    pub draw: bool,
+    pub style: OverlayStyle,
+    detections: Vec<Detection>,
+}
+
+impl ObjectDetectionPipeline {
+    pub fn new(model: OnnxModel, labels: Vec<String>) -> Self {
+        ObjectDetectionPipeline {
+            model,
+            labels,
+            confidence: 0.25,
+            iou: 0.45,
+            class_agnostic: false,
+            draw: true,
+            style: OverlayStyle::default(),
+            detections: Vec::new(),
+        }
+    }
+
+    /// Detections in the last frame, best first.
+    pub fn detections(&self) -> &[Detection] {
+        &self.detections
+    }
+
+    /// Name of a class, or an empty string if the labels don't cover it.
+    pub fn label(&self, class_id: u32) -> &str {
+        self.labels.get(class_id as usize).map(|l| l.as_str()).unwrap_or("")
+    }
+}
+
+impl Pipeline for ObjectDetectionPipeline {
+    fn pipeline(&mut self, input: Image<Rgb<u8>>) -> crate::Result<Option<Image<Rgb<u8>>>> {
+        self.detections = self.model.detect(&input, self.confidence, self.iou, self.class_agnostic)?;
+        if !self.draw {
+            return Ok(None);
+        }
+        let mut output = input;
+        Overlay::new(self.style).detections(&self.detections).draw(&mut output);
+        Ok(Some(output))
+    }
+
+    fn output_color_type(&self) -> ColorType {
+        ColorType::Rgb8
+    }
+}
@@ -0,0 +1,63 @@
+use image::{ColorType, Rgb};
+use image::imageops::grayscale;
+use imageproc::definitions::Image;
+use crate::optical_flow::{FlowResult, FlowTracker};
+use crate::overlay::{Overlay, OverlayStyle};
+use crate::pipeline::Pipeline;
+
+/// Runs a `FlowTracker` on every frame and draws the flow vectors, green where they agree with
+/// the global motion (in the style's color) and red where they don't.
+pub struct OpticalFlowPipeline {
+    pub tracker: FlowTracker,
+    pub draw: bool,
+    pub style: OverlayStyle,
+    result: Option<FlowResult>,
+}
+
+impl OpticalFlowPipeline {
+    pub fn new() -> Self {
+        OpticalFlowPipeline {
+            tracker: FlowTracker::new(),
+            draw: true,
+            style: OverlayStyle::default(),
+            result: None,
+        }
+    }
+
+    /// Flow between the last two frames.
+    pub fn result(&self) -> Option<&FlowResult> {
+        self.result.as_ref()
+    }
+}
+
+impl Default for OpticalFlowPipeline {
+    fn default() -> Self {
+        OpticalFlowPipeline::new()
+    }
+}
+
+impl Pipeline for OpticalFlowPipeline {
+    fn pipeline(&mut self, input: Image<Rgb<u8>>) -> crate::Result<Option<Image<Rgb<u8>>>> {
+        let result = self.tracker.update(&grayscale(&input));
+        let output = if self.draw {
+            let mut inliers = Overlay::new(self.style);
+            let mut outliers = Overlay::new(OverlayStyle { color: Rgb([255, 0, 0]), ..self.style });
+            for vector in result.vectors.iter() {
+                let overlay = if vector.inlier { &mut inliers } else { &mut outliers };
+                overlay.line(vector.from, vector.to);
+            }
+            let mut output = input;
+            inliers.draw(&mut output);
+            outliers.draw(&mut output);
+            Some(output)
+        } else {
+            None
+        };
+        self.result = Some(result);
+        Ok(output)
+    }
+
+    fn output_color_type(&self) -> ColorType {
+        ColorType::Rgb8
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::BinaryHeap;
+use image::{ColorType, GrayImage, Luma, Rgb};
+use imageproc::definitions::Image;
+use imageproc::distance_transform::{euclidean_squared_distance_transform, Norm};
+use imageproc::region_labelling::{connected_components, Connectivity};
+use nalgebra::Point2;
+use crate::ground_plane::GroundPlane;
+use crate::overlay::{Overlay, OverlayStyle};
+use crate::pipeline::Pipeline;
+use crate::util::{in_range_hsv, Hsv};
+
+/// A game piece with its rotated bounding rectangle.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OrientedSample {
+    pub center: (f32, f32),
+    /// Rectangle corners, going around the piece.
+    pub corners: [(f32, f32); 4],
+    pub length: f32,
+    pub width: f32,
+    /// Angle of the long axis in the image, in radians from the x axis towards y, in -pi/2..pi/2.
+    pub angle: f32,
+    pub area: u32,
+    /// Center on the floor, if a ground plane is set.
+    pub floor_position: Option<(f64, f64)>,
+    /// Angle of the long axis on the floor, if a ground plane is set.
+    pub floor_angle: Option<f64>,
+}
+
+/// Finds pieces of one color, splits pieces that touch, and reports how each one is rotated.
+pub struct OrientedSamplePipeline {
+    pub lower: Hsv,
+    pub higher: Hsv,
+    /// Radius of the opening applied to the color mask to drop speckles.
+    pub open_radius: u8,
+    /// Pieces with fewer pixels than this are ignored.
+    pub min_area: u32,
+    /// How far, in pixels, the thickest part of a piece has to stand out from the neck joining it
+    /// to a thicker one for it to be split off. Lower values split touching pieces more eagerly.
+    pub seed_depth: f32,
+    /// Maps image pixels to floor coordinates.
+    pub ground_plane: Option<GroundPlane>,
+    pub draw: bool,
+    pub style: OverlayStyle,
+    samples: Vec<OrientedSample>,
+}
+
+impl OrientedSamplePipeline {
+    pub fn new(lower: Hsv, higher: Hsv) -> Self {
+        OrientedSamplePipeline {
+            lower,
+            higher,
+            open_radius: 1,
+            min_area: 100,
+            seed_depth: 2.0,
+            ground_plane: None,
+            draw: true,
+            style: OverlayStyle::default(),
+            samples: Vec::new(),
+        }
+    }
+
+    /// Pieces found in the last frame, largest first.
+    pub fn samples(&self) -> &[OrientedSample] {
+        &self.samples
+    }
+
+    pub fn detect(&self, input: &Image<Rgb<u8>>) -> Vec<OrientedSample> {
+        let mut mask = GrayImage::new(input.width(), input.height());
+        in_range_hsv(input, self.lower, self.higher, &mut mask);
+        if self.open_radius > 0 {
+            imageproc::morphology::open_mut(&mut mask, Norm::LInf, self.open_radius);
+        }
+        let labels = split_touching(&mask, self.seed_depth);
+
+        let count = labels.pixels().map(|p| p[0]).max().unwrap_or(0) as usize;
+        let mut regions: Vec<Vec<(u32, u32)>> = vec![Vec::new(); count + 1];
+        for (x, y, label) in labels.enumerate_pixels() {
+            if label[0] != 0 {
+                regions[label[0] as usize].push((x, y));
+            }
+        }
+        let mut samples: Vec<OrientedSample> = regions.iter()
+            .filter(|pixels| pixels.len() as u32 >= self.min_area)
+            .map(|pixels| self.oriented_rect(pixels))
+            .collect();
+        samples.sort_by_key(|s| std::cmp::Reverse(s.area));
+        samples
+    }
+
+    // Long axis from the second moments, extents from projecting every pixel onto the axes
+    fn oriented_rect(&self, pixels: &[(u32, u32)]) -> OrientedSample {
+        let n = pixels.len() as f64;
+        let mx = pixels.iter().map(|p| p.0 as f64).sum::<f64>() / n;
+        let my = pixels.iter().map(|p| p.1 as f64).sum::<f64>() / n;
+        let (mut mu20, mut mu02, mut mu11) = (0.0, 0.0, 0.0);
+        for &(x, y) in pixels {
+            let (dx, dy) = (x as f64 - mx, y as f64 - my);
+            mu20 += dx * dx;
+            mu02 += dy * dy;
+            mu11 += dx * dy;
+        }
+        let angle = 0.5 * (2.0 * mu11).atan2(mu20 - mu02);
+        let (cos, sin) = (angle.cos(), angle.sin());
+
+        let (mut min_u, mut max_u, mut min_v, mut max_v) = (f64::MAX, f64::MIN, f64::MAX, f64::MIN);
+        for &(x, y) in pixels {
+            let (dx, dy) = (x as f64 - mx, y as f64 - my);
+            let u = dx * cos + dy * sin;
+            let v = -dx * sin + dy * cos;
+            min_u = min_u.min(u);
+            max_u = max_u.max(u);
+            min_v = min_v.min(v);
+            max_v = max_v.max(v);
+        }
+        // Pixels have area, so the rectangle reaches half a pixel past the outermost centers
+        let (min_u, max_u, min_v, max_v) = (min_u - 0.5, max_u + 0.5, min_v - 0.5, max_v + 0.5);
+        let to_image = |u: f64, v: f64| (mx + u * cos - v * sin, my + u * sin + v * cos);
+        let center = to_image((min_u + max_u) / 2.0, (min_v + max_v) / 2.0);
+        let corners = [
+            to_image(min_u, min_v),
+            to_image(max_u, min_v),
+            to_image(max_u, max_v),
+            to_image(min_u, max_v),
+        ];
+
+        let (floor_position, floor_angle) = match &self.ground_plane {
+            Some(plane) => {
+                let pixel = Point2::new(center.0, center.1);
+                let position = plane.image_to_floor(&pixel).map(|p| (p.x, p.y));
+                (position, plane.floor_angle(&pixel, (cos, sin)))
+            }
+            None => (None, None),
+        };
+
+        OrientedSample {
+            center: (center.0 as f32, center.1 as f32),
+            corners: corners.map(|c| (c.0 as f32, c.1 as f32)),
+            length: (max_u - min_u) as f32,
+            width: (max_v - min_v) as f32,
+            angle: angle as f32,
+            area: pixels.len() as u32,
+            floor_position,
+            floor_angle,
+        }
+    }
+}
+
+/// Labels the blobs in `mask`, splitting blobs that are several pieces touching. Each piece is
+/// seeded at a peak of the distance transform standing more than `seed_depth` pixels above the
+/// pass to any higher peak (the h-maxima), and the seeds are flooded outward, so a narrow neck
+/// between two pieces becomes the border between them (a marker-based watershed).
+pub fn split_touching(mask: &GrayImage, seed_depth: f32) -> Image<Luma<u32>> {
+    let (width, height) = mask.dimensions();
+    let inside: Vec<bool> = mask.pixels().map(|p| p[0] != 0).collect();
+    let background = GrayImage::from_fn(width, height, |x, y| Luma([if mask.get_pixel(x, y)[0] == 0 { 255 } else { 0 }]));
+    let distance = euclidean_squared_distance_transform(&background);
+    let distance: Vec<f32> = distance.pixels().map(|d| (d[0] as f32).sqrt()).collect();
+
+    let seeds = peak_seeds(&distance, &inside, width, height, seed_depth);
+    let mut labels = connected_components(&seeds, Connectivity::Eight, Luma([0]));
+
+    // Flood outward from the seeds, always growing from the pixel deepest inside its blob
+    let mut queue = BinaryHeap::new();
+    for (x, y, label) in labels.enumerate_pixels() {
+        if label[0] != 0 {
+            let index = (y * width + x) as usize;
+            queue.push(((distance[index] * 16.0) as u32, index));
+        }
+    }
+    while let Some((_, index)) = queue.pop() {
+        let label = labels.get_pixel(index as u32 % width, index as u32 / width)[0];
+        for n in neighbours(index, width, height) {
+            let (nx, ny) = (n as u32 % width, n as u32 / width);
+            if !inside[n] || labels.get_pixel(nx, ny)[0] != 0 {
+                continue;
+            }
+            labels.put_pixel(nx, ny, Luma([label]));
+            queue.push(((distance[n] * 16.0) as u32, n));
+        }
+    }
+    labels
+}
+
+// The regional maxima of `distance` once every peak is lowered by `depth` but no further than
+// the highest pass out of it, which leaves only the peaks deeper than `depth`.
+fn peak_seeds(distance: &[f32], inside: &[bool], width: u32, height: u32, depth: f32) -> GrayImage {
+    // Reconstruction by dilation of distance - depth under distance. Non-negative floats order
+    // the same as their bits.
+    let mut lowered: Vec<f32> = distance.iter().map(|d| (d - depth).max(0.0)).collect();
+    let mut queue: BinaryHeap<(u32, usize)> = (0..distance.len())
+        .filter(|i| inside[*i])
+        .map(|i| (lowered[i].to_bits(), i))
+        .collect();
+    while let Some((bits, index)) = queue.pop() {
+        if bits != lowered[index].to_bits() {
+            continue;
+        }
+        for n in neighbours(index, width, height) {
+            let value = lowered[index].min(distance[n]);
+            if inside[n] && value > lowered[n] {
+                lowered[n] = value;
+                queue.push((value.to_bits(), n));
+            }
+        }
+    }
+
+    // Plateaus with no higher neighbour
+    let mut seeds = vec![0; distance.len()];
+    let mut visited = vec![false; distance.len()];
+    for start in 0..distance.len() {
+        if !inside[start] || visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        let mut plateau = vec![start];
+        let mut maximum = true;
+        let mut next = 0;
+        while let Some(&index) = plateau.get(next) {
+            next += 1;
+            for n in neighbours(index, width, height).filter(|n| inside[*n]) {
+                if lowered[n] > lowered[index] {
+                    maximum = false;
+                } else if lowered[n] == lowered[index] && !visited[n] {
+                    visited[n] = true;
+                    plateau.push(n);
+                }
+            }
+        }
+        if maximum {
+            for index in plateau {
+                seeds[index] = 255;
+            }
+        }
+    }
+    GrayImage::from_raw(width, height, seeds).unwrap()
+}
+
+// Indices of the 8 neighbours of the pixel at `index`.
+fn neighbours(index: usize, width: u32, height: u32) -> impl Iterator<Item = usize> {
+    let (width, height) = (width as i64, height as i64);
+    let (x, y) = (index as i64 % width, index as i64 / width);
+    (-1..=1)
+        .flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+        .filter(move |&(nx, ny)| (nx, ny) != (x, y) && nx >= 0 && ny >= 0 && nx < width && ny < height)
+        .map(move |(nx, ny)| (ny * width + nx) as usize)
+}
+
+impl Pipeline for OrientedSamplePipeline {
+    fn pipeline(&mut self, input: Image<Rgb<u8>>) -> crate::Result<Option<Image<Rgb<u8>>>> {
+        self.samples = self.detect(&input);
+        if !self.draw {
+            return Ok(None);
+        }
+        // Outlines in the style's color and long axes in magenta
+        let mut outlines = Overlay::new(self.style);
+        let mut axes = Overlay::new(OverlayStyle { color: Rgb([255, 0, 255]), ..self.style });
+        for sample in self.samples.iter() {
+            outlines.polygon(&sample.corners);
+            let half = sample.length / 2.0;
+            let (dx, dy) = (half * sample.angle.cos(), half * sample.angle.sin());
+            axes.line((sample.center.0 - dx, sample.center.1 - dy), (sample.center.0 + dx, sample.center.1 + dy));
+        }
+        let mut output = input;
+        outlines.draw(&mut output);
+        axes.draw(&mut output);
+        Ok(Some(output))
+    }
+
+    fn output_color_type(&self) -> ColorType {
+        ColorType::Rgb8
+    }
+}
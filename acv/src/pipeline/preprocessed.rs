@@ -0,0 +1,108 @@
+use image::{ColorType, Rgb};
+use image::imageops::{crop_imm, overlay, resize, FilterType};
+use imageproc::definitions::Image;
+use crate::detection::BoundingBox;
+use crate::overlay::Overlay;
+use crate::pipeline::graph::StageTiming;
+use crate::pipeline::Pipeline;
+use crate::preprocess::{Downscale, FrameTransform, MapToFrame};
+
+/// Crops each frame to a region of interest and shrinks it before passing it to `pipeline`.
+///
+/// Results read from the inner pipeline are in the coordinates of the image it was given; pass
+/// them through [`Preprocessed::map`] or [`Preprocessed::results`] to get full frame pixels. The
+/// inner pipeline's output is scaled back up and drawn over the frame, with the region outlined.
+pub struct Preprocessed<P> {
+    pub pipeline: P,
+    /// Part of the frame to process, as fractions of its width and height. The whole frame if
+    /// `None`.
+    pub roi: Option<BoundingBox>,
+    pub downscale: Downscale,
+    transform: FrameTransform,
+}
+
+impl<P: Pipeline> Preprocessed<P> {
+    pub fn new(pipeline: P, roi: Option<BoundingBox>, downscale: Downscale) -> Self {
+        Preprocessed {
+            pipeline,
+            roi,
+            downscale,
+            transform: FrameTransform::identity(),
+        }
+    }
+
+    /// Maps the last frame's processed coordinates to the full frame.
+    pub fn transform(&self) -> FrameTransform {
+        self.transform
+    }
+
+    /// `results` from the last frame in full frame coordinates.
+    pub fn map<T: MapToFrame>(&self, results: &[T]) -> Vec<T> {
+        results.iter().map(|r| r.map_to_frame(&self.transform)).collect()
+    }
+
+    /// The inner pipeline's results from the last frame in full frame coordinates, e.g.
+    /// `preprocessed.results(|p| p.codes())`.
+    pub fn results<T: MapToFrame, F: Fn(&P) -> &[T]>(&self, get: F) -> Vec<T> {
+        self.map(get(&self.pipeline))
+    }
+
+    // The region in pixels, at least one pixel and inside the frame.
+    fn crop_rect(&self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let roi = match self.roi {
+            Some(roi) => roi,
+            None => return (0, 0, width, height),
+        };
+        let x = ((roi.x.clamp(0.0, 1.0) * width as f32) as u32).min(width - 1);
+        let y = ((roi.y.clamp(0.0, 1.0) * height as f32) as u32).min(height - 1);
+        let right = (((roi.x + roi.width).clamp(0.0, 1.0) * width as f32).round() as u32).max(x + 1);
+        let bottom = (((roi.y + roi.height).clamp(0.0, 1.0) * height as f32).round() as u32).max(y + 1);
+        (x, y, right - x, bottom - y)
+    }
+}
+
+impl<P: Pipeline> Pipeline for Preprocessed<P> {
+    fn pipeline(&mut self, input: Image<Rgb<u8>>) -> crate::Result<Option<Image<Rgb<u8>>>> {
+        if input.width() == 0 || input.height() == 0 {
+            return Err("Empty frame".into());
+        }
+        let (x, y, width, height) = self.crop_rect(input.width(), input.height());
+        let factor = self.downscale.factor();
+        self.transform = FrameTransform { offset: (x as f32, y as f32), scale: factor as f32 };
+        if (width, height) == input.dimensions() && factor == 1 {
+            return self.pipeline.pipeline(input);
+        }
+
+        let cropped = crop_imm(&input, x, y, width, height).to_image();
+        let processed = match self.downscale {
+            Downscale::None => cropped,
+            downscale => downscale.apply(&cropped),
+        };
+        if processed.width() == 0 || processed.height() == 0 {
+            return Err(format!("Region of interest is too small to downscale by {}", factor).into());
+        }
+        let processed_size = processed.dimensions();
+        let output = match self.pipeline.pipeline(processed)? {
+            Some(output) => output,
+            None => return Ok(None),
+        };
+        // Outputs of some other size aren't in processed coordinates, so they're passed on as they are.
+        if output.dimensions() != processed_size {
+            return Ok(Some(output));
+        }
+        let mut frame = input;
+        let scaled = resize(&output, processed_size.0 * factor, processed_size.1 * factor, FilterType::Nearest);
+        overlay(&mut frame, &scaled, x as i64, y as i64);
+        let region = BoundingBox::new(x as f32, y as f32, width as f32, height as f32);
+        Overlay::default().bbox(&region, None).draw(&mut frame);
+        Ok(Some(frame))
+    }
+
+    fn output_color_type(&self) -> ColorType {
+        self.pipeline.output_color_type()
+    }
+
+    fn stage_timings(&self) -> &[StageTiming] {
+        self.pipeline.stage_timings()
+    }
+}
@@ -0,0 +1,50 @@
+use image::{ColorType, Rgb};
+use image::imageops::grayscale;
+use imageproc::definitions::Image;
+use crate::overlay::{Overlay, OverlayStyle};
+use crate::pipeline::Pipeline;
+use crate::template_matching::{TemplateMatch, TemplateMatcher};
+
+/// Runs a `TemplateMatcher` on every frame and outlines what it finds.
+pub struct TemplateMatchPipeline {
+    pub matcher: TemplateMatcher,
+    pub draw: bool,
+    pub style: OverlayStyle,
+    matches: Vec<TemplateMatch>,
+}
+
+impl TemplateMatchPipeline {
+    pub fn new(matcher: TemplateMatcher) -> Self {
+        TemplateMatchPipeline {
+            matcher,
+            draw: true,
+            style: OverlayStyle::default(),
+            matches: Vec::new(),
+        }
+    }
+
+    /// Matches in the last frame, best first.
+    pub fn matches(&self) -> &[TemplateMatch] {
+        &self.matches
+    }
+}
+
+impl Pipeline for TemplateMatchPipeline {
+    fn pipeline(&mut self, input: Image<Rgb<u8>>) -> crate::Result<Option<Image<Rgb<u8>>>> {
+        self.matches = self.matcher.find(&grayscale(&input));
+        if !self.draw {
+            return Ok(None);
+        }
+        let mut overlay = Overlay::new(self.style);
+        for m in self.matches.iter() {
+            overlay.polygon(&m.corners);
+        }
+        let mut output = input;
+        overlay.draw(&mut output);
+        Ok(Some(output))
+    }
+
+    fn output_color_type(&self) -> ColorType {
+        ColorType::Rgb8
+    }
+}
@@ -0,0 +1,279 @@
+use image::{ColorType, GrayImage, Luma, Rgb};
+use imageproc::definitions::Image;
+use imageproc::drawing::draw_polygon_mut;
+use imageproc::point::Point;
+use crate::overlay::{Overlay, OverlayStyle};
+use crate::pipeline::Pipeline;
+use crate::util::{hsv_in_range, Hsv};
+
+/// A polygonal region of interest in frame pixel coordinates.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Zone {
+    pub name: String,
+    pub polygon: Vec<Point<i32>>,
+}
+
+impl Zone {
+    pub fn new(name: &str, polygon: Vec<Point<i32>>) -> Self {
+        Zone { name: name.to_string(), polygon }
+    }
+}
+
+/// Decides whether a pixel belongs to the game element, and how far its color is from it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorCriterion {
+    Hsv { lower: Hsv, higher: Hsv },
+    Rgb { lower: Rgb<u8>, higher: Rgb<u8> },
+    /// Pixels within `max_distance` (Euclidean, in RGB) of `color`.
+    Reference { color: Rgb<u8>, max_distance: f32 },
+}
+
+impl ColorCriterion {
+    /// Whether the pixel matches, and its distance from the target color normalized to 0..1.
+    fn evaluate(&self, pixel: &Rgb<u8>) -> (bool, f32) {
+        match self {
+            ColorCriterion::Hsv { lower, higher } => {
+                let hsv = Hsv::from(*pixel);
+                let matches = hsv_in_range(hsv, *lower, *higher);
+                // A range with lower.h above higher.h wraps through 0, and so does its center
+                let span = if lower.h <= higher.h { higher.h - lower.h } else { higher.h + 360.0 - lower.h };
+                let center_h = lower.h + span / 2.0;
+                let dh = (hsv.h - center_h).rem_euclid(360.0);
+                let dh = dh.min(360.0 - dh) / 180.0;
+                let ds = hsv.s - (lower.s + higher.s) / 2.0;
+                let dv = hsv.v - (lower.v + higher.v) / 2.0;
+                (matches, ((dh * dh + ds * ds + dv * dv) / 3.0).sqrt())
+            }
+            ColorCriterion::Rgb { lower, higher } => {
+                let matches = (0..3).all(|c| pixel[c] >= lower[c] && pixel[c] <= higher[c]);
+                let center = Rgb([0, 1, 2].map(|c| ((lower[c] as u16 + higher[c] as u16) / 2) as u8));
+                (matches, rgb_distance(pixel, &center) / MAX_RGB_DISTANCE)
+            }
+            ColorCriterion::Reference { color, max_distance } => {
+                let distance = rgb_distance(pixel, color);
+                (distance <= *max_distance, distance / MAX_RGB_DISTANCE)
+            }
+        }
+    }
+}
+
+const MAX_RGB_DISTANCE: f32 = 441.67294;
+
+fn rgb_distance(a: &Rgb<u8>, b: &Rgb<u8>) -> f32 {
+    let d: f32 = (0..3).map(|c| (a[c] as f32 - b[c] as f32).powi(2)).sum();
+    d.sqrt()
+}
+
+/// Which zone statistic picks the winner.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ZoneMetric {
+    /// Highest fraction of matching pixels.
+    MatchingFraction,
+    /// Lowest mean color distance.
+    MeanColorDistance,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ZoneScore {
+    /// Fraction of the zone's pixels that match the criterion.
+    pub fraction: f32,
+    /// Mean normalized color distance of the zone's pixels from the target color.
+    pub mean_distance: f32,
+}
+
+impl ZoneScore {
+    /// Higher is better, whichever metric is used.
+    fn value(&self, metric: ZoneMetric) -> f32 {
+        match metric {
+            ZoneMetric::MatchingFraction => self.fraction,
+            ZoneMetric::MeanColorDistance => 1.0 - self.mean_distance,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZoneResult {
+    /// Index of the winning zone after hysteresis.
+    pub zone: usize,
+    pub name: String,
+    /// How far ahead the winning zone is of the best other zone this frame, from 0 to 1. 0 while a
+    /// challenger leads it.
+    pub confidence: f32,
+    pub scores: Vec<ZoneScore>,
+}
+
+/// Scores a set of zones against a color criterion and reports which one holds the game element.
+pub struct ZoneClassifierPipeline {
+    pub zones: Vec<Zone>,
+    pub criterion: ColorCriterion,
+    pub metric: ZoneMetric,
+    /// How much a challenger has to beat the current winner by before it can take over.
+    pub hysteresis: f32,
+    /// How many consecutive frames a challenger has to lead for before it takes over.
+    pub switch_frames: u32,
+    pub draw: bool,
+    /// Style of the winning zone. The others are drawn in red.
+    pub style: OverlayStyle,
+    masks: Vec<Vec<u32>>,
+    mask_size: (u32, u32),
+    current: Option<usize>,
+    challenger: Option<(usize, u32)>,
+    result: Option<ZoneResult>,
+}
+
+impl ZoneClassifierPipeline {
+    pub fn new(zones: Vec<Zone>, criterion: ColorCriterion) -> Self {
+        ZoneClassifierPipeline {
+            zones,
+            criterion,
+            metric: ZoneMetric::MatchingFraction,
+            hysteresis: 0.05,
+            switch_frames: 3,
+            draw: true,
+            style: OverlayStyle::default(),
+            masks: Vec::new(),
+            mask_size: (0, 0),
+            current: None,
+            challenger: None,
+            result: None,
+        }
+    }
+
+    /// The latest classification, if any frame has been processed.
+    pub fn result(&self) -> Option<&ZoneResult> {
+        self.result.as_ref()
+    }
+
+    /// Forgets the current winner, e.g. at the start of a new match.
+    pub fn reset(&mut self) {
+        self.current = None;
+        self.challenger = None;
+        self.result = None;
+    }
+
+    // Pixel indices inside each zone, rebuilt whenever the frame size changes
+    fn update_masks(&mut self, width: u32, height: u32) {
+        if self.mask_size == (width, height) && self.masks.len() == self.zones.len() {
+            return;
+        }
+        self.masks = self.zones.iter().map(|zone| {
+            let mut mask = GrayImage::new(width, height);
+            if zone.polygon.len() >= 3 {
+                draw_polygon_mut(&mut mask, &zone.polygon, Luma([255]));
+            }
+            mask.enumerate_pixels()
+                .filter(|(_, _, p)| p[0] != 0)
+                .map(|(x, y, _)| y * width + x)
+                .collect()
+        }).collect();
+        self.mask_size = (width, height);
+    }
+
+    fn score(&self, input: &Image<Rgb<u8>>) -> Vec<ZoneScore> {
+        let width = input.width();
+        self.masks.iter().map(|indices| {
+            if indices.is_empty() {
+                return ZoneScore { fraction: 0.0, mean_distance: 1.0 };
+            }
+            let mut matching = 0;
+            let mut distance = 0.0;
+            for index in indices {
+                let (matches, d) = self.criterion.evaluate(input.get_pixel(index % width, index / width));
+                if matches {
+                    matching += 1;
+                }
+                distance += d;
+            }
+            ZoneScore {
+                fraction: matching as f32 / indices.len() as f32,
+                mean_distance: distance / indices.len() as f32,
+            }
+        }).collect()
+    }
+
+    // Only hand the win to another zone once it has led by the hysteresis margin for long enough
+    fn choose(&mut self, scores: &[ZoneScore]) -> usize {
+        let values: Vec<f32> = scores.iter().map(|s| s.value(self.metric)).collect();
+        let best = (0..values.len()).max_by(|a, b| values[*a].partial_cmp(&values[*b]).unwrap()).unwrap_or(0);
+        let current = match self.current {
+            Some(current) if current < values.len() => current,
+            _ => {
+                self.current = Some(best);
+                return best;
+            }
+        };
+        if best == current || values[best] < values[current] + self.hysteresis {
+            self.challenger = None;
+            return current;
+        }
+        let frames = match self.challenger {
+            Some((zone, frames)) if zone == best => frames + 1,
+            _ => 1,
+        };
+        if frames >= self.switch_frames {
+            self.current = Some(best);
+            self.challenger = None;
+            best
+        } else {
+            self.challenger = Some((best, frames));
+            current
+        }
+    }
+
+    // Outlines every zone, labelled with its name and score at the center of its bounding box.
+    fn draw_zones(&self, image: &mut Image<Rgb<u8>>, result: &ZoneResult) {
+        let mut winner = Overlay::new(self.style);
+        let mut others = Overlay::new(OverlayStyle { color: Rgb([255, 0, 0]), ..self.style });
+        for (i, (zone, score)) in self.zones.iter().zip(result.scores.iter()).enumerate() {
+            let overlay = if i == result.zone { &mut winner } else { &mut others };
+            let polygon: Vec<(f32, f32)> = zone.polygon.iter().map(|p| (p.x as f32, p.y as f32)).collect();
+            let (left, right) = polygon.iter().fold((f32::MAX, f32::MIN), |(l, r), p| (l.min(p.0), r.max(p.0)));
+            let (top, bottom) = polygon.iter().fold((f32::MAX, f32::MIN), |(t, b), p| (t.min(p.1), b.max(p.1)));
+            let label = format!("{} {:.2}", zone.name, score.value(self.metric));
+            overlay.polygon(&polygon).label(((left + right) / 2.0, (top + bottom) / 2.0), &label);
+        }
+        others.draw(image);
+        winner.draw(image);
+    }
+}
+
+impl Pipeline for ZoneClassifierPipeline {
+    fn pipeline(&mut self, input: Image<Rgb<u8>>) -> crate::Result<Option<Image<Rgb<u8>>>> {
+        if self.zones.is_empty() {
+            return Err("Zone classifier has no zones".into());
+        }
+        self.update_masks(input.width(), input.height());
+        let scores = self.score(&input);
+        let zone = self.choose(&scores);
+
+        // Measured for the zone reported, which trails a challenger still waiting out hysteresis
+        let values: Vec<f32> = scores.iter().map(|s| s.value(self.metric)).collect();
+        let reported = values[zone];
+        let runner_up = values.iter().enumerate().filter(|(i, _)| *i != zone).map(|(_, v)| *v).reduce(f32::max);
+        let confidence = match runner_up {
+            _ if reported <= 0.0 => 0.0,
+            Some(runner_up) => ((reported - runner_up) / reported).clamp(0.0, 1.0),
+            None => 1.0,
+        };
+        let result = ZoneResult {
+            zone,
+            name: self.zones[zone].name.clone(),
+            confidence,
+            scores,
+        };
+
+        let output = if self.draw {
+            let mut output = input;
+            self.draw_zones(&mut output, &result);
+            Some(output)
+        } else {
+            None
+        };
+        self.result = Some(result);
+        Ok(output)
+    }
+
+    fn output_color_type(&self) -> ColorType {
+        ColorType::Rgb8
+    }
+}
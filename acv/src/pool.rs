@@ -0,0 +1,86 @@
+use image::{GrayImage, Rgb};
+use imageproc::definitions::Image;
+use crate::mask::MaskBuffers;
+use crate::mask::blur::BlurBuffers;
+use crate::stage::Data;
+
+// Buffers kept of each kind; more than a frame's worth means some aren't coming back.
+const MAX_POOLED: usize = 32;
+
+/// Image buffers handed out while processing a frame and given back once its results are done
+/// with, so a pipeline running at a steady frame size stops allocating after the first frame.
+///
+/// Images come out the requested size with whatever the last user left in them.
+#[derive(Debug, Default)]
+pub struct BufferPool {
+    gray: Vec<Vec<u8>>,
+    rgb: Vec<Vec<u8>>,
+    blur: BlurBuffers,
+    mask: MaskBuffers,
+}
+
+impl BufferPool {
+    pub fn new() -> Self {
+        BufferPool::default()
+    }
+
+    pub fn gray(&mut self, width: u32, height: u32) -> GrayImage {
+        let raw = take(&mut self.gray, (width * height) as usize);
+        GrayImage::from_raw(width, height, raw).expect("Pooled buffer is sized to fit")
+    }
+
+    pub fn rgb(&mut self, width: u32, height: u32) -> Image<Rgb<u8>> {
+        let raw = take(&mut self.rgb, (width * height * 3) as usize);
+        Image::from_raw(width, height, raw).expect("Pooled buffer is sized to fit")
+    }
+
+    pub fn recycle_gray(&mut self, image: GrayImage) {
+        if self.gray.len() < MAX_POOLED {
+            self.gray.push(image.into_raw());
+        }
+    }
+
+    pub fn recycle_rgb(&mut self, image: Image<Rgb<u8>>) {
+        if self.rgb.len() < MAX_POOLED {
+            self.rgb.push(image.into_raw());
+        }
+    }
+
+    /// Scratch space for the blurs in [`crate::mask::blur`].
+    pub fn blur_buffers(&mut self) -> &mut BlurBuffers {
+        &mut self.blur
+    }
+
+    /// Scratch space for the operations in [`crate::mask`].
+    pub fn mask_buffers(&mut self) -> &mut MaskBuffers {
+        &mut self.mask
+    }
+
+    /// Takes back the images of a stage result. Other results are dropped.
+    pub fn recycle(&mut self, data: Data) {
+        match data {
+            Data::Rgb(image) => self.recycle_rgb(image),
+            Data::Gray(image) => self.recycle_gray(image),
+            _ => {}
+        }
+    }
+
+    /// Buffers waiting to be reused.
+    pub fn len(&self) -> usize {
+        self.gray.len() + self.rgb.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// A buffer of `len` bytes, preferring one that's already big enough.
+fn take(buffers: &mut Vec<Vec<u8>>, len: usize) -> Vec<u8> {
+    let mut raw = match buffers.iter().position(|b| b.capacity() >= len) {
+        Some(index) => buffers.swap_remove(index),
+        None => buffers.pop().unwrap_or_default(),
+    };
+    raw.resize(len, 0);
+    raw
+}
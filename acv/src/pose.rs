@@ -0,0 +1,266 @@
+use nalgebra::{DVector, Isometry3, Matrix2, Matrix3, Point2, Point3, Rotation3, UnitQuaternion, Vector3};
+use crate::geometry::{self, CameraIntrinsics, EulerAngles};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PoseEstimate {
+    /// Transform from the target frame to the camera frame.
+    pub pose: Isometry3<f64>,
+    /// RMS reprojection error in pixels.
+    pub reprojection_error: f64,
+    /// The other solution of the planar pose ambiguity, with its reprojection error.
+    pub alternate: Option<(Isometry3<f64>, f64)>,
+    /// Ratio of the best to the alternate reprojection error. Close to 1 means the two solutions
+    /// can't be told apart and the rotation shouldn't be trusted.
+    pub ambiguity: f64,
+}
+
+impl PoseEstimate {
+    pub fn translation(&self) -> Vector3<f64> {
+        self.pose.translation.vector
+    }
+
+    pub fn quaternion(&self) -> UnitQuaternion<f64> {
+        self.pose.rotation
+    }
+
+    pub fn euler_angles(&self) -> EulerAngles {
+        EulerAngles::from(&self.pose.rotation)
+    }
+
+    pub fn distance(&self) -> f64 {
+        self.pose.translation.vector.norm()
+    }
+}
+
+// About 3 degrees
+const MIN_CORNER_SINE: f64 = 0.05;
+
+/// Corners of a square target of side `size` in the target frame: x right, y up, z out of the
+/// target. Ordered top-left, top-right, bottom-right, bottom-left.
+pub fn square_target_points(size: f64) -> [Point3<f64>; 4] {
+    let half = size / 2.0;
+    [
+        Point3::new(-half, half, 0.0),
+        Point3::new(half, half, 0.0),
+        Point3::new(half, -half, 0.0),
+        Point3::new(-half, -half, 0.0),
+    ]
+}
+
+/// Estimates the pose of a square tag from its four image corners, ordered as in
+/// [`square_target_points`]. Fails for corners that don't form a convex quadrilateral.
+pub fn estimate_tag_pose(corners: &[Point2<f64>; 4], size: f64, intrinsics: &CameraIntrinsics) -> crate::Result<PoseEstimate> {
+    if corners.iter().any(|p| !p.x.is_finite() || !p.y.is_finite()) {
+        return Err("Tag corners aren't finite".into());
+    }
+    // Sine of each corner's angle, signed by its turn. A square seen from in front of the camera
+    // turns the same way at every corner and none of its corners is close to flat.
+    let sines: Vec<f64> = (0..4).map(|i| {
+        let before = corners[i] - corners[(i + 3) % 4];
+        let after = corners[(i + 1) % 4] - corners[i];
+        before.perp(&after) / (before.norm() * after.norm())
+    }).collect();
+    let same_turn = sines.iter().all(|s| *s > 0.0) || sines.iter().all(|s| *s < 0.0);
+    if !same_turn || sines.iter().any(|s| !s.is_finite() || s.abs() < MIN_CORNER_SINE) {
+        return Err("Degenerate tag corners".into());
+    }
+    estimate_planar_pose(&square_target_points(size), corners, intrinsics)
+}
+
+/// Estimates the pose of a planar target (all object points with z = 0) from at least four
+/// correspondences. The initial solutions come from decomposing the homography (IPPE) and are
+/// then refined by minimizing the reprojection error.
+pub fn estimate_planar_pose(object: &[Point3<f64>], image: &[Point2<f64>], intrinsics: &CameraIntrinsics) -> crate::Result<PoseEstimate> {
+    if object.len() != image.len() || object.len() < 4 {
+        return Err("Planar pose estimation needs at least four correspondences".into());
+    }
+    if object.iter().any(|p| p.z.abs() > 1e-9) {
+        return Err("Planar pose estimation needs object points with z = 0".into());
+    }
+
+    // IPPE expects the object origin at the centroid of the points
+    let n = object.len() as f64;
+    let centroid = Vector3::new(
+        object.iter().map(|p| p.x).sum::<f64>() / n,
+        object.iter().map(|p| p.y).sum::<f64>() / n,
+        0.0,
+    );
+    let centered: Vec<Point2<f64>> = object.iter().map(|p| Point2::new(p.x - centroid.x, p.y - centroid.y)).collect();
+    let normalized: Vec<Point2<f64>> = image.iter().map(|p| intrinsics.normalize(p)).collect();
+    let h = geometry::find_homography(&centered, &normalized).ok_or("Degenerate target corners")?;
+
+    let j = Matrix2::new(
+        h[(0, 0)] - h[(2, 0)] * h[(0, 2)], h[(0, 1)] - h[(2, 1)] * h[(0, 2)],
+        h[(1, 0)] - h[(2, 0)] * h[(1, 2)], h[(1, 1)] - h[(2, 1)] * h[(1, 2)],
+    );
+    let (r1, r2) = ippe_rotations(&j, h[(0, 2)], h[(1, 2)]);
+
+    let mut solutions: Vec<(Isometry3<f64>, f64)> = [r1, r2].iter().map(|r| {
+        let t = planar_translation(r, &centered, &normalized);
+        // Move the origin back from the centroid to the target frame
+        let rotation = UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(*r));
+        let translation = t - rotation * centroid;
+        let initial = Isometry3::from_parts(translation.into(), rotation);
+        refine_pose(object, image, intrinsics, &initial)
+    }).collect();
+    solutions.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    let (pose, reprojection_error) = solutions[0];
+    if pose.translation.vector.z <= 0.0 {
+        return Err("Target is behind the camera".into());
+    }
+    let alternate = solutions[1];
+    let ambiguity = if alternate.1 > 0.0 { reprojection_error / alternate.1 } else { 1.0 };
+    Ok(PoseEstimate {
+        pose,
+        reprojection_error,
+        alternate: Some(alternate),
+        ambiguity,
+    })
+}
+
+/// Refines a pose estimate with Levenberg-Marquardt, returning the refined pose and its RMS
+/// reprojection error in pixels.
+pub fn refine_pose(object: &[Point3<f64>], image: &[Point2<f64>], intrinsics: &CameraIntrinsics, initial: &Isometry3<f64>) -> (Isometry3<f64>, f64) {
+    let initial = DVector::from_row_slice(&geometry::isometry_to_params(initial));
+    let params = geometry::levenberg_marquardt(initial, 50, |params| {
+        let pose = geometry::isometry_from_params(params.as_slice());
+        reprojection_residuals(object, image, intrinsics, &pose)
+    });
+    let pose = geometry::isometry_from_params(params.as_slice());
+    let error = reprojection_error(object, image, intrinsics, &pose);
+    (pose, error)
+}
+
+/// RMS distance in pixels between `image` and `object` projected through `pose`.
+pub fn reprojection_error(object: &[Point3<f64>], image: &[Point2<f64>], intrinsics: &CameraIntrinsics, pose: &Isometry3<f64>) -> f64 {
+    let residuals = reprojection_residuals(object, image, intrinsics, pose);
+    (residuals.norm_squared() / object.len() as f64).sqrt()
+}
+
+fn reprojection_residuals(object: &[Point3<f64>], image: &[Point2<f64>], intrinsics: &CameraIntrinsics, pose: &Isometry3<f64>) -> DVector<f64> {
+    let mut residuals = DVector::zeros(object.len() * 2);
+    for (i, (o, p)) in object.iter().zip(image).enumerate() {
+        let projected = intrinsics.project(&(pose * o));
+        residuals[2 * i] = projected.x - p.x;
+        residuals[2 * i + 1] = projected.y - p.y;
+    }
+    residuals
+}
+
+// Infinitesimal Plane-based Pose Estimation (Collins & Bartoli, 2014). `j` is the Jacobian of the
+// homography at the origin and (p, q) is where the origin projects in normalized coordinates.
+fn ippe_rotations(j: &Matrix2<f64>, p: f64, q: f64) -> (Matrix3<f64>, Matrix3<f64>) {
+    // Rotation that takes the ray through (p, q) onto the optical axis
+    let t = (p * p + q * q).sqrt();
+    let rv = if t < f64::EPSILON {
+        Matrix3::identity()
+    } else {
+        let s = (p * p + q * q + 1.0).sqrt();
+        let cos = 1.0 / s;
+        let sin = (1.0 - 1.0 / (s * s)).sqrt();
+        let k = Matrix3::new(
+            0.0, 0.0, p / t,
+            0.0, 0.0, q / t,
+            -p / t, -q / t, 0.0,
+        );
+        Matrix3::identity() + k * sin + k * k * (1.0 - cos)
+    };
+
+    let b = Matrix2::new(
+        rv[(0, 0)] - p * rv[(2, 0)], rv[(0, 1)] - p * rv[(2, 1)],
+        rv[(1, 0)] - q * rv[(2, 0)], rv[(1, 1)] - q * rv[(2, 1)],
+    );
+    let a = b.try_inverse().unwrap_or_else(Matrix2::identity) * j;
+
+    // Largest singular value of A
+    let sum = a.norm_squared();
+    let det = a.determinant();
+    let gamma = (0.5 * (sum + (sum * sum - 4.0 * det * det).max(0.0).sqrt())).sqrt();
+
+    let r22 = a / gamma;
+    let h = Matrix2::identity() - r22.transpose() * r22;
+    let b0 = h[(0, 0)].max(0.0).sqrt();
+    let mut b1 = h[(1, 1)].max(0.0).sqrt();
+    if h[(0, 1)] < 0.0 {
+        b1 = -b1;
+    }
+    let c = Vector3::new(r22[(0, 0)], r22[(1, 0)], b0).cross(&Vector3::new(r22[(0, 1)], r22[(1, 1)], b1));
+
+    let first = Matrix3::new(
+        r22[(0, 0)], r22[(0, 1)], c.x,
+        r22[(1, 0)], r22[(1, 1)], c.y,
+        b0, b1, c.z,
+    );
+    let second = Matrix3::new(
+        r22[(0, 0)], r22[(0, 1)], -c.x,
+        r22[(1, 0)], r22[(1, 1)], -c.y,
+        -b0, -b1, c.z,
+    );
+    (rv * first, rv * second)
+}
+
+// Least-squares translation for a known rotation, from points in normalized image coordinates
+fn planar_translation(rotation: &Matrix3<f64>, object: &[Point2<f64>], normalized: &[Point2<f64>]) -> Vector3<f64> {
+    let mut ata = Matrix3::zeros();
+    let mut atb = Vector3::zeros();
+    for (o, u) in object.iter().zip(normalized) {
+        let rotated = rotation * Vector3::new(o.x, o.y, 0.0);
+        let rows = [
+            (Vector3::new(1.0, 0.0, -u.x), u.x * rotated.z - rotated.x),
+            (Vector3::new(0.0, 1.0, -u.y), u.y * rotated.z - rotated.y),
+        ];
+        for (row, rhs) in rows.iter() {
+            ata += row * row.transpose();
+            atb += row * *rhs;
+        }
+    }
+    ata.try_inverse().map(|inverse| inverse * atb).unwrap_or_else(Vector3::zeros)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intrinsics() -> CameraIntrinsics {
+        CameraIntrinsics::new(600.0, 600.0, 320.0, 240.0)
+    }
+
+    #[test]
+    fn recovers_tag_pose() {
+        let pose = Isometry3::new(Vector3::new(0.1, -0.05, 1.0), Vector3::new(0.3, -0.2, 0.1));
+        let corners = square_target_points(0.2).map(|p| intrinsics().project(&(pose * p)));
+        let estimate = estimate_tag_pose(&corners, 0.2, &intrinsics()).unwrap();
+        assert!(estimate.reprojection_error < 1e-6);
+        assert!((estimate.translation() - pose.translation.vector).norm() < 1e-6);
+    }
+
+    #[test]
+    fn rejects_non_finite_corners() {
+        let mut corners = square_target_points(0.2).map(|p| intrinsics().project(&(p + Vector3::z())));
+        corners[2].x = f64::NAN;
+        assert!(estimate_tag_pose(&corners, 0.2, &intrinsics()).is_err());
+        corners[2].x = f64::INFINITY;
+        assert!(estimate_tag_pose(&corners, 0.2, &intrinsics()).is_err());
+    }
+
+    #[test]
+    fn rejects_degenerate_corners() {
+        let nearly_collinear = [
+            Point2::new(100.0, 100.0),
+            Point2::new(200.0, 100.0),
+            Point2::new(300.0, 102.0),
+            Point2::new(100.0, 200.0),
+        ];
+        assert!(estimate_tag_pose(&nearly_collinear, 0.2, &intrinsics()).is_err());
+        let crossed = [
+            Point2::new(100.0, 100.0),
+            Point2::new(200.0, 100.0),
+            Point2::new(100.0, 200.0),
+            Point2::new(200.0, 200.0),
+        ];
+        assert!(estimate_tag_pose(&crossed, 0.2, &intrinsics()).is_err());
+        let collapsed = [Point2::new(100.0, 100.0); 4];
+        assert!(estimate_tag_pose(&collapsed, 0.2, &intrinsics()).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use image::{Pixel, Rgb};
+use imageproc::definitions::Image;
+use nalgebra::Point2;
+use crate::aruco::ArucoMarker;
+use crate::barcode::DecodedCode;
+use crate::detection::{BoundingBox, Detection};
+use crate::pipeline::motion::MotionBlob;
+
+/// How to shrink a frame before processing.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Downscale {
+    None,
+    /// Averages each `factor` x `factor` block into one pixel.
+    Area(u32),
+    /// Halves the frame `levels` times, smoothing before each halving.
+    Pyramid(u32),
+}
+
+impl Downscale {
+    pub fn factor(&self) -> u32 {
+        match *self {
+            Downscale::None => 1,
+            Downscale::Area(factor) => factor.max(1),
+            Downscale::Pyramid(levels) => 1 << levels,
+        }
+    }
+
+    pub fn apply(&self, image: &Image<Rgb<u8>>) -> Image<Rgb<u8>> {
+        match *self {
+            Downscale::None => image.clone(),
+            Downscale::Area(factor) => downscale_area(image, factor.max(1)),
+            Downscale::Pyramid(levels) => (0..levels).fold(image.clone(), |image, _| pyramid_down(&image)),
+        }
+    }
+}
+
+/// Each `factor` x `factor` block averaged into one pixel. Partial blocks at the right and
+/// bottom edges are dropped.
+pub fn downscale_area(image: &Image<Rgb<u8>>, factor: u32) -> Image<Rgb<u8>> {
+    let (width, height) = (image.width() / factor, image.height() / factor);
+    let count = factor * factor;
+    Image::from_fn(width, height, |x, y| {
+        let mut sum = [0u32; 3];
+        for dy in 0..factor {
+            for dx in 0..factor {
+                let pixel = image.get_pixel(x * factor + dx, y * factor + dy);
+                for (s, c) in sum.iter_mut().zip(pixel.channels()) {
+                    *s += *c as u32;
+                }
+            }
+        }
+        Rgb(sum.map(|s| ((s + count / 2) / count) as u8))
+    })
+}
+
+/// Half the size, each pixel a 4x4 binomial average of the pixels around its 2x2 block, so pixel
+/// centers line up the same way as [`downscale_area`] with a factor of 2.
+pub fn pyramid_down(image: &Image<Rgb<u8>>) -> Image<Rgb<u8>> {
+    const WEIGHTS: [u32; 4] = [1, 3, 3, 1];
+    let (width, height) = (image.width() / 2, image.height() / 2);
+    let clamp = |v: i64, max: u32| v.clamp(0, max as i64 - 1) as u32;
+    Image::from_fn(width, height, |x, y| {
+        let mut sum = [0u32; 3];
+        for (j, wy) in WEIGHTS.iter().enumerate() {
+            let sy = clamp(2 * y as i64 + j as i64 - 1, image.height());
+            for (i, wx) in WEIGHTS.iter().enumerate() {
+                let sx = clamp(2 * x as i64 + i as i64 - 1, image.width());
+                for (s, c) in sum.iter_mut().zip(image.get_pixel(sx, sy).channels()) {
+                    *s += wx * wy * *c as u32;
+                }
+            }
+        }
+        Rgb(sum.map(|s| ((s + 32) / 64) as u8))
+    })
+}
+
+/// Maps pixel coordinates in a cropped and downscaled image back to the full frame.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FrameTransform {
+    /// Top left of the crop in the frame.
+    pub offset: (f32, f32),
+    /// Frame pixels per processed pixel.
+    pub scale: f32,
+}
+
+impl FrameTransform {
+    pub fn identity() -> Self {
+        FrameTransform { offset: (0.0, 0.0), scale: 1.0 }
+    }
+
+    /// A position where pixel `(i, j)` covers `i..i + 1` and `j..j + 1`.
+    pub fn point(&self, (x, y): (f32, f32)) -> (f32, f32) {
+        (self.offset.0 + x * self.scale, self.offset.1 + y * self.scale)
+    }
+
+    /// A position where pixel `(i, j)` is centered on `(i, j)`, as with centroids of pixel
+    /// indices and corners found on image gradients.
+    pub fn pixel_center(&self, (x, y): (f32, f32)) -> (f32, f32) {
+        let half = (self.scale - 1.0) / 2.0;
+        (self.offset.0 + x * self.scale + half, self.offset.1 + y * self.scale + half)
+    }
+
+    /// A length or width.
+    pub fn length(&self, length: f32) -> f32 {
+        length * self.scale
+    }
+
+    /// An area in pixels.
+    pub fn area(&self, area: f32) -> f32 {
+        area * self.scale * self.scale
+    }
+
+    /// A box covering whole pixels, like [`FrameTransform::point`].
+    pub fn bbox(&self, bbox: &BoundingBox) -> BoundingBox {
+        let (x, y) = self.point((bbox.x, bbox.y));
+        BoundingBox::new(x, y, bbox.width * self.scale, bbox.height * self.scale)
+    }
+}
+
+/// Results that can be moved from processed image coordinates back to the full frame.
+pub trait MapToFrame {
+    fn map_to_frame(&self, transform: &FrameTransform) -> Self;
+}
+
+/// Points with pixel centers at integer coordinates, like the rest of the geometry code.
+impl MapToFrame for Point2<f64> {
+    fn map_to_frame(&self, transform: &FrameTransform) -> Self {
+        let (x, y) = transform.pixel_center((self.x as f32, self.y as f32));
+        Point2::new(x as f64, y as f64)
+    }
+}
+
+impl MapToFrame for BoundingBox {
+    fn map_to_frame(&self, transform: &FrameTransform) -> Self {
+        transform.bbox(self)
+    }
+}
+
+impl MapToFrame for Detection {
+    fn map_to_frame(&self, transform: &FrameTransform) -> Self {
+        Detection { bbox: transform.bbox(&self.bbox), ..*self }
+    }
+}
+
+impl MapToFrame for DecodedCode {
+    fn map_to_frame(&self, transform: &FrameTransform) -> Self {
+        DecodedCode { corners: self.corners.map(|c| transform.point(c)), ..self.clone() }
+    }
+}
+
+impl MapToFrame for ArucoMarker {
+    fn map_to_frame(&self, transform: &FrameTransform) -> Self {
+        ArucoMarker { corners: self.corners.map(|c| c.map_to_frame(transform)), ..self.clone() }
+    }
+}
+
+impl MapToFrame for MotionBlob {
+    fn map_to_frame(&self, transform: &FrameTransform) -> Self {
+        MotionBlob {
+            bbox: transform.bbox(&self.bbox),
+            centroid: transform.pixel_center(self.centroid),
+            area: transform.area(self.area as f32).round() as u32,
+        }
+    }
+}
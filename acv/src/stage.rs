@@ -0,0 +1,86 @@
+use image::{GrayImage, Rgb};
+use imageproc::contours::Contour;
+use imageproc::definitions::Image;
+use crate::detection::Detection;
+use crate::pool::BufferPool;
+
+pub mod contours;
+pub mod filters;
+pub mod normalize;
+
+/// What flows between the stages of a [`crate::pipeline::graph::StagePipeline`].
+#[derive(Debug)]
+pub enum Data {
+    Rgb(Image<Rgb<u8>>),
+    /// A grayscale image, or a mask with 255 for set pixels and 0 elsewhere.
+    Gray(GrayImage),
+    Contours(Vec<Contour<i32>>),
+    Detections(Vec<Detection>),
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DataType {
+    Rgb,
+    Gray,
+    Contours,
+    Detections,
+}
+
+impl Data {
+    pub fn data_type(&self) -> DataType {
+        match self {
+            Data::Rgb(_) => DataType::Rgb,
+            Data::Gray(_) => DataType::Gray,
+            Data::Contours(_) => DataType::Contours,
+            Data::Detections(_) => DataType::Detections,
+        }
+    }
+
+    pub fn as_rgb(&self) -> Option<&Image<Rgb<u8>>> {
+        match self {
+            Data::Rgb(image) => Some(image),
+            _ => None,
+        }
+    }
+
+    pub fn as_gray(&self) -> Option<&GrayImage> {
+        match self {
+            Data::Gray(image) => Some(image),
+            _ => None,
+        }
+    }
+
+    pub fn as_contours(&self) -> Option<&[Contour<i32>]> {
+        match self {
+            Data::Contours(contours) => Some(contours),
+            _ => None,
+        }
+    }
+
+    pub fn as_detections(&self) -> Option<&[Detection]> {
+        match self {
+            Data::Detections(detections) => Some(detections),
+            _ => None,
+        }
+    }
+}
+
+/// One step of a stage graph. Stages can keep state between frames, and can be run on their own
+/// by passing them [`Data`] directly.
+pub trait Stage {
+    /// What the stage makes from an input of type `input`, or `None` if it doesn't take it.
+    fn output_type(&self, input: DataType) -> Option<DataType>;
+
+    fn run(&mut self, input: &Data) -> crate::Result<Data>;
+
+    /// [`Stage::run`] taking its output images from `pool`, which the graph pipeline refills with
+    /// the last frame's results. Stages that make images override it.
+    fn run_pooled(&mut self, input: &Data, pool: &mut BufferPool) -> crate::Result<Data> {
+        let _ = pool;
+        self.run(input)
+    }
+}
+
+pub(crate) fn unsupported_input(stage: &str, input: &Data) -> crate::Error {
+    format!("{} can't take {:?} input", stage, input.data_type()).into()
+}
@@ -0,0 +1,72 @@
+use imageproc::contours::{find_contours, BorderType, Contour};
+use crate::detection::{BoundingBox, Detection};
+use crate::stage::{unsupported_input, Data, DataType, Stage};
+
+/// Traces the borders of the set regions of a mask.
+pub struct FindContours {
+    /// Whether to keep only outer borders and drop the borders of holes.
+    pub outer_only: bool,
+}
+
+impl Stage for FindContours {
+    fn output_type(&self, input: DataType) -> Option<DataType> {
+        (input == DataType::Gray).then_some(DataType::Contours)
+    }
+
+    fn run(&mut self, input: &Data) -> crate::Result<Data> {
+        let mask = input.as_gray().ok_or_else(|| unsupported_input("FindContours", input))?;
+        let mut contours = find_contours::<i32>(mask);
+        if self.outer_only {
+            contours.retain(|c| c.border_type == BorderType::Outer);
+        }
+        Ok(Data::Contours(contours))
+    }
+}
+
+/// Turns the outer contours enclosing at least `min_area` pixels into detections of `class_id`,
+/// scored by how much of their bounding box they fill.
+pub struct ContourBoxes {
+    pub min_area: f32,
+    pub class_id: u32,
+}
+
+impl Stage for ContourBoxes {
+    fn output_type(&self, input: DataType) -> Option<DataType> {
+        (input == DataType::Contours).then_some(DataType::Detections)
+    }
+
+    fn run(&mut self, input: &Data) -> crate::Result<Data> {
+        let contours = input.as_contours().ok_or_else(|| unsupported_input("ContourBoxes", input))?;
+        let mut detections: Vec<Detection> = contours.iter()
+            .filter(|c| c.border_type == BorderType::Outer && !c.points.is_empty())
+            .filter_map(|c| {
+                let bbox = bounding_box(c);
+                let area = enclosed_area(c);
+                (area >= self.min_area).then(|| Detection::new(bbox, self.class_id, area / bbox.area()))
+            })
+            .collect();
+        detections.sort_by(|a, b| b.bbox.area().partial_cmp(&a.bbox.area()).unwrap());
+        Ok(Data::Detections(detections))
+    }
+}
+
+// The box covering every pixel of the contour.
+fn bounding_box(contour: &Contour<i32>) -> BoundingBox {
+    let min_x = contour.points.iter().map(|p| p.x).min().unwrap_or(0);
+    let max_x = contour.points.iter().map(|p| p.x).max().unwrap_or(0);
+    let min_y = contour.points.iter().map(|p| p.y).min().unwrap_or(0);
+    let max_y = contour.points.iter().map(|p| p.y).max().unwrap_or(0);
+    BoundingBox::new(min_x as f32, min_y as f32, (max_x - min_x + 1) as f32, (max_y - min_y + 1) as f32)
+}
+
+// Pixels inside and on the contour, by Pick's theorem on the polygon through the border pixels.
+fn enclosed_area(contour: &Contour<i32>) -> f32 {
+    let points = &contour.points;
+    let twice_area: i64 = (0..points.len())
+        .map(|i| {
+            let (p, q) = (points[i], points[(i + 1) % points.len()]);
+            p.x as i64 * q.y as i64 - q.x as i64 * p.y as i64
+        })
+        .sum();
+    twice_area.abs() as f32 / 2.0 + points.len() as f32 / 2.0 + 1.0
+}
@@ -0,0 +1,291 @@
+use image::{GrayImage, Rgb};
+use imageproc::contrast::otsu_level;
+use imageproc::definitions::Image;
+use crate::mask::{close_into, dilate_into, erode_into, fill_holes_into, open_into, StructuringElement};
+use crate::mask::blur::{gaussian_blur_into, median_blur_into};
+use crate::parallel::par_bands;
+use crate::pool::BufferPool;
+use crate::stage::{unsupported_input, Data, DataType, Stage};
+use crate::util::{in_range_hsv, in_range_rgb, in_range_ycrcb, rgb_to_gray_into, Hsv, YCrCb};
+
+/// Gaussian blur of an RGB or gray image.
+pub struct GaussianBlur {
+    pub sigma: f32,
+}
+
+impl Stage for GaussianBlur {
+    fn output_type(&self, input: DataType) -> Option<DataType> {
+        match input {
+            DataType::Rgb | DataType::Gray => Some(input),
+            _ => None,
+        }
+    }
+
+    fn run(&mut self, input: &Data) -> crate::Result<Data> {
+        self.run_pooled(input, &mut BufferPool::new())
+    }
+
+    fn run_pooled(&mut self, input: &Data, pool: &mut BufferPool) -> crate::Result<Data> {
+        match input {
+            Data::Rgb(image) => Ok(Data::Rgb(blur_channels(image, pool, |plane, output, pool| {
+                gaussian_blur_into(plane, self.sigma, pool.blur_buffers(), output);
+            }))),
+            Data::Gray(image) => {
+                let mut output = pool.gray(image.width(), image.height());
+                gaussian_blur_into(image, self.sigma, pool.blur_buffers(), &mut output);
+                Ok(Data::Gray(output))
+            }
+            _ => Err(unsupported_input("GaussianBlur", input)),
+        }
+    }
+}
+
+/// Median of the square window of `radius` around each pixel, per channel. Removes salt and
+/// pepper noise while keeping edges.
+pub struct MedianBlur {
+    pub radius: u32,
+}
+
+impl Stage for MedianBlur {
+    fn output_type(&self, input: DataType) -> Option<DataType> {
+        match input {
+            DataType::Rgb | DataType::Gray => Some(input),
+            _ => None,
+        }
+    }
+
+    fn run(&mut self, input: &Data) -> crate::Result<Data> {
+        self.run_pooled(input, &mut BufferPool::new())
+    }
+
+    fn run_pooled(&mut self, input: &Data, pool: &mut BufferPool) -> crate::Result<Data> {
+        match input {
+            Data::Rgb(image) => Ok(Data::Rgb(blur_channels(image, pool, |plane, output, _| {
+                median_blur_into(plane, self.radius, output);
+            }))),
+            Data::Gray(image) => {
+                let mut output = pool.gray(image.width(), image.height());
+                median_blur_into(image, self.radius, &mut output);
+                Ok(Data::Gray(output))
+            }
+            _ => Err(unsupported_input("MedianBlur", input)),
+        }
+    }
+}
+
+// Blurs each channel of `image` on its own with `blur`, taking the planes and the output from
+// `pool`.
+fn blur_channels<F>(image: &Image<Rgb<u8>>, pool: &mut BufferPool, blur: F) -> Image<Rgb<u8>>
+where
+    F: Fn(&GrayImage, &mut GrayImage, &mut BufferPool),
+{
+    let (width, height) = image.dimensions();
+    let row = width as usize;
+    let mut output = pool.rgb(width, height);
+    let (mut plane, mut blurred) = (pool.gray(width, height), pool.gray(width, height));
+    for channel in 0..3 {
+        par_bands(image.as_raw(), row * 3, &mut plane, row, row, |_, src, dst| {
+            for (d, s) in dst.iter_mut().zip(src.chunks_exact(3)) {
+                *d = s[channel];
+            }
+        });
+        blur(&plane, &mut blurred, pool);
+        par_bands(blurred.as_raw(), row, &mut output, row * 3, row, |_, src, dst| {
+            for (d, s) in dst.chunks_exact_mut(3).zip(src) {
+                d[channel] = *s;
+            }
+        });
+    }
+    pool.recycle_gray(plane);
+    pool.recycle_gray(blurred);
+    output
+}
+
+/// Luma of an RGB image, weighted like YCrCb's Y and OpenCV's gray.
+pub struct Grayscale;
+
+impl Stage for Grayscale {
+    fn output_type(&self, input: DataType) -> Option<DataType> {
+        (input == DataType::Rgb).then_some(DataType::Gray)
+    }
+
+    fn run(&mut self, input: &Data) -> crate::Result<Data> {
+        self.run_pooled(input, &mut BufferPool::new())
+    }
+
+    fn run_pooled(&mut self, input: &Data, pool: &mut BufferPool) -> crate::Result<Data> {
+        let image = input.as_rgb().ok_or_else(|| unsupported_input("Grayscale", input))?;
+        let mut gray = pool.gray(image.width(), image.height());
+        rgb_to_gray_into(image, &mut gray);
+        Ok(Data::Gray(gray))
+    }
+}
+
+/// Masks the pixels of an RGB image inside an HSV range.
+pub struct HsvThreshold {
+    pub lower: Hsv,
+    pub higher: Hsv,
+}
+
+impl Stage for HsvThreshold {
+    fn output_type(&self, input: DataType) -> Option<DataType> {
+        (input == DataType::Rgb).then_some(DataType::Gray)
+    }
+
+    fn run(&mut self, input: &Data) -> crate::Result<Data> {
+        self.run_pooled(input, &mut BufferPool::new())
+    }
+
+    fn run_pooled(&mut self, input: &Data, pool: &mut BufferPool) -> crate::Result<Data> {
+        let image = input.as_rgb().ok_or_else(|| unsupported_input("HsvThreshold", input))?;
+        let mut mask = pool.gray(image.width(), image.height());
+        in_range_hsv(image, self.lower, self.higher, &mut mask);
+        Ok(Data::Gray(mask))
+    }
+}
+
+/// Masks the pixels of an RGB image inside an RGB range.
+pub struct RgbThreshold {
+    pub lower: Rgb<u8>,
+    pub higher: Rgb<u8>,
+}
+
+impl Stage for RgbThreshold {
+    fn output_type(&self, input: DataType) -> Option<DataType> {
+        (input == DataType::Rgb).then_some(DataType::Gray)
+    }
+
+    fn run(&mut self, input: &Data) -> crate::Result<Data> {
+        self.run_pooled(input, &mut BufferPool::new())
+    }
+
+    fn run_pooled(&mut self, input: &Data, pool: &mut BufferPool) -> crate::Result<Data> {
+        let image = input.as_rgb().ok_or_else(|| unsupported_input("RgbThreshold", input))?;
+        let mut mask = pool.gray(image.width(), image.height());
+        in_range_rgb(image, self.lower, self.higher, &mut mask);
+        Ok(Data::Gray(mask))
+    }
+}
+
+/// Masks the pixels of an RGB image inside a YCrCb range. Less sensitive to brightness than RGB,
+/// and with no hue wrap-around to deal with.
+pub struct YCrCbThreshold {
+    pub lower: YCrCb,
+    pub higher: YCrCb,
+}
+
+impl Stage for YCrCbThreshold {
+    fn output_type(&self, input: DataType) -> Option<DataType> {
+        (input == DataType::Rgb).then_some(DataType::Gray)
+    }
+
+    fn run(&mut self, input: &Data) -> crate::Result<Data> {
+        self.run_pooled(input, &mut BufferPool::new())
+    }
+
+    fn run_pooled(&mut self, input: &Data, pool: &mut BufferPool) -> crate::Result<Data> {
+        let image = input.as_rgb().ok_or_else(|| unsupported_input("YCrCbThreshold", input))?;
+        let mut mask = pool.gray(image.width(), image.height());
+        in_range_ycrcb(image, self.lower, self.higher, &mut mask);
+        Ok(Data::Gray(mask))
+    }
+}
+
+/// Masks the pixels of a gray image brighter than `level`, or than the Otsu level of each frame
+/// if it's `None`.
+pub struct Threshold {
+    pub level: Option<u8>,
+}
+
+impl Stage for Threshold {
+    fn output_type(&self, input: DataType) -> Option<DataType> {
+        (input == DataType::Gray).then_some(DataType::Gray)
+    }
+
+    fn run(&mut self, input: &Data) -> crate::Result<Data> {
+        self.run_pooled(input, &mut BufferPool::new())
+    }
+
+    fn run_pooled(&mut self, input: &Data, pool: &mut BufferPool) -> crate::Result<Data> {
+        let image = input.as_gray().ok_or_else(|| unsupported_input("Threshold", input))?;
+        let level = self.level.unwrap_or_else(|| otsu_level(image));
+        let mut mask = pool.gray(image.width(), image.height());
+        let width = image.width() as usize;
+        par_bands(image.as_raw(), width, &mut mask, width, width, |_, src, dst| {
+            for (s, d) in src.iter().zip(dst.iter_mut()) {
+                *d = if *s > level { 255 } else { 0 };
+            }
+        });
+        Ok(Data::Gray(mask))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MorphologyOperation {
+    Dilate,
+    Erode,
+    /// Erode then dilate, which drops specks smaller than the radius.
+    Open,
+    /// Dilate then erode, which fills gaps smaller than the radius.
+    Close,
+}
+
+/// Morphology on a mask with any structuring element.
+pub struct Morphology {
+    pub operation: MorphologyOperation,
+    pub element: StructuringElement,
+}
+
+impl Morphology {
+    pub fn new(operation: MorphologyOperation, element: StructuringElement) -> Self {
+        Morphology { operation, element }
+    }
+
+    /// With the square of `radius`.
+    pub fn square(operation: MorphologyOperation, radius: u32) -> Self {
+        Morphology::new(operation, StructuringElement::square(radius))
+    }
+}
+
+impl Stage for Morphology {
+    fn output_type(&self, input: DataType) -> Option<DataType> {
+        (input == DataType::Gray).then_some(DataType::Gray)
+    }
+
+    fn run(&mut self, input: &Data) -> crate::Result<Data> {
+        self.run_pooled(input, &mut BufferPool::new())
+    }
+
+    fn run_pooled(&mut self, input: &Data, pool: &mut BufferPool) -> crate::Result<Data> {
+        let mask = input.as_gray().ok_or_else(|| unsupported_input("Morphology", input))?;
+        let mut output = pool.gray(mask.width(), mask.height());
+        let operation = match self.operation {
+            MorphologyOperation::Dilate => dilate_into,
+            MorphologyOperation::Erode => erode_into,
+            MorphologyOperation::Open => open_into,
+            MorphologyOperation::Close => close_into,
+        };
+        operation(mask, &self.element, pool.mask_buffers(), &mut output);
+        Ok(Data::Gray(output))
+    }
+}
+
+/// Fills the holes in a mask's blobs.
+pub struct FillHoles;
+
+impl Stage for FillHoles {
+    fn output_type(&self, input: DataType) -> Option<DataType> {
+        (input == DataType::Gray).then_some(DataType::Gray)
+    }
+
+    fn run(&mut self, input: &Data) -> crate::Result<Data> {
+        self.run_pooled(input, &mut BufferPool::new())
+    }
+
+    fn run_pooled(&mut self, input: &Data, pool: &mut BufferPool) -> crate::Result<Data> {
+        let mask = input.as_gray().ok_or_else(|| unsupported_input("FillHoles", input))?;
+        let mut output = pool.gray(mask.width(), mask.height());
+        fill_holes_into(mask, pool.mask_buffers(), &mut output);
+        Ok(Data::Gray(output))
+    }
+}
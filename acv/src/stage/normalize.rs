@@ -0,0 +1,91 @@
+use crate::normalize::{equalize_luminance, gray_world, reference_patches, white_patch, Clahe, ColorCorrection, ExposureCorrection, ReferencePatch};
+use crate::stage::{unsupported_input, Data, DataType, Stage};
+
+/// How [`WhiteBalance`] estimates its correction.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WhiteBalanceMethod {
+    GrayWorld,
+    /// Brightest values at this percentile (0 to 1) go to white, e.g. 0.99.
+    WhitePatch(f64),
+    /// Fits a known color card in view.
+    Reference(Vec<ReferencePatch>),
+}
+
+/// Corrects the color of RGB frames before thresholding, so ranges tuned under one venue's
+/// lighting hold up under another's.
+pub struct WhiteBalance {
+    pub method: WhiteBalanceMethod,
+    /// Keep the first correction estimated instead of estimating one every frame, so thresholds
+    /// don't drift as robots move through the view. [`WhiteBalance::reset`] estimates again.
+    pub lock: bool,
+    correction: Option<ColorCorrection>,
+}
+
+impl WhiteBalance {
+    pub fn new(method: WhiteBalanceMethod) -> Self {
+        WhiteBalance { method, lock: false, correction: None }
+    }
+
+    /// The correction applied to the last frame.
+    pub fn correction(&self) -> Option<&ColorCorrection> {
+        self.correction.as_ref()
+    }
+
+    pub fn reset(&mut self) {
+        self.correction = None;
+    }
+}
+
+impl Stage for WhiteBalance {
+    fn output_type(&self, input: DataType) -> Option<DataType> {
+        (input == DataType::Rgb).then_some(DataType::Rgb)
+    }
+
+    fn run(&mut self, input: &Data) -> crate::Result<Data> {
+        let image = input.as_rgb().ok_or_else(|| unsupported_input("WhiteBalance", input))?;
+        let correction = match self.correction {
+            Some(correction) if self.lock => correction,
+            _ => match &self.method {
+                WhiteBalanceMethod::GrayWorld => gray_world(image),
+                WhiteBalanceMethod::WhitePatch(percentile) => white_patch(image, *percentile),
+                WhiteBalanceMethod::Reference(patches) => reference_patches(image, patches)?,
+            },
+        };
+        self.correction = Some(correction);
+        Ok(Data::Rgb(correction.apply(image)))
+    }
+}
+
+/// Equalizes the luma of RGB frames, globally or with CLAHE, keeping their hues.
+pub struct EqualizeLuminance {
+    /// Equalizes tile by tile if set, the whole frame at once otherwise.
+    pub clahe: Option<Clahe>,
+    correction: Option<ExposureCorrection>,
+}
+
+impl EqualizeLuminance {
+    pub fn new(clahe: Option<Clahe>) -> Self {
+        EqualizeLuminance { clahe, correction: None }
+    }
+
+    /// How the last frame's exposure changed.
+    pub fn correction(&self) -> Option<&ExposureCorrection> {
+        self.correction.as_ref()
+    }
+}
+
+impl Stage for EqualizeLuminance {
+    fn output_type(&self, input: DataType) -> Option<DataType> {
+        (input == DataType::Rgb).then_some(DataType::Rgb)
+    }
+
+    fn run(&mut self, input: &Data) -> crate::Result<Data> {
+        let image = input.as_rgb().ok_or_else(|| unsupported_input("EqualizeLuminance", input))?;
+        let (output, correction) = match &self.clahe {
+            Some(clahe) => clahe.apply(image),
+            None => equalize_luminance(image),
+        };
+        self.correction = Some(correction);
+        Ok(Data::Rgb(output))
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+/// Durations of the last `capacity` samples of something that happens every frame.
+#[derive(Clone, Debug)]
+pub struct RollingStats {
+    samples: VecDeque<Duration>,
+    capacity: usize,
+}
+
+impl RollingStats {
+    pub fn new(capacity: usize) -> Self {
+        RollingStats {
+            samples: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn push(&mut self, sample: Duration) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        self.samples.iter().sum::<Duration>() / self.samples.len() as u32
+    }
+
+    /// The sample below which a `fraction` of the samples fall, nearest rank.
+    pub fn percentile(&self, fraction: f64) -> Duration {
+        let mut sorted: Vec<Duration> = self.samples.iter().copied().collect();
+        sorted.sort();
+        match sorted.len() {
+            0 => Duration::ZERO,
+            n => sorted[((fraction.clamp(0.0, 1.0) * n as f64).ceil() as usize).clamp(1, n) - 1],
+        }
+    }
+
+    pub fn max(&self) -> Duration {
+        self.samples.iter().max().copied().unwrap_or(Duration::ZERO)
+    }
+}
+
+/// Where frame time went, over the last `window` frames.
+///
+/// Phases are named when they're first recorded. The camera loop records `capture`, `pipeline`,
+/// `encode` and `output` (which includes encoding), `frame` for the whole loop, and
+/// `stage/<name>` for each stage of a pipeline that reports its stages.
+#[derive(Clone, Debug)]
+pub struct Telemetry {
+    window: usize,
+    phases: Vec<(String, RollingStats)>,
+    frame_times: VecDeque<Instant>,
+    frames: u64,
+    dropped: u64,
+}
+
+impl Telemetry {
+    pub fn new(window: usize) -> Self {
+        Telemetry {
+            window: window.max(2),
+            phases: Vec::new(),
+            frame_times: VecDeque::with_capacity(window),
+            frames: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn record(&mut self, phase: &str, duration: Duration) {
+        match self.phases.iter_mut().find(|(name, _)| name == phase) {
+            Some((_, stats)) => stats.push(duration),
+            None => {
+                let mut stats = RollingStats::new(self.window);
+                stats.push(duration);
+                self.phases.push((phase.to_string(), stats));
+            }
+        }
+    }
+
+    /// Times `f` as `phase`.
+    pub fn time<T, F: FnOnce() -> T>(&mut self, phase: &str, f: F) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.record(phase, start.elapsed());
+        result
+    }
+
+    /// Counts a frame that made it through the loop.
+    pub fn frame_completed(&mut self, at: Instant) {
+        if self.frame_times.len() == self.window {
+            self.frame_times.pop_front();
+        }
+        self.frame_times.push_back(at);
+        self.frames += 1;
+    }
+
+    /// Counts a frame that was lost to a capture, pipeline or output error.
+    pub fn frame_dropped(&mut self) {
+        self.dropped += 1;
+    }
+
+    pub fn phase(&self, name: &str) -> Option<&RollingStats> {
+        self.phases.iter().find(|(phase, _)| phase == name).map(|(_, stats)| stats)
+    }
+
+    /// Completed frames per second over the window; dropped frames don't count.
+    pub fn fps(&self) -> f64 {
+        match (self.frame_times.front(), self.frame_times.back()) {
+            (Some(first), Some(last)) if last > first => {
+                (self.frame_times.len() - 1) as f64 / (*last - *first).as_secs_f64()
+            }
+            _ => 0.0,
+        }
+    }
+
+    pub fn snapshot(&self) -> TelemetrySnapshot {
+        let millis = |d: Duration| d.as_secs_f64() * 1000.0;
+        TelemetrySnapshot {
+            fps: self.fps(),
+            frames: self.frames,
+            dropped_frames: self.dropped,
+            phases: self.phases.iter()
+                .map(|(name, stats)| PhaseStats {
+                    name: name.clone(),
+                    mean_ms: millis(stats.mean()),
+                    p95_ms: millis(stats.percentile(0.95)),
+                    max_ms: millis(stats.max()),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Telemetry::new(120)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PhaseStats {
+    pub name: String,
+    pub mean_ms: f64,
+    pub p95_ms: f64,
+    pub max_ms: f64,
+}
+
+/// Telemetry at one point in time, as sent over the control socket and to outputs. Displays as
+/// one line with the mean, 95th percentile and max of each phase.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TelemetrySnapshot {
+    pub fps: f64,
+    /// Frames completed since start.
+    pub frames: u64,
+    /// Frames lost since start.
+    pub dropped_frames: u64,
+    pub phases: Vec<PhaseStats>,
+}
+
+impl Display for TelemetrySnapshot {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.1} fps, {} frames, {} dropped", self.fps, self.frames, self.dropped_frames)?;
+        for phase in self.phases.iter() {
+            write!(f, "; {} {:.2}/{:.2}/{:.2} ms", phase.name, phase.mean_ms, phase.p95_ms, phase.max_ms)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+use image::imageops::{resize, FilterType};
+use image::GrayImage;
+use crate::detection::BoundingBox;
+
+/// Where a template was found.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TemplateMatch {
+    /// Axis-aligned box around the (possibly scaled and rotated) template.
+    pub bbox: BoundingBox,
+    pub center: (f32, f32),
+    /// The template's corners in the image: top left, top right, bottom right, bottom left.
+    pub corners: [(f32, f32); 4],
+    pub scale: f32,
+    /// Rotation in radians, from the x axis towards y.
+    pub angle: f32,
+    /// Zero-mean normalized cross-correlation, from -1 to 1.
+    pub score: f32,
+}
+
+/// Finds a small fixed-appearance target by zero-mean normalized cross-correlation, over a set of
+/// scales and rotations of the template.
+pub struct TemplateMatcher {
+    /// Matches scoring below this are dropped.
+    pub threshold: f32,
+    /// Template scales to try.
+    pub scales: Vec<f32>,
+    /// Template rotations to try, in radians.
+    pub angles: Vec<f32>,
+    /// Number of times the image is halved for a coarse search before refining at full
+    /// resolution. Zero searches every position at full resolution.
+    pub pyramid_levels: u32,
+    /// Matches overlapping a better match by more than this intersection over union are dropped.
+    pub nms_iou: f32,
+    pub max_matches: usize,
+    template: GrayImage,
+}
+
+// A scaled and rotated copy of the template. Only pixels inside the rotated rectangle take part,
+// stored as offsets in the bounding canvas with their value minus the mean.
+struct Variant {
+    width: u32,
+    height: u32,
+    pixels: Vec<(u32, u32, f32)>,
+    norm: f32,
+    scale: f32,
+    angle: f32,
+}
+
+impl TemplateMatcher {
+    pub fn new(template: GrayImage) -> Self {
+        TemplateMatcher {
+            threshold: 0.8,
+            scales: vec![1.0],
+            angles: vec![0.0],
+            pyramid_levels: 1,
+            nms_iou: 0.3,
+            max_matches: 10,
+            template,
+        }
+    }
+
+    pub fn template(&self) -> &GrayImage {
+        &self.template
+    }
+
+    /// Best matches above the threshold, best first.
+    pub fn find(&self, image: &GrayImage) -> Vec<TemplateMatch> {
+        let mut matches = Vec::new();
+        for &scale in self.scales.iter() {
+            for &angle in self.angles.iter() {
+                matches.extend(self.find_variant(image, scale, angle));
+            }
+        }
+        matches.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+        let mut kept: Vec<TemplateMatch> = Vec::new();
+        for m in matches {
+            if kept.len() >= self.max_matches {
+                break;
+            }
+            if kept.iter().all(|k| k.bbox.iou(&m.bbox) <= self.nms_iou) {
+                kept.push(m);
+            }
+        }
+        kept
+    }
+
+    fn find_variant(&self, image: &GrayImage, scale: f32, angle: f32) -> Vec<TemplateMatch> {
+        let full = match Variant::new(&self.template, scale, angle) {
+            Some(v) => v,
+            None => return Vec::new(),
+        };
+        // Go as coarse as asked, but not so coarse the template loses its detail
+        let mut level = self.pyramid_levels;
+        while level > 0 && (full.width >> level < 8 || full.height >> level < 8) {
+            level -= 1;
+        }
+        let positions = if level == 0 {
+            local_maxima(image, &full, self.threshold)
+        } else {
+            let factor = 1 << level;
+            let coarse_image = resize(image, (image.width() / factor).max(1), (image.height() / factor).max(1), FilterType::Triangle);
+            let coarse = match Variant::new(&self.template, scale / factor as f32, angle) {
+                Some(v) => v,
+                None => return Vec::new(),
+            };
+            // Downsampling blurs away some correlation, so let weaker coarse peaks through
+            let coarse_threshold = self.threshold - 0.2;
+            local_maxima(&coarse_image, &coarse, coarse_threshold).into_iter()
+                .filter_map(|(x, y, _)| refine(image, &full, x * factor, y * factor, factor))
+                .filter(|(_, _, score)| *score >= self.threshold)
+                .collect()
+        };
+        positions.into_iter().map(|(x, y, score)| full.to_match(x, y, score, &self.template)).collect()
+    }
+}
+
+impl Variant {
+    fn new(template: &GrayImage, scale: f32, angle: f32) -> Option<Self> {
+        let (tw, th) = (template.width() as f32 * scale, template.height() as f32 * scale);
+        if tw < 2.0 || th < 2.0 {
+            return None;
+        }
+        let (sin, cos) = angle.sin_cos();
+        let width = (tw * cos.abs() + th * sin.abs()).ceil() as u32;
+        let height = (tw * sin.abs() + th * cos.abs()).ceil() as u32;
+        let (cx, cy) = (width as f32 / 2.0, height as f32 / 2.0);
+
+        let mut pixels = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                // Back into the unrotated, unscaled template
+                let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+                let u = (dx * cos + dy * sin + tw / 2.0) / scale - 0.5;
+                let v = (-dx * sin + dy * cos + th / 2.0) / scale - 0.5;
+                if let Some(value) = bilinear(template, u, v) {
+                    pixels.push((x, y, value));
+                }
+            }
+        }
+        let n = pixels.len() as f32;
+        let mean = pixels.iter().map(|p| p.2).sum::<f32>() / n;
+        for p in pixels.iter_mut() {
+            p.2 -= mean;
+        }
+        let norm = pixels.iter().map(|p| p.2 * p.2).sum::<f32>().sqrt();
+        if norm < 1e-3 {
+            return None;
+        }
+        Some(Variant { width, height, pixels, norm, scale, angle })
+    }
+
+    fn score(&self, image: &GrayImage, x: u32, y: u32) -> f32 {
+        let (mut sum, mut sum_squares, mut cross) = (0.0_f32, 0.0_f32, 0.0_f32);
+        for &(dx, dy, t) in self.pixels.iter() {
+            let value = image.get_pixel(x + dx, y + dy)[0] as f32;
+            sum += value;
+            sum_squares += value * value;
+            cross += t * value;
+        }
+        let variance = sum_squares - sum * sum / self.pixels.len() as f32;
+        if variance < 1e-3 {
+            return 0.0;
+        }
+        cross / (self.norm * variance.sqrt())
+    }
+
+    fn to_match(&self, x: u32, y: u32, score: f32, template: &GrayImage) -> TemplateMatch {
+        let (cx, cy) = (x as f32 + self.width as f32 / 2.0, y as f32 + self.height as f32 / 2.0);
+        let (hw, hh) = (template.width() as f32 * self.scale / 2.0, template.height() as f32 * self.scale / 2.0);
+        let (sin, cos) = self.angle.sin_cos();
+        let corners = [(-hw, -hh), (hw, -hh), (hw, hh), (-hw, hh)]
+            .map(|(u, v)| (cx + u * cos - v * sin, cy + u * sin + v * cos));
+        TemplateMatch {
+            bbox: BoundingBox::new(x as f32, y as f32, self.width as f32, self.height as f32),
+            center: (cx, cy),
+            corners,
+            scale: self.scale,
+            angle: self.angle,
+            score,
+        }
+    }
+}
+
+fn bilinear(image: &GrayImage, x: f32, y: f32) -> Option<f32> {
+    if x < -0.5 || y < -0.5 || x > image.width() as f32 - 0.5 || y > image.height() as f32 - 0.5 {
+        return None;
+    }
+    let (x, y) = (x.clamp(0.0, image.width() as f32 - 1.0), y.clamp(0.0, image.height() as f32 - 1.0));
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(image.width() - 1), (y0 + 1).min(image.height() - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+    let at = |x, y| image.get_pixel(x, y)[0] as f32;
+    let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+    let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+    Some(top * (1.0 - fy) + bottom * fy)
+}
+
+// Positions scoring at least `threshold` that no neighbour beats
+fn local_maxima(image: &GrayImage, variant: &Variant, threshold: f32) -> Vec<(u32, u32, f32)> {
+    if image.width() < variant.width || image.height() < variant.height {
+        return Vec::new();
+    }
+    let (width, height) = (image.width() - variant.width + 1, image.height() - variant.height + 1);
+    let scores: Vec<f32> = (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| variant.score(image, x, y))
+        .collect();
+    let mut maxima = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            let score = scores[(y * width + x) as usize];
+            if score < threshold {
+                continue;
+            }
+            let is_max = (y.saturating_sub(1)..(y + 2).min(height)).all(|ny| {
+                (x.saturating_sub(1)..(x + 2).min(width)).all(|nx| {
+                    let other = scores[(ny * width + nx) as usize];
+                    other < score || (other == score && (nx, ny) >= (x, y))
+                })
+            });
+            if is_max {
+                maxima.push((x, y, score));
+            }
+        }
+    }
+    maxima
+}
+
+// Best full resolution position within `radius` of a coarse estimate
+fn refine(image: &GrayImage, variant: &Variant, x: u32, y: u32, radius: u32) -> Option<(u32, u32, f32)> {
+    if image.width() < variant.width || image.height() < variant.height {
+        return None;
+    }
+    let (max_x, max_y) = (image.width() - variant.width, image.height() - variant.height);
+    let mut best: Option<(u32, u32, f32)> = None;
+    for ny in y.saturating_sub(radius)..=(y + radius).min(max_y) {
+        for nx in x.saturating_sub(radius)..=(x + radius).min(max_x) {
+            let score = variant.score(image, nx, ny);
+            match best {
+                Some((_, _, best_score)) if best_score >= score => {}
+                _ => best = Some((nx, ny, score)),
+            }
+        }
+    }
+    best
+}
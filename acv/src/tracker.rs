@@ -0,0 +1,276 @@
+use nalgebra::{SMatrix, SVector};
+use crate::detection::{BoundingBox, Detection};
+
+type State = SVector<f32, 6>;
+type Covariance = SMatrix<f32, 6, 6>;
+type Measurement = SVector<f32, 4>;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TrackerConfig {
+    /// Detections overlapping a track's prediction by less than this can't be matched to it.
+    pub min_iou: f32,
+    /// Consecutive hits before a new track is confirmed.
+    pub min_hits: u32,
+    /// Consecutive misses before a confirmed track is dropped.
+    pub max_misses: u32,
+    /// Standard deviation of the unmodelled acceleration, in pixels per second squared.
+    pub acceleration_noise: f32,
+    /// Standard deviation of detection positions and sizes, in pixels.
+    pub measurement_noise: f32,
+}
+
+impl Default for TrackerConfig {
+    fn default() -> Self {
+        TrackerConfig {
+            min_iou: 0.2,
+            min_hits: 3,
+            max_misses: 5,
+            acceleration_noise: 200.0,
+            measurement_noise: 4.0,
+        }
+    }
+}
+
+/// An object followed across frames.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Track {
+    pub id: u64,
+    pub class_id: u32,
+    /// Filtered box, which is the prediction on frames where the object was missed.
+    pub bbox: BoundingBox,
+    /// Velocity of the box center in pixels per second.
+    pub velocity: (f32, f32),
+    pub score: f32,
+    pub hits: u32,
+    pub misses: u32,
+    pub age: u32,
+    pub confirmed: bool,
+    // Constant velocity state: center x, center y, velocity x, velocity y, width, height
+    state: State,
+    covariance: Covariance,
+}
+
+impl Track {
+    fn new(id: u64, detection: &Detection, config: &TrackerConfig) -> Self {
+        let (cx, cy) = detection.bbox.center();
+        let r = config.measurement_noise.powi(2);
+        // Nothing is known about the velocity yet
+        let covariance = Covariance::from_diagonal(&State::from_row_slice(&[r, r, 1e4, 1e4, r, r]));
+        let mut track = Track {
+            id,
+            class_id: detection.class_id,
+            bbox: detection.bbox,
+            velocity: (0.0, 0.0),
+            score: detection.score,
+            hits: 1,
+            misses: 0,
+            age: 1,
+            confirmed: config.min_hits <= 1,
+            state: State::from_row_slice(&[cx, cy, 0.0, 0.0, detection.bbox.width, detection.bbox.height]),
+            covariance,
+        };
+        track.sync();
+        track
+    }
+
+    fn predict(&mut self, dt: f32, config: &TrackerConfig) {
+        let mut f = Covariance::identity();
+        f[(0, 2)] = dt;
+        f[(1, 3)] = dt;
+        let q = config.acceleration_noise.powi(2);
+        let mut noise = Covariance::zeros();
+        for (position, velocity) in [(0, 2), (1, 3)] {
+            noise[(position, position)] = q * dt.powi(4) / 4.0;
+            noise[(position, velocity)] = q * dt.powi(3) / 2.0;
+            noise[(velocity, position)] = q * dt.powi(3) / 2.0;
+            noise[(velocity, velocity)] = q * dt.powi(2);
+        }
+        // Let the size drift slowly
+        noise[(4, 4)] = config.measurement_noise.powi(2) * dt;
+        noise[(5, 5)] = config.measurement_noise.powi(2) * dt;
+        self.state = f * self.state;
+        self.covariance = f * self.covariance * f.transpose() + noise;
+        self.age += 1;
+        self.sync();
+    }
+
+    fn correct(&mut self, detection: &Detection, config: &TrackerConfig) {
+        let (cx, cy) = detection.bbox.center();
+        let z = Measurement::new(cx, cy, detection.bbox.width, detection.bbox.height);
+        let mut h = SMatrix::<f32, 4, 6>::zeros();
+        h[(0, 0)] = 1.0;
+        h[(1, 1)] = 1.0;
+        h[(2, 4)] = 1.0;
+        h[(3, 5)] = 1.0;
+        let r = SMatrix::<f32, 4, 4>::identity() * config.measurement_noise.powi(2);
+        let innovation = z - h * self.state;
+        let s = h * self.covariance * h.transpose() + r;
+        if let Some(s_inverse) = s.try_inverse() {
+            let gain = self.covariance * h.transpose() * s_inverse;
+            self.state += gain * innovation;
+            self.covariance = (Covariance::identity() - gain * h) * self.covariance;
+        }
+        self.score = detection.score;
+        self.hits += 1;
+        self.misses = 0;
+        if self.hits >= config.min_hits {
+            self.confirmed = true;
+        }
+        self.sync();
+    }
+
+    fn sync(&mut self) {
+        let s = &self.state;
+        self.bbox = BoundingBox::from_center(s[0], s[1], s[4].max(1.0), s[5].max(1.0));
+        self.velocity = (s[2], s[3]);
+    }
+}
+
+/// Multi-object tracker: a constant velocity Kalman filter per object, with detections assigned
+/// to tracks by the Hungarian algorithm on IoU.
+pub struct Tracker {
+    pub config: TrackerConfig,
+    tracks: Vec<Track>,
+    next_id: u64,
+}
+
+impl Tracker {
+    pub fn new(config: TrackerConfig) -> Self {
+        Tracker {
+            config,
+            tracks: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Every live track, including unconfirmed ones.
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    pub fn confirmed_tracks(&self) -> impl Iterator<Item = &Track> {
+        self.tracks.iter().filter(|t| t.confirmed)
+    }
+
+    /// Advances every track by `dt` seconds and matches them against this frame's detections.
+    pub fn update(&mut self, detections: &[Detection], dt: f32) -> &[Track] {
+        let config = self.config;
+        for track in self.tracks.iter_mut() {
+            track.predict(dt, &config);
+        }
+
+        let costs: Vec<Vec<f32>> = self.tracks.iter().map(|track| {
+            detections.iter().map(|detection| {
+                if detection.class_id != track.class_id {
+                    return 1.0;
+                }
+                1.0 - track.bbox.iou(&detection.bbox)
+            }).collect()
+        }).collect();
+        let assignment = hungarian(&costs);
+
+        let mut matched = vec![false; detections.len()];
+        for (track, assigned) in self.tracks.iter_mut().zip(assignment) {
+            match assigned {
+                Some(d) if detections[d].class_id == track.class_id && track.bbox.iou(&detections[d].bbox) >= config.min_iou => {
+                    track.correct(&detections[d], &config);
+                    matched[d] = true;
+                }
+                _ => track.misses += 1,
+            }
+        }
+        // Unconfirmed tracks get no second chance
+        self.tracks.retain(|t| t.misses == 0 || (t.confirmed && t.misses <= config.max_misses));
+
+        for (detection, _) in detections.iter().zip(matched).filter(|(_, m)| !m) {
+            self.tracks.push(Track::new(self.next_id, detection, &config));
+            self.next_id += 1;
+        }
+        &self.tracks
+    }
+
+    pub fn clear(&mut self) {
+        self.tracks.clear();
+    }
+}
+
+/// Minimum cost assignment of rows to columns (Kuhn-Munkres). Returns the column assigned to each
+/// row; rows are left unassigned only when there are more rows than columns.
+pub fn hungarian(costs: &[Vec<f32>]) -> Vec<Option<usize>> {
+    let rows = costs.len();
+    let columns = costs.first().map(|r| r.len()).unwrap_or(0);
+    if rows == 0 || columns == 0 {
+        return vec![None; rows];
+    }
+    if rows > columns {
+        let transposed: Vec<Vec<f32>> = (0..columns).map(|c| (0..rows).map(|r| costs[r][c]).collect()).collect();
+        let mut assignment = vec![None; rows];
+        for (column, row) in hungarian(&transposed).into_iter().enumerate() {
+            if let Some(row) = row {
+                assignment[row] = Some(column);
+            }
+        }
+        return assignment;
+    }
+
+    // Shortest augmenting paths with row and column potentials, 1-indexed with 0 as a sentinel
+    let (n, m) = (rows, columns);
+    let mut u = vec![0.0_f64; n + 1];
+    let mut v = vec![0.0_f64; m + 1];
+    let mut owner = vec![0_usize; m + 1];
+    let mut way = vec![0_usize; m + 1];
+    for row in 1..=n {
+        owner[0] = row;
+        let mut j0 = 0;
+        let mut min_v = vec![f64::INFINITY; m + 1];
+        let mut used = vec![false; m + 1];
+        loop {
+            used[j0] = true;
+            let i0 = owner[j0];
+            let mut delta = f64::INFINITY;
+            let mut j1 = 0;
+            for j in 1..=m {
+                if used[j] {
+                    continue;
+                }
+                let current = costs[i0 - 1][j - 1] as f64 - u[i0] - v[j];
+                if current < min_v[j] {
+                    min_v[j] = current;
+                    way[j] = j0;
+                }
+                if min_v[j] < delta {
+                    delta = min_v[j];
+                    j1 = j;
+                }
+            }
+            for j in 0..=m {
+                if used[j] {
+                    u[owner[j]] += delta;
+                    v[j] -= delta;
+                } else {
+                    min_v[j] -= delta;
+                }
+            }
+            j0 = j1;
+            if owner[j0] == 0 {
+                break;
+            }
+        }
+        loop {
+            let j1 = way[j0];
+            owner[j0] = owner[j1];
+            j0 = j1;
+            if j0 == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut assignment = vec![None; rows];
+    for j in 1..=m {
+        if owner[j] != 0 {
+            assignment[owner[j] - 1] = Some(j - 1);
+        }
+    }
+    assignment
+}
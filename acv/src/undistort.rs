@@ -0,0 +1,187 @@
+use image::{Pixel, Rgb};
+use imageproc::definitions::Image;
+use nalgebra::Point2;
+use crate::calibration::Calibration;
+use crate::geometry::{CameraIntrinsics, Distortion};
+use crate::pipeline::graph::StageTiming;
+use crate::pipeline::Pipeline;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Interpolation {
+    Nearest,
+    Bilinear,
+}
+
+// Source pixel for one output pixel, with the bilinear weights of the right and lower neighbours in
+// 1/256ths. Pixels that map outside the source image have an index of u32::MAX.
+#[derive(Copy, Clone, Debug)]
+struct MapEntry {
+    index: u32,
+    wx: u16,
+    wy: u16,
+}
+
+/// A precomputed lookup from undistorted output pixels to distorted source pixels, built once per
+/// calibration and applied to every frame.
+pub struct UndistortMap {
+    width: u32,
+    height: u32,
+    interpolation: Interpolation,
+    entries: Vec<MapEntry>,
+}
+
+impl UndistortMap {
+    /// Builds a map whose output has the same intrinsics as the calibrated camera.
+    pub fn new(calibration: &Calibration, interpolation: Interpolation) -> Self {
+        Self::with_output_intrinsics(calibration, &calibration.intrinsics, interpolation)
+    }
+
+    /// Builds a map whose output is rendered with `output` intrinsics, e.g. a shorter focal length
+    /// to keep the whole field of view.
+    pub fn with_output_intrinsics(calibration: &Calibration, output: &CameraIntrinsics, interpolation: Interpolation) -> Self {
+        let (width, height) = (calibration.width, calibration.height);
+        let mut entries = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let normalized = output.normalize(&Point2::new(x as f64, y as f64));
+                let source = calibration.intrinsics.denormalize(&calibration.distortion.distort(&normalized));
+                entries.push(map_entry(source, width, height, interpolation));
+            }
+        }
+        UndistortMap {
+            width,
+            height,
+            interpolation,
+            entries,
+        }
+    }
+
+    pub fn interpolation(&self) -> Interpolation {
+        self.interpolation
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Undistorts `src` into `dst`. Both must have the calibrated resolution.
+    pub fn remap<P: Pixel<Subpixel = u8>>(&self, src: &Image<P>, dst: &mut Image<P>) -> crate::Result<()> {
+        if src.dimensions() != (self.width, self.height) || dst.dimensions() != (self.width, self.height) {
+            return Err(format!("Undistort map is {}x{}, got a {}x{} image", self.width, self.height, src.width(), src.height()).into());
+        }
+        let channels = P::CHANNEL_COUNT as usize;
+        let row = self.width as usize * channels;
+        let src = src.as_raw();
+        for (entry, out) in self.entries.iter().zip(dst.chunks_exact_mut(channels)) {
+            if entry.index == u32::MAX {
+                out.fill(0);
+                continue;
+            }
+            let base = entry.index as usize * channels;
+            match self.interpolation {
+                Interpolation::Nearest => out.copy_from_slice(&src[base..base + channels]),
+                Interpolation::Bilinear => {
+                    let (wx, wy) = (entry.wx as u32, entry.wy as u32);
+                    for c in 0..channels {
+                        let top = src[base + c] as u32 * (256 - wx) + src[base + channels + c] as u32 * wx;
+                        let bottom = src[base + row + c] as u32 * (256 - wx) + src[base + row + channels + c] as u32 * wx;
+                        out[c] = ((top * (256 - wy) + bottom * wy + (1 << 15)) >> 16) as u8;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn map_entry(source: Point2<f64>, width: u32, height: u32, interpolation: Interpolation) -> MapEntry {
+    let outside = MapEntry { index: u32::MAX, wx: 0, wy: 0 };
+    match interpolation {
+        Interpolation::Nearest => {
+            let (x, y) = (source.x.round(), source.y.round());
+            if x < 0.0 || y < 0.0 || x > (width - 1) as f64 || y > (height - 1) as f64 {
+                return outside;
+            }
+            MapEntry { index: y as u32 * width + x as u32, wx: 0, wy: 0 }
+        }
+        Interpolation::Bilinear => {
+            // Bilinear reads the pixel to the right and below, so stay off the last row and column
+            if source.x < 0.0 || source.y < 0.0 || source.x >= (width - 1) as f64 || source.y >= (height - 1) as f64 {
+                return outside;
+            }
+            let (x, y) = (source.x.floor(), source.y.floor());
+            MapEntry {
+                index: y as u32 * width + x as u32,
+                wx: ((source.x - x) * 256.0).round() as u16,
+                wy: ((source.y - y) * 256.0).round() as u16,
+            }
+        }
+    }
+}
+
+/// Removes lens distortion from a normalized image point.
+pub fn undistort_normalized(point: &Point2<f64>, distortion: &Distortion) -> Point2<f64> {
+    if distortion.is_zero() {
+        return *point;
+    }
+    // Fixed point iteration, like OpenCV's undistortPoints
+    let mut estimate = *point;
+    for _ in 0..20 {
+        let (x, y) = (estimate.x, estimate.y);
+        let r2 = x * x + y * y;
+        let radial = 1.0 + r2 * (distortion.k1 + r2 * (distortion.k2 + r2 * distortion.k3));
+        let dx = 2.0 * distortion.p1 * x * y + distortion.p2 * (r2 + 2.0 * x * x);
+        let dy = distortion.p1 * (r2 + 2.0 * y * y) + 2.0 * distortion.p2 * x * y;
+        let next = Point2::new((point.x - dx) / radial, (point.y - dy) / radial);
+        let shift = (next - estimate).norm();
+        estimate = next;
+        if shift < 1e-10 {
+            break;
+        }
+    }
+    estimate
+}
+
+/// Maps a pixel in the distorted image to where it lands in the undistorted image. Much cheaper
+/// than remapping the whole frame when only a few detected points matter.
+pub fn undistort_point(calibration: &Calibration, pixel: &Point2<f64>) -> Point2<f64> {
+    let normalized = undistort_normalized(&calibration.intrinsics.normalize(pixel), &calibration.distortion);
+    calibration.intrinsics.denormalize(&normalized)
+}
+
+pub fn undistort_points(calibration: &Calibration, pixels: &[Point2<f64>]) -> Vec<Point2<f64>> {
+    pixels.iter().map(|p| undistort_point(calibration, p)).collect()
+}
+
+/// Undistorts every frame before handing it to the wrapped pipeline.
+pub struct UndistortPipeline<P: Pipeline> {
+    map: UndistortMap,
+    pub inner: P,
+    // Remapped into and then swapped with the input frame, whose buffer is reused for the next one
+    buffer: Image<Rgb<u8>>,
+}
+
+impl<P: Pipeline> UndistortPipeline<P> {
+    pub fn new(map: UndistortMap, inner: P) -> Self {
+        UndistortPipeline { map, inner, buffer: Image::new(0, 0) }
+    }
+}
+
+impl<P: Pipeline> Pipeline for UndistortPipeline<P> {
+    fn pipeline(&mut self, input: Image<Rgb<u8>>) -> crate::Result<Option<Image<Rgb<u8>>>> {
+        if self.buffer.dimensions() != input.dimensions() {
+            self.buffer = Image::new(input.width(), input.height());
+        }
+        self.map.remap(&input, &mut self.buffer)?;
+        let undistorted = std::mem::replace(&mut self.buffer, input);
+        self.inner.pipeline(undistorted)
+    }
+
+    fn output_color_type(&self) -> image::ColorType {
+        self.inner.output_color_type()
+    }
+
+    fn stage_timings(&self) -> &[StageTiming] {
+        self.inner.stage_timings()
+    }
+}
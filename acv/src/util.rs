@@ -1,6 +1,16 @@
 use image::{GrayImage, Luma, Rgb};
 use imageproc::definitions::Image;
+use imageproc::drawing::draw_polygon_mut;
+use imageproc::point::Point;
+use serde::{Deserialize, Serialize};
+use crate::parallel::par_bands;
+use crate::util::simd::Backend;
 
+mod simd;
+
+/// Hue in degrees, saturation and value from 0 to 1. Reds with more blue than green come out
+/// with hues just below 0 rather than just below 360.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Hsv {
     pub h: f32,
     pub s: f32,
@@ -34,29 +44,425 @@ impl From<Rgb<u8>> for Hsv {
     }
 }
 
+/// Full range (JPEG) BT.601 luma and chroma, as OpenCV's `COLOR_RGB2YCrCb`. Computed in 14 bit
+/// fixed point so results don't depend on float rounding.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct YCrCb {
+    pub y: u8,
+    pub cr: u8,
+    pub cb: u8,
+}
+
+// Weights of R, G and B in Y, of R - Y in Cr and of B - Y in Cb, out of 1 << 14.
+const Y_WEIGHTS: [i32; 3] = [4899, 9617, 1868];
+const CR_WEIGHT: i32 = 11682;
+const CB_WEIGHT: i32 = 9241;
 
+impl From<Rgb<u8>> for YCrCb {
+    fn from(value: Rgb<u8>) -> Self {
+        let [r, g, b] = value.0.map(|c| c as i32);
+        let y = (r * Y_WEIGHTS[0] + g * Y_WEIGHTS[1] + b * Y_WEIGHTS[2] + (1 << 13)) >> 14;
+        let cr = ((r - y) * CR_WEIGHT + (128 << 14) + (1 << 13)) >> 14;
+        let cb = ((b - y) * CB_WEIGHT + (128 << 14) + (1 << 13)) >> 14;
+        YCrCb { y: y as u8, cr: cr.clamp(0, 255) as u8, cb: cb.clamp(0, 255) as u8 }
+    }
+}
+
+impl YCrCb {
+    pub fn to_rgb(&self) -> Rgb<u8> {
+        let y = (self.y as i32) << 14;
+        let (cr, cb) = (self.cr as i32 - 128, self.cb as i32 - 128);
+        let channel = |v: i32| ((v + (1 << 13)) >> 14).clamp(0, 255) as u8;
+        Rgb([
+            channel(y + 22987 * cr),
+            channel(y - 11698 * cr - 5636 * cb),
+            channel(y + 29049 * cb),
+        ])
+    }
+}
+
+/// Whether `hsv` is inside the range. Hue ranges with `lower.h` above `higher.h` wrap through 0.
+pub fn hsv_in_range(hsv: Hsv, lower: Hsv, higher: Hsv) -> bool {
+    let hue = if lower.h <= higher.h {
+        (hsv.h >= lower.h && hsv.h <= higher.h) || (hsv.h + 360.0 >= lower.h && hsv.h + 360.0 <= higher.h)
+    } else {
+        let h = hsv.h.rem_euclid(360.0);
+        h >= lower.h || h <= higher.h
+    };
+    hue && hsv.s >= lower.s && hsv.s <= higher.s && hsv.v >= lower.v && hsv.v <= higher.v
+}
+
+/// Sets `dst` to 255 where `src` pixels are within the HSV range and 0 elsewhere, as
+/// [`hsv_in_range`] does pixel by pixel, so hue ranges wrap around through 0 the same way.
 pub fn in_range_hsv(src: &Image<Rgb<u8>>, lower: Hsv, higher: Hsv, dst: &mut GrayImage) {
-    for (src_pixel, dst_pixel) in src.pixels().zip(dst.pixels_mut()) {
-        let hsv = Hsv::from(*src_pixel);
-        if hsv.h >= lower.h && hsv.h <= higher.h &&
-            hsv.s >= lower.s && hsv.s <= higher.s &&
-            hsv.v >= lower.v && hsv.v <= higher.v {
-            *dst_pixel = Luma([255]);
-        } else {
-            *dst_pixel = Luma([0]);
+    let (backend, width) = (Backend::detect(), src.width() as usize);
+    par_bands(src.as_raw(), width * 3, dst, width, width, |_, src, dst| {
+        simd::in_range_hsv(backend, src, lower, higher, dst);
+    });
+}
+
+pub fn in_range_rgb(src: &Image<Rgb<u8>>, lower: Rgb<u8>, higher: Rgb<u8>, dst: &mut GrayImage) {
+    let (backend, width) = (Backend::detect(), src.width() as usize);
+    par_bands(src.as_raw(), width * 3, dst, width, width, |_, src, dst| {
+        simd::in_range_rgb(backend, src, lower.0, higher.0, dst);
+    });
+}
+
+pub fn ycrcb_in_range(ycrcb: YCrCb, lower: YCrCb, higher: YCrCb) -> bool {
+    ycrcb.y >= lower.y && ycrcb.y <= higher.y &&
+        ycrcb.cr >= lower.cr && ycrcb.cr <= higher.cr &&
+        ycrcb.cb >= lower.cb && ycrcb.cb <= higher.cb
+}
+
+pub fn in_range_ycrcb(src: &Image<Rgb<u8>>, lower: YCrCb, higher: YCrCb, dst: &mut GrayImage) {
+    let (backend, width) = (Backend::detect(), src.width() as usize);
+    par_bands(src.as_raw(), width * 3, dst, width, width, |_, src, dst| {
+        simd::in_range_ycrcb(backend, src, lower, higher, dst);
+    });
+}
+
+/// Converts every pixel of `src` to HSV into `dst`, row by row, reusing its allocation.
+pub fn rgb_to_hsv_into(src: &Image<Rgb<u8>>, dst: &mut Vec<Hsv>) {
+    let (backend, width) = (Backend::detect(), src.width() as usize);
+    dst.clear();
+    dst.resize(width * src.height() as usize, Hsv { h: 0.0, s: 0.0, v: 0.0 });
+    par_bands(src.as_raw(), width * 3, dst, width, width, |_, src, dst| {
+        simd::rgb_to_hsv(backend, src, dst);
+    });
+}
+
+/// Converts `src` to YCrCb into `dst`, with Y, Cr and Cb in the three channels like OpenCV's
+/// `cvtColor`.
+pub fn rgb_to_ycrcb_into(src: &Image<Rgb<u8>>, dst: &mut Image<Rgb<u8>>) {
+    if dst.dimensions() != src.dimensions() {
+        *dst = Image::new(src.width(), src.height());
+    }
+    let (backend, width) = (Backend::detect(), src.width() as usize);
+    par_bands(src.as_raw(), width * 3, dst, width * 3, width, |_, src, dst| {
+        simd::rgb_to_ycrcb(backend, src, dst);
+    });
+}
+
+/// Converts `src` to gray into `dst` with the luma weights of YCrCb's Y, like OpenCV's
+/// `cvtColor`.
+pub fn rgb_to_gray_into(src: &Image<Rgb<u8>>, dst: &mut GrayImage) {
+    if dst.dimensions() != src.dimensions() {
+        *dst = GrayImage::new(src.width(), src.height());
+    }
+    let (backend, width) = (Backend::detect(), src.width() as usize);
+    par_bands(src.as_raw(), width * 3, dst, width, width, |_, src, dst| {
+        simd::rgb_to_gray(backend, src, dst);
+    });
+}
+
+/// Sets `dst` to 255 where a pixel is more than `offset` darker than the mean of the window of
+/// `radius` around it, and 0 elsewhere. Flat areas come out as 0 whatever their brightness.
+pub fn dark_mask(src: &GrayImage, radius: u32, offset: u8, dst: &mut GrayImage) {
+    let (width, height) = src.dimensions();
+    let stride = width as usize + 1;
+    let mut integral = vec![0u64; stride * (height as usize + 1)];
+    for y in 0..height as usize {
+        let mut row = 0u64;
+        for x in 0..width as usize {
+            row += src.get_pixel(x as u32, y as u32)[0] as u64;
+            integral[(y + 1) * stride + x + 1] = integral[y * stride + x + 1] + row;
         }
     }
+    let (width, height, radius) = (width as usize, height as usize, radius as usize);
+    par_bands(src.as_raw(), width, dst, width, width, |first_row, src, dst| {
+        for (row, (src_row, dst_row)) in src.chunks_exact(width).zip(dst.chunks_exact_mut(width)).enumerate() {
+            let y = first_row + row;
+            let (y0, y1) = (y.saturating_sub(radius), (y + radius + 1).min(height));
+            for (x, (value, out)) in src_row.iter().zip(dst_row.iter_mut()).enumerate() {
+                let (x0, x1) = (x.saturating_sub(radius), (x + radius + 1).min(width));
+                let sum = integral[y1 * stride + x1] + integral[y0 * stride + x0]
+                    - integral[y0 * stride + x1] - integral[y1 * stride + x0];
+                let mean = sum / ((x1 - x0) * (y1 - y0)) as u64;
+                let dark = (*value as u64 + offset as u64) < mean;
+                *out = if dark { 255 } else { 0 };
+            }
+        }
+    });
 }
 
+/// Settings for [`learn_thresholds`].
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThresholdLearning {
+    /// Fraction of sample pixels left out below and above the range on each channel, so a few
+    /// stray pixels at the polygon's edge don't widen it.
+    pub outlier_fraction: f32,
+    /// Added to each side of the range, as a fraction of the channel's full scale (360 degrees of
+    /// hue, 1 of saturation and value, 255 of YCrCb), to allow for lighting changes.
+    pub margin: f32,
+    /// Most of the sample pixels that tightening against negative samples may give up.
+    pub max_tightening_loss: f32,
+}
 
-pub fn in_range_rgb(src: &Image<Rgb<u8>>, lower: Rgb<u8>, higher: Rgb<u8>, dst: &mut GrayImage) {
-    for (src_pixel, dst_pixel) in src.pixels().zip(dst.pixels_mut()) {
-        if src_pixel[0] >= lower[0] && src_pixel[0] <= higher[0] &&
-            src_pixel[1] >= lower[1] && src_pixel[1] <= higher[1] &&
-            src_pixel[2] >= lower[2] && src_pixel[2] <= higher[2] {
-            *dst_pixel = Luma([255]);
-        } else {
-            *dst_pixel = Luma([0]);
+impl Default for ThresholdLearning {
+    fn default() -> Self {
+        ThresholdLearning { outlier_fraction: 0.02, margin: 0.03, max_tightening_loss: 0.1 }
+    }
+}
+
+/// A learned range and how well it separates the samples.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LearnedRange<T> {
+    pub lower: T,
+    pub higher: T,
+    /// Fraction of the sample pixels inside the range.
+    pub coverage: f32,
+    /// Fraction of the negative sample pixels inside the range, 0 without negative samples.
+    pub negative_rate: f32,
+}
+
+/// Ranges for [`in_range_hsv`] and [`in_range_ycrcb`] learned from samples of a color.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LearnedThresholds {
+    pub hsv: LearnedRange<Hsv>,
+    pub ycrcb: LearnedRange<YCrCb>,
+}
+
+// Most samples used to fit a range. Larger polygons are subsampled evenly.
+const MAX_LEARNING_SAMPLES: usize = 20_000;
+
+/// Learns thresholds for the color of the pixels inside `samples`, polygons in frame pixels
+/// marking the target. Each channel's range runs between percentiles of the sample pixels, widened
+/// by a margin. Pixels inside `negatives` (and not inside `samples`) are things the range should
+/// leave out, like the carpet around a game piece; the range is then narrowed side by side to
+/// exclude as many of them as possible for as few sample pixels as possible.
+///
+/// Hue ranges are placed around the gap in the samples' hues, so a red can come out wrapping
+/// through 0 with `lower.h` above `higher.h`.
+pub fn learn_thresholds(
+    frame: &Image<Rgb<u8>>,
+    samples: &[Vec<Point<i32>>],
+    negatives: &[Vec<Point<i32>>],
+    options: &ThresholdLearning,
+) -> crate::Result<LearnedThresholds> {
+    let sample_mask = polygon_mask(frame.width(), frame.height(), samples);
+    let mut negative_mask = polygon_mask(frame.width(), frame.height(), negatives);
+    for (negative, sample) in negative_mask.pixels_mut().zip(sample_mask.pixels()) {
+        if sample[0] > 0 {
+            *negative = Luma([0]);
         }
     }
+    let positive_pixels = masked_pixels(frame, &sample_mask);
+    if positive_pixels.is_empty() {
+        return Err("Sample polygons cover no pixels".into());
+    }
+    let negative_pixels = masked_pixels(frame, &negative_mask);
+
+    let positive_hsv: Vec<Hsv> = positive_pixels.iter().map(|p| Hsv::from(*p)).collect();
+    let negative_hsv: Vec<Hsv> = negative_pixels.iter().map(|p| Hsv::from(*p)).collect();
+    let hue_shift = hue_gap_start(&positive_hsv);
+    let rotate = |hsv: &Hsv| [(hsv.h - hue_shift).rem_euclid(360.0), hsv.s, hsv.v];
+    let (lower, higher) = fit_range(
+        &positive_hsv.iter().map(rotate).collect::<Vec<_>>(),
+        &negative_hsv.iter().map(rotate).collect::<Vec<_>>(),
+        [360.0, 1.0, 1.0],
+        [0.01, 1e-4, 1e-4],
+        options,
+    );
+    let (lower_h, higher_h) = if higher[0] - lower[0] >= 360.0 {
+        (0.0, 360.0)
+    } else {
+        ((lower[0] + hue_shift).rem_euclid(360.0), (higher[0] + hue_shift).rem_euclid(360.0))
+    };
+    let lower_hsv = Hsv { h: lower_h, s: lower[1].max(0.0), v: lower[2].max(0.0) };
+    let higher_hsv = Hsv { h: higher_h, s: higher[1].min(1.0), v: higher[2].min(1.0) };
+
+    let positive_ycrcb: Vec<YCrCb> = positive_pixels.iter().map(|p| YCrCb::from(*p)).collect();
+    let negative_ycrcb: Vec<YCrCb> = negative_pixels.iter().map(|p| YCrCb::from(*p)).collect();
+    let channels = |c: &YCrCb| [c.y as f32, c.cr as f32, c.cb as f32];
+    let (lower, higher) = fit_range(
+        &positive_ycrcb.iter().map(channels).collect::<Vec<_>>(),
+        &negative_ycrcb.iter().map(channels).collect::<Vec<_>>(),
+        [255.0; 3],
+        [1.0; 3],
+        options,
+    );
+    let [y, cr, cb] = lower.map(|l| l.ceil().clamp(0.0, 255.0) as u8);
+    let lower_ycrcb = YCrCb { y, cr, cb };
+    let [y, cr, cb] = higher.map(|h| h.floor().clamp(0.0, 255.0) as u8);
+    let higher_ycrcb = YCrCb { y, cr, cb };
+
+    let rate = |inside: usize, total: usize| if total == 0 { 0.0 } else { inside as f32 / total as f32 };
+    let hsv_inside = |values: &[Hsv]| values.iter().filter(|v| hsv_in_range(**v, lower_hsv, higher_hsv)).count();
+    let ycrcb_inside = |values: &[YCrCb]| values.iter().filter(|v| ycrcb_in_range(**v, lower_ycrcb, higher_ycrcb)).count();
+    Ok(LearnedThresholds {
+        hsv: LearnedRange {
+            lower: lower_hsv,
+            higher: higher_hsv,
+            coverage: rate(hsv_inside(&positive_hsv), positive_hsv.len()),
+            negative_rate: rate(hsv_inside(&negative_hsv), negative_hsv.len()),
+        },
+        ycrcb: LearnedRange {
+            lower: lower_ycrcb,
+            higher: higher_ycrcb,
+            coverage: rate(ycrcb_inside(&positive_ycrcb), positive_ycrcb.len()),
+            negative_rate: rate(ycrcb_inside(&negative_ycrcb), negative_ycrcb.len()),
+        },
+    })
+}
+
+fn polygon_mask(width: u32, height: u32, polygons: &[Vec<Point<i32>>]) -> GrayImage {
+    let mut mask = GrayImage::new(width, height);
+    for polygon in polygons.iter() {
+        // draw_polygon_mut wants the polygon open.
+        let open = match polygon.split_last() {
+            Some((last, rest)) if !rest.is_empty() && rest[0] == *last => rest,
+            _ => polygon,
+        };
+        if open.len() >= 3 {
+            draw_polygon_mut(&mut mask, open, Luma([255]));
+        }
+    }
+    mask
+}
+
+fn masked_pixels(frame: &Image<Rgb<u8>>, mask: &GrayImage) -> Vec<Rgb<u8>> {
+    let pixels: Vec<Rgb<u8>> = frame.pixels().zip(mask.pixels())
+        .filter(|(_, m)| m[0] > 0)
+        .map(|(p, _)| *p)
+        .collect();
+    let step = pixels.len().div_ceil(MAX_LEARNING_SAMPLES).max(1);
+    pixels.into_iter().step_by(step).collect()
+}
+
+// The first whole degree after the widest run of hues no sample has, so that measuring hues from
+// it keeps the samples' hues together.
+fn hue_gap_start(values: &[Hsv]) -> f32 {
+    let mut occupied = [false; 360];
+    for hsv in values.iter() {
+        occupied[(hsv.h.rem_euclid(360.0) as usize).min(359)] = true;
+    }
+    let (mut best, mut best_length) = (0, 0);
+    for start in 0..360 {
+        if occupied[start] || !occupied[(start + 359) % 360] {
+            continue;
+        }
+        let length = (0..360).take_while(|i| !occupied[(start + i) % 360]).count();
+        if length > best_length {
+            best = (start + length) % 360;
+            best_length = length;
+        }
+    }
+    best as f32
+}
+
+fn percentile(sorted: &[f32], fraction: f32) -> f32 {
+    let index = (fraction.clamp(0.0, 1.0) * (sorted.len() - 1) as f32).round() as usize;
+    sorted[index]
+}
+
+// Percentile range of `positives` widened by the margin, then narrowed against `negatives` one
+// side at a time, taking the step that drops the most negatives per positive lost. `step` is how
+// far past a negative value a bound has to move to leave it out.
+fn fit_range(
+    positives: &[[f32; 3]],
+    negatives: &[[f32; 3]],
+    scale: [f32; 3],
+    step: [f32; 3],
+    options: &ThresholdLearning,
+) -> ([f32; 3], [f32; 3]) {
+    let (mut lower, mut higher) = ([0.0f32; 3], [0.0f32; 3]);
+    for channel in 0..3 {
+        let mut values: Vec<f32> = positives.iter().map(|p| p[channel]).collect();
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let margin = options.margin * scale[channel];
+        lower[channel] = percentile(&values, options.outlier_fraction) - margin;
+        higher[channel] = percentile(&values, 1.0 - options.outlier_fraction) + margin;
+    }
+
+    let inside = |p: &[f32; 3], lower: &[f32; 3], higher: &[f32; 3]| (0..3).all(|c| p[c] >= lower[c] && p[c] <= higher[c]);
+    let initial = positives.iter().filter(|p| inside(p, &lower, &higher)).count();
+    let min_kept = ((1.0 - options.max_tightening_loss.clamp(0.0, 1.0)) * initial as f32).ceil() as usize;
+    loop {
+        let negatives_inside: Vec<&[f32; 3]> = negatives.iter().filter(|n| inside(n, &lower, &higher)).collect();
+        if negatives_inside.is_empty() {
+            break;
+        }
+        let kept = positives.iter().filter(|p| inside(p, &lower, &higher)).count();
+        // (score, channel, is lower bound, new bound)
+        let mut best: Option<(f32, usize, bool, f32)> = None;
+        for channel in 0..3 {
+            let mut values: Vec<f32> = negatives_inside.iter().map(|n| n[channel]).collect();
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            for eighth in 1..=8 {
+                let index = (eighth * values.len()).div_ceil(8) - 1;
+                // Bounds stop at each other, as a lower bound above the higher one reads as a hue
+                // range wrapping through 0
+                let lower_bound = (values[index] + step[channel]).min(higher[channel]);
+                let higher_bound = (values[values.len() - 1 - index] - step[channel]).max(lower[channel]);
+                for (is_lower, bound) in [(true, lower_bound), (false, higher_bound)] {
+                    let (mut trial_lower, mut trial_higher) = (lower, higher);
+                    if is_lower {
+                        trial_lower[channel] = bound;
+                    } else {
+                        trial_higher[channel] = bound;
+                    }
+                    let remaining = positives.iter().filter(|p| inside(p, &trial_lower, &trial_higher)).count();
+                    if remaining < min_kept {
+                        continue;
+                    }
+                    let removed = negatives_inside.iter().filter(|n| !inside(n, &trial_lower, &trial_higher)).count();
+                    let score = removed as f32 / (kept - remaining + 1) as f32;
+                    let better = match best {
+                        Some((best_score, ..)) => score > best_score,
+                        None => removed > 0,
+                    };
+                    if better {
+                        best = Some((score, channel, is_lower, bound));
+                    }
+                }
+            }
+        }
+        match best {
+            Some((_, channel, true, bound)) => lower[channel] = bound,
+            Some((_, channel, false, bound)) => higher[channel] = bound,
+            None => break,
+        }
+    }
+    (lower, higher)
+}
+
+/// Small deterministic random number generator (xorshift64*), so sampling gives the same results
+/// on every run.
+pub(crate) struct Xorshift(u64);
+
+impl Xorshift {
+    pub(crate) fn new(seed: u64) -> Self {
+        Xorshift(seed.max(1))
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in 0..1.
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tightening_keeps_ranges_ordered() {
+        let positives = [[6.0, 0.0, 0.0], [1.0, 0.0, 0.0], [6.0, 0.0, 0.0]];
+        let negatives = [[4.0, 0.0, 0.0], [4.0, 0.0, 0.0], [6.0, 0.0, 0.0]];
+        let options = ThresholdLearning { outlier_fraction: 0.0, margin: 0.0, max_tightening_loss: 1.0 };
+        let (lower, higher) = fit_range(&positives, &negatives, [10.0; 3], [1.0; 3], &options);
+        assert!((0..3).all(|c| lower[c] <= higher[c]), "{:?} above {:?}", lower, higher);
+    }
 }
@@ -0,0 +1,261 @@
+use std::sync::OnceLock;
+use image::Rgb;
+use crate::util::{hsv_in_range, ycrcb_in_range, Hsv, YCrCb};
+
+#[cfg(target_arch = "aarch64")]
+mod neon;
+#[cfg(target_arch = "x86_64")]
+mod x86;
+
+// Pixels the vector kernels take at a time. Whatever is left of a row goes through the scalar
+// code.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const BLOCK: usize = 16;
+
+/// Instruction sets the color kernels have versions for. Every version gives exactly the same
+/// results as the scalar one.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Backend {
+    Scalar,
+    #[cfg(target_arch = "x86_64")]
+    Sse41,
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+    #[cfg(target_arch = "aarch64")]
+    Neon,
+}
+
+impl Backend {
+    /// The fastest backend the CPU supports, checked once.
+    pub(crate) fn detect() -> Backend {
+        static BACKEND: OnceLock<Backend> = OnceLock::new();
+        *BACKEND.get_or_init(|| *Backend::available().last().unwrap_or(&Backend::Scalar))
+    }
+
+    /// The backends the CPU supports, slowest first.
+    pub(crate) fn available() -> Vec<Backend> {
+        let mut backends = vec![Backend::Scalar];
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("sse4.1") {
+                backends.push(Backend::Sse41);
+            }
+            if is_x86_feature_detected!("avx2") {
+                backends.push(Backend::Avx2);
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            backends.push(Backend::Neon);
+        }
+        backends
+    }
+}
+
+// The kernels below take a run of RGB pixels and a backend from `Backend::available`, and leave
+// the pixels the vector code didn't get to, at most a block's worth, to the scalar code.
+
+pub(crate) fn rgb_to_hsv(backend: Backend, src: &[u8], dst: &mut [Hsv]) {
+    let done = match backend {
+        Backend::Scalar => 0,
+        #[cfg(target_arch = "x86_64")]
+        Backend::Sse41 => unsafe { x86::rgb_to_hsv_sse41(src, dst) },
+        #[cfg(target_arch = "x86_64")]
+        Backend::Avx2 => unsafe { x86::rgb_to_hsv_avx2(src, dst) },
+        #[cfg(target_arch = "aarch64")]
+        Backend::Neon => unsafe { neon::rgb_to_hsv(src, dst) },
+    };
+    for (pixel, hsv) in src[done * 3..].chunks_exact(3).zip(&mut dst[done..]) {
+        *hsv = Hsv::from(Rgb([pixel[0], pixel[1], pixel[2]]));
+    }
+}
+
+/// Writes Y, Cr and Cb in place of R, G and B.
+pub(crate) fn rgb_to_ycrcb(backend: Backend, src: &[u8], dst: &mut [u8]) {
+    let done = match backend {
+        Backend::Scalar => 0,
+        #[cfg(target_arch = "x86_64")]
+        Backend::Sse41 => unsafe { x86::rgb_to_ycrcb_sse41(src, dst) },
+        #[cfg(target_arch = "x86_64")]
+        Backend::Avx2 => unsafe { x86::rgb_to_ycrcb_avx2(src, dst) },
+        #[cfg(target_arch = "aarch64")]
+        Backend::Neon => unsafe { neon::rgb_to_ycrcb(src, dst) },
+    };
+    for (pixel, out) in src[done * 3..].chunks_exact(3).zip(dst[done * 3..].chunks_exact_mut(3)) {
+        let ycrcb = YCrCb::from(Rgb([pixel[0], pixel[1], pixel[2]]));
+        out.copy_from_slice(&[ycrcb.y, ycrcb.cr, ycrcb.cb]);
+    }
+}
+
+/// Writes the Y of [`rgb_to_ycrcb`], which is OpenCV's gray.
+pub(crate) fn rgb_to_gray(backend: Backend, src: &[u8], dst: &mut [u8]) {
+    // Runs of pixels through the YCrCb kernels, keeping Y.
+    const RUN: usize = 256;
+    let mut ycrcb = [0; 3 * RUN];
+    for (src, dst) in src.chunks(3 * RUN).zip(dst.chunks_mut(RUN)) {
+        let ycrcb = &mut ycrcb[..src.len()];
+        rgb_to_ycrcb(backend, src, ycrcb);
+        for (out, pixel) in dst.iter_mut().zip(ycrcb.chunks_exact(3)) {
+            *out = pixel[0];
+        }
+    }
+}
+
+pub(crate) fn in_range_hsv(backend: Backend, src: &[u8], lower: Hsv, higher: Hsv, dst: &mut [u8]) {
+    let done = match backend {
+        Backend::Scalar => 0,
+        #[cfg(target_arch = "x86_64")]
+        Backend::Sse41 => unsafe { x86::in_range_hsv_sse41(src, lower, higher, dst) },
+        #[cfg(target_arch = "x86_64")]
+        Backend::Avx2 => unsafe { x86::in_range_hsv_avx2(src, lower, higher, dst) },
+        #[cfg(target_arch = "aarch64")]
+        Backend::Neon => unsafe { neon::in_range_hsv(src, lower, higher, dst) },
+    };
+    for (pixel, out) in src[done * 3..].chunks_exact(3).zip(&mut dst[done..]) {
+        *out = mask(hsv_in_range(Hsv::from(Rgb([pixel[0], pixel[1], pixel[2]])), lower, higher));
+    }
+}
+
+pub(crate) fn in_range_rgb(backend: Backend, src: &[u8], lower: [u8; 3], higher: [u8; 3], dst: &mut [u8]) {
+    let done = match backend {
+        Backend::Scalar => 0,
+        // Comparing bytes gains nothing from the wider registers.
+        #[cfg(target_arch = "x86_64")]
+        Backend::Sse41 | Backend::Avx2 => unsafe { x86::in_range_rgb_sse41(src, lower, higher, dst) },
+        #[cfg(target_arch = "aarch64")]
+        Backend::Neon => unsafe { neon::in_range_rgb(src, lower, higher, dst) },
+    };
+    for (pixel, out) in src[done * 3..].chunks_exact(3).zip(&mut dst[done..]) {
+        *out = mask(pixel.iter().zip(lower.iter().zip(&higher)).all(|(v, (lower, higher))| v >= lower && v <= higher));
+    }
+}
+
+pub(crate) fn in_range_ycrcb(backend: Backend, src: &[u8], lower: YCrCb, higher: YCrCb, dst: &mut [u8]) {
+    let done = match backend {
+        Backend::Scalar => 0,
+        #[cfg(target_arch = "x86_64")]
+        Backend::Sse41 => unsafe { x86::in_range_ycrcb_sse41(src, lower, higher, dst) },
+        #[cfg(target_arch = "x86_64")]
+        Backend::Avx2 => unsafe { x86::in_range_ycrcb_avx2(src, lower, higher, dst) },
+        #[cfg(target_arch = "aarch64")]
+        Backend::Neon => unsafe { neon::in_range_ycrcb(src, lower, higher, dst) },
+    };
+    for (pixel, out) in src[done * 3..].chunks_exact(3).zip(&mut dst[done..]) {
+        *out = mask(ycrcb_in_range(YCrCb::from(Rgb([pixel[0], pixel[1], pixel[2]])), lower, higher));
+    }
+}
+
+fn mask(set: bool) -> u8 {
+    if set { 255 } else { 0 }
+}
+
+// Gathers a block's components, stored a vector at a time, into `dst`.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn write_hsv(h: &[f32; BLOCK], s: &[f32; BLOCK], v: &[f32; BLOCK], dst: &mut [Hsv]) {
+    for (i, hsv) in dst.iter_mut().enumerate() {
+        *hsv = Hsv { h: h[i], s: s[i], v: v[i] };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::Xorshift;
+
+    // Every `step`th value of each channel, then noise, then a run that doesn't fill a block.
+    fn pixels(step: usize) -> Vec<u8> {
+        let values = || (0..=255u8).step_by(step);
+        let mut pixels: Vec<u8> = values()
+            .flat_map(|r| values().flat_map(move |g| values().flat_map(move |b| [r, g, b])))
+            .collect();
+        let mut random = Xorshift::new(7);
+        pixels.extend((0..3 * (4096 + 11)).map(|_| random.next_u64() as u8));
+        pixels
+    }
+
+    fn vector_backends() -> Vec<Backend> {
+        Backend::available().into_iter().filter(|b| *b != Backend::Scalar).collect()
+    }
+
+    // Runs of pixels at a time, to keep the outputs small.
+    const RUN: usize = 3 << 16;
+
+    #[test]
+    fn hsv_matches_scalar() {
+        let bits = |hsv: &Hsv| [hsv.h.to_bits(), hsv.s.to_bits(), hsv.v.to_bits()];
+        let blank = Hsv { h: 0.0, s: 0.0, v: 0.0 };
+        let (mut expected, mut actual) = (vec![blank; RUN / 3], vec![blank; RUN / 3]);
+        for src in pixels(1).chunks(RUN) {
+            rgb_to_hsv(Backend::Scalar, src, &mut expected);
+            for backend in vector_backends() {
+                rgb_to_hsv(backend, src, &mut actual);
+                for (i, (e, a)) in expected.iter().zip(&actual).take(src.len() / 3).enumerate() {
+                    assert_eq!(bits(e), bits(a), "{:?} on {:?}", backend, &src[3 * i..3 * i + 3]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ycrcb_matches_scalar() {
+        let (mut expected, mut actual) = (vec![0; RUN], vec![0; RUN]);
+        for src in pixels(3).chunks(RUN) {
+            rgb_to_ycrcb(Backend::Scalar, src, &mut expected);
+            for backend in vector_backends() {
+                rgb_to_ycrcb(backend, src, &mut actual);
+                assert!(expected[..src.len()] == actual[..src.len()], "{:?}", backend);
+            }
+        }
+    }
+
+    #[test]
+    fn gray_is_y() {
+        let src = pixels(7);
+        let mut gray = vec![0; src.len() / 3];
+        for backend in Backend::available() {
+            rgb_to_gray(backend, &src, &mut gray);
+            for (pixel, y) in src.chunks_exact(3).zip(&gray) {
+                assert_eq!(YCrCb::from(Rgb([pixel[0], pixel[1], pixel[2]])).y, *y, "{:?}", backend);
+            }
+        }
+    }
+
+    #[test]
+    fn ranges_match_scalar() {
+        let src = pixels(5);
+        let hsv = |h, s, v| Hsv { h, s, v };
+        let hsv_ranges = [
+            (hsv(0.0, 0.0, 0.0), hsv(360.0, 1.0, 1.0)),
+            (hsv(20.0, 0.3, 0.2), hsv(60.0, 1.0, 0.9)),
+            (hsv(-20.0, 0.5, 0.5), hsv(15.0, 1.0, 1.0)),
+            (hsv(340.0, 0.25, 0.0), hsv(15.0, 0.75, 1.0)),
+            (hsv(300.0, 0.0, 0.0), hsv(0.0, 1.0, 1.0)),
+            (hsv(60.0, 0.2, 0.2), hsv(60.0, 1.0, 1.0)),
+        ];
+        let ycrcb = |y, cr, cb| YCrCb { y, cr, cb };
+        let ycrcb_ranges = [
+            (ycrcb(0, 0, 0), ycrcb(255, 255, 255)),
+            (ycrcb(40, 140, 70), ycrcb(220, 180, 125)),
+            (ycrcb(10, 128, 128), ycrcb(250, 128, 128)),
+        ];
+        let rgb_ranges = [([0, 0, 0], [255, 255, 255]), ([100, 0, 30], [200, 80, 30]), ([1, 1, 1], [0, 0, 0])];
+        let (mut expected, mut actual) = (vec![0; src.len() / 3], vec![0; src.len() / 3]);
+        for backend in vector_backends() {
+            for (lower, higher) in hsv_ranges {
+                in_range_hsv(Backend::Scalar, &src, lower, higher, &mut expected);
+                in_range_hsv(backend, &src, lower, higher, &mut actual);
+                assert!(expected == actual, "{:?} with {:?} to {:?}", backend, lower, higher);
+            }
+            for (lower, higher) in ycrcb_ranges {
+                in_range_ycrcb(Backend::Scalar, &src, lower, higher, &mut expected);
+                in_range_ycrcb(backend, &src, lower, higher, &mut actual);
+                assert!(expected == actual, "{:?} with {:?} to {:?}", backend, lower, higher);
+            }
+            for (lower, higher) in rgb_ranges {
+                in_range_rgb(Backend::Scalar, &src, lower, higher, &mut expected);
+                in_range_rgb(backend, &src, lower, higher, &mut actual);
+                assert!(expected == actual, "{:?} with {:?} to {:?}", backend, lower, higher);
+            }
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use std::arch::aarch64::*;
+use crate::util::{Hsv, YCrCb, CB_WEIGHT, CR_WEIGHT, Y_WEIGHTS};
+use crate::util::simd::{write_hsv, BLOCK};
+
+// A block is 48 bytes of interleaved RGB, which the structured loads and stores split into and
+// put back from a register per channel. Each kernel returns how many pixels it did.
+
+// The 16 bytes of `x` as 32 bit integers.
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn widen(x: uint8x16_t) -> [int32x4_t; 4] {
+    let (low, high) = (vmovl_u8(vget_low_u8(x)), vmovl_u8(vget_high_u8(x)));
+    [
+        vreinterpretq_s32_u32(vmovl_u16(vget_low_u16(low))),
+        vreinterpretq_s32_u32(vmovl_u16(vget_high_u16(low))),
+        vreinterpretq_s32_u32(vmovl_u16(vget_low_u16(high))),
+        vreinterpretq_s32_u32(vmovl_u16(vget_high_u16(high))),
+    ]
+}
+
+// Back to 16 bytes, clamping to 0 to 255.
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn narrow(x: [int32x4_t; 4]) -> uint8x16_t {
+    let low = vcombine_u16(vqmovun_s32(x[0]), vqmovun_s32(x[1]));
+    let high = vcombine_u16(vqmovun_s32(x[2]), vqmovun_s32(x[3]));
+    vcombine_u8(vqmovn_u16(low), vqmovn_u16(high))
+}
+
+// Masks of set bits down to masks of set bytes.
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn narrow_masks(x: [uint32x4_t; 4]) -> uint8x16_t {
+    let low = vcombine_u16(vmovn_u32(x[0]), vmovn_u32(x[1]));
+    let high = vcombine_u16(vmovn_u32(x[2]), vmovn_u32(x[3]));
+    vcombine_u8(vmovn_u16(low), vmovn_u16(high))
+}
+
+// `Hsv::from` step by step, including its divisions, so results are the same to the bit. The
+// hue's `% 6.0` is left out as it never changes anything: its operand is within -1 to 1.
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn hsv(r: int32x4_t, g: int32x4_t, b: int32x4_t) -> [float32x4_t; 3] {
+    let scale = vdupq_n_f32(255.0);
+    let r = vdivq_f32(vcvtq_f32_s32(r), scale);
+    let g = vdivq_f32(vcvtq_f32_s32(g), scale);
+    let b = vdivq_f32(vcvtq_f32_s32(b), scale);
+    let max = vmaxq_f32(vmaxq_f32(r, g), b);
+    let min = vminq_f32(vminq_f32(r, g), b);
+    let delta = vsubq_f32(max, min);
+    let sixty = vdupq_n_f32(60.0);
+    let from_r = vmulq_f32(sixty, vdivq_f32(vsubq_f32(g, b), delta));
+    let from_g = vmulq_f32(sixty, vaddq_f32(vdivq_f32(vsubq_f32(b, r), delta), vdupq_n_f32(2.0)));
+    let from_b = vmulq_f32(sixty, vaddq_f32(vdivq_f32(vsubq_f32(r, g), delta), vdupq_n_f32(4.0)));
+    let zero = vdupq_n_f32(0.0);
+    let h = vbslq_f32(vceqq_f32(max, g), from_g, from_b);
+    let h = vbslq_f32(vceqq_f32(max, r), from_r, h);
+    let h = vbslq_f32(vceqq_f32(delta, zero), zero, h);
+    let s = vbslq_f32(vceqq_f32(max, zero), zero, vdivq_f32(delta, max));
+    [h, s, max]
+}
+
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn within(x: float32x4_t, lower: f32, higher: f32) -> uint32x4_t {
+    vandq_u32(vcgeq_f32(x, vdupq_n_f32(lower)), vcleq_f32(x, vdupq_n_f32(higher)))
+}
+
+// `hsv_in_range` as a mask. Hues from `hsv` are within -60 to 300, so wrapping ranges only have
+// to move the negative ones up a turn.
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn hsv_in_range([h, s, v]: [float32x4_t; 3], lower: Hsv, higher: Hsv) -> uint32x4_t {
+    let turn = vdupq_n_f32(360.0);
+    let hue = if lower.h <= higher.h {
+        vorrq_u32(within(h, lower.h, higher.h), within(vaddq_f32(h, turn), lower.h, higher.h))
+    } else {
+        let h = vbslq_f32(vcltq_f32(h, vdupq_n_f32(0.0)), vaddq_f32(h, turn), h);
+        vorrq_u32(vcgeq_f32(h, vdupq_n_f32(lower.h)), vcleq_f32(h, vdupq_n_f32(higher.h)))
+    };
+    vandq_u32(hue, vandq_u32(within(s, lower.s, higher.s), within(v, lower.v, higher.v)))
+}
+
+// Y, Cr and Cb of a block, a register each, in the same fixed point as `YCrCb::from`.
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn ycrcb(block: &[u8]) -> uint8x16x3_t {
+    let pixels = vld3q_u8(block.as_ptr());
+    let (r, g, b) = (widen(pixels.0), widen(pixels.1), widen(pixels.2));
+    let (round, offset) = (vdupq_n_s32(1 << 13), vdupq_n_s32((128 << 14) + (1 << 13)));
+    let mut channels = [[vdupq_n_s32(0); 4]; 3];
+    for i in 0..4 {
+        let y = vaddq_s32(vmulq_n_s32(r[i], Y_WEIGHTS[0]), vmulq_n_s32(g[i], Y_WEIGHTS[1]));
+        let y = vshrq_n_s32::<14>(vaddq_s32(vaddq_s32(y, vmulq_n_s32(b[i], Y_WEIGHTS[2])), round));
+        channels[0][i] = y;
+        channels[1][i] = vshrq_n_s32::<14>(vaddq_s32(vmulq_n_s32(vsubq_s32(r[i], y), CR_WEIGHT), offset));
+        channels[2][i] = vshrq_n_s32::<14>(vaddq_s32(vmulq_n_s32(vsubq_s32(b[i], y), CB_WEIGHT), offset));
+    }
+    uint8x16x3_t(narrow(channels[0]), narrow(channels[1]), narrow(channels[2]))
+}
+
+// 255 where every channel is within its range.
+#[inline]
+#[target_feature(enable = "neon")]
+unsafe fn within_u8(channels: uint8x16x3_t, lower: [u8; 3], higher: [u8; 3]) -> uint8x16_t {
+    let inside = |x, lower, higher| vandq_u8(vcgeq_u8(x, vdupq_n_u8(lower)), vcleq_u8(x, vdupq_n_u8(higher)));
+    vandq_u8(
+        inside(channels.0, lower[0], higher[0]),
+        vandq_u8(inside(channels.1, lower[1], higher[1]), inside(channels.2, lower[2], higher[2])),
+    )
+}
+
+fn blocks<'a, T>(src: &'a [u8], dst: &'a mut [T], per_pixel: usize) -> impl Iterator<Item = (&'a [u8], &'a mut [T])> {
+    src.chunks_exact(3 * BLOCK).zip(dst.chunks_exact_mut(per_pixel * BLOCK))
+}
+
+#[target_feature(enable = "neon")]
+pub(super) unsafe fn rgb_to_hsv(src: &[u8], dst: &mut [Hsv]) -> usize {
+    let mut done = 0;
+    for (block, out) in blocks(src, dst, 1) {
+        let pixels = vld3q_u8(block.as_ptr());
+        let (r, g, b) = (widen(pixels.0), widen(pixels.1), widen(pixels.2));
+        let (mut h, mut s, mut v) = ([0.0; BLOCK], [0.0; BLOCK], [0.0; BLOCK]);
+        for i in 0..4 {
+            let [hue, saturation, value] = hsv(r[i], g[i], b[i]);
+            vst1q_f32(h[4 * i..].as_mut_ptr(), hue);
+            vst1q_f32(s[4 * i..].as_mut_ptr(), saturation);
+            vst1q_f32(v[4 * i..].as_mut_ptr(), value);
+        }
+        write_hsv(&h, &s, &v, out);
+        done += BLOCK;
+    }
+    done
+}
+
+#[target_feature(enable = "neon")]
+pub(super) unsafe fn in_range_hsv(src: &[u8], lower: Hsv, higher: Hsv, dst: &mut [u8]) -> usize {
+    let mut done = 0;
+    for (block, out) in blocks(src, dst, 1) {
+        let pixels = vld3q_u8(block.as_ptr());
+        let (r, g, b) = (widen(pixels.0), widen(pixels.1), widen(pixels.2));
+        let mut matches = [vdupq_n_u32(0); 4];
+        for (i, matches) in matches.iter_mut().enumerate() {
+            *matches = hsv_in_range(hsv(r[i], g[i], b[i]), lower, higher);
+        }
+        vst1q_u8(out.as_mut_ptr(), narrow_masks(matches));
+        done += BLOCK;
+    }
+    done
+}
+
+#[target_feature(enable = "neon")]
+pub(super) unsafe fn rgb_to_ycrcb(src: &[u8], dst: &mut [u8]) -> usize {
+    let mut done = 0;
+    for (block, out) in blocks(src, dst, 3) {
+        vst3q_u8(out.as_mut_ptr(), ycrcb(block));
+        done += BLOCK;
+    }
+    done
+}
+
+#[target_feature(enable = "neon")]
+pub(super) unsafe fn in_range_ycrcb(src: &[u8], lower: YCrCb, higher: YCrCb, dst: &mut [u8]) -> usize {
+    let (lower, higher) = ([lower.y, lower.cr, lower.cb], [higher.y, higher.cr, higher.cb]);
+    let mut done = 0;
+    for (block, out) in blocks(src, dst, 1) {
+        vst1q_u8(out.as_mut_ptr(), within_u8(ycrcb(block), lower, higher));
+        done += BLOCK;
+    }
+    done
+}
+
+#[target_feature(enable = "neon")]
+pub(super) unsafe fn in_range_rgb(src: &[u8], lower: [u8; 3], higher: [u8; 3], dst: &mut [u8]) -> usize {
+    let mut done = 0;
+    for (block, out) in blocks(src, dst, 1) {
+        vst1q_u8(out.as_mut_ptr(), within_u8(vld3q_u8(block.as_ptr()), lower, higher));
+        done += BLOCK;
+    }
+    done
+}
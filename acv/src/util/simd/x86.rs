@@ -0,0 +1,384 @@
+use std::arch::x86_64::*;
+use crate::util::{Hsv, YCrCb, CB_WEIGHT, CR_WEIGHT, Y_WEIGHTS};
+use crate::util::simd::{write_hsv, BLOCK};
+
+// A block is 48 bytes of interleaved RGB, three registers, split into a register per channel
+// with byte shuffles. Each kernel returns how many pixels it did.
+
+const SPLIT: [[[i8; 16]; 3]; 3] = shuffles(false);
+const MERGE: [[[i8; 16]; 3]; 3] = shuffles(true);
+
+// Shuffles by channel and register moving each pixel's byte of a channel between the channel's
+// register, in pixel order, and its place among the interleaved registers. -1 clears a byte.
+const fn shuffles(merge: bool) -> [[[i8; 16]; 3]; 3] {
+    let mut masks = [[[-1; 16]; 3]; 3];
+    let mut channel = 0;
+    while channel < 3 {
+        let mut pixel = 0;
+        while pixel < 16 {
+            let byte = 3 * pixel + channel;
+            if merge {
+                masks[channel][byte / 16][byte % 16] = pixel as i8;
+            } else {
+                masks[channel][byte / 16][pixel] = (byte % 16) as i8;
+            }
+            pixel += 1;
+        }
+        channel += 1;
+    }
+    masks
+}
+
+#[inline]
+#[target_feature(enable = "sse4.1")]
+unsafe fn split(block: &[u8]) -> [__m128i; 3] {
+    let registers = [
+        _mm_loadu_si128(block.as_ptr() as *const __m128i),
+        _mm_loadu_si128(block.as_ptr().add(16) as *const __m128i),
+        _mm_loadu_si128(block.as_ptr().add(32) as *const __m128i),
+    ];
+    let mut channels = [_mm_setzero_si128(); 3];
+    for (channel, masks) in channels.iter_mut().zip(&SPLIT) {
+        for (register, mask) in registers.iter().zip(masks) {
+            let mask = _mm_loadu_si128(mask.as_ptr() as *const __m128i);
+            *channel = _mm_or_si128(*channel, _mm_shuffle_epi8(*register, mask));
+        }
+    }
+    channels
+}
+
+#[inline]
+#[target_feature(enable = "sse4.1")]
+unsafe fn merge(channels: [__m128i; 3], block: &mut [u8]) {
+    for (i, out) in block.chunks_exact_mut(16).enumerate() {
+        let mut register = _mm_setzero_si128();
+        for (channel, masks) in channels.iter().zip(&MERGE) {
+            let mask = _mm_loadu_si128(masks[i].as_ptr() as *const __m128i);
+            register = _mm_or_si128(register, _mm_shuffle_epi8(*channel, mask));
+        }
+        _mm_storeu_si128(out.as_mut_ptr() as *mut __m128i, register);
+    }
+}
+
+// The 16 bytes of `x` as 32 bit integers.
+#[inline]
+#[target_feature(enable = "sse4.1")]
+unsafe fn widen_sse41(x: __m128i) -> [__m128i; 4] {
+    [
+        _mm_cvtepu8_epi32(x),
+        _mm_cvtepu8_epi32(_mm_srli_si128::<4>(x)),
+        _mm_cvtepu8_epi32(_mm_srli_si128::<8>(x)),
+        _mm_cvtepu8_epi32(_mm_srli_si128::<12>(x)),
+    ]
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn widen_avx2(x: __m128i) -> [__m256i; 2] {
+    [_mm256_cvtepu8_epi32(x), _mm256_cvtepu8_epi32(_mm_srli_si128::<8>(x))]
+}
+
+// Back to 16 bytes, clamping to 0 to 255.
+#[inline]
+#[target_feature(enable = "sse4.1")]
+unsafe fn narrow_sse41(x: [__m128i; 4]) -> __m128i {
+    _mm_packus_epi16(_mm_packs_epi32(x[0], x[1]), _mm_packs_epi32(x[2], x[3]))
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn narrow_avx2(x: [__m256i; 2]) -> __m128i {
+    narrow_sse41([
+        _mm256_castsi256_si128(x[0]),
+        _mm256_extracti128_si256::<1>(x[0]),
+        _mm256_castsi256_si128(x[1]),
+        _mm256_extracti128_si256::<1>(x[1]),
+    ])
+}
+
+// `Hsv::from` step by step, including its divisions, so results are the same to the bit. The
+// hue's `% 6.0` is left out as it never changes anything: its operand is within -1 to 1.
+#[inline]
+#[target_feature(enable = "sse4.1")]
+unsafe fn hsv_sse41(r: __m128i, g: __m128i, b: __m128i) -> [__m128; 3] {
+    let scale = _mm_set1_ps(255.0);
+    let r = _mm_div_ps(_mm_cvtepi32_ps(r), scale);
+    let g = _mm_div_ps(_mm_cvtepi32_ps(g), scale);
+    let b = _mm_div_ps(_mm_cvtepi32_ps(b), scale);
+    let max = _mm_max_ps(_mm_max_ps(r, g), b);
+    let min = _mm_min_ps(_mm_min_ps(r, g), b);
+    let delta = _mm_sub_ps(max, min);
+    let sixty = _mm_set1_ps(60.0);
+    let from_r = _mm_mul_ps(sixty, _mm_div_ps(_mm_sub_ps(g, b), delta));
+    let from_g = _mm_mul_ps(sixty, _mm_add_ps(_mm_div_ps(_mm_sub_ps(b, r), delta), _mm_set1_ps(2.0)));
+    let from_b = _mm_mul_ps(sixty, _mm_add_ps(_mm_div_ps(_mm_sub_ps(r, g), delta), _mm_set1_ps(4.0)));
+    let zero = _mm_setzero_ps();
+    let h = _mm_blendv_ps(from_b, from_g, _mm_cmpeq_ps(max, g));
+    let h = _mm_blendv_ps(h, from_r, _mm_cmpeq_ps(max, r));
+    let h = _mm_blendv_ps(h, zero, _mm_cmpeq_ps(delta, zero));
+    let s = _mm_blendv_ps(_mm_div_ps(delta, max), zero, _mm_cmpeq_ps(max, zero));
+    [h, s, max]
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn hsv_avx2(r: __m256i, g: __m256i, b: __m256i) -> [__m256; 3] {
+    let scale = _mm256_set1_ps(255.0);
+    let r = _mm256_div_ps(_mm256_cvtepi32_ps(r), scale);
+    let g = _mm256_div_ps(_mm256_cvtepi32_ps(g), scale);
+    let b = _mm256_div_ps(_mm256_cvtepi32_ps(b), scale);
+    let max = _mm256_max_ps(_mm256_max_ps(r, g), b);
+    let min = _mm256_min_ps(_mm256_min_ps(r, g), b);
+    let delta = _mm256_sub_ps(max, min);
+    let sixty = _mm256_set1_ps(60.0);
+    let from_r = _mm256_mul_ps(sixty, _mm256_div_ps(_mm256_sub_ps(g, b), delta));
+    let from_g = _mm256_mul_ps(sixty, _mm256_add_ps(_mm256_div_ps(_mm256_sub_ps(b, r), delta), _mm256_set1_ps(2.0)));
+    let from_b = _mm256_mul_ps(sixty, _mm256_add_ps(_mm256_div_ps(_mm256_sub_ps(r, g), delta), _mm256_set1_ps(4.0)));
+    let zero = _mm256_setzero_ps();
+    let h = _mm256_blendv_ps(from_b, from_g, _mm256_cmp_ps::<_CMP_EQ_OQ>(max, g));
+    let h = _mm256_blendv_ps(h, from_r, _mm256_cmp_ps::<_CMP_EQ_OQ>(max, r));
+    let h = _mm256_blendv_ps(h, zero, _mm256_cmp_ps::<_CMP_EQ_OQ>(delta, zero));
+    let s = _mm256_blendv_ps(_mm256_div_ps(delta, max), zero, _mm256_cmp_ps::<_CMP_EQ_OQ>(max, zero));
+    [h, s, max]
+}
+
+#[inline]
+#[target_feature(enable = "sse4.1")]
+unsafe fn within_sse41(x: __m128, lower: f32, higher: f32) -> __m128 {
+    _mm_and_ps(_mm_cmpge_ps(x, _mm_set1_ps(lower)), _mm_cmple_ps(x, _mm_set1_ps(higher)))
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn within_avx2(x: __m256, lower: f32, higher: f32) -> __m256 {
+    _mm256_and_ps(
+        _mm256_cmp_ps::<_CMP_GE_OQ>(x, _mm256_set1_ps(lower)),
+        _mm256_cmp_ps::<_CMP_LE_OQ>(x, _mm256_set1_ps(higher)),
+    )
+}
+
+// `hsv_in_range` as a mask of 255s. Hues from `hsv_sse41` are within -60 to 300, so wrapping
+// ranges only have to move the negative ones up a turn.
+#[inline]
+#[target_feature(enable = "sse4.1")]
+unsafe fn hsv_in_range_sse41([h, s, v]: [__m128; 3], lower: Hsv, higher: Hsv) -> __m128i {
+    let turn = _mm_set1_ps(360.0);
+    let hue = if lower.h <= higher.h {
+        _mm_or_ps(within_sse41(h, lower.h, higher.h), within_sse41(_mm_add_ps(h, turn), lower.h, higher.h))
+    } else {
+        let h = _mm_blendv_ps(h, _mm_add_ps(h, turn), _mm_cmplt_ps(h, _mm_setzero_ps()));
+        _mm_or_ps(_mm_cmpge_ps(h, _mm_set1_ps(lower.h)), _mm_cmple_ps(h, _mm_set1_ps(higher.h)))
+    };
+    let matches = _mm_and_ps(hue, _mm_and_ps(within_sse41(s, lower.s, higher.s), within_sse41(v, lower.v, higher.v)));
+    _mm_srli_epi32::<24>(_mm_castps_si128(matches))
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn hsv_in_range_avx2([h, s, v]: [__m256; 3], lower: Hsv, higher: Hsv) -> __m256i {
+    let turn = _mm256_set1_ps(360.0);
+    let hue = if lower.h <= higher.h {
+        _mm256_or_ps(within_avx2(h, lower.h, higher.h), within_avx2(_mm256_add_ps(h, turn), lower.h, higher.h))
+    } else {
+        let negative = _mm256_cmp_ps::<_CMP_LT_OQ>(h, _mm256_setzero_ps());
+        let h = _mm256_blendv_ps(h, _mm256_add_ps(h, turn), negative);
+        _mm256_or_ps(
+            _mm256_cmp_ps::<_CMP_GE_OQ>(h, _mm256_set1_ps(lower.h)),
+            _mm256_cmp_ps::<_CMP_LE_OQ>(h, _mm256_set1_ps(higher.h)),
+        )
+    };
+    let matches = _mm256_and_ps(hue, _mm256_and_ps(within_avx2(s, lower.s, higher.s), within_avx2(v, lower.v, higher.v)));
+    _mm256_srli_epi32::<24>(_mm256_castps_si256(matches))
+}
+
+// `YCrCb::from` in the same fixed point, before clamping.
+#[inline]
+#[target_feature(enable = "sse4.1")]
+unsafe fn ycrcb_sse41(r: __m128i, g: __m128i, b: __m128i) -> [__m128i; 3] {
+    let weigh = |x, weight| _mm_mullo_epi32(x, _mm_set1_epi32(weight));
+    let y = _mm_add_epi32(_mm_add_epi32(weigh(r, Y_WEIGHTS[0]), weigh(g, Y_WEIGHTS[1])), weigh(b, Y_WEIGHTS[2]));
+    let y = _mm_srai_epi32::<14>(_mm_add_epi32(y, _mm_set1_epi32(1 << 13)));
+    let offset = _mm_set1_epi32((128 << 14) + (1 << 13));
+    let cr = _mm_srai_epi32::<14>(_mm_add_epi32(weigh(_mm_sub_epi32(r, y), CR_WEIGHT), offset));
+    let cb = _mm_srai_epi32::<14>(_mm_add_epi32(weigh(_mm_sub_epi32(b, y), CB_WEIGHT), offset));
+    [y, cr, cb]
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn ycrcb_avx2(r: __m256i, g: __m256i, b: __m256i) -> [__m256i; 3] {
+    let weigh = |x, weight| _mm256_mullo_epi32(x, _mm256_set1_epi32(weight));
+    let y = _mm256_add_epi32(_mm256_add_epi32(weigh(r, Y_WEIGHTS[0]), weigh(g, Y_WEIGHTS[1])), weigh(b, Y_WEIGHTS[2]));
+    let y = _mm256_srai_epi32::<14>(_mm256_add_epi32(y, _mm256_set1_epi32(1 << 13)));
+    let offset = _mm256_set1_epi32((128 << 14) + (1 << 13));
+    let cr = _mm256_srai_epi32::<14>(_mm256_add_epi32(weigh(_mm256_sub_epi32(r, y), CR_WEIGHT), offset));
+    let cb = _mm256_srai_epi32::<14>(_mm256_add_epi32(weigh(_mm256_sub_epi32(b, y), CB_WEIGHT), offset));
+    [y, cr, cb]
+}
+
+// Y, Cr and Cb of a block, a register each.
+#[inline]
+#[target_feature(enable = "sse4.1")]
+unsafe fn ycrcb_block_sse41(block: &[u8]) -> [__m128i; 3] {
+    let [r, g, b] = split(block).map(|c| widen_sse41(c));
+    let mut channels = [[_mm_setzero_si128(); 4]; 3];
+    for i in 0..4 {
+        let [y, cr, cb] = ycrcb_sse41(r[i], g[i], b[i]);
+        channels[0][i] = y;
+        channels[1][i] = cr;
+        channels[2][i] = cb;
+    }
+    channels.map(|c| narrow_sse41(c))
+}
+
+#[inline]
+#[target_feature(enable = "avx2")]
+unsafe fn ycrcb_block_avx2(block: &[u8]) -> [__m128i; 3] {
+    let [r, g, b] = split(block).map(|c| widen_avx2(c));
+    let mut channels = [[_mm256_setzero_si256(); 2]; 3];
+    for i in 0..2 {
+        let [y, cr, cb] = ycrcb_avx2(r[i], g[i], b[i]);
+        channels[0][i] = y;
+        channels[1][i] = cr;
+        channels[2][i] = cb;
+    }
+    channels.map(|c| narrow_avx2(c))
+}
+
+// 255 where every channel is within its range.
+#[inline]
+#[target_feature(enable = "sse4.1")]
+unsafe fn within_u8(channels: [__m128i; 3], lower: [u8; 3], higher: [u8; 3]) -> __m128i {
+    let mut matches = _mm_set1_epi8(-1);
+    for ((x, lower), higher) in channels.into_iter().zip(lower).zip(higher) {
+        let above = _mm_cmpeq_epi8(_mm_max_epu8(x, _mm_set1_epi8(lower as i8)), x);
+        let below = _mm_cmpeq_epi8(_mm_min_epu8(x, _mm_set1_epi8(higher as i8)), x);
+        matches = _mm_and_si128(matches, _mm_and_si128(above, below));
+    }
+    matches
+}
+
+fn blocks<'a, T>(src: &'a [u8], dst: &'a mut [T], per_pixel: usize) -> impl Iterator<Item = (&'a [u8], &'a mut [T])> {
+    src.chunks_exact(3 * BLOCK).zip(dst.chunks_exact_mut(per_pixel * BLOCK))
+}
+
+#[target_feature(enable = "sse4.1")]
+pub(super) unsafe fn rgb_to_hsv_sse41(src: &[u8], dst: &mut [Hsv]) -> usize {
+    let mut done = 0;
+    for (block, out) in blocks(src, dst, 1) {
+        let [r, g, b] = split(block).map(|c| widen_sse41(c));
+        let (mut h, mut s, mut v) = ([0.0; BLOCK], [0.0; BLOCK], [0.0; BLOCK]);
+        for i in 0..4 {
+            let [hue, saturation, value] = hsv_sse41(r[i], g[i], b[i]);
+            _mm_storeu_ps(h[4 * i..].as_mut_ptr(), hue);
+            _mm_storeu_ps(s[4 * i..].as_mut_ptr(), saturation);
+            _mm_storeu_ps(v[4 * i..].as_mut_ptr(), value);
+        }
+        write_hsv(&h, &s, &v, out);
+        done += BLOCK;
+    }
+    done
+}
+
+#[target_feature(enable = "avx2")]
+pub(super) unsafe fn rgb_to_hsv_avx2(src: &[u8], dst: &mut [Hsv]) -> usize {
+    let mut done = 0;
+    for (block, out) in blocks(src, dst, 1) {
+        let [r, g, b] = split(block).map(|c| widen_avx2(c));
+        let (mut h, mut s, mut v) = ([0.0; BLOCK], [0.0; BLOCK], [0.0; BLOCK]);
+        for i in 0..2 {
+            let [hue, saturation, value] = hsv_avx2(r[i], g[i], b[i]);
+            _mm256_storeu_ps(h[8 * i..].as_mut_ptr(), hue);
+            _mm256_storeu_ps(s[8 * i..].as_mut_ptr(), saturation);
+            _mm256_storeu_ps(v[8 * i..].as_mut_ptr(), value);
+        }
+        write_hsv(&h, &s, &v, out);
+        done += BLOCK;
+    }
+    done
+}
+
+#[target_feature(enable = "sse4.1")]
+pub(super) unsafe fn in_range_hsv_sse41(src: &[u8], lower: Hsv, higher: Hsv, dst: &mut [u8]) -> usize {
+    let mut done = 0;
+    for (block, out) in blocks(src, dst, 1) {
+        let [r, g, b] = split(block).map(|c| widen_sse41(c));
+        let mut matches = [_mm_setzero_si128(); 4];
+        for (i, matches) in matches.iter_mut().enumerate() {
+            *matches = hsv_in_range_sse41(hsv_sse41(r[i], g[i], b[i]), lower, higher);
+        }
+        _mm_storeu_si128(out.as_mut_ptr() as *mut __m128i, narrow_sse41(matches));
+        done += BLOCK;
+    }
+    done
+}
+
+#[target_feature(enable = "avx2")]
+pub(super) unsafe fn in_range_hsv_avx2(src: &[u8], lower: Hsv, higher: Hsv, dst: &mut [u8]) -> usize {
+    let mut done = 0;
+    for (block, out) in blocks(src, dst, 1) {
+        let [r, g, b] = split(block).map(|c| widen_avx2(c));
+        let mut matches = [_mm256_setzero_si256(); 2];
+        for (i, matches) in matches.iter_mut().enumerate() {
+            *matches = hsv_in_range_avx2(hsv_avx2(r[i], g[i], b[i]), lower, higher);
+        }
+        _mm_storeu_si128(out.as_mut_ptr() as *mut __m128i, narrow_avx2(matches));
+        done += BLOCK;
+    }
+    done
+}
+
+#[target_feature(enable = "sse4.1")]
+pub(super) unsafe fn rgb_to_ycrcb_sse41(src: &[u8], dst: &mut [u8]) -> usize {
+    let mut done = 0;
+    for (block, out) in blocks(src, dst, 3) {
+        merge(ycrcb_block_sse41(block), out);
+        done += BLOCK;
+    }
+    done
+}
+
+#[target_feature(enable = "avx2")]
+pub(super) unsafe fn rgb_to_ycrcb_avx2(src: &[u8], dst: &mut [u8]) -> usize {
+    let mut done = 0;
+    for (block, out) in blocks(src, dst, 3) {
+        merge(ycrcb_block_avx2(block), out);
+        done += BLOCK;
+    }
+    done
+}
+
+#[target_feature(enable = "sse4.1")]
+pub(super) unsafe fn in_range_ycrcb_sse41(src: &[u8], lower: YCrCb, higher: YCrCb, dst: &mut [u8]) -> usize {
+    let (lower, higher) = ([lower.y, lower.cr, lower.cb], [higher.y, higher.cr, higher.cb]);
+    let mut done = 0;
+    for (block, out) in blocks(src, dst, 1) {
+        let matches = within_u8(ycrcb_block_sse41(block), lower, higher);
+        _mm_storeu_si128(out.as_mut_ptr() as *mut __m128i, matches);
+        done += BLOCK;
+    }
+    done
+}
+
+#[target_feature(enable = "avx2")]
+pub(super) unsafe fn in_range_ycrcb_avx2(src: &[u8], lower: YCrCb, higher: YCrCb, dst: &mut [u8]) -> usize {
+    let (lower, higher) = ([lower.y, lower.cr, lower.cb], [higher.y, higher.cr, higher.cb]);
+    let mut done = 0;
+    for (block, out) in blocks(src, dst, 1) {
+        let matches = within_u8(ycrcb_block_avx2(block), lower, higher);
+        _mm_storeu_si128(out.as_mut_ptr() as *mut __m128i, matches);
+        done += BLOCK;
+    }
+    done
+}
+
+#[target_feature(enable = "sse4.1")]
+pub(super) unsafe fn in_range_rgb_sse41(src: &[u8], lower: [u8; 3], higher: [u8; 3], dst: &mut [u8]) -> usize {
+    let mut done = 0;
+    for (block, out) in blocks(src, dst, 1) {
+        let matches = within_u8(split(block), lower, higher);
+        _mm_storeu_si128(out.as_mut_ptr() as *mut __m128i, matches);
+        done += BLOCK;
+    }
+    done
+}